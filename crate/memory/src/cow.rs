@@ -1,9 +1,12 @@
 //! Shared memory & Copy-on-write extension for page table
 //!
-//! To use the CowExt, make a wrapper over the original apge table
-//! Like: CowExt::new(origin_page_table)
-//! Invoke page_fault_handler() on the CowExt to run the COW process
+//! The CowExt keeps the reference counts of frames shared by pages of any page table,
+//! and `COW` is the one of the system, used by memory handlers to clone pages on fork.
+//! Invoke share() on a mapped page to share its frame, and map other pages to the frame
+//! followed by share() on each of them.
+//! Invoke page_fault_handler() on the page fault of a shared page to run the COW process.
 //! If the method above returns true, the COW process is executed, else do your own things.
+//! Invoke remove_shared() before a page is unmapped, and deallocate its frame only if it returns false.
 //!
 //! To implement the CowExt, we added a "shared state" to the page table entry
 //! We use 2bits in the entry for "readonly and shared" and "writable and shared"
@@ -23,124 +26,165 @@
 use super::paging::*;
 use super::*;
 use alloc::collections::BTreeMap;
-use core::ops::{Deref, DerefMut};
+use spin::Mutex;
 
-/// Wrapper for page table, supporting shared map & copy-on-write
-pub struct CowExt<T: PageTable> {
-    page_table: T,
+/// The frames shared copy-on-write by all page tables
+pub static COW: Mutex<CowExt> = Mutex::new(CowExt::new());
+
+/// Reference counts of shared frames, supporting shared map & copy-on-write
+#[derive(Default)]
+pub struct CowExt {
     rc_map: FrameRcMap,
 }
 
-impl<T: PageTable> CowExt<T> {
+impl CowExt {
     /*
      **  @brief  create a COW extension
-     **  @param  page_table: T        the inner page table
      **  @retval CowExt               the COW extension created
      */
-    pub fn new(page_table: T) -> Self {
+    pub const fn new() -> Self {
         CowExt {
-            page_table,
-            rc_map: FrameRcMap::default(),
+            rc_map: FrameRcMap(None),
         }
     }
     /*
-     **  @brief  map the virtual address to a target physics address as shared
-     **  @param  addr: VirtAddr       the virual address to map
-     **  @param  target: VirtAddr     the target physics address
+     **  @brief  share the frame of a mapped page, if it is not shared yet
+     **  @param  pt: &mut dyn PageTable
+     **                               the page table of the page
+     **  @param  addr: VirtAddr       the virual address of the page
      **  @param  writable: bool       if it is true, set the page as writable and shared
      **                               else set the page as readonly and shared
-     **  @retval none
+     **  @retval bool                 whether the page is shared,
+     **                               false if the page table can not mark shared pages
      */
-    pub fn map_to_shared(&mut self, addr: VirtAddr, target: PhysAddr, writable: bool) {
-        let entry = self.page_table.map(addr, target);
-        entry.set_writable(false);
+    pub fn share(&mut self, pt: &mut dyn PageTable, addr: VirtAddr, writable: bool) -> bool {
+        let entry = pt.get_entry(addr).expect("entry not exist");
+        if entry.readonly_shared() || entry.writable_shared() {
+            return true;
+        }
         entry.set_shared(writable);
+        if !entry.readonly_shared() && !entry.writable_shared() {
+            return false;
+        }
+        entry.set_writable(false);
         entry.update();
-        let frame = target / PAGE_SIZE;
+        let frame = entry.target() / PAGE_SIZE;
+        // the page table may be active on other CPUs, which must fault on writes too
+        pt.flush_tlb_remote(addr);
         match writable {
             true => self.rc_map.write_increase(&frame),
             false => self.rc_map.read_increase(&frame),
         }
+        true
     }
     /*
-     **  @brief  unmap a virual address from physics address
-     **          with apecial additional process for shared page
-     **  @param  addr: VirtAddr       the virual address to unmap
-     **  @retval none
+     **  @brief  drop the reference of a page to its frame before it is unmapped
+     **  @param  pt: &mut dyn PageTable
+     **                               the page table of the page
+     **  @param  addr: VirtAddr       the virual address of the page
+     **  @retval bool                 whether the frame is still shared by other pages
      */
-    pub fn unmap_shared(&mut self, addr: VirtAddr) {
-        let entry = self.page_table.get_entry(addr).expect("entry not exist");
+    pub fn remove_shared(&mut self, pt: &mut dyn PageTable, addr: VirtAddr) -> bool {
+        let entry = pt.get_entry(addr).expect("entry not exist");
         let frame = entry.target() / PAGE_SIZE;
         if entry.readonly_shared() {
             self.rc_map.read_decrease(&frame);
         } else if entry.writable_shared() {
             self.rc_map.write_decrease(&frame);
+        } else {
+            return false;
         }
-        self.page_table.unmap(addr);
+        entry.clear_shared();
+        entry.update();
+        self.rc_map.read_count(&frame) + self.rc_map.write_count(&frame) != 0
     }
     /*
      **  @brief  execute the COW process for page fault
      **          This function must be called whenever PageFault happens.
+     **  @param  pt: &mut dyn PageTable
+     **                               the page table of the page fault
      **  @param  addr: VirtAddr       the virual address of the page fault
-     **  @param  alloc_frame: impl FnOnce() -> PhysAddr
+     **  @param  alloc_frame: impl FnOnce() -> Option<PhysAddr>
      **                               the page allocation function
      **                               that allocate a page and returns physics address
-     **                               of beginning of the page
-     **  @retval bool                 whether copy-on-write happens.
+     **                               of beginning of the page, if there is one left
+     **  @retval bool                 whether copy-on-write happens,
+     **                               false if no frame is left to copy the page
      */
     pub fn page_fault_handler(
         &mut self,
+        pt: &mut dyn PageTable,
         addr: VirtAddr,
-        alloc_frame: impl FnOnce() -> PhysAddr,
+        alloc_frame: impl FnOnce() -> Option<PhysAddr>,
     ) -> bool {
-        let entry = self.page_table.get_entry(addr);
-        if entry.is_none() {
-            return false;
+        match pt.get_entry(addr) {
+            Some(entry) if entry.present() && entry.writable_shared() => {}
+            _ => return false,
         }
-        let entry = entry.unwrap();
-        if !entry.readonly_shared() && !entry.writable_shared() {
+        if !self.unshare(pt, addr, alloc_frame) {
             return false;
         }
+        let entry = pt.get_entry(addr).unwrap();
+        entry.set_writable(true);
+        entry.update();
+        true
+    }
+    /*
+     **  @brief  give a shared page a frame of its own, keeping it readonly
+     **          The frame is copied unless the page is its last reference.
+     **  @param  pt: &mut dyn PageTable
+     **                               the page table of the page
+     **  @param  addr: VirtAddr       the virual address of the page
+     **  @param  alloc_frame: impl FnOnce() -> Option<PhysAddr>
+     **                               the page allocation function, as of page_fault_handler()
+     **  @retval bool                 whether the page is not shared any more,
+     **                               false if it is not present or no frame is left
+     */
+    pub fn unshare(
+        &mut self,
+        pt: &mut dyn PageTable,
+        addr: VirtAddr,
+        alloc_frame: impl FnOnce() -> Option<PhysAddr>,
+    ) -> bool {
+        let entry = match pt.get_entry(addr) {
+            Some(entry) if entry.present() => entry,
+            _ => return false,
+        };
+        let writable = match (entry.readonly_shared(), entry.writable_shared()) {
+            (true, _) => false,
+            (_, true) => true,
+            _ => return true,
+        };
         let frame = entry.target() / PAGE_SIZE;
-        if self.rc_map.read_count(&frame) == 0 && self.rc_map.write_count(&frame) == 1 {
+        if self.rc_map.read_count(&frame) + self.rc_map.write_count(&frame) == 1 {
             entry.clear_shared();
-            entry.set_writable(true);
             entry.update();
-            self.rc_map.write_decrease(&frame);
-            return true;
+        } else {
+            let target = match alloc_frame() {
+                Some(target) => target,
+                None => return false,
+            };
+            // the old frame stays reachable after the page is mapped to the new one
+            let data = pt.get_page_slice_mut(addr);
+            let entry = pt.get_entry(addr).unwrap();
+            entry.set_target(target);
+            entry.clear_shared();
+            entry.update();
+            pt.get_page_slice_mut(addr).copy_from_slice(data);
+        }
+        match writable {
+            true => self.rc_map.write_decrease(&frame),
+            false => self.rc_map.read_decrease(&frame),
         }
-        use core::mem::MaybeUninit;
-        let mut temp_data: [u8; PAGE_SIZE] = unsafe { MaybeUninit::zeroed().assume_init() };
-        temp_data[..].copy_from_slice(self.get_page_slice_mut(addr));
-
-        self.unmap_shared(addr);
-        self.map(addr, alloc_frame());
-
-        self.get_page_slice_mut(addr)
-            .copy_from_slice(&temp_data[..]);
         true
     }
 }
 
-impl<T: PageTable> Deref for CowExt<T> {
-    type Target = T;
-
-    fn deref(&self) -> &<Self as Deref>::Target {
-        &self.page_table
-    }
-}
-
-impl<T: PageTable> DerefMut for CowExt<T> {
-    fn deref_mut(&mut self) -> &mut <Self as Deref>::Target {
-        &mut self.page_table
-    }
-}
-
 /// A map contains reference count for shared frame
 ///
 /// It will lazily construct the `BTreeMap`, to avoid heap alloc when heap is unavailable.
 #[derive(Default)]
-struct FrameRcMap(Option<BTreeMap<Frame, (u16, u16)>>);
+struct FrameRcMap(Option<BTreeMap<Frame, (usize, usize)>>);
 
 type Frame = usize;
 
@@ -148,17 +192,17 @@ impl FrameRcMap {
     /*
      **  @brief  get the read reference count of the frame
      **  @param  frame: &Frame        the frame to get the read reference count
-     **  @retval u16                  the read reference count
+     **  @retval usize                the read reference count
      */
-    fn read_count(&mut self, frame: &Frame) -> u16 {
+    fn read_count(&mut self, frame: &Frame) -> usize {
         self.map().get(frame).unwrap_or(&(0, 0)).0
     }
     /*
      **  @brief  get the write reference count of the frame
      **  @param  frame: &Frame        the frame to get the write reference count
-     **  @retval u16                  the write reference count
+     **  @retval usize                the write reference count
      */
-    fn write_count(&mut self, frame: &Frame) -> u16 {
+    fn write_count(&mut self, frame: &Frame) -> usize {
         self.map().get(frame).unwrap_or(&(0, 0)).1
     }
     /*
@@ -177,6 +221,7 @@ impl FrameRcMap {
      */
     fn read_decrease(&mut self, frame: &Frame) {
         self.map().get_mut(frame).unwrap().0 -= 1;
+        self.remove_unused(frame);
     }
     /*
      **  @brief  increase the write reference count of the frame
@@ -194,13 +239,24 @@ impl FrameRcMap {
      */
     fn write_decrease(&mut self, frame: &Frame) {
         self.map().get_mut(frame).unwrap().1 -= 1;
+        self.remove_unused(frame);
+    }
+    /*
+     **  @brief  forget the frame if it is no longer shared
+     **  @param  frame: &Frame        the frame to check
+     **  @retval none
+     */
+    fn remove_unused(&mut self, frame: &Frame) {
+        if self.map().get(frame) == Some(&(0, 0)) {
+            self.map().remove(frame);
+        }
     }
     /*
      **  @brief  get the internal btree map, lazily initialize the btree map if it is not present
-     **  @retval &mut BTreeMap<Frame, (u16, u16)>
+     **  @retval &mut BTreeMap<Frame, (usize, usize)>
      **                               the internal btree map
      */
-    fn map(&mut self) -> &mut BTreeMap<Frame, (u16, u16)> {
+    fn map(&mut self) -> &mut BTreeMap<Frame, (usize, usize)> {
         if self.0.is_none() {
             self.0 = Some(BTreeMap::new());
         }
@@ -210,11 +266,14 @@ impl FrameRcMap {
 
 pub mod test {
     use super::*;
+    use core::cell::RefCell;
 
     #[test]
     fn test() {
-        let mut pt = CowExt::new(MockPageTable::new());
-        let pt0 = unsafe { &mut *(&mut pt as *mut CowExt<MockPageTable>) };
+        use alloc::rc::Rc;
+
+        let cow = Rc::new(RefCell::new(CowExt::new()));
+        let mut pt = MockPageTable::new();
 
         struct FrameAlloc(usize);
         impl FrameAlloc {
@@ -226,48 +285,57 @@ pub mod test {
         }
         let mut alloc = FrameAlloc(4);
 
-        pt.page_table
-            .set_handler(Box::new(move |_, addr: VirtAddr| {
-                pt0.page_fault_handler(addr, || alloc.alloc());
-            }));
+        pt.set_handler(Box::new({
+            let cow = cow.clone();
+            move |pt: &mut MockPageTable, addr: VirtAddr| {
+                cow.borrow_mut()
+                    .page_fault_handler(pt, addr, || Some(alloc.alloc()));
+            }
+        }));
 
-        test_with(&mut pt);
+        test_with(&cow, &mut pt);
     }
 
-    pub fn test_with(pt: &mut CowExt<impl PageTable>) {
+    pub fn test_with(cow: &RefCell<CowExt>, pt: &mut impl PageTable) {
         let target = 0x0;
         let frame = 0x0;
+        let count = |cow: &RefCell<CowExt>| {
+            let mut cow = cow.borrow_mut();
+            (cow.rc_map.read_count(&frame), cow.rc_map.write_count(&frame))
+        };
 
         pt.map(0x1000, target);
         pt.write(0x1000, 1);
         assert_eq!(pt.read(0x1000), 1);
-        pt.unmap(0x1000);
 
-        pt.map_to_shared(0x1000, target, true);
-        pt.map_to_shared(0x2000, target, true);
-        pt.map_to_shared(0x3000, target, false);
-        assert_eq!(pt.rc_map.read_count(&frame), 1);
-        assert_eq!(pt.rc_map.write_count(&frame), 2);
+        assert!(cow.borrow_mut().share(pt, 0x1000, true));
+        pt.map(0x2000, target);
+        assert!(cow.borrow_mut().share(pt, 0x2000, true));
+        pt.map(0x3000, target);
+        assert!(cow.borrow_mut().share(pt, 0x3000, false));
+        assert!(
+            cow.borrow_mut().share(pt, 0x1000, true),
+            "Sharing a shared page should not count it again."
+        );
+        assert_eq!(count(cow), (1, 2));
+        assert!(!pt.get_entry(0x1000).unwrap().writable());
         assert_eq!(pt.read(0x1000), 1);
         assert_eq!(pt.read(0x2000), 1);
         assert_eq!(pt.read(0x3000), 1);
 
         pt.write(0x1000, 2);
-        assert_eq!(pt.rc_map.read_count(&frame), 1);
-        assert_eq!(pt.rc_map.write_count(&frame), 1);
+        assert_eq!(count(cow), (1, 1));
         assert_ne!(pt.get_entry(0x1000).unwrap().target(), target);
         assert_eq!(pt.read(0x1000), 2);
         assert_eq!(pt.read(0x2000), 1);
         assert_eq!(pt.read(0x3000), 1);
 
-        pt.unmap_shared(0x3000);
-        assert_eq!(pt.rc_map.read_count(&frame), 0);
-        assert_eq!(pt.rc_map.write_count(&frame), 1);
-        // assert!(!pt.get_entry(0x3000).present());
+        assert!(cow.borrow_mut().remove_shared(pt, 0x3000));
+        pt.unmap(0x3000);
+        assert_eq!(count(cow), (0, 1));
 
         pt.write(0x2000, 3);
-        assert_eq!(pt.rc_map.read_count(&frame), 0);
-        assert_eq!(pt.rc_map.write_count(&frame), 0);
+        assert_eq!(count(cow), (0, 0));
         assert_eq!(
             pt.get_entry(0x2000).unwrap().target(),
             target,
@@ -275,5 +343,38 @@ pub mod test {
         );
         assert_eq!(pt.read(0x1000), 2);
         assert_eq!(pt.read(0x2000), 3);
+        assert!(
+            !cow.borrow_mut().remove_shared(pt, 0x2000),
+            "A page no longer shared owns its frame."
+        );
+    }
+
+    #[test]
+    fn unshare() {
+        let mut cow = CowExt::new();
+        let mut pt = MockPageTable::new();
+        let (target, frame) = (0x0, 0x0);
+
+        pt.map(0x1000, target);
+        pt.write(0x1000, 1);
+        assert!(cow.share(&mut pt, 0x1000, false));
+        pt.map(0x2000, target);
+        assert!(cow.share(&mut pt, 0x2000, false));
+
+        assert!(cow.unshare(&mut pt, 0x1000, || Some(PAGE_SIZE)));
+        let entry = pt.get_entry(0x1000).unwrap();
+        assert_eq!(entry.target(), PAGE_SIZE);
+        assert!(!entry.readonly_shared() && !entry.writable());
+        assert_eq!(pt.read(0x1000), 1);
+        assert_eq!(cow.rc_map.read_count(&frame), 1);
+
+        assert!(
+            cow.unshare(&mut pt, 0x2000, || None),
+            "The last reference should keep its frame."
+        );
+        assert_eq!(pt.get_entry(0x2000).unwrap().target(), target);
+        assert!(!pt.get_entry(0x2000).unwrap().readonly_shared());
+        assert_eq!(cow.rc_map.read_count(&frame), 0);
+        assert!(cow.unshare(&mut pt, 0x2000, || None), "A private page is left alone.");
     }
 }
@@ -17,8 +17,7 @@ impl<T: FrameAllocator> MemoryHandler for ByFrame<T> {
     }
 
     fn unmap(&self, pt: &mut dyn PageTable, addr: VirtAddr) {
-        let target = pt.get_entry(addr).expect("fail to get entry").target();
-        self.allocator.dealloc(target);
+        dealloc_frame(&self.allocator, pt, addr);
         pt.unmap(addr);
     }
 
@@ -28,14 +27,23 @@ impl<T: FrameAllocator> MemoryHandler for ByFrame<T> {
         src_pt: &mut dyn PageTable,
         addr: VirtAddr,
         attr: &MemoryAttr,
-    ) {
+    ) -> bool {
+        if clone_shared(pt, src_pt, addr, attr) {
+            return true;
+        }
         self.map(pt, addr, attr);
         let data = src_pt.get_page_slice_mut(addr);
         pt.get_page_slice_mut(addr).copy_from_slice(data);
+        true
     }
 
-    fn handle_page_fault(&self, _pt: &mut dyn PageTable, _addr: VirtAddr) -> bool {
-        false
+    fn handle_page_fault_ext(
+        &self,
+        pt: &mut dyn PageTable,
+        addr: VirtAddr,
+        access: super::AccessType,
+    ) -> bool {
+        handle_cow_fault(&self.allocator, pt, addr, access)
     }
 }
 
@@ -17,12 +17,12 @@ impl<T: FrameAllocator> MemoryHandler for Delay<T> {
     }
 
     fn unmap(&self, pt: &mut dyn PageTable, addr: VirtAddr) {
-        let entry = pt.get_entry(addr).expect("failed to get entry");
-        if entry.present() {
-            self.allocator.dealloc(entry.target());
+        if pt.get_entry(addr).expect("failed to get entry").present() {
+            dealloc_frame(&self.allocator, pt, addr);
         }
 
         // PageTable::unmap requires page to be present
+        let entry = pt.get_entry(addr).expect("failed to get entry");
         entry.set_present(true);
         pt.unmap(addr);
     }
@@ -33,20 +33,27 @@ impl<T: FrameAllocator> MemoryHandler for Delay<T> {
         src_pt: &mut dyn PageTable,
         addr: VirtAddr,
         attr: &MemoryAttr,
-    ) {
+    ) -> bool {
         let entry = src_pt.get_entry(addr).expect("failed to get entry");
-        if entry.present() {
+        if !entry.present() {
+            // delay map
+            self.map(pt, addr, attr);
+        } else if !clone_shared(pt, src_pt, addr, attr) {
             // eager map and copy data
+            let target = match self.allocator.alloc() {
+                Some(target) => target,
+                None => {
+                    self.map(pt, addr, attr);
+                    return false;
+                }
+            };
             let data = src_pt.get_page_slice_mut(addr);
-            let target = self.allocator.alloc().expect("failed to alloc frame");
             let entry = pt.map(addr, target);
             attr.apply(entry);
             pt.get_page_slice_mut(addr).copy_from_slice(data);
             pt.flush_cache_copy_user(addr, addr + data.len(), attr.execute);
-        } else {
-            // delay map
-            self.map(pt, addr, attr);
         }
+        true
     }
 
     fn handle_page_fault_ext(
@@ -57,6 +64,10 @@ impl<T: FrameAllocator> MemoryHandler for Delay<T> {
     ) -> bool {
         let entry = pt.get_entry(addr).expect("failed to get entry");
         if entry.present() {
+            if handle_cow_fault(&self.allocator, pt, addr, access) {
+                return true;
+            }
+            let entry = pt.get_entry(addr).expect("failed to get entry");
             // permission check.
             if access.check_access(entry) {
                 return true;
@@ -65,7 +76,10 @@ impl<T: FrameAllocator> MemoryHandler for Delay<T> {
             error!("Permission check failed at 0x{:x}.", addr);
             return false;
         }
-        let frame = self.allocator.alloc().expect("failed to alloc frame");
+        let frame = match self.allocator.alloc() {
+            Some(frame) => frame,
+            None => return false,
+        };
         entry.set_target(frame);
         entry.set_present(true);
         entry.update();
@@ -26,12 +26,12 @@ impl<F: Read, T: FrameAllocator> MemoryHandler for File<F, T> {
     }
 
     fn unmap(&self, pt: &mut dyn PageTable, addr: usize) {
-        let entry = pt.get_entry(addr).expect("failed to get entry");
-        if entry.present() {
-            self.allocator.dealloc(entry.target());
+        if pt.get_entry(addr).expect("failed to get entry").present() {
+            dealloc_frame(&self.allocator, pt, addr);
         }
 
         // PageTable::unmap requires page to be present
+        let entry = pt.get_entry(addr).expect("failed to get entry");
         entry.set_present(true);
         pt.unmap(addr);
     }
@@ -42,12 +42,22 @@ impl<F: Read, T: FrameAllocator> MemoryHandler for File<F, T> {
         src_pt: &mut dyn PageTable,
         addr: usize,
         attr: &MemoryAttr,
-    ) {
-        let entry = src_pt.get_entry(addr).expect("failed to get entry");
-        if entry.present() && !attr.readonly {
+    ) -> bool {
+        let present = src_pt.get_entry(addr).expect("failed to get entry").present();
+        if present && clone_shared(pt, src_pt, addr, attr) {
+            // share the frame until written
+            return true;
+        }
+        if present && !attr.readonly {
             // eager map and copy data
+            let target = match self.allocator.alloc() {
+                Some(target) => target,
+                None => {
+                    self.map(pt, addr, attr);
+                    return false;
+                }
+            };
             let data = src_pt.get_page_slice_mut(addr);
-            let target = self.allocator.alloc().expect("failed to alloc frame");
             let entry = pt.map(addr, target);
             attr.apply(entry);
             pt.get_page_slice_mut(addr).copy_from_slice(data);
@@ -56,6 +66,7 @@ impl<F: Read, T: FrameAllocator> MemoryHandler for File<F, T> {
             // delay map
             self.map(pt, addr, attr);
         }
+        true
     }
 
     fn handle_page_fault_ext(
@@ -67,6 +78,10 @@ impl<F: Read, T: FrameAllocator> MemoryHandler for File<F, T> {
         let addr = addr & !(PAGE_SIZE - 1);
         let entry = pt.get_entry(addr).expect("failed to get entry");
         if entry.present() {
+            if handle_cow_fault(&self.allocator, pt, addr, access) {
+                return true;
+            }
+            let entry = pt.get_entry(addr).expect("failed to get entry");
             // permission check.
             if access.check_access(entry) {
                 return true;
@@ -79,7 +94,10 @@ impl<F: Read, T: FrameAllocator> MemoryHandler for File<F, T> {
             return false;
         }
         let execute = entry.execute();
-        let frame = self.allocator.alloc().expect("failed to alloc frame");
+        let frame = match self.allocator.alloc() {
+            Some(frame) => frame,
+            None => return false,
+        };
         entry.set_target(frame);
         entry.set_present(true);
         entry.update();
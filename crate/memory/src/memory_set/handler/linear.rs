@@ -26,8 +26,9 @@ impl MemoryHandler for Linear {
         _src_pt: &mut dyn PageTable,
         addr: VirtAddr,
         attr: &MemoryAttr,
-    ) {
+    ) -> bool {
         self.map(pt, addr, attr);
+        true
     }
 
     fn handle_page_fault(&self, _pt: &mut dyn PageTable, _addr: VirtAddr) -> bool {
@@ -1,4 +1,6 @@
 use super::*;
+use crate::cow::COW;
+
 #[derive(Copy, Clone, Debug)]
 pub struct AccessType {
     pub write: bool,
@@ -52,13 +54,14 @@ pub trait MemoryHandler: Debug + Send + Sync + 'static {
     fn unmap(&self, pt: &mut dyn PageTable, addr: VirtAddr);
 
     /// Clone map `addr` from page table `src_pt` to `pt`.
+    /// Return false if no frame is left to copy the page, which is delay mapped then.
     fn clone_map(
         &self,
         pt: &mut dyn PageTable,
         src_pt: &mut dyn PageTable,
         addr: VirtAddr,
         attr: &MemoryAttr,
-    );
+    ) -> bool;
 
     /// Handle page fault on `addr`
     /// Return true if success, false if error
@@ -90,15 +93,101 @@ pub trait FrameAllocator: Debug + Clone + Send + Sync + 'static {
     fn dealloc(&self, target: PhysAddr);
 }
 
+/// Map `addr` in `pt` to the frame of the present page `addr` in `src_pt`, copy-on-write.
+/// Return false if the page table can not mark shared pages, and nothing is mapped.
+fn clone_shared(
+    pt: &mut dyn PageTable,
+    src_pt: &mut dyn PageTable,
+    addr: VirtAddr,
+    attr: &MemoryAttr,
+) -> bool {
+    if !COW.lock().share(src_pt, addr, !attr.readonly) {
+        return false;
+    }
+    let target = src_pt.get_entry(addr).expect("failed to get entry").target();
+    // mapping may allocate frames for the page table, which must not run under COW
+    let entry = pt.map(addr, target);
+    attr.apply(entry);
+    COW.lock().share(pt, addr, !attr.readonly);
+    true
+}
+
+/// Copy the shared page `addr` on write.
+/// Return true if it was shared and is writable now.
+fn handle_cow_fault(
+    allocator: &impl FrameAllocator,
+    pt: &mut dyn PageTable,
+    addr: VirtAddr,
+    access: AccessType,
+) -> bool {
+    let addr = addr & !(PAGE_SIZE - 1);
+    let shared = match pt.get_entry(addr) {
+        Some(entry) => entry.writable_shared(),
+        None => false,
+    };
+    if !access.write || !shared {
+        return false;
+    }
+    // allocate ahead, as the allocator may swap out pages and must not run under COW
+    let mut target = allocator.alloc();
+    let copied = COW.lock().page_fault_handler(pt, addr, || target.take());
+    if let Some(target) = target {
+        // not needed by the last page sharing its frame
+        allocator.dealloc(target);
+    }
+    if !copied {
+        return false;
+    }
+    let execute = pt.get_entry(addr).expect("failed to get entry").execute();
+    pt.flush_cache_copy_user(addr, addr + PAGE_SIZE, execute);
+    true
+}
+
+/// Give the present page `addr` a frame of its own if it is shared copy-on-write,
+/// keeping its permissions, e.g. for the kernel to patch it.
+/// Return false if it is not present or no frame is left to copy it.
+pub fn unshare_page(allocator: &impl FrameAllocator, pt: &mut dyn PageTable, addr: VirtAddr) -> bool {
+    let addr = addr & !(PAGE_SIZE - 1);
+    let shared = match pt.get_entry(addr) {
+        Some(entry) if entry.present() => entry.readonly_shared() || entry.writable_shared(),
+        _ => return false,
+    };
+    if !shared {
+        return true;
+    }
+    // allocate ahead, as the allocator may swap out pages and must not run under COW
+    let mut target = allocator.alloc();
+    let unshared = COW.lock().unshare(pt, addr, || target.take());
+    if let Some(target) = target {
+        // not needed by the last page sharing its frame
+        allocator.dealloc(target);
+    }
+    if unshared {
+        let execute = pt.get_entry(addr).expect("failed to get entry").execute();
+        pt.flush_cache_copy_user(addr, addr + PAGE_SIZE, execute);
+    }
+    unshared
+}
+
+/// Deallocate the frame of the present page `addr` before it is unmapped,
+/// unless other pages still share it.
+fn dealloc_frame(allocator: &impl FrameAllocator, pt: &mut dyn PageTable, addr: VirtAddr) {
+    if !COW.lock().remove_shared(pt, addr) {
+        let target = pt.get_entry(addr).expect("failed to get entry").target();
+        allocator.dealloc(target);
+    }
+}
+
 mod byframe;
 mod delay;
 mod file;
 mod linear;
 mod shared;
-//mod swap;
+mod swap;
 
 pub use self::byframe::ByFrame;
 pub use self::delay::Delay;
 pub use self::file::{File, Read};
 pub use self::linear::Linear;
 pub use self::shared::{Shared, SharedGuard};
+pub use self::swap::{Swap, SwapSpace};
@@ -113,11 +113,12 @@ impl<T: FrameAllocator> MemoryHandler for Shared<T> {
         _src_pt: &mut dyn PageTable,
         addr: VirtAddr,
         attr: &MemoryAttr,
-    ) {
+    ) -> bool {
         // actual map done when handling page fault, since guard are copied.
         let entry = pt.map(addr, 0);
         entry.set_present(false);
         attr.apply(entry);
+        true
     }
 
     fn handle_page_fault(&self, pt: &mut dyn PageTable, addr: VirtAddr) -> bool {
@@ -0,0 +1,137 @@
+use super::*;
+use crate::swap::SwapError;
+
+/// The swap of the system, keeping which pages of `Swap` areas are swappable
+pub trait SwapSpace: Debug + Clone + Send + Sync + 'static {
+    /// Make the page at `addr` swappable, after it is mapped to a frame
+    fn push(&self, pt: &mut dyn PageTable, addr: VirtAddr);
+
+    /// Make the page at `addr` unswappable before it is unmapped.
+    /// Return true if it was swapped out, with no frame mapped.
+    fn remove(&self, pt: &mut dyn PageTable, addr: VirtAddr) -> bool;
+
+    /// Swap in the page at `addr` if it is swapped out.
+    /// Return true if it is mapped to a frame again, false if it was not swapped out,
+    /// or the error if it can not be swapped in.
+    fn swap_in(&self, pt: &mut dyn PageTable, addr: VirtAddr) -> Result<bool, SwapError>;
+}
+
+/// Delay allocated memory whose pages may be swapped out
+#[derive(Debug, Clone)]
+pub struct Swap<T: FrameAllocator, S: SwapSpace> {
+    allocator: T,
+    swap: S,
+}
+
+impl<T: FrameAllocator, S: SwapSpace> MemoryHandler for Swap<T, S> {
+    fn box_clone(&self) -> Box<dyn MemoryHandler> {
+        Box::new(self.clone())
+    }
+
+    fn map(&self, pt: &mut dyn PageTable, addr: VirtAddr, attr: &MemoryAttr) {
+        let entry = pt.map(addr, 0);
+        entry.set_present(false);
+        attr.apply(entry);
+    }
+
+    fn unmap(&self, pt: &mut dyn PageTable, addr: VirtAddr) {
+        let swapped = self.swap.remove(pt, addr);
+        if !swapped && pt.get_entry(addr).expect("failed to get entry").present() {
+            dealloc_frame(&self.allocator, pt, addr);
+        }
+
+        // PageTable::unmap requires page to be present
+        let entry = pt.get_entry(addr).expect("failed to get entry");
+        entry.set_present(true);
+        pt.unmap(addr);
+    }
+
+    fn clone_map(
+        &self,
+        pt: &mut dyn PageTable,
+        src_pt: &mut dyn PageTable,
+        addr: VirtAddr,
+        attr: &MemoryAttr,
+    ) -> bool {
+        // bring the page back to share or copy it
+        if self.swap.swap_in(src_pt, addr).is_err() {
+            self.map(pt, addr, attr);
+            return false;
+        }
+        let entry = src_pt.get_entry(addr).expect("failed to get entry");
+        if !entry.present() {
+            // delay map
+            self.map(pt, addr, attr);
+        } else if clone_shared(pt, src_pt, addr, attr) {
+            // shared pages are not swappable until written
+            self.swap.remove(src_pt, addr);
+        } else {
+            // eager map and copy data
+            let target = match self.allocator.alloc() {
+                Some(target) => target,
+                None => {
+                    self.map(pt, addr, attr);
+                    return false;
+                }
+            };
+            let data = src_pt.get_page_slice_mut(addr);
+            let entry = pt.map(addr, target);
+            attr.apply(entry);
+            pt.get_page_slice_mut(addr).copy_from_slice(data);
+            pt.flush_cache_copy_user(addr, addr + data.len(), attr.execute);
+            self.swap.push(pt, addr);
+        }
+        true
+    }
+
+    fn handle_page_fault_ext(
+        &self,
+        pt: &mut dyn PageTable,
+        addr: VirtAddr,
+        access: super::AccessType,
+    ) -> bool {
+        let entry = pt.get_entry(addr).expect("failed to get entry");
+        if entry.present() {
+            if handle_cow_fault(&self.allocator, pt, addr, access) {
+                self.swap.push(pt, addr);
+                return true;
+            }
+            let entry = pt.get_entry(addr).expect("failed to get entry");
+            // permission check.
+            if access.check_access(entry) {
+                return true;
+            }
+            // permisison check failed.
+            error!("Permission check failed at 0x{:x}.", addr);
+            return false;
+        }
+        match self.swap.swap_in(pt, addr) {
+            Ok(true) => return true,
+            Ok(false) => {}
+            Err(_) => return false,
+        }
+        let frame = match self.allocator.alloc() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        let entry = pt.get_entry(addr).expect("failed to get entry");
+        entry.set_target(frame);
+        entry.set_present(true);
+        entry.update();
+        //init with zero for delay mmap mode
+        let data = pt.get_page_slice_mut(addr);
+        let len = data.len();
+        for x in data {
+            *x = 0;
+        }
+        pt.flush_cache_copy_user(addr, addr + len, false);
+        self.swap.push(pt, addr);
+        true
+    }
+}
+
+impl<T: FrameAllocator, S: SwapSpace> Swap<T, S> {
+    pub fn new(allocator: T, swap: S) -> Self {
+        Swap { allocator, swap }
+    }
+}
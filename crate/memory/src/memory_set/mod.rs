@@ -23,6 +23,14 @@ pub struct MemoryArea {
 }
 
 impl MemoryArea {
+    /// Get the start address of the memory area
+    pub fn start_addr(&self) -> VirtAddr {
+        self.start_addr
+    }
+    /// Get the end address of the memory area
+    pub fn end_addr(&self) -> VirtAddr {
+        self.end_addr
+    }
     /// Test whether a virtual address is in the memory area
     pub fn contains(&self, addr: VirtAddr) -> bool {
         addr >= self.start_addr && addr < self.end_addr
@@ -145,6 +153,9 @@ impl<T: PageTableExt> MemorySet<T> {
         self.check_write_array(ptr, 1).map(|s| &mut s[0])
     }
     /// Check the array is within the readable memory
+    ///
+    /// Kept out of line, so that it can be probed.
+    #[inline(never)]
     pub unsafe fn check_read_array<S>(
         &self,
         ptr: *const S,
@@ -178,12 +189,27 @@ impl<T: PageTableExt> MemorySet<T> {
     /// Return the start address of found free area.
     /// Used for mmap.
     pub fn find_free_area(&self, addr_hint: usize, len: usize) -> VirtAddr {
+        self.find_free_area_outside(addr_hint, len, 0, 0)
+    }
+    /// Find a free area like `find_free_area`, but out of [`hole_start`, `hole_end`),
+    /// which is kept free for an area to grow into, e.g. the heap.
+    pub fn find_free_area_outside(
+        &self,
+        addr_hint: usize,
+        len: usize,
+        hole_start: usize,
+        hole_end: usize,
+    ) -> VirtAddr {
         // brute force:
         // try each area's end address as the start
         core::iter::once(addr_hint)
             .chain(self.areas.iter().map(|area| area.end_addr))
+            .chain(core::iter::once(hole_end))
             .map(|addr| (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)) // round up a page
-            .find(|&addr| self.test_free_area(addr, addr + len))
+            .find(|&addr| {
+                (addr + len <= hole_start || addr >= hole_end)
+                    && self.test_free_area(addr, addr + len)
+            })
             .expect("failed to find free area ???")
     }
     /// Test if [`start_addr`, `end_addr`) is a free area
@@ -228,6 +254,30 @@ impl<T: PageTableExt> MemorySet<T> {
         self.areas.insert(idx, area);
     }
 
+    /// Extend the area ending at `end_addr` to `new_end_addr`,
+    /// and map the new pages with its handler
+    pub fn extend(&mut self, end_addr: VirtAddr, mut new_end_addr: VirtAddr) {
+        new_end_addr = (new_end_addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        assert!(end_addr < new_end_addr, "invalid memory area");
+        assert!(
+            self.test_free_area(end_addr, new_end_addr),
+            "memory area overlap"
+        );
+        let Self {
+            ref mut page_table,
+            ref mut areas,
+            ..
+        } = self;
+        let area = areas
+            .iter_mut()
+            .find(|area| area.end_addr == end_addr)
+            .expect("no memory area found");
+        for page in Page::range_of(end_addr, new_end_addr) {
+            area.handler.map(page_table, page.start_address(), &area.attr);
+        }
+        area.end_addr = new_end_addr;
+    }
+
     /// Remove the area `[start_addr, end_addr)` from `MemorySet`
     pub fn pop(&mut self, start_addr: VirtAddr, end_addr: VirtAddr) {
         assert!(start_addr <= end_addr, "invalid memory area");
@@ -333,6 +383,14 @@ impl<T: PageTableExt> MemorySet<T> {
         self.areas.iter()
     }
 
+    /// Get the size of all areas in bytes, counting whole pages
+    pub fn size(&self) -> usize {
+        self.areas
+            .iter()
+            .map(|area| Page::range_of(area.start_addr, area.end_addr).count() * PAGE_SIZE)
+            .sum()
+    }
+
     /// Execute function `f` with the associated page table
     pub unsafe fn with(&self, f: impl FnOnce()) {
         self.page_table.with(f);
@@ -393,16 +451,20 @@ impl<T: PageTableExt> MemorySet<T> {
         }
     }
 
-    pub fn clone(&mut self) -> Self {
+    /// Clone the areas to a new page table,
+    /// sharing mapped pages copy-on-write if their handler supports it.
+    /// Return None if no frame is left to copy a page.
+    pub fn clone(&mut self) -> Option<Self> {
         let mut new_page_table = T::new();
         let Self {
             ref mut page_table,
             ref areas,
             ..
         } = self;
+        let mut complete = true;
         for area in areas.iter() {
             for page in Page::range_of(area.start_addr, area.end_addr) {
-                area.handler.clone_map(
+                complete &= area.handler.clone_map(
                     &mut new_page_table,
                     page_table,
                     page.start_address(),
@@ -410,9 +472,15 @@ impl<T: PageTableExt> MemorySet<T> {
                 );
             }
         }
-        MemorySet {
+        // every page is mapped, so that a partial clone is dropped as a whole
+        let new = MemorySet {
             areas: areas.clone(),
             page_table: new_page_table,
+        };
+        if complete {
+            Some(new)
+        } else {
+            None
         }
     }
 }
@@ -128,6 +128,10 @@ impl PageTable for MockPageTable {
         let data = unsafe { &mut *(&mut self.data as *mut [u8; PAGE_SIZE * PAGE_COUNT]) };
         &mut data[pa..pa + PAGE_SIZE]
     }
+    fn flush_cache_copy_user(&mut self, _start: VirtAddr, _end: VirtAddr, _execute: bool) {}
+    fn token(&self) -> usize {
+        self as *const Self as usize
+    }
     fn read(&mut self, addr: usize) -> u8 {
         self._read(addr);
         self.data[self.translate(addr)]
@@ -29,6 +29,14 @@ pub trait PageTable {
     /// When copied user data (in page fault handler)，maybe need to flush I/D cache.
     fn flush_cache_copy_user(&mut self, start: VirtAddr, end: VirtAddr, execute: bool);
 
+    /// CR3 on x86, SATP on RISCV, TTBR on AArch64
+    fn token(&self) -> usize;
+
+    /// Flush the TLB of the page of virtual address `addr` on the other CPUs.
+    /// Call it after `Entry::update()` when a page table active elsewhere
+    /// takes away a mapping or a permission.
+    fn flush_tlb_remote(&mut self, _addr: VirtAddr) {}
+
     /// Read data from virtual address `addr`
     /// Used for testing with mock
     fn read(&mut self, _addr: VirtAddr) -> u8 {
@@ -102,8 +110,6 @@ pub trait PageTableExt: PageTable + Sized {
     /// Map kernel segments
     fn map_kernel(&mut self);
 
-    /// CR3 on x86, SATP on RISCV, TTBR on AArch64
-    fn token(&self) -> usize;
     unsafe fn set_token(token: usize);
     fn active_token() -> usize;
     fn flush_tlb();
@@ -1,76 +1,84 @@
 //! Implememnt the swap manager with the enhanced clock page replacement algorithm
 
-use alloc::collections::VecDeque;
 use super::*;
-use crate::paging::Entry;
+use alloc::collections::VecDeque;
 
 #[derive(Default)]
 pub struct EnhancedClockSwapManager {
     clock_ptr: usize,
-    deque: VecDeque<VirtAddr>,
+    deque: VecDeque<Frame>,
 }
 
-// FIXME: It's unusable. But can pass a simple test.
 impl SwapManager for EnhancedClockSwapManager {
-    fn tick(&mut self) {
-    }
+    fn tick(&mut self) {}
 
-    fn push(&mut self, addr: usize) {
-        let pos = if self.clock_ptr == 0 {self.deque.len()} else {self.clock_ptr};
-        self.deque.insert(pos, addr);
+    fn push(&mut self, frame: Frame) {
+        // right behind the clock hand, to be visited last
+        let pos = if self.clock_ptr == 0 {
+            self.deque.len()
+        } else {
+            self.clock_ptr
+        };
+        self.deque.insert(pos, frame);
+        if self.clock_ptr != 0 {
+            self.clock_ptr += 1;
+        }
     }
 
-    fn remove(&mut self, addr: usize) {
-        let id = self.deque.iter()
-            .position(|&x| x == addr)
-            .expect("address not found");
-        if id < self.clock_ptr {
-            self.clock_ptr -= 1;
+    fn remove(&mut self, token: usize, addr: VirtAddr) {
+        // the page may have been popped as a victim already
+        if let Some(id) = self
+            .deque
+            .iter()
+            .position(|x| x.get_virtaddr() == addr && x.get_token() == token)
+        {
+            if id < self.clock_ptr {
+                self.clock_ptr -= 1;
+            }
+            self.deque.remove(id);
+            if self.clock_ptr == self.deque.len() {
+                self.clock_ptr = 0;
+            }
         }
-        self.deque.remove(id);
     }
 
-    fn pop<T, S>(&mut self, page_table: &mut T, _swapper: &mut S) -> Option<VirtAddr>
-        where T: PageTable, S: Swapper
-    {
-        loop {
-            let addr = self.deque[self.clock_ptr];
-            // FIXME: Once define `slice`, all modifies of `entry` below will fail.
-            //        Then the loop will never stop.
-            //        The reason may be `get_page_slice_mut()` contains unsafe operation,
-            //        which lead the compiler to do a wrong optimization.
-//            let slice = page_table.get_page_slice_mut(addr);
-            let entry = page_table.get_entry(addr).unwrap();
-//            println!("{:#x} , {}, {}", addr, entry.accessed(), entry.dirty());
-
-            match (entry.accessed(), entry.dirty()) {
-                (true, _) => {
-                    entry.clear_accessed();
-                },
-                (false, true) => {
-                    // FIXME:
-//                    if let Ok(token) = swapper.swap_out(slice) {
-//                        entry.clear_dirty();
-//                    }
-                },
-                _ => {
+    fn pop(&mut self, visit: &mut EntryVisitor) -> Option<Frame> {
+        // Sweep for a page neither accessed nor dirty, then for a page not accessed,
+        // clearing the accessed bits on the way. After the first two sweeps
+        // every page is not accessed, so the next two find a victim,
+        // unless the owners of all pages are busy.
+        for round in 0..4 {
+            let clear_accessed = round % 2 == 1;
+            for _ in 0..self.deque.len() {
+                let frame = self.deque[self.clock_ptr];
+                let (mut accessed, mut dirty) = (true, true);
+                let visited = visit(&frame, &mut |entry| {
+                    accessed = entry.accessed();
+                    dirty = entry.dirty();
+                    if clear_accessed && accessed {
+                        entry.clear_accessed();
+                        entry.update();
+                    }
+                });
+                if visited && !accessed && (clear_accessed || !dirty) {
                     return self.remove_current();
                 }
+                self.move_next();
             }
-            self.move_next();
         }
+        None
     }
 }
 
-
 impl EnhancedClockSwapManager {
-    fn remove_current(&mut self) -> Option<VirtAddr> {
-        let addr = self.deque.remove(self.clock_ptr);
+    fn remove_current(&mut self) -> Option<Frame> {
+        let frame = self.deque.remove(self.clock_ptr);
         if self.clock_ptr == self.deque.len() {
             self.clock_ptr = 0;
         }
-        return addr;
+        frame
     }
+
     fn move_next(&mut self) {
         self.clock_ptr += 1;
         if self.clock_ptr == self.deque.len() {
@@ -82,7 +90,7 @@ impl EnhancedClockSwapManager {
 #[cfg(test)]
 mod test {
     use super::*;
-    use swap::test::*;
+    use crate::swap::test::*;
 
     #[test]
     fn test() {
@@ -12,7 +12,7 @@ impl SwapManager for FifoSwapManager {
     fn tick(&mut self) {}
 
     fn push(&mut self, frame: Frame) {
-        info!(
+        trace!(
             "SwapManager push token: {:x?} vaddr: {:x?}",
             frame.get_token(),
             frame.get_virtaddr()
@@ -21,30 +21,27 @@ impl SwapManager for FifoSwapManager {
     }
 
     fn remove(&mut self, token: usize, addr: VirtAddr) {
-        info!("SwapManager remove token: {:x?} vaddr: {:x?}", token, addr);
-        let id = self
+        trace!("SwapManager remove token: {:x?} vaddr: {:x?}", token, addr);
+        // the page may have been popped as a victim already
+        if let Some(id) = self
             .deque
             .iter()
             .position(|ref x| x.get_virtaddr() == addr && x.get_token() == token)
-            .expect("address not found");
-        self.deque.remove(id);
+        {
+            self.deque.remove(id);
+        }
         //info!("SwapManager remove token finished: {:x?} vaddr: {:x?}", token, addr);
     }
 
-    fn pop<T, S>(&mut self, _: &mut T, _: &mut S) -> Option<Frame>
-    where
-        T: PageTable,
-        S: Swapper,
-    {
+    fn pop(&mut self, _visit: &mut EntryVisitor) -> Option<Frame> {
         self.deque.pop_front()
     }
 }
 
-/*
 #[cfg(test)]
 mod test {
     use super::*;
-    use swap::test::*;
+    use crate::swap::test::*;
 
     #[test]
     fn test() {
@@ -62,4 +59,3 @@ mod test {
         test_manager(FifoSwapManager::default(), &ops, &pgfault_count);
     }
 }
-*/
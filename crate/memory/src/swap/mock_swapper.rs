@@ -39,6 +39,9 @@ impl Swapper for MockSwapper {
         }
         Ok(())
     }
+    fn swap_free(&mut self, token: usize) -> Result<(), ()> {
+        self.map.remove(&token).map(|_| ()).ok_or(())
+    }
 }
 
 impl MockSwapper {
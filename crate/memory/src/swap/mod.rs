@@ -1,28 +1,64 @@
-//! Swap extension for page table
+//! Swap extension for page tables
 //! and generic interface for swap manager and swapper
 //!
-//! To use the SwapExt, make a wrapper over the original apge table using swap manager and swapper
-//! Like: SwapExt::new(origin_page_table,swap_manager,swapper)
-//! Invoke page_fault_handler() on the SwapExt to run the swap process
-//! If the method above returns true, a page is swapped in, else do your own things.
+//! To use the SwapExt, create it with a swap manager and a swapper:
+//! SwapExt::new(swap_manager, swapper)
+//! A page is named by the token of its page table and its virtual address,
+//! so a victim can be swapped out of any page table, not only the active one.
+//! Invoke set_swappable() when a page is mapped to a frame, and remove_from_swappable() before it is unmapped.
+//! Invoke swap_out_any() to free a frame, and swap_in() on the page fault of a swapped page.
+//! Page tables are only touched through the owners' locks given to swap_out_any(),
+//! so a page is not swapped out while its owner is changing it.
 
-use super::addr::Frame;
 use super::paging::*;
 use super::*;
-use core::ops::{Deref, DerefMut};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
 
-//pub use self::fifo::FifoSwapManager;
-//pub use self::enhanced_clock::EnhancedClockSwapManager;
+pub use self::enhanced_clock::EnhancedClockSwapManager;
+pub use self::fifo::FifoSwapManager;
 
+mod enhanced_clock;
 pub mod fifo;
-//mod enhanced_clock;
-pub mod mock_swapper;
-//#[cfg(test)]
-//mod mock_swapper;
+#[cfg(test)]
+mod mock_swapper;
+
+/// A swappable page
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Frame {
+    token: usize,
+    virtaddr: VirtAddr,
+}
+
+impl Frame {
+    /*
+     **  @brief  create the frame of a page
+     **  @param  token: usize         the token of the page table the page is in
+     **  @param  virtaddr: VirtAddr   the virtual address of the page
+     **  @retval Frame                the frame created
+     */
+    pub fn new(token: usize, virtaddr: VirtAddr) -> Self {
+        Frame {
+            token,
+            virtaddr: virtaddr & !(PAGE_SIZE - 1),
+        }
+    }
+
+    pub fn get_token(&self) -> usize {
+        self.token
+    }
+
+    pub fn get_virtaddr(&self) -> VirtAddr {
+        self.virtaddr
+    }
+}
+
+/// Visit the entry of a swappable page with the owner of its page table locked.
+/// Return false, without visiting, if the owner is busy or the page is not mapped.
+pub type EntryVisitor<'a> = dyn FnMut(&Frame, &mut dyn FnMut(&mut dyn Entry)) -> bool + 'a;
 
 /// Manage all swappable pages, decide which to swap out
 pub trait SwapManager {
-    //type Inactive: InactivePageTable;
     /*
      **  @brief  update intarnal state pre tick
      **          Called when tick interrupt occured
@@ -39,22 +75,18 @@ pub trait SwapManager {
     /*
      **  @brief  update intarnal state when page is removed from memory
      **          Called to delete the addr entry from the swap manager
-     **  @param  token: usize         the inactive page table token for the virtual address
+     **  @param  token: usize         the page table token for the virtual address
      **  @param  addr: VirtAddr       the virual address of the page removed from memory
      **  @retval none
      */
     fn remove(&mut self, token: usize, addr: VirtAddr);
     /*
      **  @brief  select swap out victim when there is need to swap out a page
-     **          (The params is only used by `EnhancedClockSwapManager` currently)
-     **  @param  page_table: &mut T   the current page table
-     **  @param  swapper: &mut S      the swapper used
+     **  @param  visit: &mut EntryVisitor
+     **                               the access to the entries of swappable pages
      **  @retval Option<Frame>     the Frame of the victim page, if present
      */
-    fn pop<T, S>(&mut self, page_table: &mut T, swapper: &mut S) -> Option<Frame>
-    where
-        T: PageTable,
-        S: Swapper;
+    fn pop(&mut self, visit: &mut EntryVisitor) -> Option<Frame>;
 }
 
 /// Implement swap in & out execution
@@ -79,333 +111,264 @@ pub trait Swapper {
      **  @retval Result<(), ()>       the execute result
      */
     fn swap_in(&mut self, token: usize, data: &mut [u8]) -> Result<(), ()>;
+    /*
+     **  @brief  Deallocate the space on device without reading it.
+     **  @param  token: usize         the token indicating the location on the device
+     **  @retval Result<(), ()>       the execute result
+     */
+    fn swap_free(&mut self, token: usize) -> Result<(), ()>;
 }
 
-/// Wrapper for page table, supporting swap functions
-pub struct SwapExt<T: PageTable, M: SwapManager, S: Swapper> {
-    page_table: T,
+/// Swappable pages of all page tables, and their space on the device
+pub struct SwapExt<M: SwapManager, S: Swapper> {
     swap_manager: M,
     swapper: S,
+    /// Swapped out pages, and their tokens of location on the device
+    swapped: BTreeMap<Frame, usize>,
 }
 
-impl<T: PageTable, M: SwapManager, S: Swapper> SwapExt<T, M, S> {
+impl<M: SwapManager, S: Swapper> SwapExt<M, S> {
     /*
      **  @brief  create a swap extension
-     **  @param  page_table: T        the inner page table
      **  @param  swap_manager: M      the SwapManager used
      **  @param  swapper: S           the Swapper used
      **  @retval SwapExt              the swap extension created
      */
-    pub fn new(page_table: T, swap_manager: M, swapper: S) -> Self {
+    pub fn new(swap_manager: M, swapper: S) -> Self {
         SwapExt {
-            page_table,
             swap_manager,
             swapper,
+            swapped: BTreeMap::new(),
         }
     }
 
+    pub fn swapper(&self) -> &S {
+        &self.swapper
+    }
+
+    pub fn swapper_mut(&mut self) -> &mut S {
+        &mut self.swapper
+    }
+
     /*
-     **  @brief set a page swappable
-     **  @param pt: *mut T2           the raw pointer for the target page's inactive page table
-     **  @param addr: VirtAddr        the target page's virtual address
+     **  @brief  the pages swapped out
+     **  @retval impl Iterator<Item = Frame>
+     **                               the Frame of every page swapped out
      */
-    pub unsafe fn set_swappable<T2: InactivePageTable>(&mut self, pt: *mut T2, addr: VirtAddr) {
-        let Self {
-            ref mut page_table,
-            ref mut swap_manager,
-            ..
-        } = self;
-        let targetpt = &mut *(pt);
-        let pttoken = {
-            info!(
-                "SET_SWAPPABLE: the target page table token is {:x?}, addr is {:x?}",
-                targetpt.token(),
-                addr
-            );
-            targetpt.token()
-        };
-        targetpt.with(|| {
-            let entry = page_table
-                .get_entry(addr)
-                .expect("failed to get page entry when set swappable");
-            if entry.present() {
-                let frame = Frame::new(pt as usize, addr, pttoken);
-                swap_manager.push(frame);
-            }
-        });
-        /*
-        let token = unsafe{
-            (*pt).token()
-        };
-        let frame = Frame::new(pt as usize, addr, token);
-        self.swap_manager.push(frame);
-        */
+    pub fn swapped(&self) -> impl Iterator<Item = Frame> + '_ {
+        self.swapped.keys().cloned()
     }
 
     /*
-     **  @brief remove a page (given virtual address) from swappable pages, if the page is swapped, swap in at first
-     **  @param pt: *mut T2           the raw pointer for the target page's inactive page table
-     **  @param addr: VirtAddr        the target page's virtual address
-     **  @param alloc_frame:          the function to alloc a free physical frame for once
+     **  @brief  test whether a page is swapped out
+     **  @param  token: usize         the token of the page's page table
+     **  @param  addr: VirtAddr       the page's virtual address
+     **  @retval bool                 whether the page is swapped out
      */
-    pub unsafe fn remove_from_swappable<T2: InactivePageTable>(
-        &mut self,
-        pt: *mut T2,
-        addr: VirtAddr,
-        alloc_frame: impl FnOnce() -> PhysAddr,
-    ) {
-        //info!("come into remove_from swappable");
-        let Self {
-            ref mut page_table,
-            ref mut swap_manager,
-            ref mut swapper,
-        } = self;
-        let targetpt = &mut *(pt);
-        let pttoken = {
-            info!(
-                "SET_UNSWAPPABLE: the target page table token is {:x?}, addr is {:x?}",
-                targetpt.token(),
-                addr
-            );
-            targetpt.token()
-        };
-        //info!("try to change pagetable");
-        targetpt.with(|| {
-            let token = {
-                let entry = page_table.get_entry(addr).unwrap();
-                if !entry.swapped() {
-                    if entry.present() {
-                        // if the addr isn't indicating a swapped page, panic occured here
-                        swap_manager.remove(pttoken, addr);
-                    }
-                    return;
-                }
-                let token = entry.target() / PAGE_SIZE;
-                let frame = alloc_frame();
-                entry.set_target(frame);
-                entry.set_swapped(false);
-                entry.set_present(true);
-                entry.update();
-                token
-            };
-            info!("swap in vaddr {:x?} at remove from swappable.", addr);
-            let data = page_table.get_page_slice_mut(addr);
-            swapper.swap_in(token, data).unwrap();
-        });
-        trace!("come out of femove_from swappable");
+    pub fn is_swapped(&self, token: usize, addr: VirtAddr) -> bool {
+        self.swapped.contains_key(&Frame::new(token, addr))
     }
 
     /*
-     **  @brief  map the virtual address to a target physics address as swappable
-     **  @param  addr: VirtAddr       the virual address to map
-     **  @param  target: VirtAddr     the target physics address
-     **  @retval none
+     **  @brief set a page swappable, after it is mapped to a frame
+     **  @param token: usize          the token of the page's page table
+     **  @param addr: VirtAddr        the page's virtual address
      */
+    pub fn set_swappable(&mut self, token: usize, addr: VirtAddr) {
+        self.swap_manager.push(Frame::new(token, addr));
+    }
+
     /*
-    pub fn map_to_swappable(&mut self, addr: VirtAddr, target: PhysAddr) -> &mut T::Entry {
-        self.swap_manager.push(addr);
-        self.map(addr, target)
-    }*/
+     **  @brief remove a page from swappable pages before it is unmapped,
+     **         if the page is swapped, deallocate its space on device
+     **  @param pt: &mut dyn PageTable
+     **                               the page table of the page
+     **  @param addr: VirtAddr        the page's virtual address
+     **  @retval bool                 whether the page was swapped out, with no frame mapped
+     */
+    pub fn remove_from_swappable(&mut self, pt: &mut dyn PageTable, addr: VirtAddr) -> bool {
+        let frame = Frame::new(pt.token(), addr);
+        match self.swapped.remove(&frame) {
+            Some(token) => {
+                debug!("free swapped page {:x?} of {:x?}", addr, frame.token);
+                self.swapper.swap_free(token).ok();
+                if let Some(entry) = pt.get_entry(addr) {
+                    entry.set_swapped(false);
+                    entry.update();
+                }
+                true
+            }
+            None => {
+                self.swap_manager.remove(frame.token, frame.virtaddr);
+                false
+            }
+        }
+    }
 
     /*
-     **  @brief  Swap out any one of the swapped pages
+     **  @brief  Swap out any one of the swappable pages
+     **  @param  lock_page_table: impl FnMut(usize, &mut dyn FnMut(&mut dyn PageTable)) -> bool
+     **                               run the function on the page table of a token
+     **                               with its owner locked, false if the owner is busy
      **  @retval Result<PhysAddr, SwapError>
      **                               the physics address of released frame if success,
      **                               the error if failed
      */
-    pub fn swap_out_any<T2: InactivePageTable>(&mut self) -> Result<PhysAddr, SwapError> {
-        info!("COME in to swap_out_any");
-        let victim: Option<Frame> = {
-            let Self {
-                ref mut page_table,
-                ref mut swap_manager,
-                ref mut swapper,
-            } = self;
-            swap_manager.pop(page_table, swapper)
+    pub fn swap_out_any(
+        &mut self,
+        mut lock_page_table: impl FnMut(usize, &mut dyn FnMut(&mut dyn PageTable)) -> bool,
+    ) -> Result<PhysAddr, SwapError> {
+        // pages of busy owners are skipped, and stay swappable
+        let mut busy = Vec::new();
+        let ret = loop {
+            let frame = {
+                let mut visit = |frame: &Frame, f: &mut dyn FnMut(&mut dyn Entry)| {
+                    let mut mapped = false;
+                    let locked = lock_page_table(frame.token, &mut |pt| {
+                        if let Some(entry) = pt.get_entry(frame.virtaddr) {
+                            f(entry);
+                            mapped = true;
+                        }
+                    });
+                    locked && mapped
+                };
+                match self.swap_manager.pop(&mut visit) {
+                    Some(frame) => frame,
+                    None => break Err(SwapError::NoSwapped),
+                }
+            };
+            let mut ret = Err(SwapError::NotMapped);
+            if !lock_page_table(frame.token, &mut |pt| ret = self.swap_out(pt, &frame)) {
+                busy.push(frame);
+                continue;
+            }
+            match ret {
+                Ok(target) => break Ok(target),
+                Err(SwapError::IOError) => {
+                    self.swap_manager.push(frame);
+                    break Err(SwapError::IOError);
+                }
+                // the page is gone or swapped, pick another one
+                Err(_) => continue,
+            }
         };
-        info!("swap out page {}", victim.unwrap().get_virtaddr());
-        match victim {
-            None => Err(SwapError::NoSwapped),
-            Some(frame) => self.swap_out::<T2>(&frame),
+        for frame in busy {
+            self.swap_manager.push(frame);
         }
+        ret
     }
 
     /*
      **  @brief  Swap out page
+     **  @param  pt: &mut dyn PageTable
+     **                               the page table of the page
      **  @param  frame: Frame       the Frame of page recording the page info
      **  @retval Result<PhysAddr, SwapError>
      **                               the physics address of the original map target frame if success,
      **                               the error if failed
      */
-    fn swap_out<T2: InactivePageTable>(&mut self, frame: &Frame) -> Result<PhysAddr, SwapError> {
-        let Self {
-            ref mut page_table,
-            ref mut swapper,
-            ..
-        } = self;
-        let ret = unsafe {
-            let pt = &mut *(frame.get_page_table() as *mut T2);
-            pt.with(|| {
-                //use core::slice;
-                //let data = unsafe { slice::from_raw_parts_mut((frame.virtaddr & !(PAGE_SIZE - 1)) as *mut u8, PAGE_SIZE) };
-                let data = page_table.get_page_slice_mut(frame.get_virtaddr());
-                let entry = page_table
-                    .get_entry(frame.get_virtaddr())
-                    .ok_or(SwapError::NotMapped)?;
-                if entry.swapped() {
-                    return Err(SwapError::AlreadySwapped);
-                }
-                //assert!(!entry.swapped(), "Page already swapped!");
-                let token = swapper.swap_out(data).map_err(|_| SwapError::IOError)?;
-                //let token = swapper.swap_out(data).unwrap();
-                let target = entry.target();
+    fn swap_out(&mut self, pt: &mut dyn PageTable, frame: &Frame) -> Result<PhysAddr, SwapError> {
+        let addr = frame.virtaddr;
+        {
+            let entry = pt.get_entry(addr).ok_or(SwapError::NotMapped)?;
+            if entry.swapped() {
+                return Err(SwapError::AlreadySwapped);
+            }
+            if !entry.present() {
+                return Err(SwapError::NotMapped);
+            }
+            if entry.readonly_shared() || entry.writable_shared() {
+                return Err(SwapError::Shared);
+            }
+        }
+        // the data stays reachable in the frame after it is unmapped
+        let data = pt.get_page_slice_mut(addr);
+        let entry = pt.get_entry(addr).unwrap();
+        let target = entry.target();
+        // unmap first, so that nothing is written to the page while it is written out
+        entry.set_present(false);
+        entry.update();
+        pt.flush_tlb_remote(addr);
+        match self.swapper.swap_out(data) {
+            Ok(token) => {
+                let entry = pt.get_entry(addr).unwrap();
                 entry.set_target(token * PAGE_SIZE);
                 entry.set_swapped(true);
-                entry.set_present(false);
                 entry.update();
+                self.swapped.insert(*frame, token);
                 Ok(target)
-            })
-        };
-        ret
+            }
+            Err(()) => {
+                let entry = pt.get_entry(addr).unwrap();
+                entry.set_present(true);
+                entry.update();
+                Err(SwapError::IOError)
+            }
+        }
     }
+
     /*
-     **  @brief  map the virtual address to a target physics address and then swap in page data, noted that the page should be in the current page table
-     **  @param pt: *mut T2           the raw pointer for the swapping page's inactive page table
+     **  @brief  map the virtual address to a target physics address and then swap in page data
+     **  @param  pt: &mut dyn PageTable
+     **                               the page table of the page
      **  @param  addr: VirtAddr       the virual address of beginning of page
      **  @param  target: PhysAddr       the target physics address
      **  @retval Result<()), SwapError>
      **                               the execute result, and the error if failed
      */
-    fn swap_in<T2: InactivePageTable>(
+    pub fn swap_in(
         &mut self,
-        pt: *mut T2,
+        pt: &mut dyn PageTable,
         addr: VirtAddr,
         target: PhysAddr,
     ) -> Result<(), SwapError> {
-        info!("come in to swap in");
-        let entry = self
-            .page_table
-            .get_entry(addr)
-            .ok_or(SwapError::NotMapped)?;
-        if !entry.swapped() {
-            return Err(SwapError::NotSwapped);
+        let frame = Frame::new(pt.token(), addr);
+        let token = *self.swapped.get(&frame).ok_or(SwapError::NotSwapped)?;
+        {
+            let entry = pt.get_entry(addr).ok_or(SwapError::NotMapped)?;
+            entry.set_target(target);
+            entry.set_present(true);
+        }
+        let data = pt.get_page_slice_mut(addr);
+        let entry = pt.get_entry(addr).unwrap();
+        if self.swapper.swap_in(token, data).is_err() {
+            entry.set_target(token * PAGE_SIZE);
+            entry.set_present(false);
+            return Err(SwapError::IOError);
         }
-        let token = entry.target() / PAGE_SIZE;
-        entry.set_target(target);
         entry.set_swapped(false);
-        entry.set_present(true);
         entry.update();
-        let data = self.page_table.get_page_slice_mut(addr);
-        self.swapper
-            .swap_in(token, data)
-            .map_err(|_| SwapError::IOError)?;
-        let pttoken = unsafe { (*pt).token() };
-        let frame = Frame::new(pt as usize, addr, pttoken);
-;
+        self.swapped.remove(&frame);
         self.swap_manager.push(frame);
         Ok(())
     }
-    /*
-     **  @brief  execute the frame delayed allocate and  swap process for page fault
-     **          This function must be called whenever PageFault happens.
-     **  @param  pt: *mut T2          the raw pointer for the target page's inactive page table (exactly the current page table)
-     **  @param  addr: VirtAddr       the virual address of the page fault
-     **  @param  swapin: bool         whether to set the page swappable if delayed allocate a frame for a page
-     **  @param  alloc_frame: impl FnOnce() -> PhysAddr
-     **                               the page allocation function
-     **                               that allocate a page and returns physics address
-     **                               of beginning of the page
-     **  @retval bool                 whether swap in happens.
-     */
-    pub fn page_fault_handler<T2: InactivePageTable>(
-        &mut self,
-        pt: *mut T2,
-        addr: VirtAddr,
-        swapin: bool,
-        alloc_frame: impl FnOnce() -> PhysAddr,
-    ) -> bool {
-        // handle page delayed allocating
-        {
-            info!("try handling delayed frame allocator");
-            let need_alloc = {
-                let entry = self.page_table.get_entry(addr).expect("fail to get entry");
-                //info!("got entry!");
-                !entry.present() && !entry.swapped()
-            };
-            info!("need_alloc got");
-            if need_alloc {
-                info!("need_alloc!");
-                let frame = alloc_frame();
-                {
-                    let entry = self.page_table.get_entry(addr).unwrap();
-                    entry.set_target(frame);
-                    //let new_entry = self.page_table.map(addr, frame);
-                    entry.set_present(true);
-                    entry.update();
-                }
-                if swapin {
-                    unsafe {
-                        self.set_swappable(pt, addr & 0xfffff000);
-                    }
-                }
-                //area.get_flags().apply(new_entry); this instruction may be used when hide attr is used
-                info!("allocated successfully");
-                return true;
-            }
-            info!("not need alloc!");
-        }
-        // now we didn't attach the cow so the present will be false when swapped(), to enable the cow some changes will be needed
-        match self.page_table.get_entry(addr) {
-            // infact the get_entry(addr) should not be None here
-            None => return false,
-            Some(entry) => {
-                if !entry.swapped() {
-                    return false;
-                }
-            }
-        }
-        // Allocate a frame, if failed, swap out a page
-        let frame = alloc_frame();
-        self.swap_in(pt, addr, frame).ok().unwrap();
-        true
-    }
 }
 
+#[derive(Debug)]
 pub enum SwapError {
     /// attempt to swap out a page that is already swapped out
     AlreadySwapped,
-    ///
+    /// attempt to swap a page that is not mapped to a frame
     NotMapped,
     /// attempt to swap in a page that is already in the memory
     NotSwapped,
+    /// attempt to swap out a page shared copy-on-write
+    Shared,
     /// there are no page to be swapped out
     NoSwapped,
+    /// there are no frame to swap a page in
+    NoFrame,
     /// swap failed due to IO error while interact with device
     IOError,
 }
 
-impl<T: PageTable, M: SwapManager, S: Swapper> Deref for SwapExt<T, M, S> {
-    type Target = T;
-
-    fn deref(&self) -> &<Self as Deref>::Target {
-        &self.page_table
-    }
-}
-
-impl<T: PageTable, M: SwapManager, S: Swapper> DerefMut for SwapExt<T, M, S> {
-    fn deref_mut(&mut self) -> &mut <Self as Deref>::Target {
-        &mut self.page_table
-    }
-}
-
-/*
 #[cfg(test)]
-mod test {
-    use super::*;
+pub mod test {
     use super::mock_swapper::MockSwapper;
-    use alloc::{sync::Arc, boxed::Box};
+    use super::*;
+    use crate::paging::MockPageTable;
+    use alloc::rc::Rc;
     use core::cell::RefCell;
-    use paging::MockPageTable;
 
     #[derive(Debug)]
     pub enum MemOp {
@@ -425,41 +388,152 @@ mod test {
         }
     }
 
-    unsafe fn clone<'a, 'b, T>(x: &'a mut T) -> &'b mut T {
-        &mut *(x as *mut T)
+    type Ext<M> = Rc<RefCell<SwapExt<M, MockSwapper>>>;
+
+    /// Map pages on page faults with 4 frames, swapping out pages for them.
+    fn mock_page_table<M: SwapManager + 'static>(ext: Ext<M>, faults: Rc<RefCell<u8>>) -> MockPageTable {
+        let mut pt = MockPageTable::new();
+        let mut alloc = FrameAlloc(4);
+        pt.set_handler(Box::new(move |pt: &mut MockPageTable, addr: VirtAddr| {
+            *faults.borrow_mut() += 1;
+            let mut ext = ext.borrow_mut();
+            let target = alloc
+                .alloc()
+                .or_else(|| {
+                    ext.swap_out_any(|_, f| {
+                        f(&mut *pt);
+                        true
+                    })
+                    .ok()
+                })
+                .expect("no more frame in both allocator and swap_manager");
+            if ext.is_swapped(pt.token(), addr) {
+                ext.swap_in(pt, addr, target).unwrap();
+            } else {
+                pt.map(addr, target);
+                ext.set_swappable(pt.token(), addr);
+            }
+        }));
+        pt
     }
 
     /// Test framework with different SwapManagers.
     /// See `fifo::test` mod for example.
     pub fn test_manager(swap_manager: impl 'static + SwapManager, ops: &[MemOp], pgfault_count: &[u8]) {
         use self::MemOp::{R, W};
-        let page_fault_count = Arc::new(RefCell::new(0u8));
-
-        let mut pt = SwapExt::new(MockPageTable::new(), swap_manager, MockSwapper::default());
-
-        // Move to closure
-        let pt0 = unsafe{ clone(&mut pt) };
-        let page_fault_count1 = page_fault_count.clone();
-        let mut alloc = FrameAlloc(4);
-
-        pt.set_handler(Box::new(move |_, addr: VirtAddr| {
-            *page_fault_count1.borrow_mut() += 1;
-            if pt0.page_fault_handler(addr, || alloc.alloc()) {
-                return;
-            }
-            // The page is not mapped, map it to a new frame, if no more frame, swap out.
-            let target = alloc.alloc().or_else(|| pt0.swap_out_any().ok())
-                .expect("no more frame in both allocator and swap_manager");
-            pt0.map_to_swappable(addr, target);
-        }));
+        let faults = Rc::new(RefCell::new(0u8));
+        let ext = Rc::new(RefCell::new(SwapExt::new(swap_manager, MockSwapper::default())));
+        let mut pt = mock_page_table(ext, faults.clone());
 
         for (op, &count) in ops.iter().zip(pgfault_count.iter()) {
             match op {
-                R(addr) => { pt.read(*addr); }
+                R(addr) => {
+                    pt.read(*addr);
+                }
                 W(addr) => pt.write(*addr, 0),
             }
-            assert_eq!(*(*page_fault_count).borrow(), count);
+            assert_eq!(*faults.borrow(), count, "after {:x?}", op);
+        }
+    }
+
+    #[test]
+    fn keep_data() {
+        let faults = Rc::new(RefCell::new(0u8));
+        let ext = Rc::new(RefCell::new(SwapExt::new(FifoSwapManager::default(), MockSwapper::default())));
+        let mut pt = mock_page_table(ext.clone(), faults.clone());
+        for i in 1..=8 {
+            pt.write(i * PAGE_SIZE, i as u8);
+        }
+        assert_eq!(ext.borrow().swapped().count(), 4);
+        for i in 1..=8 {
+            assert_eq!(pt.read(i * PAGE_SIZE), i as u8);
+        }
+        assert_eq!(*faults.borrow(), 16);
+    }
+
+    #[test]
+    fn skip_busy_owner() {
+        let mut ext = SwapExt::new(FifoSwapManager::default(), MockSwapper::default());
+        let mut pt = MockPageTable::new();
+        pt.map(0x1000, 0x1000);
+        ext.set_swappable(pt.token(), 0x1000);
+        let ret = ext.swap_out_any(|_, _| false);
+        assert!(matches!(ret, Err(SwapError::NoSwapped)));
+        // the page is still swappable once its owner is free
+        let ret = ext.swap_out_any(|_, f| {
+            f(&mut pt);
+            true
+        });
+        assert_eq!(ret.unwrap(), 0x1000);
+        assert!(ext.is_swapped(pt.token(), 0x1000));
+    }
+
+    #[test]
+    fn skip_shared() {
+        let mut ext = SwapExt::new(FifoSwapManager::default(), MockSwapper::default());
+        let mut pt = MockPageTable::new();
+        pt.map(0x1000, 0x1000).set_shared(false);
+        pt.map(0x2000, 0x2000);
+        ext.set_swappable(pt.token(), 0x1000);
+        ext.set_swappable(pt.token(), 0x2000);
+        let ret = ext.swap_out_any(|_, f| {
+            f(&mut pt);
+            true
+        });
+        assert_eq!(ret.unwrap(), 0x2000);
+        assert!(!ext.is_swapped(pt.token(), 0x1000));
+    }
+
+    #[test]
+    fn remove_swapped() {
+        let mut ext = SwapExt::new(FifoSwapManager::default(), MockSwapper::default());
+        let mut pt = MockPageTable::new();
+        pt.map(0x1000, 0x1000);
+        ext.set_swappable(pt.token(), 0x1000);
+        ext.swap_out_any(|_, f| {
+            f(&mut pt);
+            true
+        })
+        .unwrap();
+        assert!(ext.remove_from_swappable(&mut pt, 0x1000));
+        assert!(!ext.is_swapped(pt.token(), 0x1000));
+        assert!(!pt.get_entry(0x1000).unwrap().swapped());
+        let mut data = [0u8; PAGE_SIZE];
+        assert_eq!(ext.swapper_mut().swap_in(0, &mut data), Err(()));
+    }
+
+    /// A device that fails every write
+    struct BrokenSwapper;
+
+    impl Swapper for BrokenSwapper {
+        fn swap_out(&mut self, _data: &[u8]) -> Result<usize, ()> {
+            Err(())
+        }
+        fn swap_update(&mut self, _token: usize, _data: &[u8]) -> Result<(), ()> {
+            Err(())
+        }
+        fn swap_in(&mut self, _token: usize, _data: &mut [u8]) -> Result<(), ()> {
+            Err(())
         }
+        fn swap_free(&mut self, _token: usize) -> Result<(), ()> {
+            Err(())
+        }
+    }
+
+    #[test]
+    fn io_error() {
+        let mut ext = SwapExt::new(FifoSwapManager::default(), BrokenSwapper);
+        let mut pt = MockPageTable::new();
+        pt.map(0x1000, 0x1000);
+        ext.set_swappable(pt.token(), 0x1000);
+        let ret = ext.swap_out_any(|_, f| {
+            f(&mut pt);
+            true
+        });
+        assert!(matches!(ret, Err(SwapError::IOError)));
+        // the page stays mapped and swappable
+        assert!(pt.get_entry(0x1000).unwrap().present());
+        assert!(!ext.is_swapped(pt.token(), 0x1000));
+        assert!(matches!(ext.swap_out_any(|_, _| false), Err(SwapError::NoSwapped)));
     }
 }
-*/
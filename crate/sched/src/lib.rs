@@ -0,0 +1,22 @@
+//! Scheduling policies for rCore
+//!
+//! A `Scheduler` keeps the states of tasks, and picks the runnable one to run.
+//! Real-time tasks always run before normal ones, the highest priority first.
+//! Normal tasks share the CPU fairly: the one with the least virtual runtime,
+//! the running time divided by its weight, runs next.
+//!
+//! A `SmpScheduler` keeps a `Scheduler` for each CPU, and balances the tasks
+//! among the CPUs allowed by their affinity.
+
+#![cfg_attr(not(test), no_std)]
+#![deny(non_snake_case)]
+
+extern crate alloc;
+
+mod param;
+mod scheduler;
+mod smp;
+
+pub use crate::param::*;
+pub use crate::scheduler::*;
+pub use crate::smp::*;
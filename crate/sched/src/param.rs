@@ -0,0 +1,130 @@
+//! Scheduling policies and parameters of a task, as sched(7)
+
+pub const SCHED_NORMAL: usize = 0;
+pub const SCHED_FIFO: usize = 1;
+pub const SCHED_RR: usize = 2;
+pub const SCHED_BATCH: usize = 3;
+pub const SCHED_IDLE: usize = 5;
+
+/// Priorities of real-time tasks are in `1..=MAX_RT_PRIO`
+pub const MAX_RT_PRIO: u8 = 99;
+
+pub const MIN_NICE: i8 = -20;
+pub const MAX_NICE: i8 = 19;
+
+/// Weight of a normal task with nice 0
+pub const NICE_0_WEIGHT: u64 = 1024;
+
+/// Weight of a `SCHED_IDLE` task, less than nice 19
+const IDLE_WEIGHT: u64 = 3;
+
+/// Weights of nice -20 to 19, each level is about 10% of CPU time
+const NICE_TO_WEIGHT: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Fair time sharing
+    Normal,
+    /// Real-time, run until blocked or preempted by a higher priority
+    Fifo,
+    /// Real-time, share the CPU with the same priority by time slices
+    RoundRobin,
+    /// Fair time sharing, for CPU-bound tasks
+    Batch,
+    /// Fair time sharing, only when nothing else runs
+    Idle,
+}
+
+impl Policy {
+    pub fn from_raw(policy: usize) -> Option<Self> {
+        match policy {
+            SCHED_NORMAL => Some(Policy::Normal),
+            SCHED_FIFO => Some(Policy::Fifo),
+            SCHED_RR => Some(Policy::RoundRobin),
+            SCHED_BATCH => Some(Policy::Batch),
+            SCHED_IDLE => Some(Policy::Idle),
+            _ => None,
+        }
+    }
+
+    pub fn raw(&self) -> usize {
+        match self {
+            Policy::Normal => SCHED_NORMAL,
+            Policy::Fifo => SCHED_FIFO,
+            Policy::RoundRobin => SCHED_RR,
+            Policy::Batch => SCHED_BATCH,
+            Policy::Idle => SCHED_IDLE,
+        }
+    }
+
+    pub fn is_realtime(&self) -> bool {
+        matches!(self, Policy::Fifo | Policy::RoundRobin)
+    }
+}
+
+/// How a task is scheduled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SchedParam {
+    pub policy: Policy,
+    /// Real-time priority, 0 for other policies
+    pub priority: u8,
+    /// Nice value of fair policies, kept for real-time ones
+    pub nice: i8,
+}
+
+impl Default for SchedParam {
+    fn default() -> Self {
+        SchedParam {
+            policy: Policy::Normal,
+            priority: 0,
+            nice: 0,
+        }
+    }
+}
+
+/// Limit `nice` to `MIN_NICE..=MAX_NICE`
+fn clamp_nice(nice: i8) -> i8 {
+    if nice < MIN_NICE {
+        return MIN_NICE;
+    }
+    nice.min(MAX_NICE)
+}
+
+impl SchedParam {
+    /// Create the parameters of `policy`, if `priority` is valid for it
+    pub fn new(policy: Policy, priority: u8, nice: i8) -> Option<Self> {
+        let valid = if policy.is_realtime() {
+            (1..=MAX_RT_PRIO).contains(&priority)
+        } else {
+            priority == 0
+        };
+        if !valid {
+            return None;
+        }
+        Some(SchedParam {
+            policy,
+            priority,
+            nice: clamp_nice(nice),
+        })
+    }
+
+    /// The parameters with another nice value
+    pub fn with_nice(self, nice: i8) -> Self {
+        SchedParam {
+            nice: clamp_nice(nice),
+            ..self
+        }
+    }
+
+    /// Share of CPU time of a fair task, relative to `NICE_0_WEIGHT`
+    pub fn weight(&self) -> u64 {
+        match self.policy {
+            Policy::Idle => IDLE_WEIGHT,
+            _ => NICE_TO_WEIGHT[(self.nice - MIN_NICE) as usize],
+        }
+    }
+}
@@ -0,0 +1,435 @@
+use crate::param::*;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+
+/// Length of a round-robin time slice, in ticks
+pub const RR_TIMESLICE: usize = 10;
+
+/// Virtual runtime of a nice 0 task running for a tick
+const TICK_VRUNTIME: u64 = 1 << 10;
+
+/// A woken task is placed at most this far before the running ones,
+/// so it runs soon, but can not take the CPU for long
+const WAKEUP_CREDIT: u64 = TICK_VRUNTIME * 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Waiting for an event, not in the run queue
+    Sleeping,
+    /// In the run queue
+    Runnable,
+    /// Picked to run
+    Running,
+    /// Woken while running, to be put back to the run queue
+    Woken,
+}
+
+struct Entity {
+    param: SchedParam,
+    state: State,
+    /// Running time divided by the weight, of fair tasks
+    vruntime: u64,
+    /// Ticks left in the time slice, of round-robin tasks
+    slice: usize,
+}
+
+/// The run queue of tasks identified by `T`
+pub struct Scheduler<T> {
+    tasks: BTreeMap<T, Entity>,
+    /// Runnable real-time tasks of each priority, in order
+    rt_queue: BTreeMap<u8, VecDeque<T>>,
+    /// Runnable fair tasks ordered by virtual runtime
+    fair_queue: BTreeSet<(u64, T)>,
+    /// Never decreasing lower bound of virtual runtime of runnable tasks
+    min_vruntime: u64,
+}
+
+impl<T: Ord + Copy> Default for Scheduler<T> {
+    fn default() -> Self {
+        Scheduler {
+            tasks: BTreeMap::new(),
+            rt_queue: BTreeMap::new(),
+            fair_queue: BTreeSet::new(),
+            min_vruntime: 0,
+        }
+    }
+}
+
+impl<T: Ord + Copy> Scheduler<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a sleeping task, `wake` it to run
+    pub fn add(&mut self, id: T, param: SchedParam) {
+        let entity = Entity {
+            param,
+            state: State::Sleeping,
+            vruntime: self.min_vruntime,
+            slice: RR_TIMESLICE,
+        };
+        if self.tasks.insert(id, entity).is_some() {
+            panic!("task exists");
+        }
+    }
+
+    /// Remove a task, return false if there is no such task
+    pub fn remove(&mut self, id: T) -> bool {
+        if self.state(id) == Some(State::Runnable) {
+            self.dequeue(id);
+        }
+        self.tasks.remove(&id).is_some()
+    }
+
+    pub fn state(&self, id: T) -> Option<State> {
+        self.tasks.get(&id).map(|entity| entity.state)
+    }
+
+    pub fn param(&self, id: T) -> Option<SchedParam> {
+        self.tasks.get(&id).map(|entity| entity.param)
+    }
+
+    /// Change the parameters of a task, return false if there is no such task
+    pub fn set_param(&mut self, id: T, param: SchedParam) -> bool {
+        let state = match self.state(id) {
+            Some(state) => state,
+            None => return false,
+        };
+        if state == State::Runnable {
+            self.dequeue(id);
+        }
+        let min_vruntime = self.min_vruntime;
+        let entity = self.tasks.get_mut(&id).unwrap();
+        if entity.param.policy.is_realtime() && !param.policy.is_realtime() {
+            entity.vruntime = entity.vruntime.max(min_vruntime);
+        }
+        entity.param = param;
+        entity.slice = RR_TIMESLICE;
+        if state == State::Runnable {
+            self.enqueue(id);
+        }
+        true
+    }
+
+    /// Make a task runnable
+    pub fn wake(&mut self, id: T) {
+        let min_vruntime = self.min_vruntime;
+        let entity = match self.tasks.get_mut(&id) {
+            Some(entity) => entity,
+            None => return,
+        };
+        match entity.state {
+            State::Sleeping => {
+                entity.vruntime = entity
+                    .vruntime
+                    .max(min_vruntime.saturating_sub(WAKEUP_CREDIT));
+                self.enqueue(id);
+            }
+            State::Running => entity.state = State::Woken,
+            State::Runnable | State::Woken => {}
+        }
+    }
+
+    /// Pick the next task to run: real-time tasks of the highest priority
+    /// in order, then the fair task with the least virtual runtime.
+    pub fn pick(&mut self) -> Option<T> {
+        let id = if let Some(prio) = self.highest_rt() {
+            self.rt_queue.get(&prio).unwrap()[0]
+        } else {
+            let &(vruntime, id) = self.fair_queue.iter().next()?;
+            self.min_vruntime = self.min_vruntime.max(vruntime);
+            id
+        };
+        self.dequeue(id);
+        self.tasks.get_mut(&id).unwrap().state = State::Running;
+        Some(id)
+    }
+
+    /// Put back a task that stops running and is not finished.
+    /// It sleeps, unless it was woken meanwhile.
+    pub fn put(&mut self, id: T) {
+        let entity = match self.tasks.get_mut(&id) {
+            Some(entity) => entity,
+            None => return,
+        };
+        match entity.state {
+            State::Woken => self.enqueue(id),
+            State::Running => entity.state = State::Sleeping,
+            State::Sleeping | State::Runnable => {}
+        }
+    }
+
+    /// Account a timer tick of a running task.
+    /// Return true if it should give up the CPU to another task.
+    pub fn tick(&mut self, id: T) -> bool {
+        let highest_rt = self.highest_rt();
+        let entity = match self.tasks.get_mut(&id) {
+            Some(entity) => entity,
+            None => return false,
+        };
+        let param = entity.param;
+        match param.policy {
+            Policy::Fifo => highest_rt > Some(param.priority),
+            Policy::RoundRobin => {
+                entity.slice -= 1;
+                if entity.slice == 0 {
+                    // the slice is used up, take turns with the same priority
+                    entity.slice = RR_TIMESLICE;
+                    highest_rt >= Some(param.priority)
+                } else {
+                    highest_rt > Some(param.priority)
+                }
+            }
+            _ => {
+                entity.vruntime += TICK_VRUNTIME * NICE_0_WEIGHT / param.weight();
+                let vruntime = entity.vruntime;
+                let first = self.fair_queue.iter().next().map(|&(vruntime, _)| vruntime);
+                self.min_vruntime = self
+                    .min_vruntime
+                    .max(first.map_or(vruntime, |first| first.min(vruntime)));
+                highest_rt.is_some() || matches!(first, Some(first) if first < vruntime)
+            }
+        }
+    }
+
+    /// Number of runnable tasks in the run queue
+    pub fn runnable(&self) -> usize {
+        self.fair_queue.len()
+            + self
+                .rt_queue
+                .values()
+                .map(|queue| queue.len())
+                .sum::<usize>()
+    }
+
+    /// Runnable tasks, in the order to run
+    pub fn runnable_tasks(&self) -> impl Iterator<Item = T> + '_ {
+        self.rt_queue
+            .values()
+            .rev()
+            .flatten()
+            .cloned()
+            .chain(self.fair_queue.iter().map(|&(_, id)| id))
+    }
+
+    /// Move a task to another run queue, keeping its state
+    pub fn migrate(&mut self, id: T, to: &mut Scheduler<T>) -> bool {
+        let state = match self.state(id) {
+            Some(state) => state,
+            None => return false,
+        };
+        if state == State::Runnable {
+            self.dequeue(id);
+        }
+        let mut entity = self.tasks.remove(&id).unwrap();
+        // keep how far it is ahead of the other tasks
+        entity.vruntime = entity.vruntime.saturating_sub(self.min_vruntime) + to.min_vruntime;
+        if to.tasks.insert(id, entity).is_some() {
+            panic!("task exists");
+        }
+        if state == State::Runnable {
+            to.enqueue(id);
+        }
+        true
+    }
+
+    fn highest_rt(&self) -> Option<u8> {
+        self.rt_queue.keys().next_back().cloned()
+    }
+
+    fn enqueue(&mut self, id: T) {
+        let entity = self.tasks.get_mut(&id).unwrap();
+        entity.state = State::Runnable;
+        if entity.param.policy.is_realtime() {
+            self.rt_queue
+                .entry(entity.param.priority)
+                .or_default()
+                .push_back(id);
+        } else {
+            self.fair_queue.insert((entity.vruntime, id));
+        }
+    }
+
+    fn dequeue(&mut self, id: T) {
+        let entity = &self.tasks[&id];
+        if entity.param.policy.is_realtime() {
+            let prio = entity.param.priority;
+            let queue = self.rt_queue.get_mut(&prio).unwrap();
+            queue.retain(|&task| task != id);
+            if queue.is_empty() {
+                self.rt_queue.remove(&prio);
+            }
+        } else {
+            self.fair_queue.remove(&(entity.vruntime, id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Run `ticks` timer ticks on a CPU, switching tasks when preempted.
+    /// Return the ticks each task runs.
+    fn run(sched: &mut Scheduler<usize>, ticks: usize) -> BTreeMap<usize, usize> {
+        let mut count = BTreeMap::new();
+        let mut current = sched.pick();
+        for _ in 0..ticks {
+            let id = current.expect("nothing to run");
+            *count.entry(id).or_insert(0) += 1;
+            if sched.tick(id) {
+                // yield
+                sched.wake(id);
+                sched.put(id);
+                current = sched.pick();
+            }
+        }
+        if let Some(id) = current {
+            sched.wake(id);
+            sched.put(id);
+        }
+        count
+    }
+
+    #[test]
+    fn realtime_preempts_normal() {
+        let mut sched = Scheduler::new();
+        sched.add(1, SchedParam::default());
+        sched.wake(1);
+        assert_eq!(sched.pick(), Some(1));
+        for _ in 0..100 {
+            assert!(!sched.tick(1), "A lone task should keep running.");
+        }
+
+        let fifo = SchedParam::new(Policy::Fifo, 10, 0).unwrap();
+        sched.add(2, fifo);
+        sched.wake(2);
+        assert!(
+            sched.tick(1),
+            "A CPU-bound task should be preempted by a real-time one."
+        );
+        sched.wake(1);
+        sched.put(1);
+        assert_eq!(sched.pick(), Some(2));
+        for _ in 0..100 {
+            assert!(
+                !sched.tick(2),
+                "A FIFO task should not be preempted by normal ones."
+            );
+        }
+
+        // waits for something
+        sched.put(2);
+        assert_eq!(sched.state(2), Some(State::Sleeping));
+        assert_eq!(sched.pick(), Some(1));
+        sched.wake(2);
+        assert!(sched.tick(1));
+    }
+
+    #[test]
+    fn fifo_priority() {
+        let mut sched = Scheduler::new();
+        for (id, prio) in [(1, 10), (2, 20), (3, 10)].iter() {
+            sched.add(*id, SchedParam::new(Policy::Fifo, *prio, 0).unwrap());
+            sched.wake(*id);
+        }
+        assert_eq!(sched.pick(), Some(2));
+        assert!(!sched.tick(2));
+        sched.put(2);
+        assert_eq!(sched.pick(), Some(1));
+        assert!(!sched.tick(1));
+        sched.wake(2);
+        assert!(sched.tick(1), "A higher priority should preempt.");
+    }
+
+    #[test]
+    fn round_robin() {
+        let mut sched = Scheduler::new();
+        for id in 1..=2 {
+            sched.add(id, SchedParam::new(Policy::RoundRobin, 1, 0).unwrap());
+            sched.wake(id);
+        }
+        assert_eq!(sched.pick(), Some(1));
+        for _ in 1..RR_TIMESLICE {
+            assert!(!sched.tick(1));
+        }
+        assert!(sched.tick(1), "The time slice should be used up.");
+        sched.wake(1);
+        sched.put(1);
+        assert_eq!(sched.pick(), Some(2));
+    }
+
+    #[test]
+    fn fair_share() {
+        let mut sched = Scheduler::new();
+        sched.add(1, SchedParam::default());
+        sched.add(2, SchedParam::default().with_nice(5));
+        sched.add(3, SchedParam::new(Policy::Idle, 0, 0).unwrap());
+        for id in 1..=3 {
+            sched.wake(id);
+        }
+        let count = run(&mut sched, 10000);
+        // weights are 1024, 335 and 3
+        let ratio = count[&1] as f64 / count[&2] as f64;
+        assert!(ratio > 2.8 && ratio < 3.3, "ratio: {}", ratio);
+        assert!(count[&3] < 50, "idle task runs {} ticks", count[&3]);
+    }
+
+    #[test]
+    fn woken_task_runs_soon() {
+        let mut sched = Scheduler::new();
+        sched.add(1, SchedParam::default());
+        sched.add(2, SchedParam::default());
+        sched.wake(1);
+        // task 2 sleeps long, and should not monopolize the CPU when woken
+        run(&mut sched, 1000);
+        sched.wake(2);
+        let count = run(&mut sched, 100);
+        assert!(count[&2] >= 49 && count[&2] <= 53, "{:?}", count);
+    }
+
+    #[test]
+    fn migrate() {
+        let mut from = Scheduler::new();
+        let mut to = Scheduler::new();
+        let rr = SchedParam::new(Policy::RoundRobin, 5, 0).unwrap();
+        from.add(1, SchedParam::default());
+        from.add(2, rr);
+        from.wake(1);
+        from.wake(2);
+        assert_eq!(from.runnable_tasks().collect::<Vec<_>>(), vec![2, 1]);
+        assert!(from.migrate(2, &mut to));
+        assert!(!from.migrate(2, &mut to));
+        assert_eq!(from.runnable_tasks().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(to.param(2), Some(rr));
+        assert_eq!(to.pick(), Some(2));
+    }
+
+    #[test]
+    fn state() {
+        let mut sched = Scheduler::new();
+        sched.add(1, SchedParam::default());
+        assert_eq!(sched.pick(), None);
+        sched.wake(1);
+        sched.wake(1);
+        assert_eq!(sched.runnable(), 1);
+        assert_eq!(sched.pick(), Some(1));
+        assert_eq!(sched.state(1), Some(State::Running));
+        sched.wake(1);
+        assert_eq!(sched.state(1), Some(State::Woken));
+        assert_eq!(
+            sched.pick(),
+            None,
+            "A running task is not in the run queue."
+        );
+        sched.put(1);
+        assert_eq!(sched.state(1), Some(State::Runnable));
+
+        let rr = SchedParam::new(Policy::RoundRobin, 5, 0).unwrap();
+        assert!(sched.set_param(1, rr));
+        assert_eq!(sched.param(1), Some(rr));
+        assert_eq!(sched.pick(), Some(1));
+        assert!(sched.remove(1));
+        assert_eq!(sched.runnable(), 0);
+        assert!(!sched.set_param(1, rr));
+    }
+}
@@ -0,0 +1,384 @@
+//! Run queues of multiple CPUs
+//!
+//! Each CPU has its own `Scheduler`, and a task stays on one CPU allowed by its
+//! affinity. A CPU with nothing to run steals a runnable task from the busiest
+//! other CPU which the task is allowed to run on.
+
+use crate::param::SchedParam;
+use crate::scheduler::{Scheduler, State};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+/// Max number of CPUs in a `CpuSet`
+pub const MAX_CPUS: usize = 64;
+
+/// A set of CPUs, bit `i` for CPU `i`, as `cpu_set_t` of sched_setaffinity(2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CpuSet(u64);
+
+impl CpuSet {
+    pub const fn empty() -> Self {
+        CpuSet(0)
+    }
+
+    pub const fn all() -> Self {
+        CpuSet(!0)
+    }
+
+    pub const fn from_bits(bits: u64) -> Self {
+        CpuSet(bits)
+    }
+
+    pub const fn bits(&self) -> u64 {
+        self.0
+    }
+
+    pub fn contains(&self, cpu: usize) -> bool {
+        cpu < MAX_CPUS && self.0 & (1 << cpu) != 0
+    }
+
+    pub fn insert(&mut self, cpu: usize) {
+        assert!(cpu < MAX_CPUS, "no such CPU");
+        self.0 |= 1 << cpu;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn intersection(&self, other: CpuSet) -> CpuSet {
+        CpuSet(self.0 & other.0)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> {
+        let bits = self.0;
+        (0..MAX_CPUS).filter(move |&cpu| bits & (1 << cpu) != 0)
+    }
+}
+
+/// Load statistics of a CPU
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CpuStat {
+    /// Tasks in the run queue
+    pub runnable: usize,
+    /// Timer ticks spent running tasks
+    pub busy_ticks: usize,
+    /// Tasks picked to run
+    pub switches: usize,
+    /// Tasks stolen from other CPUs
+    pub steals: usize,
+}
+
+struct Placement {
+    /// The CPU whose run queue has the task
+    cpu: usize,
+    affinity: CpuSet,
+}
+
+/// Run queues of CPUs, the tasks are identified by `T`
+pub struct SmpScheduler<T> {
+    queues: Vec<Scheduler<T>>,
+    stats: Vec<CpuStat>,
+    tasks: BTreeMap<T, Placement>,
+    /// CPUs running tasks, the others only get tasks which can not run elsewhere
+    online: CpuSet,
+}
+
+impl<T: Ord + Copy> SmpScheduler<T> {
+    pub fn new(cpus: usize) -> Self {
+        assert!(cpus <= MAX_CPUS, "too many CPUs");
+        SmpScheduler {
+            queues: (0..cpus).map(|_| Scheduler::new()).collect(),
+            stats: (0..cpus).map(|_| CpuStat::default()).collect(),
+            tasks: BTreeMap::new(),
+            online: CpuSet::empty(),
+        }
+    }
+
+    /// Start running tasks on `cpu`
+    pub fn set_online(&mut self, cpu: usize) {
+        self.online.insert(cpu);
+    }
+
+    pub fn online(&self) -> CpuSet {
+        self.online
+    }
+
+    /// Add a sleeping task, `wake` it to run.
+    /// It is placed on the least loaded CPU in `affinity`, preferring `cpu`.
+    /// Return false if no CPU is in `affinity`.
+    pub fn add(&mut self, id: T, param: SchedParam, affinity: CpuSet, cpu: usize) -> bool {
+        let target = match self.place(affinity, cpu) {
+            Some(target) => target,
+            None => return false,
+        };
+        self.queues[target].add(id, param);
+        let placement = Placement {
+            cpu: target,
+            affinity,
+        };
+        self.tasks.insert(id, placement);
+        true
+    }
+
+    /// Remove a task, return false if there is no such task
+    pub fn remove(&mut self, id: T) -> bool {
+        match self.tasks.remove(&id) {
+            Some(placement) => self.queues[placement.cpu].remove(id),
+            None => false,
+        }
+    }
+
+    pub fn state(&self, id: T) -> Option<State> {
+        self.queues[self.cpu(id)?].state(id)
+    }
+
+    pub fn param(&self, id: T) -> Option<SchedParam> {
+        self.queues[self.cpu(id)?].param(id)
+    }
+
+    /// Change the parameters of a task, return false if there is no such task
+    pub fn set_param(&mut self, id: T, param: SchedParam) -> bool {
+        match self.cpu(id) {
+            Some(cpu) => self.queues[cpu].set_param(id, param),
+            None => false,
+        }
+    }
+
+    /// The CPU whose run queue has the task
+    pub fn cpu(&self, id: T) -> Option<usize> {
+        self.tasks.get(&id).map(|placement| placement.cpu)
+    }
+
+    pub fn affinity(&self, id: T) -> Option<CpuSet> {
+        self.tasks.get(&id).map(|placement| placement.affinity)
+    }
+
+    /// Change the CPUs a task can run on, moving it if its CPU is not allowed.
+    /// A running task keeps running, and runs on the new CPU once put back.
+    /// Return false if there is no such task, or no CPU is in `affinity`.
+    pub fn set_affinity(&mut self, id: T, affinity: CpuSet) -> bool {
+        let cpu = match self.cpu(id) {
+            Some(cpu) => cpu,
+            None => return false,
+        };
+        let target = match self.place(affinity, cpu) {
+            Some(target) => target,
+            None => return false,
+        };
+        self.tasks.get_mut(&id).unwrap().affinity = affinity;
+        if !affinity.contains(cpu) {
+            self.migrate(id, cpu, target);
+        }
+        true
+    }
+
+    /// Make a task runnable
+    pub fn wake(&mut self, id: T) {
+        if let Some(cpu) = self.cpu(id) {
+            self.queues[cpu].wake(id);
+        }
+    }
+
+    /// Pick the next task to run on `cpu`, stealing one if its run queue is empty
+    pub fn pick(&mut self, cpu: usize) -> Option<T> {
+        if self.queues[cpu].runnable() == 0 {
+            self.steal(cpu)?;
+            self.stats[cpu].steals += 1;
+        }
+        let id = self.queues[cpu].pick()?;
+        self.stats[cpu].switches += 1;
+        Some(id)
+    }
+
+    /// Put back a task that stops running and is not finished
+    pub fn put(&mut self, id: T) {
+        if let Some(cpu) = self.cpu(id) {
+            self.queues[cpu].put(id);
+        }
+    }
+
+    /// Account a timer tick of a running task.
+    /// Return true if it should give up the CPU to another task.
+    pub fn tick(&mut self, id: T) -> bool {
+        match self.cpu(id) {
+            Some(cpu) => {
+                self.stats[cpu].busy_ticks += 1;
+                self.queues[cpu].tick(id)
+            }
+            None => false,
+        }
+    }
+
+    /// Load statistics of `cpu`
+    pub fn stat(&self, cpu: usize) -> CpuStat {
+        CpuStat {
+            runnable: self.queues[cpu].runnable(),
+            ..self.stats[cpu]
+        }
+    }
+
+    /// The least loaded CPU in `affinity`, online ones first, `cpu` on a tie
+    fn place(&self, affinity: CpuSet, cpu: usize) -> Option<usize> {
+        let valid = affinity.intersection(CpuSet::from_bits(self.all_cpus()));
+        let online = valid.intersection(self.online);
+        let candidates = if online.is_empty() { valid } else { online };
+        candidates
+            .iter()
+            .min_by_key(|&other| (self.queues[other].runnable(), other != cpu))
+    }
+
+    /// Move a runnable task allowed on `cpu` from the busiest other CPU to it
+    fn steal(&mut self, cpu: usize) -> Option<T> {
+        let mut others: Vec<usize> = (0..self.queues.len())
+            .filter(|&other| other != cpu && self.queues[other].runnable() > 0)
+            .collect();
+        others.sort_by_key(|&other| core::cmp::Reverse(self.queues[other].runnable()));
+        for other in others {
+            let id = self.queues[other]
+                .runnable_tasks()
+                .find(|id| self.tasks[id].affinity.contains(cpu));
+            if let Some(id) = id {
+                self.migrate(id, other, cpu);
+                return Some(id);
+            }
+        }
+        None
+    }
+
+    fn migrate(&mut self, id: T, from: usize, to: usize) {
+        let (from_queue, to_queue) = if from < to {
+            let (left, right) = self.queues.split_at_mut(to);
+            (&mut left[from], &mut right[0])
+        } else {
+            let (left, right) = self.queues.split_at_mut(from);
+            (&mut right[0], &mut left[to])
+        };
+        from_queue.migrate(id, to_queue);
+        self.tasks.get_mut(&id).unwrap().cpu = to;
+    }
+
+    fn all_cpus(&self) -> u64 {
+        if self.queues.len() == MAX_CPUS {
+            !0
+        } else {
+            (1 << self.queues.len()) - 1
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::param::Policy;
+
+    fn online(cpus: usize) -> SmpScheduler<usize> {
+        let mut sched = SmpScheduler::new(cpus);
+        for cpu in 0..cpus {
+            sched.set_online(cpu);
+        }
+        sched
+    }
+
+    #[test]
+    fn cpu_set() {
+        let mut set = CpuSet::empty();
+        assert!(set.is_empty());
+        set.insert(1);
+        set.insert(63);
+        assert!(set.contains(63) && !set.contains(0) && !set.contains(64));
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![1, 63]);
+        assert_eq!(set.intersection(CpuSet::from_bits(0b11)).bits(), 0b10);
+    }
+
+    #[test]
+    fn spread_tasks() {
+        let mut sched = online(4);
+        for id in 0..8 {
+            assert!(sched.add(id, SchedParam::default(), CpuSet::all(), 0));
+            sched.wake(id);
+        }
+        for cpu in 0..4 {
+            assert_eq!(sched.stat(cpu).runnable, 2);
+        }
+
+        let pinned = CpuSet::from_bits(0b100);
+        assert!(sched.add(8, SchedParam::default(), pinned, 0));
+        assert_eq!(sched.cpu(8), Some(2));
+        assert!(!sched.add(9, SchedParam::default(), CpuSet::from_bits(0b10000), 0));
+    }
+
+    #[test]
+    fn offline_cpu() {
+        let mut sched = SmpScheduler::new(4);
+        sched.set_online(0);
+        sched.add(1, SchedParam::default(), CpuSet::all(), 0);
+        sched.wake(1);
+        sched.add(2, SchedParam::default(), CpuSet::all(), 0);
+        assert_eq!(sched.cpu(2), Some(0), "Offline CPUs should not get tasks.");
+        sched.add(3, SchedParam::default(), CpuSet::from_bits(0b1000), 0);
+        assert_eq!(sched.cpu(3), Some(3));
+    }
+
+    #[test]
+    fn steal_respects_affinity() {
+        let mut sched = online(2);
+        let cpu0 = CpuSet::from_bits(0b01);
+        for id in 1..=3 {
+            sched.add(id, SchedParam::default(), CpuSet::all(), 0);
+            sched.wake(id);
+        }
+        assert_eq!(sched.cpu(2), Some(1));
+        for id in 1..=3 {
+            assert!(sched.set_affinity(id, cpu0));
+            assert_eq!(
+                sched.cpu(id),
+                Some(0),
+                "Tasks should leave disallowed CPUs."
+            );
+        }
+        assert_eq!(sched.pick(1), None, "Pinned tasks should not be stolen.");
+
+        sched.set_affinity(3, CpuSet::all());
+        assert_eq!(sched.pick(1), Some(3));
+        assert_eq!(sched.cpu(3), Some(1));
+        assert_eq!(sched.stat(1).steals, 1);
+        assert_eq!(sched.stat(0).runnable, 2);
+    }
+
+    #[test]
+    fn steal_from_busiest() {
+        let mut sched = online(3);
+        for (id, cpu) in [(1, 0), (2, 1), (3, 1)].iter() {
+            sched.add(*id, SchedParam::default(), CpuSet::all(), *cpu);
+            sched.wake(*id);
+        }
+        assert_eq!(sched.cpu(3), Some(2), "An idle CPU is preferred.");
+        sched.set_affinity(3, CpuSet::from_bits(0b011));
+        assert_eq!(sched.stat(0).runnable, 2);
+        assert_eq!(sched.pick(2), Some(1));
+        assert_eq!(sched.stat(0).runnable, 1);
+        assert_eq!(sched.stat(1).runnable, 1);
+    }
+
+    #[test]
+    fn running_task_moves() {
+        let mut sched = online(2);
+        let fifo = SchedParam::new(Policy::Fifo, 10, 0).unwrap();
+        sched.add(1, fifo, CpuSet::all(), 0);
+        sched.wake(1);
+        assert_eq!(sched.pick(0), Some(1));
+        assert!(sched.set_affinity(1, CpuSet::from_bits(0b10)));
+        assert_eq!(sched.cpu(1), Some(1));
+        assert_eq!(sched.state(1), Some(State::Running));
+        assert!(!sched.tick(1));
+        sched.wake(1);
+        sched.put(1);
+        assert_eq!(sched.pick(0), None);
+        assert_eq!(sched.pick(1), Some(1));
+        assert_eq!(sched.param(1), Some(fifo));
+        assert_eq!(sched.stat(1).busy_ticks, 1);
+        assert!(sched.remove(1));
+        assert!(!sched.set_affinity(1, CpuSet::all()));
+    }
+}
@@ -3,6 +3,7 @@
 pub use self::handler::*;
 use crate::arch::board::timer::is_pending;
 use crate::process::thread::Thread;
+use crate::signal::{Signal, BUS_ADRALN, ILL_ILLOPC, ILL_ILLTRP, SEGV_ACCERR, TRAP_BRKPT};
 use aarch64::regs::*;
 use alloc::sync::Arc;
 use trapframe::UserContext;
@@ -78,3 +79,24 @@ pub fn handle_user_page_fault(thread: &Arc<Thread>, addr: usize) -> bool {
 pub fn handle_reserved_inst(tf: &mut UserContext) -> bool {
     false
 }
+
+/// The signal, its `si_code` and `si_addr` of an exception from user mode
+pub fn get_trap_signal(_trap: usize, tf: &UserContext) -> (Signal, i32, usize) {
+    use self::syndrome::{Fault, Syndrome};
+    let far = FAR_EL1.get() as usize;
+    match Syndrome::from(ESR_EL1.get() as u32) {
+        Syndrome::Unknown => (Signal::SIGILL, ILL_ILLOPC, tf.elr),
+        Syndrome::PCAlignmentFault | Syndrome::SpAlignmentFault => {
+            (Signal::SIGBUS, BUS_ADRALN, tf.elr)
+        }
+        Syndrome::DataAbort {
+            kind: Fault::Alignment,
+            ..
+        } => (Signal::SIGBUS, BUS_ADRALN, far),
+        Syndrome::DataAbort { .. } | Syndrome::InstructionAbort { .. } => {
+            (Signal::SIGSEGV, SEGV_ACCERR, far)
+        }
+        Syndrome::Breakpoint | Syndrome::Brk(_) => (Signal::SIGTRAP, TRAP_BRKPT, tf.elr),
+        _ => (Signal::SIGILL, ILL_ILLTRP, tf.elr),
+    }
+}
@@ -91,6 +91,10 @@ impl PageTable for PageTableImpl {
             }
         }
     }
+
+    fn token(&self) -> usize {
+        self.root_frame.start_address().as_u64() as usize // as TTBR0_EL1
+    }
 }
 
 fn frame_to_page_table(frame: Frame) -> *mut Aarch64PageTable {
@@ -232,7 +236,11 @@ impl PageTableImpl {
     /// Unsafely get the current active page table.
     /// Using ManuallyDrop to wrap the page table: this is how `core::mem::forget` is implemented now.
     pub unsafe fn active() -> ManuallyDrop<Self> {
-        let frame = Frame::of_addr(PageTableImpl::active_token() as u64);
+        Self::from_token(PageTableImpl::active_token())
+    }
+    /// Unsafely get the page table of `token`, which may not be active.
+    pub unsafe fn from_token(token: usize) -> ManuallyDrop<Self> {
+        let frame = Frame::of_addr(token as u64);
         let table = &mut *frame_to_page_table(frame);
         ManuallyDrop::new(PageTableImpl {
             page_table: MappedPageTable::new(table, frame_to_page_table),
@@ -298,10 +306,6 @@ impl PageTableExt for PageTableImpl {
         // kernel page table is based on TTBR1_EL1 and will nerver change.
     }
 
-    fn token(&self) -> usize {
-        self.root_frame.start_address().as_u64() as usize // as TTBR0_EL1
-    }
-
     unsafe fn set_token(token: usize) {
         ttbr_el1_write(0, Frame::of_addr(token as u64));
     }
@@ -1,6 +1,7 @@
 use crate::arch::paging::get_root_page_table_ptr;
 use crate::drivers::IRQ_MANAGER;
 use crate::process::thread::Thread;
+use crate::signal::{Signal, ILL_ILLOPC, ILL_ILLTRP};
 use alloc::sync::Arc;
 use log::*;
 use mips::addr::*;
@@ -205,6 +206,16 @@ pub fn handle_reserved_inst(tf: &mut UserContext) -> bool {
     false
 }
 
+/// The signal, its `si_code` and `si_addr` of an exception from user mode
+pub fn get_trap_signal(trap: usize, tf: &UserContext) -> (Signal, i32, usize) {
+    use cp0::cause::Exception as E;
+    let cause = cp0::cause::Cause { bits: trap as u32 };
+    match cause.cause() {
+        E::ReservedInstruction => (Signal::SIGILL, ILL_ILLOPC, tf.epc),
+        _ => (Signal::SIGILL, ILL_ILLTRP, tf.epc),
+    }
+}
+
 pub fn handle_user_page_fault(thread: &Arc<Thread>, addr: usize) -> bool {
     let virt_addr = VirtAddr::new(addr);
     let root_table = unsafe { &mut *(get_root_page_table_ptr() as *mut MIPSPageTable) };
@@ -60,6 +60,10 @@ impl PageTable for PageTableImpl {
     }
 
     fn flush_cache_copy_user(&mut self, _start: usize, _end: usize, _execute: bool) {}
+
+    fn token(&self) -> usize {
+        self.root_frame.to_kernel_unmapped().as_usize()
+    }
 }
 
 extern "C" {
@@ -160,6 +164,20 @@ impl PageTableImpl {
         })
     }
 
+    /// Unsafely get the page table of `token`, which may not be active.
+    pub unsafe fn from_token(token: usize) -> ManuallyDrop<Self> {
+        if token == PageTableImpl::active_token() {
+            return Self::active();
+        }
+        let frame = Frame::of_addr(PhysAddr::new(token & 0x7fffffff));
+        let table = &mut *(frame.start_address().as_usize() as *mut MIPSPageTable);
+        ManuallyDrop::new(PageTableImpl {
+            page_table: TwoLevelPageTable::new(table),
+            root_frame: frame,
+            entry: None,
+        })
+    }
+
     /// The method for getting the kernel page table.
     /// In mipsel kernel page table and user page table are the same table. However you have to do the initialization.
     pub unsafe fn kernel_table() -> ManuallyDrop<Self> {
@@ -186,10 +204,6 @@ impl PageTableExt for PageTableImpl {
         /* nothing to do */
     }
 
-    fn token(&self) -> usize {
-        self.root_frame.to_kernel_unmapped().as_usize()
-    }
-
     unsafe fn set_token(token: usize) {
         set_root_page_table_ptr(token);
     }
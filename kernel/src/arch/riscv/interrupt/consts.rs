@@ -1,3 +1,10 @@
+pub const InstructionMisaligned: usize = 0;
+pub const InstructionFault: usize = 1;
+pub const IllegalInstruction: usize = 2;
+pub const LoadMisaligned: usize = 4;
+pub const LoadFault: usize = 5;
+pub const StoreMisaligned: usize = 6;
+pub const StoreFault: usize = 7;
 pub const Syscall: usize = 8;
 pub const InstructionPageFault: usize = 12;
 pub const LoadPageFault: usize = 13;
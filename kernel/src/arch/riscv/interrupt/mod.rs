@@ -1,6 +1,7 @@
 use crate::arch::interrupt::consts::SupervisorExternal;
 use crate::drivers::IRQ_MANAGER;
 use crate::process::thread::Thread;
+use crate::signal::{Signal, BUS_ADRALN, ILL_ILLOPC, ILL_ILLTRP, SEGV_ACCERR, TRAP_BRKPT};
 use alloc::sync::Arc;
 use log::*;
 use riscv::register::*;
@@ -142,3 +143,18 @@ pub fn handle_user_page_fault_ext(thread: &Arc<Thread>, addr: usize, access: Acc
 pub fn handle_reserved_inst(tf: &mut UserContext) -> bool {
     false
 }
+
+/// The signal, its `si_code` and `si_addr` of an exception from user mode
+pub fn get_trap_signal(trap: usize, tf: &UserContext) -> (Signal, i32, usize) {
+    match trap {
+        consts::IllegalInstruction => (Signal::SIGILL, ILL_ILLOPC, tf.sepc),
+        consts::InstructionMisaligned | consts::LoadMisaligned | consts::StoreMisaligned => {
+            (Signal::SIGBUS, BUS_ADRALN, stval::read())
+        }
+        consts::InstructionFault | consts::LoadFault | consts::StoreFault => {
+            (Signal::SIGSEGV, SEGV_ACCERR, stval::read())
+        }
+        consts::Ebreak => (Signal::SIGTRAP, TRAP_BRKPT, tf.sepc),
+        _ => (Signal::SIGILL, ILL_ILLTRP, tf.sepc),
+    }
+}
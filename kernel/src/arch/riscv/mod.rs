@@ -76,6 +76,8 @@ pub extern "C" fn rust_main(hartid: usize, device_tree_paddr: usize) -> ! {
         board::init_external_interrupt();
     }
     crate::process::init();
+    #[cfg(feature = "kprobes_test")]
+    crate::kprobes::run_tests();
     info!(
         "Hello RISCV! in hart {}, device tree @ {:#x}",
         hartid, device_tree_vaddr
@@ -3,6 +3,7 @@ use crate::memory::{alloc_frame, dealloc_frame, phys_to_virt};
 use core::mem::ManuallyDrop;
 use log::*;
 use rcore_memory::paging::*;
+use rcore_memory::PAGE_SIZE;
 use riscv::addr::*;
 use riscv::asm::{sfence_vma, sfence_vma_all};
 use riscv::paging::MapperFlushable;
@@ -74,6 +75,18 @@ impl PageTable for PageTableImpl {
     }
 
     fn flush_cache_copy_user(&mut self, _start: usize, _end: usize, _execute: bool) {}
+
+    fn flush_tlb_remote(&mut self, addr: usize) {
+        let others = !(1 << super::cpu::id());
+        super::sbi::remote_sfence_vma(others, addr, PAGE_SIZE);
+    }
+
+    fn token(&self) -> usize {
+        #[cfg(target_arch = "riscv32")]
+        return self.root_frame.number() | (1 << 31);
+        #[cfg(target_arch = "riscv64")]
+        return self.root_frame.number() | (8 << 60);
+    }
 }
 
 /// implementation for the Entry trait in /crate/memory/src/paging/mod.rs
@@ -130,7 +143,8 @@ impl Entry for PageEntry {
         self.0.flags_mut().remove(EF::RESERVED1 | EF::RESERVED2);
     }
     fn swapped(&self) -> bool {
-        self.0.flags().contains(EF::RESERVED1)
+        // shares the bit with writable_shared, which is only set on valid pages
+        !self.0.flags().contains(EF::VALID) && self.0.flags().contains(EF::RESERVED1)
     }
     fn set_swapped(&mut self, value: bool) {
         self.0.flags_mut().set(EF::RESERVED1, value);
@@ -157,11 +171,15 @@ impl PageTableImpl {
     /// Unsafely get the current active page table.
     /// Using ManuallyDrop to wrap the page table: this is how `core::mem::forget` is implemented now.
     pub unsafe fn active() -> ManuallyDrop<Self> {
+        Self::from_token(PageTableImpl::active_token())
+    }
+    /// Unsafely get the page table of `token`, which may not be active.
+    pub unsafe fn from_token(token: usize) -> ManuallyDrop<Self> {
         #[cfg(target_arch = "riscv32")]
         let mask = 0x7fffffff;
         #[cfg(target_arch = "riscv64")]
         let mask = 0x0fffffff_ffffffff;
-        let frame = Frame::of_ppn(PageTableImpl::active_token() & mask);
+        let frame = Frame::of_ppn(token & mask);
         let table = frame.as_kernel_mut(PHYSICAL_MEMORY_OFFSET as u64);
         ManuallyDrop::new(PageTableImpl {
             page_table: TopLevelPageTable::new(table, PHYSICAL_MEMORY_OFFSET),
@@ -218,13 +236,6 @@ impl PageTableExt for PageTableImpl {
         }
     }
 
-    fn token(&self) -> usize {
-        #[cfg(target_arch = "riscv32")]
-        return self.root_frame.number() | (1 << 31);
-        #[cfg(target_arch = "riscv64")]
-        return self.root_frame.number() | (8 << 60);
-    }
-
     unsafe fn set_token(token: usize) {
         satp::write(token);
     }
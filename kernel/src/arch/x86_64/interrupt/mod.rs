@@ -4,6 +4,9 @@ mod handler;
 pub use self::handler::*;
 use crate::memory::phys_to_virt;
 use crate::process::thread::Thread;
+use crate::signal::{
+    Signal, BUS_ADRALN, FPE_FLTINV, FPE_INTDIV, ILL_ILLOPC, ILL_ILLTRP, SI_KERNEL, TRAP_BRKPT,
+};
 use alloc::sync::Arc;
 use apic::*;
 use trapframe::{TrapFrame, UserContext};
@@ -70,3 +73,20 @@ pub fn handle_user_page_fault(thread: &Arc<Thread>, addr: usize) -> bool {
 pub fn handle_reserved_inst(tf: &mut UserContext) -> bool {
     false
 }
+
+/// The signal, its `si_code` and `si_addr` of an exception from user mode
+pub fn get_trap_signal(trap: usize, tf: &UserContext) -> (Signal, i32, usize) {
+    let pc = tf.general.rip;
+    match trap {
+        consts::DivideError => (Signal::SIGFPE, FPE_INTDIV, pc),
+        consts::FloatingPointException | consts::SIMDFloatingPointException => {
+            (Signal::SIGFPE, FPE_FLTINV, pc)
+        }
+        consts::InvalidOpcode => (Signal::SIGILL, ILL_ILLOPC, pc),
+        consts::Breakpoint | consts::Debug => (Signal::SIGTRAP, TRAP_BRKPT, pc),
+        consts::AlignmentCheck => (Signal::SIGBUS, BUS_ADRALN, 0),
+        consts::SegmentNotPresent | consts::StackSegmentFault => (Signal::SIGBUS, SI_KERNEL, 0),
+        consts::GeneralProtectionFault => (Signal::SIGSEGV, SI_KERNEL, 0),
+        _ => (Signal::SIGILL, ILL_ILLTRP, pc),
+    }
+}
@@ -94,6 +94,10 @@ impl PageTable for PageTableImpl {
     }
 
     fn flush_cache_copy_user(&mut self, _start: usize, _end: usize, _execute: bool) {}
+
+    fn token(&self) -> usize {
+        self.2.start_address().as_u64() as usize // as CR3
+    }
 }
 
 fn frame_to_page_table(frame: Frame) -> *mut x86PageTable {
@@ -200,7 +204,11 @@ impl PageTableImpl {
     /// Unsafely get the current active page table.
     /// Using ManuallyDrop to wrap the page table: this is how `core::mem::forget` is implemented now.
     pub unsafe fn active() -> ManuallyDrop<Self> {
-        let frame = Cr3::read().0;
+        Self::from_token(PageTableImpl::active_token())
+    }
+    /// Unsafely get the page table of `token`, which may not be active.
+    pub unsafe fn from_token(token: usize) -> ManuallyDrop<Self> {
+        let frame = Frame::containing_address(PhysAddr::new(token as u64));
         let table = &mut *frame_to_page_table(frame);
         ManuallyDrop::new(PageTableImpl(
             MappedPageTable::new(table, frame_to_page_table),
@@ -241,10 +249,6 @@ impl PageTableExt for PageTableImpl {
         table[KSEG2_PM4].set_addr(ekseg2.addr(), ekseg2.flags() | EF::GLOBAL);
     }
 
-    fn token(&self) -> usize {
-        self.2.start_address().as_u64() as usize // as CR3
-    }
-
     unsafe fn set_token(token: usize) {
         Cr3::write(
             Frame::containing_address(PhysAddr::new(token as u64)),
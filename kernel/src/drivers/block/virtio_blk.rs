@@ -9,7 +9,7 @@ use super::{
 };
 use crate::{drivers::NetDriver, sync::SpinNoIrqLock as Mutex};
 
-struct VirtIOBlkDriver(Mutex<VirtIOBlk<'static>>);
+pub struct VirtIOBlkDriver(Mutex<VirtIOBlk<'static>>);
 
 impl Driver for VirtIOBlkDriver {
     fn try_handle_interrupt(&self, _irq: Option<usize>) -> bool {
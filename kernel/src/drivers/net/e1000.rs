@@ -17,7 +17,7 @@ use isomorphic_drivers::net::ethernet::structs::EthernetAddress as DriverEtherne
 use rcore_memory::PAGE_SIZE;
 
 use crate::drivers::{provider::Provider, BlockDriver};
-use crate::net::SOCKETS;
+use crate::net::{filter_received, receive_hook, SOCKETS};
 use crate::sync::SpinNoIrqLock as Mutex;
 
 use super::{
@@ -25,8 +25,9 @@ use super::{
     NetDriver,
 };
 
+/// The device and its index in `NET_DRIVERS`
 #[derive(Clone)]
-pub struct E1000Driver(Arc<Mutex<E1000<Provider>>>);
+pub struct E1000Driver(Arc<Mutex<E1000<Provider>>>, usize);
 
 pub struct E1000Interface {
     iface: Mutex<EthernetInterface<'static, 'static, 'static, E1000Driver>>,
@@ -49,6 +50,7 @@ impl Driver for E1000Interface {
             let mut sockets = SOCKETS.lock();
             match self.iface.lock().poll(&mut sockets, timestamp) {
                 Ok(_) => {
+                    filter_received(&mut sockets);
                     SOCKET_ACTIVITY.notify_all();
                 }
                 Err(err) => {
@@ -100,6 +102,7 @@ impl NetDriver for E1000Interface {
         let mut sockets = SOCKETS.lock();
         match self.iface.lock().poll(&mut sockets, timestamp) {
             Ok(_) => {
+                filter_received(&mut sockets);
                 SOCKET_ACTIVITY.notify_all();
             }
             Err(err) => {
@@ -139,10 +142,13 @@ impl phy::Device<'_> for E1000Driver {
     type TxToken = E1000TxToken;
 
     fn receive(&mut self) -> Option<(Self::RxToken, Self::TxToken)> {
-        self.0
-            .lock()
-            .receive()
-            .map(|vec| (E1000RxToken(vec), E1000TxToken(self.clone())))
+        loop {
+            // not locked while the hook may send
+            let mut frame = self.0.lock().receive()?;
+            if receive_hook(self.1, &mut frame) {
+                return Some((E1000RxToken(frame), E1000TxToken(self.clone())));
+            }
+        }
     }
 
     fn transmit(&mut self) -> Option<Self::TxToken> {
@@ -194,7 +200,8 @@ pub fn init(name: String, irq: Option<usize>, header: usize, size: usize, index:
 
     let e1000 = E1000::new(header, size, DriverEthernetAddress::from_bytes(&mac));
 
-    let net_driver = E1000Driver(Arc::new(Mutex::new(e1000)));
+    let ifindex = NET_DRIVERS.read().len();
+    let net_driver = E1000Driver(Arc::new(Mutex::new(e1000)), ifindex);
 
     let ethernet_addr = EthernetAddress::from_bytes(&mac);
     let ip_addrs = [IpCidr::new(IpAddress::v4(10, 0, index as u8, 2), 24)];
@@ -15,7 +15,7 @@ use smoltcp::wire::EthernetAddress;
 use smoltcp::wire::*;
 use smoltcp::Result;
 
-use crate::net::SOCKETS;
+use crate::net::{filter_received, receive_hook, SOCKETS};
 use crate::sync::FlagsGuard;
 use crate::{drivers::BlockDriver, sync::SpinNoIrqLock as Mutex};
 
@@ -32,6 +32,8 @@ struct IXGBEDriver {
     header: usize,
     size: usize,
     mtu: usize,
+    /// index in `NET_DRIVERS`
+    ifindex: usize,
 }
 
 pub struct IXGBEInterface {
@@ -59,6 +61,7 @@ impl Driver for IXGBEInterface {
             let mut sockets = SOCKETS.lock();
             match self.iface.lock().poll(&mut sockets, timestamp) {
                 Ok(_) => {
+                    filter_received(&mut sockets);
                     SOCKET_ACTIVITY.notify_all();
                 }
                 Err(err) => {
@@ -110,6 +113,7 @@ impl NetDriver for IXGBEInterface {
         let mut sockets = SOCKETS.lock();
         match self.iface.lock().poll(&mut sockets, timestamp) {
             Ok(_) => {
+                filter_received(&mut sockets);
                 SOCKET_ACTIVITY.notify_all();
             }
             Err(err) => {
@@ -139,10 +143,12 @@ impl<'a> phy::Device<'a> for IXGBEDriver {
     fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
         let _ = FlagsGuard::no_irq_region();
         if self.inner.lock().can_send() {
-            if let Some(data) = self.inner.lock().recv() {
-                Some((IXGBERxToken(data), IXGBETxToken(self.clone())))
-            } else {
-                None
+            loop {
+                // not locked while the hook may send
+                let mut data = self.inner.lock().recv()?;
+                if receive_hook(self.ifindex, &mut data) {
+                    return Some((IXGBERxToken(data), IXGBETxToken(self.clone())));
+                }
             }
         } else {
             None
@@ -212,6 +218,7 @@ pub fn ixgbe_init(
         header,
         size,
         mtu: 1500,
+        ifindex: NET_DRIVERS.read().len(),
     };
 
     let ip_addrs = [IpCidr::new(IpAddress::v4(10, 0, index as u8, 2), 24)];
@@ -1,21 +1,24 @@
 use alloc::format;
 use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 
 use smoltcp::phy::{self, DeviceCapabilities};
 use smoltcp::time::Instant;
 use smoltcp::wire::{EthernetAddress, Ipv4Address};
-use smoltcp::Result;
+use smoltcp::{Error, Result};
 use virtio_drivers::{VirtIOHeader, VirtIONet};
 
 use super::{
-    super::{DeviceType, Driver, DRIVERS, IRQ_MANAGER, NET_DRIVERS},
+    super::{DeviceType, Driver, DRIVERS, IRQ_MANAGER, NET_DRIVERS, SOCKET_ACTIVITY},
     NetDriver,
 };
+use crate::net::{receive_hook, SOCKETS};
 use crate::{drivers::BlockDriver, sync::SpinNoIrqLock as Mutex};
 
+/// The device and its index in `NET_DRIVERS`
 #[derive(Clone)]
-pub struct VirtIONetDriver(Arc<Mutex<VirtIONet<'static>>>);
+pub struct VirtIONetDriver(Arc<Mutex<VirtIONet<'static>>>, usize);
 
 impl NetDriver for VirtIONetDriver {
     fn get_mac(&self) -> EthernetAddress {
@@ -33,11 +36,39 @@ impl NetDriver for VirtIONetDriver {
     fn poll(&self) {
         unimplemented!()
     }
+
+    fn send(&self, data: &[u8]) -> Option<usize> {
+        self.0.lock().send(data).ok().map(|_| data.len())
+    }
+}
+
+impl VirtIONetDriver {
+    /// Take a received frame off the device.
+    fn recv_frame(&self) -> Option<Vec<u8>> {
+        let mut buffer = [0u8; 2000];
+        let mut driver = self.0.lock();
+        if !driver.can_recv() {
+            return None;
+        }
+        let len = driver.recv(&mut buffer).expect("failed to recv packet");
+        Some(buffer[..len].to_vec())
+    }
 }
 
 impl Driver for VirtIONetDriver {
     fn try_handle_interrupt(&self, _irq: Option<usize>) -> bool {
-        self.0.lock().ack_interrupt()
+        if !self.0.lock().ack_interrupt() {
+            return false;
+        }
+        // there is no network stack on virtio-net, received frames only take
+        // the early receive path
+        let sockets = SOCKETS.lock();
+        while let Some(mut frame) = self.recv_frame() {
+            receive_hook(self.1, &mut frame);
+        }
+        drop(sockets);
+        SOCKET_ACTIVITY.notify_all();
+        true
     }
 
     fn device_type(&self) -> DeviceType {
@@ -92,10 +123,11 @@ impl phy::RxToken for VirtIONetDriver {
     where
         F: FnOnce(&mut [u8]) -> Result<R>,
     {
-        let mut buffer = [0u8; 2000];
-        let mut driver = self.0.lock();
-        let len = driver.recv(&mut buffer).expect("failed to recv packet");
-        f(&mut buffer[..len])
+        let mut frame = self.recv_frame().ok_or(Error::Exhausted)?;
+        if !receive_hook(self.1, &mut frame) {
+            return Err(Error::Dropped);
+        }
+        f(&mut frame)
     }
 }
 
@@ -114,7 +146,8 @@ impl phy::TxToken for VirtIONetDriver {
 
 pub fn init(header: &'static mut VirtIOHeader) {
     let net = VirtIONet::new(header).expect("failed to create net driver");
-    let driver = Arc::new(VirtIONetDriver(Arc::new(Mutex::new(net))));
+    let ifindex = NET_DRIVERS.read().len();
+    let driver = Arc::new(VirtIONetDriver(Arc::new(Mutex::new(net)), ifindex));
 
     DRIVERS.write().push(driver.clone());
     IRQ_MANAGER.write().register_all(driver.clone());
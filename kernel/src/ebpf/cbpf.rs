@@ -0,0 +1,281 @@
+//! Translation of classic BPF filters to eBPF.
+//!
+//! Registers are mapped as A to r0, X to r7 and the context to r6; the 16
+//! scratch words `M[]` live at the bottom of the stack frame. Classic
+//! filters only jump forward, so the result needs no further checks.
+//! Packet loads of socket filters are bounds checked at run time, and one
+//! past the end of the packet makes the filter return 0.
+
+use alloc::vec::Vec;
+use crate::syscall::SysError;
+use super::vm::*;
+
+/// `struct sock_filter`
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SockFilter {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+/// `struct sock_fprog`
+#[repr(C)]
+#[derive(Debug)]
+pub struct SockFprog {
+    pub len: u16,
+    pub filter: usize,
+}
+
+pub const BPF_MAXINSNS: usize = 4096;
+const BPF_MEMWORDS: u32 = 16;
+
+// classic classes not shared with eBPF
+const BPF_RET: u8 = 0x06;
+const BPF_MISC: u8 = 0x07;
+
+// classic modes
+const BPF_ABS: u8 = 0x20;
+const BPF_IND: u8 = 0x40;
+const BPF_LEN: u8 = 0x80;
+const BPF_MSH: u8 = 0xa0;
+
+const BPF_K: u8 = 0x00;
+const BPF_A: u8 = 0x10;
+
+const BPF_MOV: u8 = 0xb0;
+const BPF_NEG: u8 = 0x80;
+const BPF_ADD: u8 = 0x00;
+const BPF_AND: u8 = 0x50;
+const BPF_LSH: u8 = 0x60;
+const BPF_END: u8 = 0xd0;
+const BPF_TO_BE: u8 = 0x08;
+const BPF_DIV: u8 = 0x30;
+const BPF_MOD: u8 = 0x90;
+const BPF_JA: u8 = 0x00;
+const BPF_JNE: u8 = 0x50;
+const BPF_JLE: u8 = 0xb0;
+const BPF_TAX: u8 = 0x00;
+const BPF_TXA: u8 = 0x80;
+
+const REG_A: u8 = 0;
+const REG_ARG: u8 = 1;
+const REG_OFF: u8 = 2;
+const REG_END: u8 = 3;
+const REG_TMP: u8 = 4;
+const REG_CTX: u8 = 6;
+const REG_X: u8 = 7;
+const REG_FP: u8 = 10;
+
+/// What a filter is run on.
+#[derive(Clone, Copy, Debug)]
+pub enum CbpfContext {
+    /// `struct seccomp_data` of the given size; only aligned word loads.
+    Seccomp { size: u32 },
+    /// A packet, described by the packet length (u32) and a pointer to its
+    /// first byte (u64) at the given offsets of the context. Loads are big
+    /// endian, as in network byte order.
+    Socket { len_off: i16, data_off: i16 },
+}
+
+fn mem_offset(k: u32) -> i16 {
+    -((BPF_MEMWORDS - k) as i16 * 4)
+}
+
+/// Check a classic filter the way `bpf_check_classic` does.
+fn check(filter: &[SockFilter], cx: CbpfContext) -> Result<(), SysError> {
+    if filter.is_empty() || filter.len() > BPF_MAXINSNS {
+        return Err(SysError::EINVAL);
+    }
+    for (pc, insn) in filter.iter().enumerate() {
+        let code = insn.code as u8;
+        let ok = match code & 0x07 {
+            BPF_LD | BPF_LDX => match code & 0xe0 {
+                BPF_IMM => true,
+                BPF_MEM => insn.k < BPF_MEMWORDS,
+                BPF_LEN => code & 0x18 == BPF_W,
+                BPF_ABS if code & 0x07 == BPF_LD => match cx {
+                    CbpfContext::Seccomp { size } => {
+                        code & 0x18 == BPF_W && insn.k % 4 == 0 && insn.k < size
+                    }
+                    // negative offsets address ancillary data, which there is none of
+                    CbpfContext::Socket { .. } => code & 0x18 != BPF_DW && (insn.k as i32) >= 0,
+                },
+                BPF_IND if code & 0x07 == BPF_LD => match cx {
+                    CbpfContext::Seccomp { .. } => false,
+                    CbpfContext::Socket { .. } => code & 0x18 != BPF_DW,
+                },
+                BPF_MSH if code & 0x07 == BPF_LDX => match cx {
+                    CbpfContext::Seccomp { .. } => false,
+                    CbpfContext::Socket { .. } => code & 0x18 == BPF_B && (insn.k as i32) >= 0,
+                },
+                _ => false,
+            },
+            BPF_ST | BPF_STX => insn.k < BPF_MEMWORDS,
+            BPF_ALU => match code & 0xf0 {
+                BPF_DIV | BPF_MOD if code & BPF_X == BPF_K => insn.k != 0,
+                op => op <= 0xa0,
+            },
+            BPF_JMP => match code & 0xf0 {
+                BPF_JA => (insn.k as usize) < filter.len() - pc - 1,
+                0x10..=0x40 => {
+                    (insn.jt as usize) < filter.len() - pc - 1
+                        && (insn.jf as usize) < filter.len() - pc - 1
+                }
+                _ => false,
+            },
+            BPF_RET => code & 0x18 != 0x18,
+            BPF_MISC => code & 0xf8 == BPF_TAX || code & 0xf8 == BPF_TXA,
+            _ => false,
+        };
+        if !ok || insn.code > 0xff {
+            warn!("cbpf: invalid instruction {:#x} at {}", insn.code, pc);
+            return Err(SysError::EINVAL);
+        }
+    }
+    // every path has to end with a return
+    match filter[filter.len() - 1].code as u8 & 0x07 {
+        BPF_RET => Ok(()),
+        _ => Err(SysError::EINVAL),
+    }
+}
+
+/// Load `size` bytes of the packet at `k`, plus X if `indexed`, into `dst`.
+fn load_packet(insns: &mut Vec<u64>, cx: CbpfContext, code: u8, k: i32, indexed: bool, dst: u8) {
+    let (len_off, data_off) = match cx {
+        CbpfContext::Socket { len_off, data_off } => (len_off, data_off),
+        CbpfContext::Seccomp { .. } => unreachable!(),
+    };
+    let size = match code & 0x18 {
+        BPF_W => 4,
+        BPF_H => 2,
+        _ => 1,
+    };
+    if indexed {
+        insns.push(encode(BPF_ALU | BPF_MOV | BPF_X, REG_OFF, REG_X, 0, 0));
+        insns.push(encode(BPF_ALU | BPF_ADD | BPF_K, REG_OFF, 0, 0, k));
+    } else {
+        insns.push(encode(BPF_ALU | BPF_MOV | BPF_K, REG_OFF, 0, 0, k));
+    }
+    // return 0 unless offset + size <= len
+    insns.push(encode(BPF_ALU64 | BPF_MOV | BPF_X, REG_END, REG_OFF, 0, 0));
+    insns.push(encode(BPF_ALU64 | BPF_ADD | BPF_K, REG_END, 0, 0, size));
+    insns.push(encode(BPF_LDX | BPF_MEM | BPF_W, REG_TMP, REG_CTX, len_off, 0));
+    insns.push(encode(BPF_JMP | BPF_JLE | BPF_X, REG_END, REG_TMP, 2, 0));
+    insns.push(encode(BPF_ALU | BPF_MOV | BPF_K, REG_A, 0, 0, 0));
+    insns.push(encode(BPF_JMP | BPF_EXIT, 0, 0, 0, 0));
+    insns.push(encode(BPF_LDX | BPF_MEM | BPF_DW, REG_TMP, REG_CTX, data_off, 0));
+    insns.push(encode(BPF_ALU64 | BPF_ADD | BPF_X, REG_TMP, REG_OFF, 0, 0));
+    insns.push(encode(BPF_LDX | BPF_MEM | (code & 0x18), dst, REG_TMP, 0, 0));
+    if size > 1 {
+        insns.push(encode(BPF_ALU | BPF_END | BPF_TO_BE, dst, 0, 0, size * 8));
+    }
+}
+
+/// Translate a classic filter to an eBPF program taking the context in r1.
+pub fn convert(filter: &[SockFilter], cx: CbpfContext) -> Result<Vec<u64>, SysError> {
+    check(filter, cx)?;
+    let mut insns = Vec::new();
+    // index of the first eBPF instruction of each classic one
+    let mut start = Vec::with_capacity(filter.len());
+    // jumps to patch: (eBPF index, classic target)
+    let mut jumps: Vec<(usize, usize)> = Vec::new();
+
+    insns.push(encode(BPF_ALU64 | BPF_MOV | BPF_X, REG_CTX, REG_ARG, 0, 0));
+    insns.push(encode(BPF_ALU | BPF_MOV | BPF_K, REG_A, 0, 0, 0));
+    insns.push(encode(BPF_ALU | BPF_MOV | BPF_K, REG_X, 0, 0, 0));
+
+    for (pc, insn) in filter.iter().enumerate() {
+        start.push(insns.len());
+        let code = insn.code as u8;
+        let k = insn.k as i32;
+        match code & 0x07 {
+            BPF_LD | BPF_LDX => {
+                let dst = if code & 0x07 == BPF_LD { REG_A } else { REG_X };
+                match code & 0xe0 {
+                    BPF_IMM => insns.push(encode(BPF_ALU | BPF_MOV | BPF_K, dst, 0, 0, k)),
+                    BPF_MEM => insns.push(encode(BPF_LDX | BPF_MEM | BPF_W, dst, REG_FP, mem_offset(insn.k), 0)),
+                    BPF_LEN => match cx {
+                        CbpfContext::Seccomp { size } => {
+                            insns.push(encode(BPF_ALU | BPF_MOV | BPF_K, dst, 0, 0, size as i32))
+                        }
+                        CbpfContext::Socket { len_off, .. } => {
+                            insns.push(encode(BPF_LDX | BPF_MEM | BPF_W, dst, REG_CTX, len_off, 0))
+                        }
+                    },
+                    BPF_MSH => {
+                        // X = 4 * (P[k] & 0xf), the length of an IPv4 header
+                        load_packet(&mut insns, cx, code, k, false, REG_X);
+                        insns.push(encode(BPF_ALU | BPF_AND | BPF_K, REG_X, 0, 0, 0xf));
+                        insns.push(encode(BPF_ALU | BPF_LSH | BPF_K, REG_X, 0, 0, 2));
+                    }
+                    mode => match cx {
+                        CbpfContext::Seccomp { .. } => {
+                            insns.push(encode(BPF_LDX | BPF_MEM | BPF_W, REG_A, REG_CTX, k as i16, 0))
+                        }
+                        CbpfContext::Socket { .. } => {
+                            load_packet(&mut insns, cx, code, k, mode == BPF_IND, REG_A)
+                        }
+                    },
+                }
+            }
+            BPF_ST | BPF_STX => {
+                let src = if code & 0x07 == BPF_ST { REG_A } else { REG_X };
+                insns.push(encode(BPF_STX | BPF_MEM | BPF_W, REG_FP, src, mem_offset(insn.k), 0));
+            }
+            BPF_ALU => {
+                let op = code & 0xf0;
+                if (op == BPF_DIV || op == BPF_MOD) && code & BPF_X != 0 {
+                    // dividing by zero makes the filter return 0
+                    insns.push(encode(BPF_JMP32 | BPF_JNE | BPF_K, REG_X, 0, 2, 0));
+                    insns.push(encode(BPF_ALU | BPF_MOV | BPF_K, REG_A, 0, 0, 0));
+                    insns.push(encode(BPF_JMP | BPF_EXIT, 0, 0, 0, 0));
+                }
+                if op == BPF_NEG {
+                    insns.push(encode(BPF_ALU | BPF_NEG, REG_A, 0, 0, 0));
+                } else {
+                    insns.push(encode(BPF_ALU | op | (code & BPF_X), REG_A, REG_X, 0, k));
+                }
+            }
+            BPF_JMP => {
+                let next = pc + 1;
+                if code & 0xf0 == BPF_JA {
+                    jumps.push((insns.len(), next + insn.k as usize));
+                    insns.push(encode(BPF_JMP | BPF_JA, 0, 0, 0, 0));
+                } else {
+                    // compare the 32-bit A, then jump to the false branch unless it follows
+                    jumps.push((insns.len(), next + insn.jt as usize));
+                    insns.push(encode(BPF_JMP32 | (code & 0xf8), REG_A, REG_X, 0, k));
+                    if insn.jf != 0 {
+                        jumps.push((insns.len(), next + insn.jf as usize));
+                        insns.push(encode(BPF_JMP | BPF_JA, 0, 0, 0, 0));
+                    }
+                }
+            }
+            BPF_RET => {
+                match code & 0x18 {
+                    BPF_K => insns.push(encode(BPF_ALU | BPF_MOV | BPF_K, REG_A, 0, 0, k)),
+                    BPF_A => {}
+                    _ => insns.push(encode(BPF_ALU | BPF_MOV | BPF_X, REG_A, REG_X, 0, 0)),
+                }
+                insns.push(encode(BPF_JMP | BPF_EXIT, 0, 0, 0, 0));
+            }
+            _ => {
+                if code & 0xf8 == BPF_TAX {
+                    insns.push(encode(BPF_ALU | BPF_MOV | BPF_X, REG_X, REG_A, 0, 0));
+                } else {
+                    insns.push(encode(BPF_ALU | BPF_MOV | BPF_X, REG_A, REG_X, 0, 0));
+                }
+            }
+        }
+    }
+    for (at, target) in jumps {
+        let off = start[target] as isize - at as isize - 1;
+        if off > i16::MAX as isize {
+            return Err(SysError::E2BIG);
+        }
+        insns[at] = (insns[at] & !0xffff_0000) | ((off as i16 as u16 as u64) << 16);
+    }
+    Ok(insns)
+}
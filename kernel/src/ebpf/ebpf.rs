@@ -5,11 +5,13 @@ use alloc::vec::Vec;
 use num::integer;
 use num_traits::int;
 use core::cell::RefCell;
-use ebpf_rs::interpret::interpret;
+use super::prog::BpfProg;
+use super::vm::interpret;
+use alloc::sync::{Arc, Weak};
 use lazy_static::*;
 use spin::Mutex;
 use trapframe::{TrapFrame, UserContext};
-use crate::kprobes::{ProbeType, uprobe_register, kprobe_register, kprobe_unregister,ProbePlace};
+use crate::kprobes::{ProbeType, uprobe_register, uprobe_unregister, kprobe_check, kprobe_register, kprobe_unregister,ProbePlace};
 use riscv::register::*;
 use core::{
     future::Future,
@@ -18,16 +20,97 @@ use core::{
     task::{Context, Poll},
 };
 use crate::lkm::manager::ModuleManager;
-use executor;
+use crate::process::usdt::{UsdtContext, UsdtProbe};
+use crate::arch::cpu;
+use crate::consts::MAX_CPU_NUM;
+use crate::arch::timer::timer_now;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::sched;
 use riscv::register::mcause::Trap;
 
 pub struct Ebpf {
-    pub inner: RefCell<BTreeMap<usize, EbpfInner>>,
+    pub inner: RefCell<BTreeMap<SiteKey, EbpfSite>>,
 }
 
-pub struct EbpfInner {
+/// A probed place. Kernel probes are found by address, and user probes by
+/// the binary too, as binaries share their virtual addresses.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SiteKey {
+    Kernel(usize),
+    User(String, usize),
+}
+
+impl SiteKey {
+    pub fn new(pp: &ProbePlace, path: String, addr: usize) -> Self {
+        match pp {
+            ProbePlace::Kernel(_) => SiteKey::Kernel(addr),
+            ProbePlace::User(_) => SiteKey::User(path, addr),
+        }
+    }
+}
+
+/// The probe at an address, shared by the programs attached there.
+pub struct EbpfSite {
     addr: usize,
-    prog: Vec<u64>,
+    path: String,
+    pp: ProbePlace,
+    /// The USDT probe at `addr`, whose arguments the programs are run on.
+    usdt: Option<UsdtProbe>,
+    /// Attachments in the order they run: by priority, then by age.
+    chain: Arc<Mutex<Vec<EbpfInner>>>,
+}
+
+pub struct EbpfInner {
+    prog_id: u32,
+    prog: Weak<BpfProg>,
+    /// Set when the attachment is the only owner of its program, as for
+    /// programs passed inline to `sys_register_ebpf`.
+    owned: Option<Arc<BpfProg>>,
+    /// lower runs first
+    priority: i32,
+    /// returned by `bpf_get_attach_cookie` to the program
+    cookie: u64,
+}
+
+/// Where an attachment goes among the others at its address, and what its
+/// program is told about it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AttachOpts {
+    /// lower runs first
+    pub priority: i32,
+    /// returned by `bpf_get_attach_cookie`
+    pub cookie: u64,
+}
+
+// attach types of a `ProbeContext`
+pub const BPF_PROBE_KERNEL_INSN: u32 = 0;
+pub const BPF_PROBE_KERNEL_FUNC: u32 = 1;
+pub const BPF_PROBE_USER_INSN: u32 = 2;
+pub const BPF_PROBE_USER_FUNC: u32 = 3;
+
+/// What a program attached to a kernel or user probe is run on, passed in
+/// r1. USDT probes pass a `UsdtContext` instead.
+#[repr(C)]
+pub struct ProbeContext {
+    /// the `TrapFrame` of a kernel probe, or the `UserContext` of a user one
+    pub regs: u64,
+    /// address of the probe
+    pub addr: u64,
+    /// `BPF_PROBE_*`
+    pub attach_type: u32,
+    pub pad: u32,
+}
+
+/// An attachment of a program, as reported by introspection.
+pub struct EbpfAttachment {
+    pub addr: usize,
+    pub prog_id: u32,
+    pub path: String,
+    pub pp: ProbePlace,
+    /// `provider:name` of a USDT probe
+    pub usdt: Option<String>,
+    pub priority: i32,
+    pub cookie: u64,
 }
 
 unsafe impl Sync for Ebpf {}
@@ -42,17 +125,65 @@ fn resolve_symbol(symbol: &str) -> Option<usize> {
 }
 
 impl EbpfInner {
-    pub fn new(addr: usize, prog: Vec<u64>) -> Self {
-        Self { addr, prog }
+    pub fn new(prog: &Arc<BpfProg>, owned: bool, opts: AttachOpts) -> Self {
+        Self {
+            prog_id: prog.id,
+            prog: Arc::downgrade(prog),
+            owned: if owned { Some(prog.clone()) } else { None },
+            priority: opts.priority,
+            cookie: opts.cookie,
+        }
     }
-    pub fn arm(&self, path: String, pp: ProbePlace) -> isize {
-        let prog = self.prog.clone();
-        match pp {
+}
+
+/// The programs of a chain, taken out so that none of them runs with the
+/// chain locked.
+fn chain_progs(chain: &Mutex<Vec<EbpfInner>>) -> Vec<(Weak<BpfProg>, u64)> {
+    chain
+        .lock()
+        .iter()
+        .map(|ebpf| (ebpf.prog.clone(), ebpf.cookie))
+        .collect()
+}
+
+impl EbpfSite {
+    fn new(addr: usize, path: String, pp: ProbePlace, usdt: Option<UsdtProbe>) -> Self {
+        Self {
+            addr,
+            path,
+            pp,
+            usdt,
+            chain: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+    fn key(&self) -> SiteKey {
+        SiteKey::new(&self.pp, self.path.clone(), self.addr)
+    }
+    pub fn arm(&self) -> isize {
+        let chain = self.chain.clone();
+        let path = self.path.clone();
+        let addr = self.addr;
+        // a program overriding the return of a function skips the rest of the chain
+        let kernel_handler = move |attach_type: u32, cx: &mut TrapFrame| {
+            let pcx = ProbeContext {
+                regs: cx as *mut TrapFrame as u64,
+                addr: addr as u64,
+                attach_type,
+                pad: 0,
+            };
+            for (prog, cookie) in chain_progs(&chain) {
+                run_prog(&prog, &pcx as *const ProbeContext as u64, cookie);
+                if cx.sepc != addr {
+                    break;
+                }
+            }
+        };
+        match self.pp {
             ProbePlace::Kernel(ProbeType::Insn) => {
                 kprobe_register(
                     self.addr,
                     alloc::sync::Arc::new(Mutex::new(move |cx: &mut TrapFrame| {
-                        interpret(&prog, &HELPERS, cx as *const TrapFrame as usize as u64);
+                        kernel_handler(BPF_PROBE_KERNEL_INSN, cx);
                     })),
                     Some(alloc::sync::Arc::new(Mutex::new(move |cx: &mut TrapFrame| {
                         test_kernel_post_handler(cx);
@@ -64,7 +195,7 @@ impl EbpfInner {
                 kprobe_register(
                     self.addr,
                     alloc::sync::Arc::new(Mutex::new(move |cx: &mut TrapFrame| {
-                        interpret(&prog, &HELPERS, cx as *const TrapFrame as usize as u64);
+                        kernel_handler(BPF_PROBE_KERNEL_FUNC, cx);
                     })),
                     Some(alloc::sync::Arc::new(Mutex::new(move |cx: &mut TrapFrame| {
                         test_kernel_post_handler(cx);
@@ -73,11 +204,32 @@ impl EbpfInner {
                 )
             }
             ProbePlace::User(ProbeType::Insn) => {
+                let chain = self.chain.clone();
+                let usdt = self.usdt.clone();
                 uprobe_register(
                     path,
                     self.addr,
                     alloc::sync::Arc::new(Mutex::new(move |cx: &mut UserContext| {
-                        interpret(&prog, &HELPERS, cx as *const UserContext as usize as u64);
+                        let progs = chain_progs(&chain);
+                        match &usdt {
+                            Some(probe) => {
+                                let ucx = probe.context(cx);
+                                for (prog, cookie) in progs {
+                                    run_prog(&prog, &ucx as *const UsdtContext as u64, cookie);
+                                }
+                            }
+                            None => {
+                                let pcx = ProbeContext {
+                                    regs: cx as *mut UserContext as u64,
+                                    addr: addr as u64,
+                                    attach_type: BPF_PROBE_USER_INSN,
+                                    pad: 0,
+                                };
+                                for (prog, cookie) in progs {
+                                    run_prog(&prog, &pcx as *const ProbeContext as u64, cookie);
+                                }
+                            }
+                        }
                     })),
                     Some(alloc::sync::Arc::new(Mutex::new(move |cx: &mut UserContext| {
                         test_post_handler(cx);
@@ -86,11 +238,20 @@ impl EbpfInner {
                 )
             }
             ProbePlace::User(ProbeType::SyncFunc) => {
+                let chain = self.chain.clone();
                 uprobe_register(
                     path,
                     self.addr,
                     alloc::sync::Arc::new(Mutex::new(move |cx: &mut UserContext| {
-                        interpret(&prog, &HELPERS, cx as *const UserContext as usize as u64);
+                        let pcx = ProbeContext {
+                            regs: cx as *mut UserContext as u64,
+                            addr: addr as u64,
+                            attach_type: BPF_PROBE_USER_FUNC,
+                            pad: 0,
+                        };
+                        for (prog, cookie) in chain_progs(&chain) {
+                            run_prog(&prog, &pcx as *const ProbeContext as u64, cookie);
+                        }
                     })),
                     Some(alloc::sync::Arc::new(Mutex::new(move |cx: &mut UserContext| {
                         test_post_handler(cx);
@@ -104,10 +265,60 @@ impl EbpfInner {
         }
     }
     pub fn disarm(&self) -> isize {
-        kprobe_unregister(self.addr)
+        match self.pp {
+            ProbePlace::Kernel(_) => kprobe_unregister(self.addr),
+            ProbePlace::User(_) => uprobe_unregister(&self.path, self.addr),
+        }
     }
 }
 
+/// Bit `i` is set while a program runs on hart `i`.
+static PROG_ACTIVE: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// Cookie of the attachment whose program runs on each hart.
+    static ref ATTACH_COOKIE: Vec<AtomicU64> =
+        (0..MAX_CPU_NUM).map(|_| AtomicU64::new(0)).collect();
+}
+
+/// Run an attached program if it is still loaded.
+fn run_prog(prog: &Weak<BpfProg>, ctx: u64, cookie: u64) {
+    if let Some(prog) = prog.upgrade() {
+        run_attached(&prog, ctx, cookie);
+    }
+}
+
+/// The cookie of the attachment the program running on this hart was run
+/// for, 0 for programs not run from an attachment.
+pub fn attach_cookie() -> u64 {
+    ATTACH_COOKIE[cpu::id()].load(Ordering::Relaxed)
+}
+
+/// Run a program, accounting its run time. A program hit by another one
+/// running on the same hart, e.g. a kprobe program triggered from a uprobe
+/// program, is skipped and counted as missed, and `None` is returned.
+pub fn run_accounted(prog: &Arc<BpfProg>, ctx: u64) -> Option<u64> {
+    run_attached(prog, ctx, 0)
+}
+
+fn run_attached(prog: &Arc<BpfProg>, ctx: u64, cookie: u64) -> Option<u64> {
+    let bit = 1u64 << cpu::id();
+    if PROG_ACTIVE.fetch_or(bit, Ordering::Acquire) & bit != 0 {
+        prog.stats.miss_cnt.fetch_add(1, Ordering::Relaxed);
+        return None;
+    }
+    ATTACH_COOKIE[cpu::id()].store(cookie, Ordering::Relaxed);
+    let start = timer_now();
+    let ret = interpret(prog, &HELPERS, ctx);
+    let elapsed = timer_now() - start;
+    PROG_ACTIVE.fetch_and(!bit, Ordering::Release);
+    prog.stats.run_cnt.fetch_add(1, Ordering::Relaxed);
+    prog.stats
+        .run_time_ns
+        .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    Some(ret)
+}
+
 pub fn test_pre_handler(cx: &mut UserContext){
     println!{"pre_handler: spec:{:#x}", cx.sepc};
 }
@@ -132,24 +343,198 @@ impl Ebpf {
             inner: RefCell::new(BTreeMap::new()),
         }
     }
-    pub fn register(&self, addr: usize, prog: Vec<u64>, path: String, pp: ProbePlace) -> isize {
-        let ebpf = EbpfInner::new(addr, prog);
-        let ret = ebpf.arm(path, pp);
+    /// Attach `prog` at `addr`, to run after the programs there of the same
+    /// or a lower priority. If `owned`, the attachment keeps the program
+    /// alive until it is unregistered; otherwise the program is detached once
+    /// its last fd or pin is gone.
+    pub fn register(&self, addr: usize, prog: &Arc<BpfProg>, path: String, pp: ProbePlace, owned: bool, opts: AttachOpts) -> isize {
+        self.insert(EbpfSite::new(addr, path, pp, None), EbpfInner::new(prog, owned, opts))
+    }
+    /// Attach `prog` to the USDT `probes` of the binary at `path`, either to
+    /// all of them or, if any fails, to none. It stays attached while the
+    /// program is referenced elsewhere.
+    pub fn register_usdt(&self, probes: &[&UsdtProbe], prog: &Arc<BpfProg>, path: String, cookie: u64) -> isize {
+        let pp = ProbePlace::User(ProbeType::Insn);
+        for (i, probe) in probes.iter().enumerate() {
+            let site = EbpfSite::new(probe.addr, path.clone(), pp.clone(), Some((*probe).clone()));
+            let ret = self.insert(site, EbpfInner::new(prog, false, AttachOpts { priority: 0, cookie }));
+            if ret != 0 {
+                for attached in &probes[..i] {
+                    self.detach(&SiteKey::new(&pp, path.clone(), attached.addr), prog.id);
+                }
+                return ret;
+            }
+        }
+        0
+    }
+    /// Attach `prog` to the kernel functions at `addrs`, given with their
+    /// cookies, either to all of them or, if any fails, to none.
+    pub fn register_multi(&self, addrs: &[(usize, u64)], prog: &Arc<BpfProg>, pp: ProbePlace, priority: i32) -> isize {
+        let pt = match &pp {
+            ProbePlace::Kernel(pt) => pt,
+            ProbePlace::User(_) => return -1,
+        };
+        // check every function first, so that none is armed for nothing
+        {
+            let inner = self.inner.borrow();
+            for &(addr, _) in addrs {
+                let ret = match inner.get(&SiteKey::Kernel(addr)) {
+                    Some(site) if site.pp != pp => -1,
+                    Some(site) if site.chain.lock().iter().any(|a| a.prog_id == prog.id) => -1,
+                    Some(_) => 0,
+                    None => kprobe_check(addr, pt),
+                };
+                if ret != 0 {
+                    warn!("ebpf: cannot attach program {} to {:#x}", prog.id, addr);
+                    return ret;
+                }
+            }
+        }
+        for (i, &(addr, cookie)) in addrs.iter().enumerate() {
+            let opts = AttachOpts { priority, cookie };
+            let ret = self.register(addr, prog, String::new(), pp.clone(), false, opts);
+            if ret != 0 {
+                for &(attached, _) in &addrs[..i] {
+                    self.detach(&SiteKey::Kernel(attached), prog.id);
+                }
+                return ret;
+            }
+        }
+        0
+    }
+    fn insert(&self, site: EbpfSite, ebpf: EbpfInner) -> isize {
+        // arguments are dropped after the borrow, as they may release a program
+        let mut inner = self.inner.borrow_mut();
+        if let Some(armed) = inner.get(&site.key()) {
+            if armed.pp != site.pp {
+                warn!("ebpf: {:#x} is probed as another kind of place", site.addr);
+                return -1;
+            }
+            let mut chain = armed.chain.lock();
+            if chain.iter().any(|attached| attached.prog_id == ebpf.prog_id) {
+                warn!("ebpf: program {} is attached to {:#x} already", ebpf.prog_id, site.addr);
+                return -1;
+            }
+            let pos = chain
+                .iter()
+                .position(|attached| attached.priority > ebpf.priority)
+                .unwrap_or(chain.len());
+            chain.insert(pos, ebpf);
+            return 0;
+        }
+        site.chain.lock().push(ebpf);
+        let ret = site.arm();
         if ret != 0 {
             return ret;
         }
-        if let Some(replaced) = self.inner.borrow_mut().insert(addr, ebpf) {
-            replaced.disarm();
-        }
+        inner.insert(site.key(), site);
         0
     }
-    pub fn unregister(&self, addr: usize) -> isize {
-        if let Some(ebpf) = self.inner.borrow_mut().remove(&addr) {
-            ebpf.disarm();
+    /// Detach every program at `key` and remove its probe.
+    pub fn unregister(&self, key: &SiteKey) -> isize {
+        let removed = self.inner.borrow_mut().remove(key);
+        if let Some(site) = removed {
+            site.disarm();
             return 0;
         }
         -1
     }
+    /// Detach one program from `key`, leaving the others there attached.
+    pub fn detach(&self, key: &SiteKey, prog_id: u32) -> isize {
+        let (_detached, emptied) = {
+            let mut inner = self.inner.borrow_mut();
+            let site = match inner.get(key) {
+                Some(site) => site,
+                None => return -1,
+            };
+            let mut chain = site.chain.lock();
+            let detached = match chain.iter().position(|ebpf| ebpf.prog_id == prog_id) {
+                Some(pos) => chain.remove(pos),
+                None => return -1,
+            };
+            let empty = chain.is_empty();
+            drop(chain);
+            (detached, if empty { inner.remove(key) } else { None })
+        };
+        if let Some(site) = emptied {
+            site.disarm();
+        }
+        0
+    }
+    pub fn attachments(&self) -> Vec<EbpfAttachment> {
+        self.inner
+            .borrow()
+            .values()
+            .flat_map(|site| {
+                site.chain
+                    .lock()
+                    .iter()
+                    .map(|ebpf| EbpfAttachment {
+                        addr: site.addr,
+                        prog_id: ebpf.prog_id,
+                        path: site.path.clone(),
+                        pp: site.pp.clone(),
+                        usdt: site
+                            .usdt
+                            .as_ref()
+                            .map(|probe| format!("{}:{}", probe.provider, probe.name)),
+                        priority: ebpf.priority,
+                        cookie: ebpf.cookie,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+    /// Detach the kernel probes in `start..end`, the memory of module `name`
+    /// that is being unloaded, telling their owners.
+    pub fn module_unload(&self, name: &str, start: usize, end: usize) {
+        let removed: Vec<EbpfSite> = {
+            let mut inner = self.inner.borrow_mut();
+            let keys: Vec<SiteKey> = inner
+                .keys()
+                .filter(|key| match key {
+                    SiteKey::Kernel(addr) => start <= *addr && *addr < end,
+                    SiteKey::User(..) => false,
+                })
+                .cloned()
+                .collect();
+            keys.iter().filter_map(|key| inner.remove(key)).collect()
+        };
+        for site in removed.iter() {
+            site.disarm();
+            // the owners of the programs see their fds hung up
+            for (prog, _) in chain_progs(&site.chain) {
+                if let Some(prog) = prog.upgrade() {
+                    warn!(
+                        "ebpf: program {} detached from {:#x} as module {} unloads",
+                        prog.id, site.addr, name
+                    );
+                    prog.stats.detached_cnt.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+    /// Detach every attachment of a program that is being released.
+    pub fn detach_prog(&self, prog_id: u32) {
+        let mut inner = self.inner.borrow_mut();
+        let mut emptied = Vec::new();
+        for (key, site) in inner.iter() {
+            let mut chain = site.chain.lock();
+            let attached = chain.len();
+            chain.retain(|ebpf| ebpf.prog_id != prog_id);
+            if chain.len() != attached {
+                info!("ebpf: detach program {} from {:#x}", prog_id, site.addr);
+                if chain.is_empty() {
+                    emptied.push(key.clone());
+                }
+            }
+        }
+        for key in emptied {
+            if let Some(site) = inner.remove(&key) {
+                site.disarm();
+            }
+        }
+    }
 }
 
 fn get_time_ms() -> usize{
@@ -160,7 +545,7 @@ fn get_time_ms() -> usize{
 // before function: 
 #[inline(never)]
 pub async fn test_async(){
-    executor::spawn(test1_async());
+    sched::spawn(test1_async());
     println!("in_async");
     test2_async().await;
 }
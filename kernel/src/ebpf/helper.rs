@@ -1,29 +1,166 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
 use ebpf_rs::interpret::Helper;
-use crate::process::current_thread;
-
-pub const HELPERS: [Helper; 16] = [
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    bpf_ktime_get_ns,
-    bpf_trace_printk,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    nop,
-    bpf_get_current_pid_tgid,
-    nop,
-    nop,
-];
+use trapframe::TrapFrame;
+use crate::kprobes::kprobe_override_return;
+use crate::net::{xdp_redirect, SkBuff, XdpMd, XDP_ABORTED, XDP_REDIRECT};
+use crate::process::{current_thread, Thread, COMM_LEN};
+use crate::syscall::SysError;
+use super::ebpf::{attach_cookie, ProbeContext, BPF_PROBE_KERNEL_FUNC};
+use super::map::map_get;
+use super::prog::ProgType;
+use super::vm::BPF_FUNC_TAIL_CALL;
+
+// helper ids, as in linux
+pub const BPF_FUNC_MAP_LOOKUP_ELEM: usize = 1;
+pub const BPF_FUNC_MAP_UPDATE_ELEM: usize = 2;
+pub const BPF_FUNC_MAP_DELETE_ELEM: usize = 3;
+pub const BPF_FUNC_KTIME_GET_NS: usize = 5;
+pub const BPF_FUNC_TRACE_PRINTK: usize = 6;
+pub const BPF_FUNC_GET_CURRENT_PID_TGID: usize = 14;
+pub const BPF_FUNC_GET_CURRENT_UID_GID: usize = 15;
+pub const BPF_FUNC_GET_CURRENT_COMM: usize = 16;
+pub const BPF_FUNC_REDIRECT: usize = 23;
+pub const BPF_FUNC_SKB_LOAD_BYTES: usize = 26;
+pub const BPF_FUNC_GET_CURRENT_TASK: usize = 35;
+pub const BPF_FUNC_XDP_ADJUST_HEAD: usize = 44;
+pub const BPF_FUNC_OVERRIDE_RETURN: usize = 58;
+pub const BPF_FUNC_XDP_ADJUST_TAIL: usize = 65;
+pub const BPF_FUNC_GET_ATTACH_COOKIE: usize = 67;
+/// There are no cgroups, the process group stands in for them.
+pub const BPF_FUNC_GET_CURRENT_CGROUP_ID: usize = 80;
+
+// rCore specific helpers, after the ids used by linux
+pub const BPF_FUNC_GET_CURRENT_PPID: usize = 1000;
+pub const BPF_FUNC_GET_CURRENT_PGID: usize = 1001;
+
+lazy_static! {
+    /// Helpers indexed by id, ids without a helper map to `nop`.
+    pub static ref HELPERS: Vec<Helper> = {
+        let mut helpers: Vec<Helper> = vec![nop as Helper; BPF_FUNC_GET_CURRENT_PGID + 1];
+        helpers[BPF_FUNC_MAP_LOOKUP_ELEM] = bpf_map_lookup_elem;
+        helpers[BPF_FUNC_MAP_UPDATE_ELEM] = bpf_map_update_elem;
+        helpers[BPF_FUNC_MAP_DELETE_ELEM] = bpf_map_delete_elem;
+        helpers[BPF_FUNC_KTIME_GET_NS] = bpf_ktime_get_ns;
+        helpers[BPF_FUNC_TRACE_PRINTK] = bpf_trace_printk;
+        // bpf_tail_call (12) is handled by the interpreter
+        helpers[BPF_FUNC_GET_CURRENT_PID_TGID] = bpf_get_current_pid_tgid;
+        helpers[BPF_FUNC_GET_CURRENT_UID_GID] = bpf_get_current_uid_gid;
+        helpers[BPF_FUNC_GET_CURRENT_COMM] = bpf_get_current_comm;
+        helpers[BPF_FUNC_REDIRECT] = bpf_redirect;
+        helpers[BPF_FUNC_SKB_LOAD_BYTES] = bpf_skb_load_bytes;
+        helpers[BPF_FUNC_GET_CURRENT_TASK] = bpf_get_current_task;
+        helpers[BPF_FUNC_XDP_ADJUST_HEAD] = bpf_xdp_adjust_head;
+        helpers[BPF_FUNC_OVERRIDE_RETURN] = bpf_override_return;
+        helpers[BPF_FUNC_XDP_ADJUST_TAIL] = bpf_xdp_adjust_tail;
+        helpers[BPF_FUNC_GET_ATTACH_COOKIE] = bpf_get_attach_cookie;
+        helpers[BPF_FUNC_GET_CURRENT_CGROUP_ID] = bpf_get_current_pgid;
+        helpers[BPF_FUNC_GET_CURRENT_PPID] = bpf_get_current_ppid;
+        helpers[BPF_FUNC_GET_CURRENT_PGID] = bpf_get_current_pgid;
+        helpers
+    };
+}
+
+/// Whether programs of `prog_type` may call helper `id`. Helpers reading
+/// the context are only for the type run on that context.
+pub fn helper_allowed(prog_type: ProgType, id: i32) -> bool {
+    let id = id as usize;
+    let common = id == BPF_FUNC_TAIL_CALL as usize
+        || matches!(
+            id,
+            BPF_FUNC_MAP_LOOKUP_ELEM
+                | BPF_FUNC_MAP_UPDATE_ELEM
+                | BPF_FUNC_MAP_DELETE_ELEM
+                | BPF_FUNC_KTIME_GET_NS
+                | BPF_FUNC_TRACE_PRINTK
+        );
+    let tracing = matches!(
+        id,
+        BPF_FUNC_GET_CURRENT_PID_TGID
+            | BPF_FUNC_GET_CURRENT_UID_GID
+            | BPF_FUNC_GET_CURRENT_COMM
+            | BPF_FUNC_GET_CURRENT_TASK
+            | BPF_FUNC_GET_ATTACH_COOKIE
+            | BPF_FUNC_GET_CURRENT_CGROUP_ID
+            | BPF_FUNC_GET_CURRENT_PPID
+            | BPF_FUNC_GET_CURRENT_PGID
+    );
+    match prog_type {
+        ProgType::SocketFilter => common || id == BPF_FUNC_SKB_LOAD_BYTES,
+        ProgType::Kprobe => common || tracing || id == BPF_FUNC_OVERRIDE_RETURN,
+        ProgType::Tracepoint => common || tracing,
+        ProgType::Xdp => {
+            common
+                || id == BPF_FUNC_REDIRECT
+                || id == BPF_FUNC_XDP_ADJUST_HEAD
+                || id == BPF_FUNC_XDP_ADJUST_TAIL
+        }
+        // converted from classic BPF, which calls no helpers
+        ProgType::Seccomp => false,
+    }
+}
+
+fn error(err: SysError) -> u64 {
+    -(err as i64) as u64
+}
 
 pub fn nop(_: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
     0
 }
 
+// Map helpers only try the map lock, as the probe may have fired while it is
+// held on this hart, e.g. by `BPF_MAP_UPDATE_ELEM`.
+
+// void *bpf_map_lookup_elem(struct bpf_map *map, const void *key)
+unsafe fn bpf_map_lookup_elem(map: u64, key: u64, _3: u64, _4: u64, _5: u64) -> u64 {
+    let map = match map_get(map as u32) {
+        Some(map) => map,
+        None => return 0,
+    };
+    let mut map = match map.try_lock() {
+        Some(map) => map,
+        None => return 0,
+    };
+    let key = core::slice::from_raw_parts(key as *const u8, map.key_size);
+    map.lookup(key).map_or(0, |value| value as u64)
+}
+
+// long bpf_map_update_elem(struct bpf_map *map, const void *key, const void *value, u64 flags)
+unsafe fn bpf_map_update_elem(map: u64, key: u64, value: u64, flags: u64, _5: u64) -> u64 {
+    let map = match map_get(map as u32) {
+        Some(map) => map,
+        None => return -1i64 as u64,
+    };
+    let mut map = match map.try_lock() {
+        Some(map) => map,
+        None => return error(SysError::EBUSY),
+    };
+    let key = core::slice::from_raw_parts(key as *const u8, map.key_size);
+    let value = core::slice::from_raw_parts(value as *const u8, map.value_size);
+    match map.update(key, value, flags) {
+        Ok(()) => 0,
+        Err(err) => error(err),
+    }
+}
+
+// long bpf_map_delete_elem(struct bpf_map *map, const void *key)
+unsafe fn bpf_map_delete_elem(map: u64, key: u64, _3: u64, _4: u64, _5: u64) -> u64 {
+    let map = match map_get(map as u32) {
+        Some(map) => map,
+        None => return -1i64 as u64,
+    };
+    let mut map = match map.try_lock() {
+        Some(map) => map,
+        None => return error(SysError::EBUSY),
+    };
+    let key = core::slice::from_raw_parts(key as *const u8, map.key_size);
+    match map.delete(key) {
+        Ok(()) => 0,
+        Err(err) => error(err),
+    }
+}
+
 // long bpf_trace_printk(const char *fmt, u32 fmt_size, ...)
 unsafe fn bpf_trace_printk(fmt: u64, fmt_size: u64, p1: u64, p2: u64, p3: u64) -> u64 {
     let fmt = core::slice::from_raw_parts(fmt as *const u8, fmt_size as u32 as usize);
@@ -35,15 +172,128 @@ unsafe fn bpf_trace_printk(fmt: u64, fmt_size: u64, p1: u64, p2: u64, p3: u64) -
     0
 }
 
+// long bpf_skb_load_bytes(const void *skb, u32 offset, void *to, u32 len)
+// only valid in socket filters
+unsafe fn bpf_skb_load_bytes(skb: u64, offset: u64, to: u64, len: u64, _5: u64) -> u64 {
+    let skb = &*(skb as *const SkBuff);
+    let (offset, len) = (offset as u32 as usize, len as u32 as usize);
+    if offset + len > skb.len as usize {
+        return error(SysError::EFAULT);
+    }
+    let from = (skb.data as usize + offset) as *const u8;
+    core::ptr::copy_nonoverlapping(from, to as *mut u8, len);
+    0
+}
+
+// long bpf_redirect(u32 ifindex, u64 flags)
+// only valid in XDP programs, which then return its result
+fn bpf_redirect(ifindex: u64, flags: u64, _3: u64, _4: u64, _5: u64) -> u64 {
+    if flags != 0 {
+        return XDP_ABORTED as u64;
+    }
+    xdp_redirect(ifindex as u32 as usize);
+    XDP_REDIRECT as u64
+}
+
+// long bpf_xdp_adjust_head(struct xdp_buff *xdp_md, int delta)
+unsafe fn bpf_xdp_adjust_head(md: u64, delta: u64, _3: u64, _4: u64, _5: u64) -> u64 {
+    match (*(md as *mut XdpMd)).adjust_head(delta as i32 as i64) {
+        Ok(()) => 0,
+        Err(err) => error(err),
+    }
+}
+
+// long bpf_xdp_adjust_tail(struct xdp_buff *xdp_md, int delta)
+unsafe fn bpf_xdp_adjust_tail(md: u64, delta: u64, _3: u64, _4: u64, _5: u64) -> u64 {
+    match (*(md as *mut XdpMd)).adjust_tail(delta as i32 as i64) {
+        Ok(()) => 0,
+        Err(err) => error(err),
+    }
+}
+
 // u64 bpf_ktime_get_ns(void)
 // return current ktime
 fn bpf_ktime_get_ns(_1: u64, _2: u64, _3: u64, _4: u64, _5: u64) -> u64 {
     crate::arch::timer::timer_now().as_nanos() as u64
 }
 
+/// Run `f` on the current thread, or fail outside of threads.
+///
+/// Its process is not locked, as the probe may have fired while it is held on
+/// this hart, so process fields are read from `thread.ids`.
+fn with_current<F: FnOnce(&Arc<Thread>) -> u64>(f: F) -> u64 {
+    match current_thread() {
+        Some(thread) => f(&thread),
+        None => error(SysError::ESRCH),
+    }
+}
+
+// u64 bpf_get_current_pid_tgid(void)
+// return tgid << 32 | tid, tgid being the pid of the process
 fn bpf_get_current_pid_tgid(_1: u64, _2: u64, _3: u64, _4: u64, _5: u64) -> u64 {
-    let thread = current_thread().unwrap();
-    let pid = thread.proc.busy_lock().pid.get() as u64;
-    // NOTE: tgid is the same with pid
-    (pid << 32) | pid
+    with_current(|thread| {
+        ((thread.ids.pid.load(Ordering::Relaxed) as u64) << 32) | thread.tid as u64
+    })
+}
+
+// u64 bpf_get_current_uid_gid(void)
+// return gid << 32 | uid
+fn bpf_get_current_uid_gid(_1: u64, _2: u64, _3: u64, _4: u64, _5: u64) -> u64 {
+    with_current(|thread| thread.ids.uid_gid.load(Ordering::Relaxed))
+}
+
+// long bpf_get_current_comm(void *buf, u32 size_of_buf)
+// copy the file name of the executable, always NUL terminated
+unsafe fn bpf_get_current_comm(buf: u64, size: u64, _3: u64, _4: u64, _5: u64) -> u64 {
+    let size = size as u32 as usize;
+    if buf == 0 || size == 0 {
+        return error(SysError::EINVAL);
+    }
+    let buf = core::slice::from_raw_parts_mut(buf as *mut u8, size);
+    with_current(|thread| {
+        let comm = thread.ids.comm();
+        let comm = &comm[..comm.iter().position(|&b| b == 0).unwrap_or(COMM_LEN)];
+        let len = comm.len().min(size - 1);
+        buf[..len].copy_from_slice(&comm[..len]);
+        for b in buf[len..].iter_mut() {
+            *b = 0;
+        }
+        0
+    })
+}
+
+// u64 bpf_get_current_task(void)
+// return a handle of the current thread, stable for its lifetime
+fn bpf_get_current_task(_1: u64, _2: u64, _3: u64, _4: u64, _5: u64) -> u64 {
+    current_thread().map_or(0, |thread| Arc::as_ptr(&thread) as u64)
+}
+
+// long bpf_override_return(struct probe_context *ctx, u64 rc)
+// only for function entry kprobes on error-injectable functions
+unsafe fn bpf_override_return(ctx: u64, rc: u64, _3: u64, _4: u64, _5: u64) -> u64 {
+    let ctx = &*(ctx as *const ProbeContext);
+    if ctx.attach_type != BPF_PROBE_KERNEL_FUNC {
+        return error(SysError::EINVAL);
+    }
+    let cx = &mut *(ctx.regs as *mut TrapFrame);
+    match kprobe_override_return(cx, rc as usize) {
+        0 => 0,
+        _ => error(SysError::EINVAL),
+    }
+}
+
+// u64 bpf_get_attach_cookie(void *ctx)
+// return the cookie of the attachment the program runs for
+fn bpf_get_attach_cookie(_1: u64, _2: u64, _3: u64, _4: u64, _5: u64) -> u64 {
+    attach_cookie()
+}
+
+// u64 bpf_get_current_ppid(void)
+fn bpf_get_current_ppid(_1: u64, _2: u64, _3: u64, _4: u64, _5: u64) -> u64 {
+    with_current(|thread| thread.ids.ppid.load(Ordering::Relaxed) as u64)
+}
+
+// u64 bpf_get_current_pgid(void)
+fn bpf_get_current_pgid(_1: u64, _2: u64, _3: u64, _4: u64, _5: u64) -> u64 {
+    with_current(|thread| thread.ids.pgid.load(Ordering::Relaxed) as u64)
 }
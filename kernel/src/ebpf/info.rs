@@ -0,0 +1,148 @@
+//! Introspection of loaded programs and armed probes.
+
+use alloc::collections::BTreeSet;
+use alloc::string::String;
+use core::fmt::Write;
+use core::sync::atomic::Ordering;
+use crate::kprobes::{error_injectable_list, kprobe_blacklist, kprobes_list, uprobes_list, ProbePlace, ProbeType};
+use crate::lkm::manager::ModuleManager;
+use crate::net::xdp_list;
+use crate::process::usdt::usdt_list;
+use super::ebpf::EBPF;
+use super::prog::{prog_get, BpfProg, PROGS};
+
+/// Describe a kernel address as `symbol+offset`.
+pub fn kernel_symbol(addr: usize) -> String {
+    match ModuleManager::with(|mm| mm.lookup_address(addr)) {
+        Some((name, offset)) => format!("{}+{:#x}", name, offset),
+        None => format!("{:#x}", addr),
+    }
+}
+
+/// Misses of a program, including nested hits its kernel probes skipped.
+/// The hits a probe skipped are charged to the first program of its chain
+/// only, so that they are counted once however many programs share it.
+pub fn prog_miss_cnt(prog: &BpfProg) -> u64 {
+    let kprobes = kprobes_list();
+    let mut sites = BTreeSet::new();
+    let skipped: usize = EBPF
+        .attachments()
+        .iter()
+        .filter(|a| match a.pp {
+            ProbePlace::Kernel(_) => true,
+            ProbePlace::User(_) => false,
+        })
+        // attachments of a probe are listed in chain order
+        .filter(|a| sites.insert(a.addr))
+        .filter(|a| a.prog_id == prog.id)
+        .filter_map(|a| kprobes.iter().find(|probe| probe.addr == a.addr))
+        .map(|probe| probe.nmissed)
+        .sum();
+    prog.stats.miss_cnt.load(Ordering::Relaxed) + skipped as u64
+}
+
+fn attach_point(addr: usize, path: &str, pp: &ProbePlace) -> String {
+    match pp {
+        ProbePlace::Kernel(_) => kernel_symbol(addr),
+        ProbePlace::User(_) => format!("{}+{:#x}", path, addr),
+    }
+}
+
+fn probe_type_name(pt: &ProbeType) -> &'static str {
+    match pt {
+        ProbeType::Insn => "insn",
+        ProbeType::SyncFunc => "func",
+        ProbeType::AsyncFunc => "async",
+    }
+}
+
+fn probe_place_name(pp: &ProbePlace) -> String {
+    match pp {
+        ProbePlace::Kernel(pt) => format!("kprobe/{}", probe_type_name(pt)),
+        ProbePlace::User(pt) => format!("uprobe/{}", probe_type_name(pt)),
+    }
+}
+
+/// A table of the loaded programs with their attachments and statistics,
+/// followed by all armed probes.
+pub fn ebpf_info() -> String {
+    let mut out = String::new();
+    let attachments = EBPF.attachments();
+    let ids: alloc::vec::Vec<u32> = PROGS.lock().keys().cloned().collect();
+    writeln!(out, "id\tinsns\trun_cnt\trun_time_ns\tmiss_cnt\ttype\tattach\tprio").unwrap();
+    for id in ids {
+        let prog = match prog_get(id) {
+            Some(prog) => prog,
+            None => continue,
+        };
+        let stats = &prog.stats;
+        let prefix = format!(
+            "{}\t{}\t{}\t{}\t{}",
+            prog.id,
+            prog.insns.len(),
+            stats.run_cnt.load(Ordering::Relaxed),
+            stats.run_time_ns.load(Ordering::Relaxed),
+            prog_miss_cnt(&prog),
+        );
+        let mut attached = false;
+        for a in attachments.iter().filter(|a| a.prog_id == id) {
+            attached = true;
+            let (place, point) = match &a.usdt {
+                Some(usdt) => (String::from("usdt"), format!("{}:{}", a.path, usdt)),
+                None => (probe_place_name(&a.pp), attach_point(a.addr, &a.path, &a.pp)),
+            };
+            writeln!(out, "{}\t{}\t{}\t{}", prefix, place, point, a.priority).unwrap();
+        }
+        if !attached {
+            writeln!(out, "{}\t-\t-\t-", prefix).unwrap();
+        }
+    }
+    writeln!(out, "\nprobes:").unwrap();
+    for probe in kprobes_list() {
+        writeln!(
+            out,
+            "kprobe/{}\t{}\tmissed {}",
+            probe_type_name(&probe.probe_type),
+            kernel_symbol(probe.addr),
+            probe.nmissed
+        )
+        .unwrap();
+    }
+    for probe in uprobes_list() {
+        let path = probe.path.unwrap_or_default();
+        writeln!(out, "uprobe/{}\t{}+{:#x}", probe_type_name(&probe.probe_type), path, probe.addr).unwrap();
+    }
+    writeln!(out, "\nusdt:").unwrap();
+    for (path, probes) in usdt_list() {
+        for probe in probes.iter() {
+            let args: alloc::vec::Vec<String> = probe
+                .args
+                .iter()
+                .map(|arg| format!("{}{}@{:?}", if arg.signed { "-" } else { "" }, arg.size, arg.loc))
+                .collect();
+            writeln!(
+                out,
+                "{}:{}:{}\t{:#x}\t{}",
+                path,
+                probe.provider,
+                probe.name,
+                probe.addr,
+                args.join(" ")
+            )
+            .unwrap();
+        }
+    }
+    writeln!(out, "\nxdp:").unwrap();
+    for (ifindex, prog_id) in xdp_list() {
+        writeln!(out, "if {}\tprog {}", ifindex, prog_id).unwrap();
+    }
+    writeln!(out, "\nerror injectable:").unwrap();
+    for (name, addr, error) in error_injectable_list() {
+        writeln!(out, "{:#x}\t{}\terror {:#x}", addr, name, error).unwrap();
+    }
+    writeln!(out, "\nnokprobe:").unwrap();
+    for (name, addr) in kprobe_blacklist() {
+        writeln!(out, "{:#x}\t{}", addr, name).unwrap();
+    }
+    out
+}
@@ -0,0 +1,210 @@
+//! eBPF maps shared between programs and user space.
+//!
+//! User space holds maps through file descriptors, while programs refer to
+//! them by a kernel-wide id: `ld_imm64` with `BPF_PSEUDO_MAP_FD` is rewritten
+//! from the fd to the id when the program is loaded.
+
+use alloc::collections::btree_map::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use lazy_static::*;
+use spin::Mutex;
+use crate::syscall::SysError;
+use super::prog::BpfProg;
+
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MapType {
+    Hash = 1,
+    Array = 2,
+    ProgArray = 3,
+}
+
+impl MapType {
+    pub fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(MapType::Hash),
+            2 => Some(MapType::Array),
+            3 => Some(MapType::ProgArray),
+            _ => None,
+        }
+    }
+}
+
+/// update flags of `bpf_map_update_elem`
+pub const BPF_ANY: u64 = 0;
+pub const BPF_NOEXIST: u64 = 1;
+pub const BPF_EXIST: u64 = 2;
+
+pub struct BpfMap {
+    pub id: u32,
+    pub map_type: MapType,
+    pub key_size: usize,
+    pub value_size: usize,
+    pub max_entries: usize,
+    /// Each value owns its heap buffer, so pointers handed out by lookup
+    /// stay valid across updates of other keys.
+    data: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// Entries of a program array, which keep their programs alive.
+    progs: BTreeMap<u32, Arc<BpfProg>>,
+    /// Fds and pins referring to the map. A program array is cleared when
+    /// the last one goes away, as its programs may refer to the array itself.
+    users: usize,
+}
+
+lazy_static! {
+    /// All live maps; the references are owned by fds, pins and programs.
+    pub static ref MAPS: Mutex<BTreeMap<u32, Weak<Mutex<BpfMap>>>> = Mutex::new(BTreeMap::new());
+}
+
+static NEXT_MAP_ID: AtomicU32 = AtomicU32::new(1);
+
+impl BpfMap {
+    fn new(map_type: MapType, key_size: usize, value_size: usize, max_entries: usize) -> Result<Self, SysError> {
+        if key_size == 0 || value_size == 0 || max_entries == 0 {
+            return Err(SysError::EINVAL);
+        }
+        let mut data = BTreeMap::new();
+        match map_type {
+            MapType::Array | MapType::ProgArray => {
+                // array keys are u32 indices; prog array values are u32 program ids
+                if key_size != 4 || (map_type == MapType::ProgArray && value_size != 4) {
+                    return Err(SysError::EINVAL);
+                }
+                if map_type == MapType::Array {
+                    for i in 0..max_entries as u32 {
+                        data.insert(i.to_le_bytes().to_vec(), vec![0u8; value_size]);
+                    }
+                }
+            }
+            MapType::Hash => {}
+        }
+        Ok(Self {
+            id: 0,
+            map_type,
+            key_size,
+            value_size,
+            max_entries,
+            data,
+            progs: BTreeMap::new(),
+            users: 0,
+        })
+    }
+
+    fn check_index(&self, key: &[u8]) -> Result<(), SysError> {
+        match self.map_type {
+            MapType::Array | MapType::ProgArray => {
+                let index = u32::from_le_bytes([key[0], key[1], key[2], key[3]]) as usize;
+                if index >= self.max_entries {
+                    return Err(SysError::E2BIG);
+                }
+                Ok(())
+            }
+            MapType::Hash => Ok(()),
+        }
+    }
+
+    /// Pointer to the value stored for `key`, valid until the key is deleted.
+    pub fn lookup(&mut self, key: &[u8]) -> Option<*mut u8> {
+        self.data.get_mut(key).map(|value| value.as_mut_ptr())
+    }
+
+    pub fn update(&mut self, key: &[u8], value: &[u8], flags: u64) -> Result<(), SysError> {
+        if self.map_type == MapType::ProgArray {
+            // program arrays hold programs, see `set_prog`
+            return Err(SysError::EINVAL);
+        }
+        self.check_index(key)?;
+        match self.data.get_mut(key) {
+            Some(_) if flags == BPF_NOEXIST => Err(SysError::EEXIST),
+            // update in place so that outstanding lookups see the new value
+            Some(old) => {
+                old.copy_from_slice(value);
+                Ok(())
+            }
+            None if flags == BPF_EXIST => Err(SysError::ENOENT),
+            None => {
+                if self.data.len() >= self.max_entries {
+                    return Err(SysError::E2BIG);
+                }
+                self.data.insert(key.to_vec(), value.to_vec());
+                Ok(())
+            }
+        }
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<(), SysError> {
+        match self.map_type {
+            // array slots always exist
+            MapType::Array => Err(SysError::EINVAL),
+            MapType::ProgArray => {
+                self.check_index(key)?;
+                let index = u32::from_le_bytes([key[0], key[1], key[2], key[3]]);
+                self.progs.remove(&index).map(|_| ()).ok_or(SysError::ENOENT)
+            }
+            MapType::Hash => self.data.remove(key).map(|_| ()).ok_or(SysError::ENOENT),
+        }
+    }
+
+    /// Store `prog` at `index` of a program array.
+    pub fn set_prog(&mut self, index: u32, prog: Arc<BpfProg>) -> Result<(), SysError> {
+        if self.map_type != MapType::ProgArray {
+            return Err(SysError::EINVAL);
+        }
+        self.check_index(&index.to_le_bytes())?;
+        self.progs.insert(index, prog);
+        Ok(())
+    }
+
+    /// The program stored at `index` of a program array.
+    pub fn get_prog(&self, index: u32) -> Option<Arc<BpfProg>> {
+        self.progs.get(&index).cloned()
+    }
+
+    /// A new fd or pin refers to the map.
+    pub fn add_user(&mut self) {
+        self.users += 1;
+    }
+
+    /// An fd or pin referring to the map went away. The programs of a program
+    /// array left without users are returned, to be dropped without the map
+    /// locked.
+    pub fn remove_user(&mut self) -> BTreeMap<u32, Arc<BpfProg>> {
+        self.users -= 1;
+        if self.users == 0 {
+            core::mem::replace(&mut self.progs, BTreeMap::new())
+        } else {
+            BTreeMap::new()
+        }
+    }
+}
+
+impl Drop for BpfMap {
+    fn drop(&mut self) {
+        MAPS.lock().remove(&self.id);
+    }
+}
+
+pub fn map_create(map_type: u32, key_size: usize, value_size: usize, max_entries: usize) -> Result<Arc<Mutex<BpfMap>>, SysError> {
+    let map_type = MapType::from_u32(map_type).ok_or(SysError::EINVAL)?;
+    let mut map = BpfMap::new(map_type, key_size, value_size, max_entries)?;
+    let id = NEXT_MAP_ID.fetch_add(1, Ordering::SeqCst);
+    map.id = id;
+    let map = Arc::new(Mutex::new(map));
+    MAPS.lock().insert(id, Arc::downgrade(&map));
+    Ok(map)
+}
+
+pub fn map_get(id: u32) -> Option<Arc<Mutex<BpfMap>>> {
+    MAPS.lock().get(&id).and_then(|map| map.upgrade())
+}
+
+/// The smallest id of a live map greater than `id`.
+pub fn map_next_id(id: u32) -> Option<u32> {
+    MAPS
+        .lock()
+        .range(id..)
+        .find(|(&next, map)| next > id && map.strong_count() > 0)
+        .map(|(id, _)| *id)
+}
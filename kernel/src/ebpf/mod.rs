@@ -1,16 +1,40 @@
+pub mod cbpf;
 pub mod ebpf;
 pub mod helper;
+pub mod info;
+pub mod map;
+pub mod prog;
+pub mod vm;
 
 use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 pub use ebpf::test_async;
 use crate::kprobes::ProbePlace;
+use ebpf::{AttachOpts, SiteKey};
+use prog::BpfProg;
 
-pub fn ebpf_register(addr: usize, prog: Vec<u64>, path: String, pp: ProbePlace) -> isize {
-    ebpf::EBPF.register(addr, prog, path, pp)
+/// Attach a program that is owned by the attachment itself.
+pub fn ebpf_register(addr: usize, prog: &Arc<BpfProg>, path: String, pp: ProbePlace) -> isize {
+    ebpf::EBPF.register(addr, prog, path, pp, true, AttachOpts::default())
 }
 
-pub fn ebpf_unregister(addr: usize) -> isize {
-    ebpf::EBPF.unregister(addr)
+/// Attach a program that stays attached only while it is referenced elsewhere.
+pub fn ebpf_attach(addr: usize, prog: &Arc<BpfProg>, path: String, pp: ProbePlace, opts: AttachOpts) -> isize {
+    ebpf::EBPF.register(addr, prog, path, pp, false, opts)
+}
+
+/// Attach a program to many kernel functions, to all of them or to none.
+pub fn ebpf_attach_multi(addrs: &[(usize, u64)], prog: &Arc<BpfProg>, pp: ProbePlace, priority: i32) -> isize {
+    ebpf::EBPF.register_multi(addrs, prog, pp, priority)
+}
+
+/// Detach one program, leaving other programs at `key` attached.
+pub fn ebpf_detach(key: &SiteKey, prog_id: u32) -> isize {
+    ebpf::EBPF.detach(key, prog_id)
+}
+
+pub fn ebpf_unregister(key: &SiteKey) -> isize {
+    ebpf::EBPF.unregister(key)
 }
 
@@ -0,0 +1,175 @@
+//! Loaded eBPF programs.
+//!
+//! A program is loaded once and gets a kernel-wide id. It stays alive while
+//! an fd, a pin, a program array or an owning attachment refers to it, and
+//! is detached from all its probes when the last reference goes away. Its
+//! type, given at load, decides what it is run on, and so the helpers it may
+//! call and where it may be attached.
+
+use alloc::collections::btree_map::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use lazy_static::*;
+use spin::Mutex;
+use crate::syscall::SysError;
+use super::ebpf::EBPF;
+use super::helper::helper_allowed;
+use super::map::BpfMap;
+use super::vm::{
+    decode, BPF_CALL, BPF_EXIT, BPF_JMP, BPF_JMP32, BPF_LDDW, BPF_PSEUDO_CALL, BPF_PSEUDO_MAP_FD,
+};
+
+/// Program types, as `prog_type` of `BPF_PROG_LOAD`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, FromPrimitive)]
+pub enum ProgType {
+    /// run on an `SkBuff` by a socket
+    SocketFilter = 1,
+    /// run on a `ProbeContext` by a kernel or user probe
+    Kprobe = 2,
+    /// run on a `UsdtContext` by a USDT probe
+    Tracepoint = 5,
+    /// run on an `XdpMd` by an interface
+    Xdp = 6,
+    /// rCore specific: a seccomp filter converted from classic BPF, run on
+    /// `SeccompData`
+    Seccomp = 1000,
+}
+
+pub struct BpfProg {
+    pub id: u32,
+    pub prog_type: ProgType,
+    pub insns: Vec<u64>,
+    pub stats: BpfProgStats,
+    /// Maps referenced by `ld_imm64`, kept alive as long as the program.
+    maps: Vec<Arc<Mutex<BpfMap>>>,
+}
+
+/// Counters reported by introspection.
+#[derive(Default)]
+pub struct BpfProgStats {
+    pub run_cnt: AtomicU64,
+    pub run_time_ns: AtomicU64,
+    /// hits skipped instead of running the program
+    pub miss_cnt: AtomicU64,
+    /// attachments dropped as the module they probed unloaded
+    pub detached_cnt: AtomicU64,
+}
+
+lazy_static! {
+    pub static ref PROGS: Mutex<BTreeMap<u32, Weak<BpfProg>>> = Mutex::new(BTreeMap::new());
+}
+
+static NEXT_PROG_ID: AtomicU32 = AtomicU32::new(1);
+
+impl Drop for BpfProg {
+    fn drop(&mut self) {
+        PROGS.lock().remove(&self.id);
+        EBPF.detach_prog(self.id);
+    }
+}
+
+/// Check the references a program makes outside of itself, and replace map
+/// fds with map ids.
+fn relocate(
+    prog_type: ProgType,
+    insns: &mut [u64],
+    resolve_map: impl Fn(u32) -> Option<Arc<Mutex<BpfMap>>>,
+) -> Result<Vec<Arc<Mutex<BpfMap>>>, SysError> {
+    let mut maps = Vec::new();
+    // the second words of ld_imm64, which are neither run nor jumped to
+    let mut imm_hi = vec![false; insns.len()];
+    let mut pc = 0;
+    while pc < insns.len() {
+        let (op, _, src, _, imm) = decode(insns[pc]);
+        if op == BPF_LDDW {
+            if pc + 1 >= insns.len() {
+                warn!("ebpf: incomplete ld_imm64 at {}", pc);
+                return Err(SysError::EINVAL);
+            }
+            if insns[pc + 1] & 0xffff_ffff != 0 {
+                warn!("ebpf: invalid second word of ld_imm64 at {}", pc);
+                return Err(SysError::EINVAL);
+            }
+            imm_hi[pc + 1] = true;
+            if src == BPF_PSEUDO_MAP_FD {
+                let map = resolve_map(imm as u32).ok_or_else(|| {
+                    warn!("ebpf: unknown map fd {} at {}", imm, pc);
+                    SysError::EBADF
+                })?;
+                let id = map.lock().id;
+                insns[pc] = (insns[pc] & 0xffff_ffff) | ((id as u64) << 32);
+                maps.push(map);
+            }
+            pc += 2;
+            continue;
+        }
+        if op == BPF_JMP | BPF_CALL
+            && src != BPF_PSEUDO_CALL
+            && !helper_allowed(prog_type, imm)
+        {
+            warn!("ebpf: helper {} at {} is not for {:?} programs", imm, pc, prog_type);
+            return Err(SysError::EINVAL);
+        }
+        pc += 1;
+    }
+    for (pc, &insn) in insns.iter().enumerate() {
+        let (op, _, src, off, imm) = decode(insn);
+        if imm_hi[pc] || (op & 0x07 != BPF_JMP && op & 0x07 != BPF_JMP32) {
+            continue;
+        }
+        let offset = match op & 0xf0 {
+            BPF_CALL if src == BPF_PSEUDO_CALL => imm as isize,
+            BPF_CALL | BPF_EXIT => continue,
+            _ => off as isize,
+        };
+        let target = pc as isize + offset + 1;
+        if target < 0 || target as usize >= insns.len() || imm_hi[target as usize] {
+            warn!("ebpf: jump target out of range at {}", pc);
+            return Err(SysError::EINVAL);
+        }
+    }
+    Ok(maps)
+}
+
+/// Load a program of `prog_type`, resolving map fds with `resolve_map`.
+pub fn prog_load(
+    prog_type: ProgType,
+    mut insns: Vec<u64>,
+    resolve_map: impl Fn(u32) -> Option<Arc<Mutex<BpfMap>>>,
+) -> Result<Arc<BpfProg>, SysError> {
+    if insns.is_empty() {
+        return Err(SysError::EINVAL);
+    }
+    let maps = relocate(prog_type, &mut insns, resolve_map)?;
+    let id = NEXT_PROG_ID.fetch_add(1, Ordering::SeqCst);
+    let prog = Arc::new(BpfProg {
+        id,
+        prog_type,
+        insns,
+        stats: BpfProgStats::default(),
+        maps,
+    });
+    PROGS.lock().insert(id, Arc::downgrade(&prog));
+    Ok(prog)
+}
+
+pub fn prog_get(id: u32) -> Option<Arc<BpfProg>> {
+    PROGS.lock().get(&id).and_then(|prog| prog.upgrade())
+}
+
+/// The smallest id of a live program greater than `id`.
+pub fn prog_next_id(id: u32) -> Option<u32> {
+    PROGS
+        .lock()
+        .range(id..)
+        .find(|(&next, prog)| next > id && prog.strong_count() > 0)
+        .map(|(id, _)| *id)
+}
+
+impl BpfProg {
+    /// Ids of the maps this program refers to.
+    pub fn map_ids(&self) -> Vec<u32> {
+        self.maps.iter().map(|map| map.lock().id).collect()
+    }
+}
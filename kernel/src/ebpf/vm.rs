@@ -0,0 +1,303 @@
+//! eBPF interpreter with BPF-to-BPF calls and tail calls.
+//!
+//! Each call frame gets its own `STACK_SIZE` bytes of stack and saves the
+//! callee-saved registers r6-r9 of its caller. A tail call replaces the
+//! running program and unwinds all frames, keeping only the context in r1.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use ebpf_rs::interpret::Helper;
+use super::map::map_get;
+use super::prog::BpfProg;
+
+pub const BPF_LD: u8 = 0x00;
+pub const BPF_LDX: u8 = 0x01;
+pub const BPF_ST: u8 = 0x02;
+pub const BPF_STX: u8 = 0x03;
+pub const BPF_ALU: u8 = 0x04;
+pub const BPF_JMP: u8 = 0x05;
+pub const BPF_JMP32: u8 = 0x06;
+pub const BPF_ALU64: u8 = 0x07;
+
+pub const BPF_W: u8 = 0x00;
+pub const BPF_H: u8 = 0x08;
+pub const BPF_B: u8 = 0x10;
+pub const BPF_DW: u8 = 0x18;
+
+pub const BPF_IMM: u8 = 0x00;
+pub const BPF_MEM: u8 = 0x60;
+pub const BPF_XADD: u8 = 0xc0;
+
+pub const BPF_X: u8 = 0x08;
+
+pub const BPF_CALL: u8 = 0x80;
+pub const BPF_EXIT: u8 = 0x90;
+
+pub const BPF_LDDW: u8 = BPF_LD | BPF_IMM | BPF_DW;
+
+/// `src_reg` of `ld_imm64` referring to a map
+pub const BPF_PSEUDO_MAP_FD: u8 = 1;
+/// `src_reg` of `call` referring to a subprogram at a relative offset
+pub const BPF_PSEUDO_CALL: u8 = 1;
+
+/// helper id handled by the interpreter itself
+pub const BPF_FUNC_TAIL_CALL: i32 = 12;
+
+pub const STACK_SIZE: usize = 512;
+pub const MAX_CALL_DEPTH: usize = 8;
+pub const MAX_TAIL_CALL_CNT: usize = 32;
+
+/// Split an instruction into (opcode, dst, src, offset, imm).
+pub fn decode(insn: u64) -> (u8, u8, u8, i16, i32) {
+    (
+        insn as u8,
+        (insn >> 8) as u8 & 0xf,
+        (insn >> 12) as u8 & 0xf,
+        (insn >> 16) as i16,
+        (insn >> 32) as i32,
+    )
+}
+
+/// Assemble an instruction from (opcode, dst, src, offset, imm).
+pub fn encode(op: u8, dst: u8, src: u8, off: i16, imm: i32) -> u64 {
+    op as u64
+        | ((dst as u64 & 0xf) << 8)
+        | ((src as u64 & 0xf) << 12)
+        | ((off as u16 as u64) << 16)
+        | ((imm as u32 as u64) << 32)
+}
+
+struct Frame {
+    ret_pc: usize,
+    saved: [u64; 4],
+}
+
+/// The top of the stack of the frame at `depth`: the stack of the program
+/// at `stack`, and those of the called subprograms from `call_stacks` up.
+fn stack_top(depth: usize, stack: u64, call_stacks: u64) -> u64 {
+    if depth == 0 {
+        stack + STACK_SIZE as u64
+    } else {
+        call_stacks + (STACK_SIZE * depth) as u64
+    }
+}
+
+pub fn interpret(prog: &Arc<BpfProg>, helpers: &[Helper], ctx: u64) -> u64 {
+    let mut prog = prog.clone();
+    let mut reg = [0u64; 11];
+    let mut stack = [0u8; STACK_SIZE];
+    // most programs call no subprogram, so their stacks are allocated at the first call
+    let mut call_stacks: Vec<u8> = Vec::new();
+    let mut frames: Vec<Frame> = Vec::new();
+    let mut tail_calls = 0;
+    let stack_base = stack.as_mut_ptr() as u64;
+    reg[1] = ctx;
+    reg[10] = stack_top(0, stack_base, 0);
+    let mut pc = 0;
+    loop {
+        let insns = &prog.insns;
+        if pc >= insns.len() {
+            error!("ebpf: pc {} out of program {}", pc, prog.id);
+            return 0;
+        }
+        let (op, dst, src, off, imm) = decode(insns[pc]);
+        let (dst, src) = (dst as usize, src as usize);
+        pc += 1;
+        match op & 0x07 {
+            BPF_ALU64 | BPF_ALU => {
+                let is64 = op & 0x07 == BPF_ALU64;
+                let x = if op & BPF_X != 0 { reg[src] } else { imm as i64 as u64 };
+                let d = reg[dst];
+                let (d32, x32) = (d as u32, x as u32);
+                let res = match op & 0xf0 {
+                    0x00 if is64 => d.wrapping_add(x),
+                    0x00 => d32.wrapping_add(x32) as u64,
+                    0x10 if is64 => d.wrapping_sub(x),
+                    0x10 => d32.wrapping_sub(x32) as u64,
+                    0x20 if is64 => d.wrapping_mul(x),
+                    0x20 => d32.wrapping_mul(x32) as u64,
+                    // division by zero yields zero, modulo by zero keeps dst
+                    0x30 if is64 => if x == 0 { 0 } else { d / x },
+                    0x30 => if x32 == 0 { 0 } else { (d32 / x32) as u64 },
+                    0x40 if is64 => d | x,
+                    0x40 => (d32 | x32) as u64,
+                    0x50 if is64 => d & x,
+                    0x50 => (d32 & x32) as u64,
+                    0x60 if is64 => d.wrapping_shl(x as u32),
+                    0x60 => d32.wrapping_shl(x32) as u64,
+                    0x70 if is64 => d.wrapping_shr(x as u32),
+                    0x70 => d32.wrapping_shr(x32) as u64,
+                    0x80 if is64 => (d as i64).wrapping_neg() as u64,
+                    0x80 => (d32 as i32).wrapping_neg() as u32 as u64,
+                    0x90 if is64 => if x == 0 { d } else { d % x },
+                    0x90 => if x32 == 0 { d32 as u64 } else { (d32 % x32) as u64 },
+                    0xa0 if is64 => d ^ x,
+                    0xa0 => (d32 ^ x32) as u64,
+                    0xb0 if is64 => x,
+                    0xb0 => x32 as u64,
+                    0xc0 if is64 => (d as i64).wrapping_shr(x as u32) as u64,
+                    0xc0 => (d32 as i32).wrapping_shr(x32) as u32 as u64,
+                    0xd0 => {
+                        // byte swap: BPF_X selects big endian
+                        let be = op & BPF_X != 0;
+                        match imm {
+                            16 => if be { (d as u16).to_be() as u64 } else { (d as u16).to_le() as u64 },
+                            32 => if be { (d as u32).to_be() as u64 } else { (d as u32).to_le() as u64 },
+                            _ => if be { d.to_be() } else { d.to_le() },
+                        }
+                    }
+                    _ => {
+                        error!("ebpf: unknown alu opcode {:#x} at {}", op, pc - 1);
+                        return 0;
+                    }
+                };
+                reg[dst] = res;
+            }
+            BPF_LD => {
+                if op != BPF_LDDW {
+                    error!("ebpf: unsupported ld opcode {:#x} at {}", op, pc - 1);
+                    return 0;
+                }
+                if pc >= insns.len() {
+                    error!("ebpf: incomplete ld_imm64 at {}", pc - 1);
+                    return 0;
+                }
+                // map references keep their id, which the map helpers resolve
+                let (_, _, _, _, hi) = decode(insns[pc]);
+                reg[dst] = (imm as u32 as u64) | ((hi as u32 as u64) << 32);
+                pc += 1;
+            }
+            BPF_LDX => {
+                let addr = reg[src].wrapping_add(off as i64 as u64);
+                reg[dst] = unsafe {
+                    match op & 0x18 {
+                        BPF_B => *(addr as *const u8) as u64,
+                        BPF_H => *(addr as *const u16) as u64,
+                        BPF_W => *(addr as *const u32) as u64,
+                        _ => *(addr as *const u64),
+                    }
+                };
+            }
+            BPF_ST | BPF_STX => {
+                let addr = reg[dst].wrapping_add(off as i64 as u64);
+                let value = if op & 0x07 == BPF_STX { reg[src] } else { imm as i64 as u64 };
+                unsafe {
+                    if op & 0xe0 == BPF_XADD {
+                        match op & 0x18 {
+                            BPF_W => *(addr as *mut u32) = (*(addr as *mut u32)).wrapping_add(value as u32),
+                            _ => *(addr as *mut u64) = (*(addr as *mut u64)).wrapping_add(value),
+                        }
+                    } else {
+                        match op & 0x18 {
+                            BPF_B => *(addr as *mut u8) = value as u8,
+                            BPF_H => *(addr as *mut u16) = value as u16,
+                            BPF_W => *(addr as *mut u32) = value as u32,
+                            _ => *(addr as *mut u64) = value,
+                        }
+                    }
+                }
+            }
+            BPF_JMP | BPF_JMP32 => {
+                match op & 0xf0 {
+                    BPF_CALL if src as u8 == BPF_PSEUDO_CALL => {
+                        if frames.len() + 1 >= MAX_CALL_DEPTH {
+                            error!("ebpf: call depth exceeded in program {}", prog.id);
+                            return 0;
+                        }
+                        if call_stacks.is_empty() {
+                            call_stacks = vec![0u8; STACK_SIZE * (MAX_CALL_DEPTH - 1)];
+                        }
+                        frames.push(Frame {
+                            ret_pc: pc,
+                            saved: [reg[6], reg[7], reg[8], reg[9]],
+                        });
+                        reg[10] = stack_top(frames.len(), stack_base, call_stacks.as_ptr() as u64);
+                        pc = (pc as isize + imm as isize) as usize;
+                        continue;
+                    }
+                    BPF_CALL if imm == BPF_FUNC_TAIL_CALL => {
+                        // on failure the caller continues with the next instruction
+                        match tail_call_target(reg[2], reg[3]) {
+                            // a program of another type would misread the context
+                            Some(next)
+                                if next.prog_type == prog.prog_type
+                                    && frames.is_empty()
+                                    && tail_calls < MAX_TAIL_CALL_CNT =>
+                            {
+                                tail_calls += 1;
+                                prog = next;
+                                pc = 0;
+                                reg[10] = stack_top(0, stack_base, 0);
+                            }
+                            _ => reg[0] = -1i64 as u64,
+                        }
+                        continue;
+                    }
+                    BPF_CALL => {
+                        match helpers.get(imm as usize) {
+                            Some(helper) => {
+                                reg[0] = unsafe { (*helper)(reg[1], reg[2], reg[3], reg[4], reg[5]) };
+                            }
+                            None => {
+                                error!("ebpf: unknown helper {} at {}", imm, pc - 1);
+                                return 0;
+                            }
+                        }
+                        continue;
+                    }
+                    BPF_EXIT => match frames.pop() {
+                        Some(frame) => {
+                            pc = frame.ret_pc;
+                            reg[6..10].copy_from_slice(&frame.saved);
+                            reg[10] = stack_top(frames.len(), stack_base, call_stacks.as_ptr() as u64);
+                            continue;
+                        }
+                        None => return reg[0],
+                    },
+                    _ => {}
+                }
+                let is64 = op & 0x07 == BPF_JMP;
+                let mut d = reg[dst];
+                let mut x = if op & BPF_X != 0 { reg[src] } else { imm as i64 as u64 };
+                if !is64 {
+                    d = d as u32 as u64;
+                    x = x as u32 as u64;
+                }
+                let (sd, sx) = if is64 {
+                    (d as i64, x as i64)
+                } else {
+                    (d as u32 as i32 as i64, x as u32 as i32 as i64)
+                };
+                let taken = match op & 0xf0 {
+                    0x00 => true,
+                    0x10 => d == x,
+                    0x20 => d > x,
+                    0x30 => d >= x,
+                    0x40 => d & x != 0,
+                    0x50 => d != x,
+                    0x60 => sd > sx,
+                    0x70 => sd >= sx,
+                    0xa0 => d < x,
+                    0xb0 => d <= x,
+                    0xc0 => sd < sx,
+                    0xd0 => sd <= sx,
+                    _ => {
+                        error!("ebpf: unknown jmp opcode {:#x} at {}", op, pc - 1);
+                        return 0;
+                    }
+                };
+                if taken {
+                    pc = (pc as isize + off as isize) as usize;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+fn tail_call_target(map_id: u64, index: u64) -> Option<Arc<BpfProg>> {
+    let map = map_get(map_id as u32)?;
+    // a tail call into a map locked on this hart falls through
+    let prog = map.try_lock()?.get_prog(index as u32);
+    prog
+}
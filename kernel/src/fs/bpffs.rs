@@ -0,0 +1,350 @@
+//! BPF file system mounted at /sys/fs/bpf
+//!
+//! Pinning a program or map creates a file here which holds a reference to
+//! the object; `BPF_OBJ_GET` opens it again and `unlink` drops the pin.
+//! `progs.debug` in the root lists the loaded programs and armed probes.
+
+use alloc::collections::btree_map::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::{Arc, Weak};
+use core::any::Any;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rcore_fs::vfs::*;
+use spin::{Mutex, RwLock};
+
+use crate::ebpf::info::ebpf_info;
+use crate::ebpf::map::BpfMap;
+use crate::ebpf::prog::BpfProg;
+
+/// Mount point of the BPF file system
+pub const BPF_FS_MOUNT_POINT: &str = "/sys/fs/bpf";
+
+#[derive(Clone)]
+pub enum BpfObject {
+    Prog(Arc<BpfProg>),
+    Map(Arc<Mutex<BpfMap>>),
+}
+
+/// A file referring to a program or map. It is both what user space gets
+/// as an fd from `bpf(2)` and what a pin stores in the file system.
+pub struct BpfObjINode {
+    pub object: BpfObject,
+    id: usize,
+}
+
+/// Read-only text generated from the current state on every read.
+pub struct BpfInfoINode {
+    id: usize,
+}
+
+pub struct BpfDirINode {
+    entries: RwLock<BTreeMap<String, Arc<dyn INode>>>,
+    parent: Weak<BpfDirINode>,
+    self_ref: RwLock<Weak<BpfDirINode>>,
+    id: usize,
+}
+
+pub struct BpfFS {
+    root: Arc<BpfDirINode>,
+}
+
+lazy_static! {
+    pub static ref BPF_FS: Arc<BpfFS> = {
+        let root = BpfDirINode::new(Weak::new());
+        root.entries
+            .write()
+            .insert(String::from("progs.debug"), Arc::new(BpfInfoINode { id: new_inode_id() }));
+        Arc::new(BpfFS { root })
+    };
+}
+
+static NEXT_INODE_ID: AtomicUsize = AtomicUsize::new(1);
+
+fn new_inode_id() -> usize {
+    NEXT_INODE_ID.fetch_add(1, Ordering::SeqCst)
+}
+
+fn metadata(id: usize, size: usize, type_: FileType, mode: u16) -> Metadata {
+    Metadata {
+        dev: 0,
+        inode: id,
+        size,
+        blk_size: 0,
+        blocks: 0,
+        atime: Timespec { sec: 0, nsec: 0 },
+        mtime: Timespec { sec: 0, nsec: 0 },
+        ctime: Timespec { sec: 0, nsec: 0 },
+        type_,
+        mode,
+        nlinks: 1,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+    }
+}
+
+impl BpfObjINode {
+    pub fn new(object: BpfObject) -> Arc<Self> {
+        if let BpfObject::Map(map) = &object {
+            map.lock().add_user();
+        }
+        Arc::new(Self {
+            object,
+            id: new_inode_id(),
+        })
+    }
+
+    fn content(&self) -> String {
+        match &self.object {
+            BpfObject::Prog(prog) => format!(
+                "prog_id:\t{}\ninsn_cnt:\t{}\ndetached_cnt:\t{}\n",
+                prog.id,
+                prog.insns.len(),
+                prog.stats.detached_cnt.load(Ordering::Relaxed)
+            ),
+            BpfObject::Map(map) => {
+                let map = map.lock();
+                format!(
+                    "map_id:\t{}\nmap_type:\t{:?}\nkey_size:\t{}\nvalue_size:\t{}\nmax_entries:\t{}\n",
+                    map.id, map.map_type, map.key_size, map.value_size, map.max_entries
+                )
+            }
+        }
+    }
+}
+
+impl Drop for BpfObjINode {
+    fn drop(&mut self) {
+        if let BpfObject::Map(map) = &self.object {
+            // a program dropped here may detach, so not with the map locked
+            let progs = map.lock().remove_user();
+            drop(progs);
+        }
+    }
+}
+
+fn read_content(content: &str, offset: usize, buf: &mut [u8]) -> Result<usize> {
+    let content = content.as_bytes();
+    if offset >= content.len() {
+        return Ok(0);
+    }
+    let len = (content.len() - offset).min(buf.len());
+    buf[..len].copy_from_slice(&content[offset..offset + len]);
+    Ok(len)
+}
+
+impl INode for BpfObjINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        read_content(&self.content(), offset, buf)
+    }
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+    /// A program hangs up once a module it probed unloads.
+    fn poll(&self) -> Result<PollStatus> {
+        let error = match &self.object {
+            BpfObject::Prog(prog) => prog.stats.detached_cnt.load(Ordering::Relaxed) > 0,
+            BpfObject::Map(_) => false,
+        };
+        Ok(PollStatus {
+            read: true,
+            write: false,
+            error,
+        })
+    }
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(metadata(self.id, self.content().len(), FileType::File, 0o600))
+    }
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        BPF_FS.clone()
+    }
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl INode for BpfInfoINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        read_content(&ebpf_info(), offset, buf)
+    }
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: true,
+            write: false,
+            error: false,
+        })
+    }
+    fn metadata(&self) -> Result<Metadata> {
+        // the size is unknown until the text is generated
+        Ok(metadata(self.id, 0, FileType::File, 0o444))
+    }
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        BPF_FS.clone()
+    }
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl BpfDirINode {
+    fn new(parent: Weak<BpfDirINode>) -> Arc<Self> {
+        let dir = Arc::new(Self {
+            entries: RwLock::new(BTreeMap::new()),
+            parent,
+            self_ref: RwLock::new(Weak::new()),
+            id: new_inode_id(),
+        });
+        *dir.self_ref.write() = Arc::downgrade(&dir);
+        dir
+    }
+
+    fn this(&self) -> Arc<BpfDirINode> {
+        self.self_ref.read().upgrade().unwrap()
+    }
+
+    /// Pin `object` as `name` in this directory.
+    pub fn pin(&self, name: &str, object: BpfObject) -> Result<()> {
+        let mut entries = self.entries.write();
+        if entries.contains_key(name) {
+            return Err(FsError::EntryExist);
+        }
+        entries.insert(name.to_string(), BpfObjINode::new(object));
+        Ok(())
+    }
+}
+
+impl INode for BpfDirINode {
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+        Err(FsError::IsDir)
+    }
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::IsDir)
+    }
+    fn poll(&self) -> Result<PollStatus> {
+        Err(FsError::IsDir)
+    }
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(metadata(self.id, 0, FileType::Dir, 0o1777))
+    }
+    fn create(&self, name: &str, type_: FileType, _mode: u32) -> Result<Arc<dyn INode>> {
+        // only directories can be created, objects are added by pinning
+        if type_ != FileType::Dir {
+            return Err(FsError::NotSupported);
+        }
+        let mut entries = self.entries.write();
+        if entries.contains_key(name) {
+            return Err(FsError::EntryExist);
+        }
+        let dir = BpfDirINode::new(self.self_ref.read().clone());
+        entries.insert(name.to_string(), dir.clone());
+        Ok(dir)
+    }
+    fn unlink(&self, name: &str) -> Result<()> {
+        let mut entries = self.entries.write();
+        let inode = entries.get(name).ok_or(FsError::EntryNotFound)?;
+        if let Some(dir) = inode.as_any_ref().downcast_ref::<BpfDirINode>() {
+            if !dir.entries.read().is_empty() {
+                return Err(FsError::DirNotEmpty);
+            }
+        }
+        // dropping the entry releases the reference held by the pin
+        entries.remove(name);
+        Ok(())
+    }
+    fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
+        match name {
+            "." | "" => Ok(self.this()),
+            ".." => Ok(self.parent.upgrade().unwrap_or_else(|| self.this())),
+            name => self
+                .entries
+                .read()
+                .get(name)
+                .cloned()
+                .ok_or(FsError::EntryNotFound),
+        }
+    }
+    fn get_entry(&self, id: usize) -> Result<String> {
+        match id {
+            0 => Ok(String::from(".")),
+            1 => Ok(String::from("..")),
+            i => self
+                .entries
+                .read()
+                .keys()
+                .nth(i - 2)
+                .cloned()
+                .ok_or(FsError::EntryNotFound),
+        }
+    }
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        BPF_FS.clone()
+    }
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl BpfFS {
+    /// Resolve `path` under the mount point to the directory holding its
+    /// last component, and that component.
+    pub fn lookup_parent<'a>(&self, path: &'a str) -> Result<(Arc<BpfDirINode>, &'a str)> {
+        let rest = path
+            .strip_prefix(BPF_FS_MOUNT_POINT)
+            .ok_or(FsError::InvalidParam)?;
+        // `/sys/fs/bpfoo` is not under the mount point
+        if !rest.is_empty() && !rest.starts_with('/') {
+            return Err(FsError::InvalidParam);
+        }
+        let rest = rest.trim_matches('/');
+        let mut dir = self.root.clone();
+        let mut components = rest.split('/').peekable();
+        while let Some(name) = components.next() {
+            if components.peek().is_none() {
+                if name.is_empty() {
+                    return Err(FsError::InvalidParam);
+                }
+                return Ok((dir, name));
+            }
+            let next = dir.find(name)?;
+            dir = match next.as_any_ref().downcast_ref::<BpfDirINode>() {
+                Some(next) => next.this(),
+                None => return Err(FsError::NotDir),
+            };
+        }
+        Err(FsError::InvalidParam)
+    }
+
+    /// Find the object pinned at `path`.
+    pub fn get(&self, path: &str) -> Result<Arc<dyn INode>> {
+        let (dir, name) = self.lookup_parent(path)?;
+        let inode = dir.find(name)?;
+        if inode.as_any_ref().downcast_ref::<BpfObjINode>().is_none() {
+            return Err(FsError::InvalidParam);
+        }
+        Ok(inode)
+    }
+}
+
+impl FileSystem for BpfFS {
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+    fn root_inode(&self) -> Arc<dyn INode> {
+        self.root.clone()
+    }
+    fn info(&self) -> FsInfo {
+        FsInfo {
+            bsize: 0,
+            frsize: 0,
+            blocks: 0,
+            bfree: 0,
+            bavail: 0,
+            files: 0,
+            ffree: 0,
+            namemax: 256,
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! Implement INode for block devices
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::any::Any;
+
+use rcore_fs::dev::Device;
+use rcore_fs::vfs::*;
+
+use crate::drivers::{BlockDriver, BlockDriverWrapper};
+
+/// Raw access to the `id`th block device, as /dev/sda, /dev/sdb ...
+pub struct BlockINode {
+    driver: BlockDriverWrapper,
+    id: usize,
+}
+
+impl BlockINode {
+    pub fn new(driver: Arc<dyn BlockDriver>, id: usize) -> Self {
+        BlockINode {
+            driver: BlockDriverWrapper(driver),
+            id,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        format!("sd{}", (b'a' + self.id as u8) as char)
+    }
+}
+
+impl INode for BlockINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        Device::read_at(&self.driver, offset, buf).map_err(|_| FsError::DeviceError)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        Device::write_at(&self.driver, offset, buf).map_err(|_| FsError::DeviceError)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: true,
+            write: true,
+            error: false,
+        })
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata {
+            dev: 1,
+            inode: 1,
+            size: 0,
+            blk_size: 512,
+            blocks: 0,
+            atime: Timespec { sec: 0, nsec: 0 },
+            mtime: Timespec { sec: 0, nsec: 0 },
+            ctime: Timespec { sec: 0, nsec: 0 },
+            type_: FileType::BlockDevice,
+            mode: 0o660,
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: make_rdev(8, self.id * 16),
+        })
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
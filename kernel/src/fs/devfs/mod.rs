@@ -1,11 +1,13 @@
 //! Device file system mounted at /dev
 
+mod block;
 mod fbdev;
 mod random;
 mod serial;
 mod shm;
 mod tty;
 
+pub use block::*;
 pub use fbdev::*;
 pub use random::*;
 pub use serial::*;
@@ -9,15 +9,18 @@ use rcore_fs_mountfs::MountFS;
 use rcore_fs_ramfs::RamFS;
 use rcore_fs_sfs::{INodeImpl, SimpleFileSystem};
 
-use self::devfs::{Fbdev, RandomINode};
+use self::devfs::{BlockINode, Fbdev, RandomINode};
 
+pub use self::bpffs::{BpfObjINode, BpfObject, BPF_FS};
 pub use self::devfs::{Serial, ShmINode, TTY};
 pub use self::file::*;
 pub use self::file_like::*;
 pub use self::pipe::Pipe;
 pub use self::pseudo::*;
+pub use self::timerfd::TimerFd;
 use crate::drivers::{BlockDriver, BlockDriverWrapper};
 
+mod bpffs;
 mod devfs;
 mod device;
 pub mod epoll;
@@ -27,6 +30,7 @@ mod file_like;
 pub mod ioctl;
 mod pipe;
 mod pseudo;
+mod timerfd;
 
 // Hard link user programs
 #[cfg(feature = "link_user")]
@@ -85,6 +89,10 @@ lazy_static! {
         for (i, serial) in Serial::wrap_all_serial_devices().into_iter().enumerate(){
             devfs.add(&format!("ttyS{}", i), Arc::new(serial)).expect("failed to add a serial");
         }
+        for (i, driver) in crate::drivers::BLK_DRIVERS.read().iter().enumerate() {
+            let block = BlockINode::new(driver.clone(), i);
+            devfs.add(&block.name(), Arc::new(block)).expect("failed to add a block device");
+        }
 
 
         #[cfg(feature = "hypervisor")]
@@ -92,7 +100,7 @@ lazy_static! {
 
         // mount DevFS at /dev
         let dev = root.find(true, "dev").unwrap_or_else(|_| {
-            root.create("dev", FileType::Dir, 0o666).expect("failed to mkdir /dev")
+            root.create("dev", FileType::Dir, 0o755).expect("failed to mkdir /dev")
         });
         let devfs = dev.mount(devfs).expect("failed to mount DevFS");
 
@@ -104,10 +112,20 @@ lazy_static! {
         // mount RamFS at /tmp
         let ramfs = RamFS::new();
         let tmp = root.find(true, "tmp").unwrap_or_else(|_| {
-            root.create("tmp", FileType::Dir, 0o666).expect("failed to mkdir /tmp")
+            root.create("tmp", FileType::Dir, 0o1777).expect("failed to mkdir /tmp")
         });
         tmp.mount(ramfs).expect("failed to mount RamFS");
 
+        // mount RamFS at /sys, and BpfFS at /sys/fs/bpf
+        let sys = root.find(true, "sys").unwrap_or_else(|_| {
+            root.create("sys", FileType::Dir, 0o755).expect("failed to mkdir /sys")
+        });
+        let sysfs = sys.mount(RamFS::new()).expect("failed to mount /sys");
+        let bpf = sysfs.root_inode()
+            .create("fs", FileType::Dir, 0o755).expect("failed to mkdir /sys/fs")
+            .create("bpf", FileType::Dir, 0o755).expect("failed to mkdir /sys/fs/bpf");
+        bpf.mount(BPF_FS.clone()).expect("failed to mount BpfFS");
+
         root
     };
 }
@@ -0,0 +1,139 @@
+//! Implement INode for timerfd
+
+use crate::process::IntervalTimer;
+use crate::sync::{Event, EventBus, SpinNoIrqLock as Mutex};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::any::Any;
+use core::mem::size_of;
+use core::time::Duration;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use rcore_fs::vfs::*;
+
+struct TimerFdData {
+    /// Expirations since the last read
+    expirations: u64,
+    eventbus: EventBus,
+}
+
+/// A file readable when its timer expires, as timerfd_create(2)
+pub struct TimerFd {
+    timer: IntervalTimer,
+    data: Arc<Mutex<TimerFdData>>,
+}
+
+impl TimerFd {
+    pub fn new() -> Self {
+        let data = Arc::new(Mutex::new(TimerFdData {
+            expirations: 0,
+            eventbus: EventBus::default(),
+        }));
+        let timer = IntervalTimer::new({
+            let data = data.clone();
+            move |count| {
+                let mut data = data.lock();
+                data.expirations += count as u64;
+                data.eventbus.set(Event::READABLE);
+            }
+        });
+        TimerFd { timer, data }
+    }
+
+    /// Expire after `value` and then every `interval`, or disarm if `value` is zero.
+    /// Return the old time left and interval.
+    pub fn set(&self, value: Duration, interval: Duration) -> (Duration, Duration) {
+        let old = self.timer.set(value, interval);
+        // expirations of the old setting are discarded
+        let mut data = self.data.lock();
+        data.expirations = 0;
+        data.eventbus.clear(Event::READABLE);
+        old
+    }
+
+    /// The time left and interval
+    pub fn get(&self) -> (Duration, Duration) {
+        self.timer.get()
+    }
+
+    fn can_read(&self) -> bool {
+        self.data.lock().expirations > 0
+    }
+}
+
+impl INode for TimerFd {
+    /// Read the number of expirations as an `u64`
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+        if buf.len() < size_of::<u64>() {
+            return Err(FsError::InvalidParam);
+        }
+        let mut data = self.data.lock();
+        if data.expirations == 0 {
+            return Err(FsError::Again);
+        }
+        buf[..size_of::<u64>()].copy_from_slice(&data.expirations.to_ne_bytes());
+        data.expirations = 0;
+        data.eventbus.clear(Event::READABLE);
+        Ok(size_of::<u64>())
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::InvalidParam)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: self.can_read(),
+            write: false,
+            error: false,
+        })
+    }
+
+    fn async_poll<'a>(
+        &'a self,
+    ) -> Pin<Box<dyn Future<Output = Result<PollStatus>> + Send + Sync + 'a>> {
+        #[must_use = "future does nothing unless polled/`await`-ed"]
+        struct TimerFdFuture<'a> {
+            timerfd: &'a TimerFd,
+            /// The waker of the last poll, None when no callback is subscribed
+            waker: Arc<Mutex<Option<Waker>>>,
+        };
+
+        impl<'a> Future for TimerFdFuture<'a> {
+            type Output = Result<PollStatus>;
+
+            fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+                // checked under the lock, so that no expiration is missed before subscribing
+                let mut data = self.timerfd.data.lock();
+                if data.expirations > 0 {
+                    drop(data);
+                    return Poll::Ready(self.timerfd.poll());
+                }
+                // subscribe once until the callback runs, however often the future is polled
+                let mut waker = self.waker.lock();
+                if waker.replace(cx.waker().clone()).is_none() {
+                    let waker = self.waker.clone();
+                    data.eventbus.subscribe(Box::new(move |_| {
+                        if let Some(waker) = waker.lock().take() {
+                            waker.wake();
+                        }
+                        true
+                    }));
+                }
+                Poll::Pending
+            }
+        }
+
+        Box::pin(TimerFdFuture {
+            timerfd: self,
+            waker: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
@@ -0,0 +1,91 @@
+//! Kernel code that must not be probed.
+//!
+//! A probe hit runs the trap entry, the probe machinery and eBPF programs,
+//! taking `SpinNoIrq` locks on the way, so a probe on any of them traps
+//! again before the first hit is handled. Functions are blacklisted one by
+//! one with `nokprobe!`, and whole modules by their demangled paths, which
+//! needs the kernel symbol table. A function is refused
+//! anywhere inside it when the table is loaded, and at its entry otherwise.
+
+use alloc::vec::Vec;
+use crate::arch::interrupt::{trap_handler, trap_handler_no_frame};
+use crate::ebpf::ebpf::run_accounted;
+use crate::ebpf::vm::interpret;
+use crate::lkm::demangle::demangle;
+use crate::lkm::manager::ModuleManager;
+use crate::sync::{FlagsGuard, MutexSupport, SpinNoIrq};
+use riscv::register::stvec;
+use super::kprobes::{self, kprobes_trap_handler};
+use super::uprobes::{self, uprobes_trap_handler};
+
+macro_rules! nokprobe {
+    ($($func:expr),* $(,)?) => {
+        vec![$((stringify!($func), $func as usize)),*]
+    };
+}
+
+lazy_static! {
+    static ref NOKPROBE_FUNCS: Vec<(&'static str, usize)> = nokprobe![
+        trap_handler,
+        trap_handler_no_frame,
+        kprobes_trap_handler,
+        uprobes_trap_handler,
+        kprobes::__ebreak,
+        uprobes::__ebreak,
+        interpret,
+        run_accounted,
+        <SpinNoIrq as MutexSupport>::before_lock,
+        <FlagsGuard as Drop>::drop,
+    ];
+}
+
+/// Demangled symbols of blacklisted modules contain one of these, as a path
+/// for their own functions or as a type for their trait implementations.
+const NOKPROBE_MODULES: &[&str] = &[
+    "trapframe::",
+    "rcore::kprobes::kprobes::",
+    "rcore::kprobes::uprobes::",
+    "rcore::kprobes::probes::",
+    "rcore::kprobes::blacklist::",
+    "rcore::ebpf::",
+    "rcore::sync::mutex::",
+    "spin::mutex::",
+];
+
+/// Unmangled symbols of the trap vectors.
+const NOKPROBE_SYMBOLS: &[&str] = &["trap_entry", "trap_return", "run_user"];
+
+/// `__ebreak` is two compressed ebreaks.
+const EBREAK_LEN: usize = 4;
+
+/// Why a probe at `addr` is refused, or `None` if it may be placed.
+pub fn kprobe_blacklisted(addr: usize) -> Option<&'static str> {
+    let symbol = ModuleManager::with(|mm| mm.lookup_address(addr));
+    let entry = match &symbol {
+        Some((_, offset)) => addr - offset,
+        None => addr,
+    };
+    let ebreaks = [kprobes::__ebreak as usize, uprobes::__ebreak as usize];
+    if ebreaks.iter().any(|&start| start <= addr && addr < start + EBREAK_LEN) {
+        return Some("__ebreak");
+    }
+    if entry == stvec::read().address() {
+        return Some("trap vector");
+    }
+    if let Some((name, _)) = NOKPROBE_FUNCS.iter().find(|(_, func)| *func == entry) {
+        return Some(name);
+    }
+    let name = symbol?.0;
+    if NOKPROBE_SYMBOLS.contains(&name.as_str()) {
+        return Some("trap vector");
+    }
+    let path = demangle(&name)?;
+    NOKPROBE_MODULES
+        .iter()
+        .find(|module| path.contains(*module))
+        .copied()
+}
+
+pub fn kprobe_blacklist() -> &'static [(&'static str, usize)] {
+    &NOKPROBE_FUNCS
+}
@@ -0,0 +1,48 @@
+//! Functions whose result may be replaced by `bpf_override_return`.
+//!
+//! Overriding returns to the caller straight from the function entry with
+//! only a0 set, so a function qualifies if a0 alone can carry its failure:
+//! `bool` and `Option<usize>` fail with 0, and so does a `Result` of a
+//! reference whose error type has a single variant, as the error is stored
+//! as a null reference. Any other a0 could make an invalid value of the
+//! result type, so each function has the one value injected as its error.
+
+use alloc::vec::Vec;
+use crate::drivers::block::ahci::AHCIDriver;
+use crate::drivers::block::ide::IDEDriver;
+use crate::drivers::block::virtio_blk::VirtIOBlkDriver;
+use crate::drivers::BlockDriver;
+use crate::memory::{FrameAllocator, GlobalFrameAlloc, MemorySet};
+
+macro_rules! error_injectable {
+    ($($func:expr => $error:expr),* $(,)?) => {
+        vec![$((stringify!($func), $func as usize, $error)),*]
+    };
+}
+
+lazy_static! {
+    /// Name, address and the a0 returned as error of each function
+    static ref ERROR_INJECTABLE: Vec<(&'static str, usize, usize)> = error_injectable![
+        // None
+        <GlobalFrameAlloc as FrameAllocator>::alloc => 0,
+        // false
+        <VirtIOBlkDriver as BlockDriver>::read_block => 0,
+        <IDEDriver as BlockDriver>::read_block => 0,
+        <AHCIDriver as BlockDriver>::read_block => 0,
+        // Err of a single variant
+        MemorySet::check_read_array::<u8> => 0,
+    ];
+}
+
+/// The error the function starting at `addr` may have its result overridden
+/// with, if it is error-injectable.
+pub fn injectable_error(addr: usize) -> Option<usize> {
+    ERROR_INJECTABLE
+        .iter()
+        .find(|(_, func, _)| *func == addr)
+        .map(|(_, _, error)| *error)
+}
+
+pub fn error_injectable_list() -> &'static [(&'static str, usize, usize)] {
+    &ERROR_INJECTABLE
+}
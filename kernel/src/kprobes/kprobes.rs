@@ -1,14 +1,21 @@
 use alloc::collections::btree_map::BTreeMap;
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::cell::RefCell;
 use core::convert::TryInto;
 use core::ops::FnMut;
 use core::slice::{from_raw_parts, from_raw_parts_mut};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use crate::arch::cpu;
+use crate::lkm::manager::module_at;
+use crate::syscall::SysError::EINVAL;
 use spin::Mutex;
 use lazy_static::*;
 use trapframe::TrapFrame;
-use super::probes::{get_sp, ProbeType};
+use super::blacklist::kprobe_blacklisted;
+use super::error_inject::injectable_error;
+use super::probes::{get_func_entry, simulate_insn, FuncEntry, ProbeInfo, ProbeType};
 use riscv_insn_decode::{insn_decode, InsnStatus, get_insn_length};
 
 fn sext(x: isize, size: usize) -> isize {
@@ -33,13 +40,17 @@ pub struct KprobesInner {
     pub addr: usize,
     pub length: usize,
     pub slot: [u8; 6],
-    pub addisp: usize,
+    pub func_entry: FuncEntry,
     pub func_ra: Vec<usize>,
     pub func_ebreak_addr: usize,
     pub insn_ebreak_addr: usize,
     pub handler: Arc<Mutex<dyn FnMut(&mut TrapFrame) + Send>>,
     pub post_handler: Option<Arc<Mutex<dyn FnMut(&mut TrapFrame) + Send>>>,
     pub probe_type: ProbeType,
+    /// hits skipped because a handler was already running on the hart
+    pub nmissed: Arc<AtomicUsize>,
+    /// the loadable module whose code is probed
+    pub module: Option<String>,
 }
 
 
@@ -60,8 +71,30 @@ lazy_static! {
     static ref CURRENT_KPROBES: CurrentKprobes = CurrentKprobes::new();
 }
 
+/// Bit `i` is set while a handler runs on hart `i`. Trap handlers run with
+/// interrupts disabled, so a hit on a hart with its bit set comes from the
+/// handler itself.
+static HANDLER_ACTIVE: AtomicU64 = AtomicU64::new(0);
+
+/// Run `handler` unless one is already running on this hart, in which case
+/// the hit is only counted in `nmissed`. Returns whether it ran.
+fn run_handler(
+    handler: &Arc<Mutex<dyn FnMut(&mut TrapFrame) + Send>>,
+    nmissed: &AtomicUsize,
+    cx: &mut TrapFrame,
+) -> bool {
+    let bit = 1u64 << cpu::id();
+    if HANDLER_ACTIVE.fetch_or(bit, Ordering::Acquire) & bit != 0 {
+        nmissed.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
+    (handler.lock())(cx);
+    HANDLER_ACTIVE.fetch_and(!bit, Ordering::Release);
+    true
+}
+
 #[naked]
-extern "C" fn __ebreak() {
+pub(super) extern "C" fn __ebreak() {
     unsafe {
         asm!("c.ebreak", "c.ebreak");
     }
@@ -100,7 +133,7 @@ impl KprobesInner {
         slot[..length].copy_from_slice(&inst[..length]);
 
         // decode the probed instruction to retrive imm
-        let mut addisp: usize = 0;
+        let mut func_entry = FuncEntry::SingleStep;
         let mut func_ebreak_addr:usize = 0;
         let mut insn_ebreak_addr:usize = 0;
         let ebreak = unsafe { from_raw_parts(__ebreak as *const u8, 2) };
@@ -123,11 +156,13 @@ impl KprobesInner {
                 func_ebreak_addr = current_ebreak.as_ptr() as usize + 2 * len;
                 let mut ebreak_ptr = unsafe { from_raw_parts_mut(func_ebreak_addr as *mut u8, 2)};
                 ebreak_ptr.copy_from_slice(ebreak);
-                match get_sp(addr){
-                    Some(sp) => addisp = sp as usize,
-                    None => {error!("sp not found!"); return None}
+                match get_func_entry(addr){
+                    Some(entry) => func_entry = entry,
+                    None => {error!("kprobes: function entry can not be replayed"); return None}
+                }
+                if let FuncEntry::SingleStep = func_entry {
+                    slot[length..length+2].copy_from_slice(ebreak);
                 }
-                // println!("addisp {}", addisp as isize);
             }
             ProbeType::AsyncFunc =>{
                 error!("not implemented yet!");
@@ -138,13 +173,15 @@ impl KprobesInner {
             addr,
             length,
             slot,
-            addisp,
+            func_entry,
             func_ra: Vec::new(),
             func_ebreak_addr,
             insn_ebreak_addr,
             handler,
             post_handler,
             probe_type,
+            nmissed: Arc::new(AtomicUsize::new(0)),
+            module: None,
         })
     }
 
@@ -179,8 +216,13 @@ impl Kprobes {
         post_handler: Option<Arc<Mutex<dyn FnMut(&mut TrapFrame) + Send>>>,
         probe_type: ProbeType,
     ) -> isize {
+        let module = match check_kprobe(addr, &probe_type) {
+            Ok(module) => module,
+            Err(ret) => return ret,
+        };
         let probe = KprobesInner::new(addr, handler, post_handler, probe_type);
-        if let Some(probe) = probe {
+        if let Some(mut probe) = probe {
+            probe.module = module;
             probe.arm();
             if let Some(replaced) = self.inner.borrow_mut().insert(addr, probe) {
                 replaced.disarm();
@@ -204,63 +246,97 @@ impl Kprobes {
 
 
     fn kprobes_trap_handler(&self, cx: &mut TrapFrame) {
-        let mut kprobes = self.inner.borrow_mut();
-        let mut current_kprobes = CURRENT_KPROBES.inner.borrow_mut();
-        match kprobes.get_mut(&cx.sepc) {
-            Some(probe) => {
-                // run user defined handler
-                (probe.handler.lock())(cx);
-                // single step the probed instruction
-                match probe.probe_type{
-                    ProbeType::SyncFunc =>{
-                        cx.general.sp = cx.general.sp.wrapping_add(probe.addisp);
-                        cx.sepc = cx.sepc.wrapping_add(probe.length);
-                        if let Some(_) = probe.post_handler{
-                            if !current_kprobes.contains_key(&probe.func_ebreak_addr){
-                                current_kprobes.insert(probe.func_ebreak_addr, probe.clone());
-                            }
-                            let current_kprobe = current_kprobes.get_mut(&probe.func_ebreak_addr).unwrap();
-                            current_kprobe.func_ra.push(cx.general.ra);
-                            cx.general.ra = probe.func_ebreak_addr as usize;
-                        }
-                    },
-                    ProbeType::Insn =>{
-                        cx.sepc = probe.slot.as_ptr() as usize;
-                        probe.insn_ebreak_addr = cx.sepc + probe.length;
-                        if !current_kprobes.contains_key(&probe.insn_ebreak_addr){
-                            current_kprobes.insert(probe.insn_ebreak_addr, probe.clone());
+        let addr = cx.sepc;
+        // handlers run without the tables borrowed, as they may hit other probes
+        let hit = self.inner.borrow().get(&addr).map(|probe| (probe.handler.clone(), probe.nmissed.clone()));
+        if let Some((handler, nmissed)) = hit {
+            // run user defined handler
+            let ran = run_handler(&handler, &nmissed, cx);
+            if cx.sepc != addr {
+                // the handler redirected execution, e.g. by overriding the return
+                return;
+            }
+            let mut kprobes = self.inner.borrow_mut();
+            let mut current_kprobes = CURRENT_KPROBES.inner.borrow_mut();
+            let probe = match kprobes.get_mut(&addr) {
+                Some(probe) => probe,
+                // unregistered by the handler, the original instruction is back
+                None => return,
+            };
+            // single step the probed instruction
+            match probe.probe_type{
+                ProbeType::SyncFunc =>{
+                    // capture the return address before the first instruction can spill it,
+                    // a skipped entry does not report its return either
+                    if probe.post_handler.is_some() && ran {
+                        if !current_kprobes.contains_key(&probe.func_ebreak_addr){
+                            current_kprobes.insert(probe.func_ebreak_addr, probe.clone());
                         }
+                        let current_kprobe = current_kprobes.get_mut(&probe.func_ebreak_addr).unwrap();
+                        current_kprobe.func_ra.push(cx.general.ra);
+                        cx.general.ra = probe.func_ebreak_addr as usize;
                     }
-                    ProbeType::AsyncFunc => {
-                        unimplemented!("probing async function is not implemented yet")
-                    }
-                }
-            }
-            None => {
-                match current_kprobes.get_mut(&cx.sepc){
-                    Some(probe) =>{
-                        if probe.insn_ebreak_addr == cx.sepc{
-                            if let Some(post_handler) = &probe.post_handler{
-                                (post_handler.lock())(cx);
-                            }
-                            let sepc = probe.addr + probe.length;
-                            current_kprobes.remove(&cx.sepc);
-                            cx.sepc = sepc;
+                    match probe.func_entry {
+                        FuncEntry::AdjustSp(addisp) => {
+                            cx.general.sp = cx.general.sp.wrapping_add(addisp);
+                            cx.sepc = addr.wrapping_add(probe.length);
+                        }
+                        FuncEntry::Simulate => {
+                            cx.sepc = simulate_insn(probe.addr, &mut cx.general).unwrap();
                         }
-                        else{
-                            (probe.post_handler.as_ref().unwrap().lock())(cx);
-                            let sepc= probe.func_ra.pop().unwrap();
-                            if probe.func_ra.len() == 0{
-                                current_kprobes.remove(&cx.sepc);
+                        FuncEntry::SingleStep => {
+                            cx.sepc = probe.slot.as_ptr() as usize;
+                            probe.insn_ebreak_addr = cx.sepc + probe.length;
+                            if !current_kprobes.contains_key(&probe.insn_ebreak_addr){
+                                current_kprobes.insert(probe.insn_ebreak_addr, probe.clone());
                             }
-                            cx.sepc = sepc;
                         }
                     }
-                    _ => {}
+                },
+                ProbeType::Insn =>{
+                    cx.sepc = probe.slot.as_ptr() as usize;
+                    probe.insn_ebreak_addr = cx.sepc + probe.length;
+                    if !current_kprobes.contains_key(&probe.insn_ebreak_addr){
+                        current_kprobes.insert(probe.insn_ebreak_addr, probe.clone());
+                    }
+                }
+                ProbeType::AsyncFunc => {
+                    unimplemented!("probing async function is not implemented yet")
+                }
+            }
+            return;
+        }
+        let current = CURRENT_KPROBES.inner.borrow().get(&addr).map(|probe| {
+            (
+                probe.insn_ebreak_addr == addr,
+                probe.probe_type.clone(),
+                probe.post_handler.clone(),
+                probe.nmissed.clone(),
+            )
+        });
+        if let Some((single_step, probe_type, post_handler, nmissed)) = current {
+            // function probes only report on return, not after the first instruction
+            let report = match probe_type {
+                ProbeType::Insn => true,
+                _ => !single_step,
+            };
+            if let (true, Some(post_handler)) = (report, post_handler) {
+                run_handler(&post_handler, &nmissed, cx);
+            }
+            let mut current_kprobes = CURRENT_KPROBES.inner.borrow_mut();
+            let probe = current_kprobes.get_mut(&addr).unwrap();
+            if single_step {
+                let sepc = probe.addr + probe.length;
+                current_kprobes.remove(&addr);
+                cx.sepc = sepc;
+            } else {
+                let sepc = probe.func_ra.pop().unwrap();
+                if probe.func_ra.len() == 0{
+                    current_kprobes.remove(&addr);
                 }
+                cx.sepc = sepc;
             }
         }
-        // cx.sepc = cx.general.ra;
     }
 }
 
@@ -268,6 +344,43 @@ pub fn kprobes_trap_handler(cx: &mut TrapFrame) {
     KPROBES.kprobes_trap_handler(cx);
 }
 
+/// Check that a probe of `probe_type` can be placed at `addr`, without
+/// placing it. Returns the module whose code would be probed, if any.
+fn check_kprobe(addr: usize, probe_type: &ProbeType) -> Result<Option<String>, isize> {
+    if let Some(reason) = kprobe_blacklisted(addr) {
+        error!("kprobes: {:#x} cannot be probed: {}", addr, reason);
+        return Err(-(EINVAL as isize));
+    }
+    // init code of a module may be running while this registers
+    let module = match module_at(addr) {
+        Some((name, true)) => {
+            error!("kprobes: {:#x} is in the init code of module {}", addr, name);
+            return Err(-1);
+        }
+        Some((name, false)) => Some(name),
+        None => None,
+    };
+    let probeable = match probe_type {
+        ProbeType::Insn => matches!(insn_decode(addr), InsnStatus::Legal),
+        ProbeType::SyncFunc => get_func_entry(addr).is_some(),
+        ProbeType::AsyncFunc => false,
+    };
+    if !probeable {
+        error!("kprobes: {:#x} cannot be probed as {:?}", addr, probe_type);
+        return Err(-1);
+    }
+    Ok(module)
+}
+
+/// Whether a probe of `probe_type` can be placed at `addr`: 0 if so, or
+/// what `kprobe_register` would return.
+pub fn kprobe_check(addr: usize, probe_type: &ProbeType) -> isize {
+    match check_kprobe(addr, probe_type) {
+        Ok(_) => 0,
+        Err(ret) => ret,
+    }
+}
+
 pub fn kprobe_register(
     addr: usize, 
     handler: Arc<Mutex<dyn FnMut(&mut TrapFrame) + Send>>, 
@@ -279,4 +392,50 @@ pub fn kprobe_register(
 
 pub fn kprobe_unregister(addr: usize) -> isize{
     KPROBES.unregister_kprobe(addr)
+}
+
+/// Return from the probed function right away with `rc`, for the handler
+/// of a function entry probe on an error-injectable function. `rc` must be
+/// the error of the function, as other values may not be valid results.
+pub fn kprobe_override_return(cx: &mut TrapFrame, rc: usize) -> isize {
+    let is_func_entry = match KPROBES.inner.borrow().get(&cx.sepc) {
+        Some(probe) => match probe.probe_type {
+            ProbeType::SyncFunc => true,
+            _ => false,
+        },
+        None => false,
+    };
+    if !is_func_entry || injectable_error(cx.sepc) != Some(rc) {
+        warn!("kprobes: cannot override the return of {:#x} with {:#x}", cx.sepc, rc);
+        return -1;
+    }
+    cx.general.a0 = rc;
+    cx.sepc = cx.general.ra;
+    0
+}
+
+/// Disarm and unregister the probes on the code of module `name`, before
+/// its memory is freed.
+pub fn kprobes_module_unload(name: &str) {
+    let mut kprobes = KPROBES.inner.borrow_mut();
+    let addrs: Vec<usize> = kprobes
+        .values()
+        .filter(|probe| probe.module.as_deref() == Some(name))
+        .map(|probe| probe.addr)
+        .collect();
+    for addr in addrs {
+        if let Some(probe) = kprobes.remove(&addr) {
+            warn!("kprobes: unregister {:#x} as module {} unloads", addr, name);
+            probe.disarm();
+        }
+    }
+}
+
+pub fn kprobes_list() -> Vec<ProbeInfo> {
+    KPROBES.inner.borrow().values().map(|probe| ProbeInfo {
+        addr: probe.addr,
+        path: None,
+        probe_type: probe.probe_type.clone(),
+        nmissed: probe.nmissed.load(Ordering::Relaxed),
+    }).collect()
 }
\ No newline at end of file
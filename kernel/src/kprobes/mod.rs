@@ -1,11 +1,19 @@
+mod blacklist;
+mod error_inject;
 mod probes;
 mod kprobes;
 mod uprobes;
+#[cfg(feature = "kprobes_test")]
+mod test;
 // mod riscv_insn_decode;
 
 use alloc::sync::Arc;
-pub use kprobes::{kprobes_trap_handler, kprobe_register, kprobe_unregister};
-pub use uprobes::{uprobes_trap_handler, uprobe_register, uprobes_init};
-pub use probes::{ProbePlace, ProbeType};
+pub use blacklist::{kprobe_blacklist, kprobe_blacklisted};
+pub use error_inject::{error_injectable_list, injectable_error};
+pub use kprobes::{kprobes_trap_handler, kprobe_check, kprobe_register, kprobe_unregister, kprobes_list, kprobe_override_return, kprobes_module_unload};
+pub use uprobes::{uprobes_trap_handler, uprobe_register, uprobe_unregister, uprobes_init, uprobes_list};
+pub use probes::{ProbeInfo, ProbePlace, ProbeType};
+#[cfg(feature = "kprobes_test")]
+pub use test::run_tests;
 
 
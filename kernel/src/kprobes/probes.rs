@@ -7,30 +7,30 @@ use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::convert::TryInto;
 use core::slice::{from_raw_parts, from_raw_parts_mut};
-use riscv_insn_decode::get_insn_length;
-use trapframe::UserContext;
+use num::FromPrimitive;
+use riscv_insn_decode::{get_insn_length, insn_decode, InsnStatus};
+use trapframe::{GeneralRegs, UserContext};
 use super::kprobes::kprobe_register;
 use super::uprobes::uprobe_register;
 
 pub fn get_sp(addr: usize) -> Option<usize>{
-    let slot = unsafe { from_raw_parts(addr as *const u8, 4) };
     let mut addisp: usize= 0;
     match get_insn_length(addr) {
         4 => {
             // normal instruction
-            let inst = u32::from_le_bytes(slot[..4].try_into().unwrap());
+            let inst = read_insn(addr);
             if inst & 0b00000000000011111111111111111111 == 0b00000000000000010000000100010011 {
                 // addi sp, sp, imm
                 addisp = sext(((inst >> 20) & 0b111111111111) as isize, 12) as usize;
                 debug!("kprobes: hook on addi sp, sp, {}", addisp);
             } else {
-                warn!("kprobes: target instruction is not addi sp, sp, imm");
+                debug!("kprobes: target instruction is not addi sp, sp, imm");
                 return None;
             }
         }
         2 => {
             // compressed instruction
-            let inst = u16::from_le_bytes(slot[..2].try_into().unwrap());
+            let inst = read_insn(addr) as u16;
             if inst & 0b1110111110000011 == 0b0110000100000001 {
                 // c.addi16sp imm
                 addisp = sext(
@@ -49,17 +49,9 @@ pub fn get_sp(addr: usize) -> Option<usize>{
                     6,
                 ) as usize;
                 debug!("kprobes: hook on c.addi sp, {}", addisp as isize);
-            } else if  inst & 0b1110000000000011 == 0 {
-                // c.addi4spn
-                addisp = sext(((((inst >> 11) & 0b111) << 3)
-                    + (((inst >> 7) & 0b1111) << 5)
-                    + (((inst >> 6) & 0b1) << 1)
-                    + (((inst >> 5) & 0b1) << 2)) as isize,
-                    10
-                ) as usize;
-                // println!("kprobes: hook on c.addi4spn, {}", addisp);
             } else {
-                error!("kprobes: target instruction is not c.addi sp, imm or c.addi16sp imm or c.addi4spn imm");
+                // c.addi4spn writes rd' and leaves sp untouched, so it is single-stepped
+                debug!("kprobes: target instruction is not c.addi sp, imm or c.addi16sp imm");
                 return None;
             }
         }
@@ -68,18 +60,244 @@ pub fn get_sp(addr: usize) -> Option<usize>{
     Some(addisp)
 }
 
+/// How the first instruction of a probed function is replayed after the pre-handler.
+#[derive(Clone, Copy, Debug)]
+pub enum FuncEntry {
+    /// `addi sp, sp, imm` and its compressed forms: simulated by adjusting sp.
+    AdjustSp(usize),
+    /// pc-relative instructions (jumps, branches, auipc): emulated by `simulate_insn`.
+    Simulate,
+    /// Any other legal instruction: single-stepped out of line in the probe slot.
+    SingleStep,
+}
+
+/// Decide how to replay the instruction at the entry of a function.
+pub fn get_func_entry(addr: usize) -> Option<FuncEntry> {
+    if let Some(sp) = get_sp(addr) {
+        return Some(FuncEntry::AdjustSp(sp));
+    }
+    if is_pc_relative(addr) {
+        return Some(FuncEntry::Simulate);
+    }
+    match insn_decode(addr) {
+        InsnStatus::Legal => Some(FuncEntry::SingleStep),
+        _ => {
+            warn!("kprobes: instruction at {:#x} can not be replayed", addr);
+            None
+        }
+    }
+}
+
+/// Read the instruction at `addr`, its upper half only if it is not compressed,
+/// as a compressed instruction may end a mapping.
+fn read_insn(addr: usize) -> u32 {
+    let low = unsafe { from_raw_parts(addr as *const u8, 2) };
+    let low = u16::from_le_bytes(low.try_into().unwrap()) as u32;
+    if low & 0b11 != 0b11 {
+        return low;
+    }
+    let high = unsafe { from_raw_parts((addr + 2) as *const u8, 2) };
+    low | (u16::from_le_bytes(high.try_into().unwrap()) as u32) << 16
+}
+
+/// Whether the instruction at `addr` depends on its own pc and so can not run out of line.
+fn is_pc_relative(addr: usize) -> bool {
+    let inst = read_insn(addr);
+    match get_insn_length(addr) {
+        4 => match inst & 0x7f {
+            // jal, jalr, auipc, branch
+            0b1101111 | 0b1100111 | 0b0010111 | 0b1100011 => true,
+            _ => false,
+        },
+        2 => {
+            let funct3 = (inst >> 13) & 0b111;
+            match inst & 0b11 {
+                // c.j, c.beqz, c.bnez
+                0b01 if funct3 == 0b101 || funct3 == 0b110 || funct3 == 0b111 => true,
+                // c.jal
+                #[cfg(target_arch = "riscv32")]
+                0b01 if funct3 == 0b001 => true,
+                // c.jr, c.jalr
+                0b10 if funct3 == 0b100 => ((inst >> 7) & 0b11111) != 0 && ((inst >> 2) & 0b11111) == 0,
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+fn reg(regs: &mut GeneralRegs, idx: u32) -> &mut usize {
+    // `GeneralRegs` is laid out as x0..x31
+    unsafe { &mut (*(regs as *mut GeneralRegs as *mut [usize; 32]))[idx as usize] }
+}
+
+fn set_reg(regs: &mut GeneralRegs, idx: u32, value: usize) {
+    if idx != 0 {
+        *reg(regs, idx) = value;
+    }
+}
+
+fn cj_imm(inst: u32) -> isize {
+    sext(
+        ((((inst >> 12) & 0b1) << 11)
+            + (((inst >> 11) & 0b1) << 4)
+            + (((inst >> 9) & 0b11) << 8)
+            + (((inst >> 8) & 0b1) << 10)
+            + (((inst >> 7) & 0b1) << 6)
+            + (((inst >> 6) & 0b1) << 7)
+            + (((inst >> 3) & 0b111) << 1)
+            + (((inst >> 2) & 0b1) << 5)) as isize,
+        12,
+    )
+}
+
+/// Emulate the pc-relative instruction at `pc` on `regs`, returning the next pc.
+pub fn simulate_insn(pc: usize, regs: &mut GeneralRegs) -> Option<usize> {
+    let inst = read_insn(pc);
+    match get_insn_length(pc) {
+        4 => {
+            let rd = (inst >> 7) & 0b11111;
+            let rs1 = (inst >> 15) & 0b11111;
+            let rs2 = (inst >> 20) & 0b11111;
+            match inst & 0x7f {
+                0b1101111 => {
+                    // jal rd, imm
+                    let imm = sext(
+                        ((((inst >> 31) & 0b1) << 20)
+                            + (((inst >> 12) & 0b11111111) << 12)
+                            + (((inst >> 20) & 0b1) << 11)
+                            + (((inst >> 21) & 0b1111111111) << 1)) as isize,
+                        21,
+                    );
+                    set_reg(regs, rd, pc + 4);
+                    Some(pc.wrapping_add(imm as usize))
+                }
+                0b1100111 => {
+                    // jalr rd, imm(rs1)
+                    let imm = sext((inst >> 20) as isize, 12);
+                    let target = reg(regs, rs1).wrapping_add(imm as usize) & !1;
+                    set_reg(regs, rd, pc + 4);
+                    Some(target)
+                }
+                0b0010111 => {
+                    // auipc rd, imm
+                    let imm = sext((inst & 0xfffff000) as isize, 32);
+                    set_reg(regs, rd, pc.wrapping_add(imm as usize));
+                    Some(pc + 4)
+                }
+                0b1100011 => {
+                    // beq, bne, blt, bge, bltu, bgeu
+                    let imm = sext(
+                        ((((inst >> 31) & 0b1) << 12)
+                            + (((inst >> 7) & 0b1) << 11)
+                            + (((inst >> 25) & 0b111111) << 5)
+                            + (((inst >> 8) & 0b1111) << 1)) as isize,
+                        13,
+                    );
+                    let a = *reg(regs, rs1);
+                    let b = *reg(regs, rs2);
+                    let taken = match (inst >> 12) & 0b111 {
+                        0b000 => a == b,
+                        0b001 => a != b,
+                        0b100 => (a as isize) < (b as isize),
+                        0b101 => (a as isize) >= (b as isize),
+                        0b110 => a < b,
+                        0b111 => a >= b,
+                        _ => return None,
+                    };
+                    Some(if taken { pc.wrapping_add(imm as usize) } else { pc + 4 })
+                }
+                _ => None,
+            }
+        }
+        2 => {
+            let funct3 = (inst >> 13) & 0b111;
+            match (inst & 0b11, funct3) {
+                (0b01, 0b101) => {
+                    // c.j imm
+                    Some(pc.wrapping_add(cj_imm(inst) as usize))
+                }
+                #[cfg(target_arch = "riscv32")]
+                (0b01, 0b001) => {
+                    // c.jal imm
+                    set_reg(regs, 1, pc + 2);
+                    Some(pc.wrapping_add(cj_imm(inst) as usize))
+                }
+                (0b01, 0b110) | (0b01, 0b111) => {
+                    // c.beqz rs1', imm, c.bnez rs1', imm
+                    let imm = sext(
+                        ((((inst >> 12) & 0b1) << 8)
+                            + (((inst >> 10) & 0b11) << 3)
+                            + (((inst >> 5) & 0b11) << 6)
+                            + (((inst >> 3) & 0b11) << 1)
+                            + (((inst >> 2) & 0b1) << 5)) as isize,
+                        9,
+                    );
+                    let zero = *reg(regs, 8 + ((inst >> 7) & 0b111)) == 0;
+                    let taken = if funct3 == 0b110 { zero } else { !zero };
+                    Some(if taken { pc.wrapping_add(imm as usize) } else { pc + 2 })
+                }
+                (0b10, 0b100) => {
+                    // c.jr rs1, c.jalr rs1
+                    let target = *reg(regs, (inst >> 7) & 0b11111) & !1;
+                    if (inst >> 12) & 0b1 == 1 {
+                        set_reg(regs, 1, pc + 2);
+                    }
+                    Some(target)
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
 fn sext(x: isize, size: usize) -> isize {
     let shift = core::mem::size_of::<isize>() * 8 - size;
     (x << shift) >> shift
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum ProbePlace {
     Kernel(ProbeType),
     User(ProbeType),
 }
 
+/// As passed by user space: the place in the low byte, 0 for the kernel and
+/// 1 for user space, and the probe type in the high byte.
+impl FromPrimitive for ProbePlace {
+    fn from_i64(n: i64) -> Option<Self> {
+        if n < 0 {
+            return None;
+        }
+        Self::from_u64(n as u64)
+    }
+
+    fn from_u64(n: u64) -> Option<Self> {
+        if n > 0xffff {
+            return None;
+        }
+        let pt = ProbeType::from_u64(n >> 8)?;
+        match n & 0xff {
+            0 => Some(ProbePlace::Kernel(pt)),
+            1 => Some(ProbePlace::User(pt)),
+            _ => None,
+        }
+    }
+}
+
+/// An armed probe, as reported by introspection.
 #[derive(Clone, Debug)]
+pub struct ProbeInfo {
+    pub addr: usize,
+    /// executable of a user-space probe
+    pub path: Option<String>,
+    pub probe_type: ProbeType,
+    /// nested hits that were skipped
+    pub nmissed: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, FromPrimitive)]
 pub enum ProbeType {
     Insn,
     SyncFunc,
@@ -0,0 +1,64 @@
+//! Self tests for function probes, enabled by the `kprobes_test` feature.
+//!
+//! The probed functions are kept out of line so that their entry is a real
+//! function start: the leaf one has no stack frame, the non-leaf one spills `ra`.
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use trapframe::TrapFrame;
+use super::{kprobe_register, kprobe_unregister, ProbeType};
+
+static ENTRY_HITS: AtomicUsize = AtomicUsize::new(0);
+static RETURN_HITS: AtomicUsize = AtomicUsize::new(0);
+static RETURN_VALUE: AtomicUsize = AtomicUsize::new(0);
+
+#[inline(never)]
+fn leaf(a: usize, b: usize) -> usize {
+    a.wrapping_mul(3) ^ b
+}
+
+#[inline(never)]
+fn non_leaf(a: usize, b: usize) -> usize {
+    let mut sum = 0;
+    for i in 0..a {
+        sum += leaf(i, b);
+    }
+    sum
+}
+
+fn probe_func(name: &str, addr: usize, call: impl Fn() -> usize) {
+    ENTRY_HITS.store(0, Ordering::SeqCst);
+    RETURN_HITS.store(0, Ordering::SeqCst);
+    let expected = call();
+    let ret = kprobe_register(
+        addr,
+        Arc::new(Mutex::new(|_cx: &mut TrapFrame| {
+            ENTRY_HITS.fetch_add(1, Ordering::SeqCst);
+        })),
+        Some(Arc::new(Mutex::new(|cx: &mut TrapFrame| {
+            RETURN_HITS.fetch_add(1, Ordering::SeqCst);
+            RETURN_VALUE.store(cx.general.a0, Ordering::SeqCst);
+        }))),
+        ProbeType::SyncFunc,
+    );
+    assert_eq!(ret, 0, "kprobes test: register on {} failed", name);
+    assert_eq!(call(), expected, "kprobes test: {} returned a wrong value", name);
+    kprobe_unregister(addr);
+    assert!(ENTRY_HITS.load(Ordering::SeqCst) >= 1, "kprobes test: {} entry missed", name);
+    assert_eq!(
+        ENTRY_HITS.load(Ordering::SeqCst),
+        RETURN_HITS.load(Ordering::SeqCst),
+        "kprobes test: {} entry and return do not match",
+        name
+    );
+    println!("kprobes test: {} pass", name);
+}
+
+pub fn run_tests() {
+    probe_func("leaf", leaf as usize, || leaf(7, 5));
+    assert_eq!(RETURN_VALUE.load(Ordering::SeqCst), leaf(7, 5));
+    probe_func("non_leaf", non_leaf as usize, || non_leaf(4, 9));
+    assert_eq!(RETURN_VALUE.load(Ordering::SeqCst), non_leaf(4, 9));
+    println!("kprobes test: all pass");
+}
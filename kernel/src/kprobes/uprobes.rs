@@ -13,9 +13,10 @@ use lazy_static::*;
 use rcore_memory::memory_set::MemoryAttr;
 use rcore_memory::memory_set::handler::{Delay, ByFrame};
 use rcore_memory::paging::PageTable;
+use rcore_memory::PAGE_SIZE;
 use riscv_insn_decode::{insn_decode, InsnStatus, get_insn_length};
-use super::probes::{get_sp, ProbeType};
-use crate::memory::{AccessType, handle_page_fault_ext, GlobalFrameAlloc};
+use super::probes::{get_func_entry, simulate_insn, FuncEntry, ProbeInfo, ProbeType};
+use crate::memory::{AccessType, handle_page_fault_ext, unshare_page, GlobalFrameAlloc};
 use crate::process::current_thread;
 use trapframe::UserContext;
 
@@ -42,13 +43,16 @@ pub struct UprobesInner {
     pub addr: usize,
     pub length: usize,
     pub slot_addr: usize,
-    pub addisp: usize,
+    pub func_entry: FuncEntry,
     pub func_ra: Vec<usize>,
     pub func_ebreak_addr: usize,
     pub insn_ebreak_addr: usize,
     pub handler: Arc<Mutex<dyn FnMut(&mut UserContext) + Send>>,
     pub post_handler: Option<Arc<Mutex<dyn FnMut(&mut UserContext) + Send>>>,
     pub probe_type: ProbeType,
+    /// Cleared when the probe is unregistered. The probed instruction is
+    /// still replayed, as processes running the binary keep it armed.
+    pub enabled: bool,
 }
 
 
@@ -67,7 +71,7 @@ lazy_static! {
 }
 
 #[naked]
-extern "C" fn __ebreak() {
+pub(super) extern "C" fn __ebreak() {
     unsafe {
         asm!("c.ebreak", "c.ebreak");
     }
@@ -97,6 +101,16 @@ impl CurrentProcessUprobes{
     ) -> isize {
         let mut uprobes_inner = self.inner.borrow_mut();
         if let Some(inner) = uprobes_inner.get_mut(&path.clone()){
+            // a probe unregistered before is armed already, enable it again
+            if let Some(probe) = inner.uprobes.inner.borrow_mut().get_mut(&addr) {
+                if probe.enabled || probe.probe_type != probe_type {
+                    return -1;
+                }
+                probe.handler = handler;
+                probe.post_handler = post_handler;
+                probe.enabled = true;
+                return 0;
+            }
             inner.uprobes.register_uprobe(addr, handler, post_handler, probe_type);
         }
         else{
@@ -113,27 +127,53 @@ impl CurrentProcessUprobes{
         info!("uprobes: path={}", get_exec_path());
         if path == get_exec_path(){
             info!("uprobes: path=execpath");
-            uprobes_inner.get_mut(&path.clone()).unwrap().uprobes.inner.borrow_mut().get_mut(&addr).unwrap().add_uprobepoint();
+            let mut uprobes = uprobes_inner.get_mut(&path.clone()).unwrap().uprobes.inner.borrow_mut();
+            if !uprobes.get_mut(&addr).unwrap().add_uprobepoint() {
+                uprobes.remove(&addr);
+                return -1;
+            }
             info!("uprobes: path=execpath, add sucess");
         }
         0
     }
 
-    fn uprobes_trap_handler(&self, cx: &mut UserContext){
+    fn unregister_uprobes(&self, path: &str, addr: usize) -> isize {
+        let uprobes_inner = self.inner.borrow();
+        let inner = match uprobes_inner.get(path) {
+            Some(inner) => inner,
+            None => return -1,
+        };
+        let mut uprobes = inner.uprobes.inner.borrow_mut();
+        match uprobes.get_mut(&addr) {
+            Some(probe) if probe.enabled => {
+                probe.enabled = false;
+                0
+            }
+            _ => -1,
+        }
+    }
+
+    /// Return false if no probe of the binary is at the breakpoint.
+    fn uprobes_trap_handler(&self, cx: &mut UserContext) -> bool {
         let path = get_exec_path();
-        let mut uprobes_inner = self.inner.borrow_mut();
-        let mut uprobes = uprobes_inner.get(&path.clone()).unwrap().uprobes.inner.borrow_mut();
-        let mut current_uprobes = uprobes_inner.get(&path.clone()).unwrap().current_uprobes.inner.borrow_mut();
+        let uprobes_inner = self.inner.borrow();
+        let inner = match uprobes_inner.get(&path) {
+            Some(inner) => inner,
+            None => return false,
+        };
+        let mut uprobes = inner.uprobes.inner.borrow_mut();
+        let mut current_uprobes = inner.current_uprobes.inner.borrow_mut();
         match uprobes.get_mut(&cx.sepc) {
             Some(probe) => {
                 // run user defined handler
-                (probe.handler.lock())(cx);
+                if probe.enabled {
+                    (probe.handler.lock())(cx);
+                }
                 // single step the probed instruction
                 match probe.probe_type{
                     ProbeType::SyncFunc =>{
-                        cx.general.sp = cx.general.sp.wrapping_add(probe.addisp);
-                        cx.sepc = cx.sepc.wrapping_add(probe.length);
-                        if let Some(_) = probe.post_handler{
+                        // capture the return address before the first instruction can spill it
+                        if probe.enabled && probe.post_handler.is_some() {
                             if !current_uprobes.contains_key(&probe.func_ebreak_addr){
                                 current_uprobes.insert(probe.func_ebreak_addr, probe.clone());
                             }
@@ -141,6 +181,21 @@ impl CurrentProcessUprobes{
                             current_uprobe.func_ra.push(cx.general.ra);
                             cx.general.ra = probe.func_ebreak_addr as usize;
                         }
+                        match probe.func_entry {
+                            FuncEntry::AdjustSp(addisp) => {
+                                cx.general.sp = cx.general.sp.wrapping_add(addisp);
+                                cx.sepc = cx.sepc.wrapping_add(probe.length);
+                            }
+                            FuncEntry::Simulate => {
+                                cx.sepc = simulate_insn(probe.addr, &mut cx.general).unwrap();
+                            }
+                            FuncEntry::SingleStep => {
+                                cx.sepc = probe.slot_addr;
+                                if !current_uprobes.contains_key(&probe.insn_ebreak_addr){
+                                    current_uprobes.insert(probe.insn_ebreak_addr, probe.clone());
+                                }
+                            }
+                        }
                     },
                     ProbeType::Insn =>{
                         cx.sepc = probe.slot_addr as usize;
@@ -158,8 +213,11 @@ impl CurrentProcessUprobes{
                 match current_uprobes.get_mut(&cx.sepc){
                     Some(probe) =>{
                         if probe.insn_ebreak_addr == cx.sepc{
-                            if let Some(post_handler) = &probe.post_handler{
-                                (post_handler.lock())(cx);
+                            // function probes only report on return, not after the first instruction
+                            if let (ProbeType::Insn, true) = (&probe.probe_type, probe.enabled) {
+                                if let Some(post_handler) = &probe.post_handler{
+                                    (post_handler.lock())(cx);
+                                }
                             }
                             let sepc = probe.addr + probe.length;
                             current_uprobes.remove(&cx.sepc);
@@ -167,16 +225,18 @@ impl CurrentProcessUprobes{
                         }
                         else{
                             (probe.post_handler.as_ref().unwrap().lock())(cx);
-                            cx.sepc = probe.func_ra.pop().unwrap();
+                            let sepc = probe.func_ra.pop().unwrap();
                             if probe.func_ra.len() == 0{
                                 current_uprobes.remove(&cx.sepc);
                             }
+                            cx.sepc = sepc;
                         }
                     }
-                    _ => {}
+                    None => return false,
                 }
             }
         }
+        true
     }
 }
 
@@ -199,58 +259,71 @@ impl UprobesInner {
             addr,
             length: 0,
             slot_addr: 0,
-            addisp: 0,
+            func_entry: FuncEntry::SingleStep,
             func_ra: Vec::new(),
             func_ebreak_addr: 0,
             insn_ebreak_addr: 0,
             handler,
             post_handler,
             probe_type,
+            enabled: true,
         })
     }
 
-    fn add_uprobepoint(&mut self){
-        // get free point in user stack
+    /// Arm the probe, or return false if the probed instruction can not be replayed.
+    fn add_uprobepoint(&mut self) -> bool {
         let addr = self.addr;
+        // refuse an instruction which can neither be simulated nor single-stepped
+        let func_entry = match self.probe_type {
+            ProbeType::Insn => match insn_decode(addr) {
+                InsnStatus::Legal => FuncEntry::SingleStep,
+                _ => {
+                    warn!("uprobes: instruction is not legal");
+                    return false;
+                }
+            },
+            ProbeType::SyncFunc => match get_func_entry(addr) {
+                Some(entry) => entry,
+                None => {
+                    error!("uprobes: function entry can not be replayed");
+                    return false;
+                }
+            },
+            ProbeType::AsyncFunc => {
+                error!("not implemented yet!");
+                return false;
+            }
+        };
+        self.func_entry = func_entry;
+
+        // read the lowest byte of the probed instruction to determine whether it is compressed
+        let length = get_insn_length(addr);
+        self.length = length;
+        if !set_writeable(addr, length) {
+            error!("uprobes: probed instruction can not be made writable");
+            return false;
+        }
+
+        // get free point in user stack
         self.func_ebreak_addr = get_new_page(addr, 2);
         self.slot_addr = get_new_page(addr, 6);
         let mut slot = unsafe { from_raw_parts_mut(self.slot_addr as *mut u8, 6)};
-        set_writeable(addr);
 
         let inst = unsafe { from_raw_parts(addr as *const u8, 2) };
-        // read the lowest byte of the probed instruction to determine whether it is compressed
-        let length = get_insn_length(addr);
-        self.length = length;
         // save the probed instruction to a buffer
         slot[..length].copy_from_slice(&inst[..length]);
 
-        // decode the probed instruction to retrive imm
         let ebreak = unsafe { from_raw_parts(__ebreak as *const u8, 2) };
-
-        match self.probe_type{
-            ProbeType::Insn =>{
-                match insn_decode(addr){
-                    InsnStatus::Legal =>{
-                        slot[length..length+2].copy_from_slice(ebreak);
-                        self.insn_ebreak_addr = self.slot_addr + length;
-                    },
-                    _ => {warn!("uprobes: instruction is not legal");},
-                }
-            }
-            ProbeType::SyncFunc =>{
-                let mut ebreak_ptr = unsafe { from_raw_parts_mut(self.func_ebreak_addr as *mut u8, 2)};
-                ebreak_ptr.copy_from_slice(ebreak);
-
-                match get_sp(addr){
-                    Some(sp) => self.addisp = sp,
-                    None => {error!("sp not found!");}
-                }
-            }
-            ProbeType::AsyncFunc =>{
-                error!("not implemented yet!");
-            }
+        if let ProbeType::SyncFunc = self.probe_type {
+            let mut ebreak_ptr = unsafe { from_raw_parts_mut(self.func_ebreak_addr as *mut u8, 2)};
+            ebreak_ptr.copy_from_slice(ebreak);
+        }
+        if let FuncEntry::SingleStep = self.func_entry {
+            slot[length..length+2].copy_from_slice(ebreak);
+            self.insn_ebreak_addr = self.slot_addr + length;
         }
-        self.arm()
+        self.arm();
+        true
     }
 
     pub fn arm(&self) {
@@ -299,9 +372,8 @@ impl Uprobes {
 
     fn add_uprobepoint(&self){
         let mut uproebs = self.inner.borrow_mut();
-        for inner in uproebs.values_mut(){
-            inner.add_uprobepoint();
-        }
+        // probes which can not be armed in this binary are dropped
+        uproebs.retain(|_, inner| inner.add_uprobepoint());
     }
 }
 
@@ -321,12 +393,23 @@ fn get_new_page(addr: usize, len: usize) -> usize{
     ebreak_addr
 }
 
-fn set_writeable(addr: usize){
+/// Make the pages of the `len` bytes at `addr` writable to patch them.
+/// A page shared copy-on-write gets a frame of its own first, so that the patch
+/// never reaches the other processes mapping it.
+fn set_writeable(addr: usize, len: usize) -> bool {
     let thread = current_thread().unwrap();
     let mut vm = thread.vm.lock();
-    let mut page_table_entry = vm.get_page_table_mut().get_entry(addr).unwrap();
-    page_table_entry.set_writable(true);
+    let page_table = vm.get_page_table_mut();
+    for page in (addr & !(PAGE_SIZE - 1)..addr + len).step_by(PAGE_SIZE) {
+        if !unshare_page(&GlobalFrameAlloc, page_table, page) {
+            return false;
+        }
+        let mut page_table_entry = page_table.get_entry(page).unwrap();
+        page_table_entry.set_writable(true);
+        page_table_entry.update();
+    }
     unsafe {asm!("fence.i");}
+    true
 }
 
 fn get_exec_path() -> String{
@@ -346,9 +429,32 @@ pub fn uprobe_register(
     CURRENT_PROCESS_UPROBES.register_uprobes(path ,addr, handler, post_handler, probe_type)
 }
 
-pub fn uprobes_trap_handler(cx: &mut UserContext) {
+/// Stop running the handlers of the probe at `addr` of the binary at `path`.
+pub fn uprobe_unregister(path: &str, addr: usize) -> isize {
+    CURRENT_PROCESS_UPROBES.unregister_uprobes(path, addr)
+}
+
+/// Handle a breakpoint of a user probe.
+/// Return false if it is not one, to be reported as SIGTRAP.
+pub fn uprobes_trap_handler(cx: &mut UserContext) -> bool {
     info!("uprobes: into uprobes trap handler");
-    CURRENT_PROCESS_UPROBES.uprobes_trap_handler(cx);
+    CURRENT_PROCESS_UPROBES.uprobes_trap_handler(cx)
+}
+
+pub fn uprobes_list() -> Vec<ProbeInfo> {
+    let mut list = Vec::new();
+    for (path, inner) in CURRENT_PROCESS_UPROBES.inner.borrow().iter() {
+        for probe in inner.uprobes.inner.borrow().values().filter(|probe| probe.enabled) {
+            list.push(ProbeInfo {
+                addr: probe.addr,
+                path: Some(path.clone()),
+                probe_type: probe.probe_type.clone(),
+                // user code cannot run inside a handler
+                nmissed: 0,
+            });
+        }
+    }
+    list
 }
 
 pub fn uprobes_init(){
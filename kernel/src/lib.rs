@@ -56,8 +56,10 @@ pub mod process;
 pub mod kprobes;
 #[cfg(feature = "hypervisor")]
 pub mod rvm;
+pub mod sched;
 pub mod shell;
 pub mod signal;
+pub mod swap;
 pub mod sync;
 pub mod syscall;
 pub mod trap;
@@ -84,7 +86,7 @@ pub mod arch;
 
 pub fn kmain() -> ! {
     loop {
-        executor::run_until_idle();
+        sched::run_until_idle();
         arch::interrupt::wait_for_interrupt();
     }
 }
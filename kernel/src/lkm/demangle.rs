@@ -0,0 +1,414 @@
+//! Demangling of Rust symbols, for matching kernel symbols by their paths.
+//!
+//! The kernel is built with the v0 mangling scheme (`_R...`), and legacy
+//! symbols (`_ZN...`) may come from modules. Both are turned into paths
+//! like `<rcore::sync::mutex::SpinNoIrq as rcore::sync::mutex::MutexSupport>::before_lock`
+//! without hashes or crate disambiguators. Lifetimes are shown as `'_`,
+//! and const generic values other than integers, bools and chars as `_`.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+/// Nesting deeper than this is taken as a malformed symbol.
+const MAX_DEPTH: usize = 64;
+
+/// The path of a mangled symbol, or `None` if it is not a Rust symbol.
+pub fn demangle(symbol: &str) -> Option<String> {
+    // vendor specific suffixes, like `.llvm.1234`
+    let symbol = symbol.split('.').next()?;
+    if let Some(rest) = symbol.strip_prefix("_R").or_else(|| symbol.strip_prefix("__R")) {
+        // an optional encoding version
+        let rest = rest.trim_start_matches(|c: char| c.is_ascii_digit());
+        let mut parser = V0Parser {
+            sym: rest.as_bytes(),
+            pos: 0,
+            depth: 0,
+        };
+        return parser.path(true);
+    }
+    let rest = symbol
+        .strip_prefix("_ZN")
+        .or_else(|| symbol.strip_prefix("__ZN"))?;
+    demangle_legacy(rest)
+}
+
+fn demangle_legacy(mut rest: &str) -> Option<String> {
+    let mut path: Vec<&str> = Vec::new();
+    while !rest.starts_with('E') {
+        let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+        let len: usize = rest[..digits].parse().ok()?;
+        let component = rest.get(digits..digits + len)?;
+        // a component starting with an escape has an `_` prepended
+        path.push(match component.strip_prefix('_') {
+            Some(escaped) if escaped.starts_with('$') => escaped,
+            _ => component,
+        });
+        rest = &rest[digits + len..];
+    }
+    if let Some(hash) = path.last() {
+        if hash.len() == 17 && hash.starts_with('h') {
+            path.pop();
+        }
+    }
+    let mut demangled = path.join("::").replace("..", "::");
+    for &(escape, c) in &[
+        ("$LT$", "<"),
+        ("$GT$", ">"),
+        ("$RF$", "&"),
+        ("$BP$", "*"),
+        ("$C$", ","),
+        ("$LP$", "("),
+        ("$RP$", ")"),
+        ("$SP$", "@"),
+        ("$u20$", " "),
+        ("$u27$", "'"),
+        ("$u5b$", "["),
+        ("$u5d$", "]"),
+        ("$u7b$", "{"),
+        ("$u7d$", "}"),
+        ("$u7e$", "~"),
+    ] {
+        demangled = demangled.replace(escape, c);
+    }
+    Some(demangled)
+}
+
+struct V0Parser<'a> {
+    sym: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a> V0Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.sym.get(self.pos).cloned()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// `_` for 0, or a base 62 number and `_` for that number plus one
+    fn base62(&mut self) -> Option<u64> {
+        if self.eat(b'_') {
+            return Some(0);
+        }
+        let mut x: u64 = 0;
+        loop {
+            let c = self.next()?;
+            let digit = match c {
+                b'0'..=b'9' => c - b'0',
+                b'a'..=b'z' => c - b'a' + 10,
+                b'A'..=b'Z' => c - b'A' + 36,
+                b'_' => return x.checked_add(1),
+                _ => return None,
+            };
+            x = x.checked_mul(62)?.checked_add(digit as u64)?;
+        }
+    }
+
+    /// A number after `tag`, 0 without the tag.
+    fn opt_integer62(&mut self, tag: u8) -> Option<u64> {
+        if !self.eat(tag) {
+            return Some(0);
+        }
+        self.base62()?.checked_add(1)
+    }
+
+    fn disambiguator(&mut self) -> Option<u64> {
+        self.opt_integer62(b's')
+    }
+
+    fn ident(&mut self) -> Option<&'a str> {
+        let punycode = self.eat(b'u');
+        let start = self.pos;
+        // a leading zero is the whole number
+        if !self.eat(b'0') {
+            while self.peek().map_or(false, |c| c.is_ascii_digit()) {
+                self.pos += 1;
+            }
+        }
+        let len: usize = core::str::from_utf8(&self.sym[start..self.pos])
+            .ok()?
+            .parse()
+            .ok()?;
+        self.eat(b'_');
+        let ident = self.sym.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        // punycode identifiers are left encoded
+        let _ = punycode;
+        core::str::from_utf8(ident).ok()
+    }
+
+    /// Parse what a backref points to with `f`.
+    fn backref<T>(&mut self, f: impl FnOnce(&mut Self) -> Option<T>) -> Option<T> {
+        let start = self.pos - 1;
+        let target = self.base62()? as usize;
+        if target >= start {
+            return None;
+        }
+        let pos = self.pos;
+        self.pos = target;
+        let ret = f(self);
+        self.pos = pos;
+        ret
+    }
+
+    fn enter(&mut self) -> Option<()> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            None
+        } else {
+            Some(())
+        }
+    }
+
+    /// Generic arguments are written `::<>` in a value path and `<>` in a type.
+    fn path(&mut self, in_value: bool) -> Option<String> {
+        self.enter()?;
+        let path = self.path_inner(in_value);
+        self.depth -= 1;
+        path
+    }
+
+    fn path_inner(&mut self, in_value: bool) -> Option<String> {
+        match self.next()? {
+            b'C' => {
+                self.disambiguator()?;
+                Some(String::from(self.ident()?))
+            }
+            b'M' => {
+                self.disambiguator()?;
+                self.path(false)?;
+                Some(format!("<{}>", self.ty()?))
+            }
+            b'X' => {
+                self.disambiguator()?;
+                self.path(false)?;
+                let ty = self.ty()?;
+                Some(format!("<{} as {}>", ty, self.path(false)?))
+            }
+            b'Y' => {
+                let ty = self.ty()?;
+                Some(format!("<{} as {}>", ty, self.path(false)?))
+            }
+            b'N' => {
+                let ns = self.next()?;
+                let parent = self.path(in_value)?;
+                let dis = self.disambiguator()?;
+                let name = self.ident()?;
+                Some(match ns {
+                    b'a'..=b'z' if name.is_empty() => parent,
+                    b'a'..=b'z' => format!("{}::{}", parent, name),
+                    b'C' => format!("{}::{{closure#{}}}", parent, dis),
+                    b'S' => format!("{}::{{shim:{}#{}}}", parent, name, dis),
+                    ns if name.is_empty() => format!("{}::{{{}#{}}}", parent, ns as char, dis),
+                    ns => format!("{}::{{{}:{}#{}}}", parent, ns as char, name, dis),
+                })
+            }
+            b'I' => {
+                let path = self.path(in_value)?;
+                let args = self.generic_args()?;
+                let sep = if in_value { "::" } else { "" };
+                Some(format!("{}{}<{}>", path, sep, args))
+            }
+            b'B' => self.backref(|parser| parser.path(in_value)),
+            _ => None,
+        }
+    }
+
+    fn generic_args(&mut self) -> Option<String> {
+        let mut args = Vec::new();
+        while !self.eat(b'E') {
+            if self.eat(b'L') {
+                self.base62()?;
+                args.push(String::from("'_"));
+            } else if self.eat(b'K') {
+                args.push(self.konst()?);
+            } else {
+                args.push(self.ty()?);
+            }
+        }
+        Some(args.join(", "))
+    }
+
+    fn basic_type(c: u8) -> Option<&'static str> {
+        Some(match c {
+            b'a' => "i8",
+            b'b' => "bool",
+            b'c' => "char",
+            b'd' => "f64",
+            b'e' => "str",
+            b'f' => "f32",
+            b'h' => "u8",
+            b'i' => "isize",
+            b'j' => "usize",
+            b'l' => "i32",
+            b'm' => "u32",
+            b'n' => "i128",
+            b'o' => "u128",
+            b's' => "i16",
+            b't' => "u16",
+            b'u' => "()",
+            b'v' => "...",
+            b'x' => "i64",
+            b'y' => "u64",
+            b'z' => "!",
+            b'p' => "_",
+            _ => return None,
+        })
+    }
+
+    fn ty(&mut self) -> Option<String> {
+        self.enter()?;
+        let ty = self.ty_inner();
+        self.depth -= 1;
+        ty
+    }
+
+    fn ty_inner(&mut self) -> Option<String> {
+        let c = self.next()?;
+        if let Some(basic) = Self::basic_type(c) {
+            return Some(String::from(basic));
+        }
+        match c {
+            b'A' => {
+                let ty = self.ty()?;
+                Some(format!("[{}; {}]", ty, self.konst()?))
+            }
+            b'S' => Some(format!("[{}]", self.ty()?)),
+            b'T' => {
+                let mut tys = Vec::new();
+                while !self.eat(b'E') {
+                    tys.push(self.ty()?);
+                }
+                if tys.len() == 1 {
+                    Some(format!("({},)", tys[0]))
+                } else {
+                    Some(format!("({})", tys.join(", ")))
+                }
+            }
+            b'R' | b'Q' => {
+                if self.eat(b'L') {
+                    self.base62()?;
+                }
+                let mutable = if c == b'Q' { "mut " } else { "" };
+                Some(format!("&{}{}", mutable, self.ty()?))
+            }
+            b'P' => Some(format!("*const {}", self.ty()?)),
+            b'O' => Some(format!("*mut {}", self.ty()?)),
+            b'F' => self.fn_sig(),
+            b'D' => {
+                self.binder()?;
+                let mut traits = Vec::new();
+                while !self.eat(b'E') {
+                    traits.push(self.dyn_trait()?);
+                }
+                // the lifetime of the trait object
+                if !self.eat(b'L') {
+                    return None;
+                }
+                self.base62()?;
+                Some(format!("dyn {}", traits.join(" + ")))
+            }
+            b'B' => self.backref(Self::ty),
+            _ => {
+                self.pos -= 1;
+                self.path(false)
+            }
+        }
+    }
+
+    fn binder(&mut self) -> Option<()> {
+        self.opt_integer62(b'G').map(|_| ())
+    }
+
+    fn fn_sig(&mut self) -> Option<String> {
+        self.binder()?;
+        let mut sig = String::new();
+        if self.eat(b'U') {
+            sig.push_str("unsafe ");
+        }
+        if self.eat(b'K') {
+            if self.eat(b'C') {
+                sig.push_str("extern \"C\" ");
+            } else {
+                let abi = self.ident()?;
+                write!(sig, "extern \"{}\" ", abi.replace('_', "-")).ok()?;
+            }
+        }
+        let mut args = Vec::new();
+        while !self.eat(b'E') {
+            args.push(self.ty()?);
+        }
+        let ret = self.ty()?;
+        write!(sig, "fn({})", args.join(", ")).ok()?;
+        if ret != "()" {
+            write!(sig, " -> {}", ret).ok()?;
+        }
+        Some(sig)
+    }
+
+    fn dyn_trait(&mut self) -> Option<String> {
+        let mut path = self.path(false)?;
+        let mut bindings = Vec::new();
+        while self.eat(b'p') {
+            let name = self.ident()?;
+            bindings.push(format!("{} = {}", name, self.ty()?));
+        }
+        if !bindings.is_empty() {
+            // bindings go with the generic arguments of the trait
+            if path.ends_with('>') {
+                path.pop();
+                write!(path, ", {}>", bindings.join(", ")).ok()?;
+            } else {
+                write!(path, "<{}>", bindings.join(", ")).ok()?;
+            }
+        }
+        Some(path)
+    }
+
+    fn konst(&mut self) -> Option<String> {
+        if self.eat(b'B') {
+            return self.backref(Self::konst);
+        }
+        let ty = self.next()?;
+        if ty == b'p' {
+            return Some(String::from("_"));
+        }
+        let negative = self.eat(b'n');
+        let start = self.pos;
+        while self.peek().map_or(false, |c| c.is_ascii_hexdigit()) {
+            self.pos += 1;
+        }
+        let hex = core::str::from_utf8(&self.sym[start..self.pos]).ok()?;
+        if !self.eat(b'_') {
+            return None;
+        }
+        let value = if hex.is_empty() {
+            0
+        } else {
+            u64::from_str_radix(hex, 16).ok()?
+        };
+        match ty {
+            b'b' => Some(String::from(if value != 0 { "true" } else { "false" })),
+            b'c' => Some(format!("{:?}", core::char::from_u32(value as u32)?)),
+            b'a' | b'h' | b'i' | b'j' | b'l' | b'm' | b'n' | b'o' | b's' | b't' | b'x' | b'y' => {
+                Some(format!("{}{}", if negative { "-" } else { "" }, value))
+            }
+            _ => Some(String::from("_")),
+        }
+    }
+}
@@ -1,11 +1,15 @@
 use super::api::*;
 use super::const_reloc as loader;
+use super::demangle::demangle;
 use super::kernelvm::*;
 use super::structs::*;
+use crate::ebpf::ebpf::EBPF;
+use crate::kprobes::kprobes_module_unload;
 use crate::lkm::structs::ModuleState::{Ready, Unloading};
 use crate::sync::SpinLock as Mutex;
 use crate::syscall::SysError::*;
 use crate::syscall::SysResult;
+use crate::util::glob_match;
 use alloc::boxed::Box;
 use alloc::collections::btree_map::BTreeMap;
 use alloc::string::*;
@@ -25,6 +29,11 @@ use xmas_elf::{header, ElfFile};
 // The symbol data table.
 global_asm!(include_str!("symbol_table.asm"));
 
+extern "C" {
+    fn stext();
+    fn etext();
+}
+
 /// Module Manager is the core part of LKM.
 /// It does these jobs: Load preset(API) symbols; manage module loading dependency and linking modules.
 pub struct ModuleManager {
@@ -35,6 +44,33 @@ pub struct ModuleManager {
 
 lazy_static! {
     pub static ref LKM_MANAGER: Mutex<Option<ModuleManager>> = Mutex::new(None);
+
+    /// Memory of the loaded modules, apart from LKM_MANAGER as that is held
+    /// while module init and cleanup code runs, which may register probes.
+    static ref MODULE_SPANS: Mutex<Vec<ModuleSpan>> = Mutex::new(Vec::new());
+}
+
+struct ModuleSpan {
+    name: String,
+    start: usize,
+    end: usize,
+    init_ranges: Vec<(usize, usize)>,
+}
+
+/// The module whose memory contains `addr`, by name, and whether `addr` is
+/// in code only run while the module loads.
+pub fn module_at(addr: usize) -> Option<(String, bool)> {
+    MODULE_SPANS
+        .lock()
+        .iter()
+        .find(|span| span.start <= addr && addr < span.end)
+        .map(|span| {
+            let in_init = span
+                .init_ranges
+                .iter()
+                .any(|&(start, end)| start <= addr && addr < end);
+            (span.name.clone(), in_init)
+        })
 }
 
 macro_rules! export_stub {
@@ -324,9 +360,16 @@ impl ModuleManager {
                 used_counts: 0,
                 using_counts: Arc::new(ModuleRef {}),
                 vspace: vspace,
+                init_ranges: Vec::new(),
                 lock: Mutex::new(()),
                 state: Ready,
             });
+            for sh in elf.section_iter() {
+                if sh.get_name(&elf).map_or(false, |name| name.starts_with(".init")) {
+                    let start = base + sh.address() as usize;
+                    loaded_minfo.init_ranges.push((start, start + sh.size() as usize));
+                }
+            }
             info!(
                 "[LKM] module load done at {:#x}, now need to do the relocation job.",
                 base
@@ -425,6 +468,9 @@ impl ModuleManager {
 
                             if exported == "init_module" {
                                 lkm_entry = base + (sym.value() as usize);
+                                loaded_minfo
+                                    .init_ranges
+                                    .push((lkm_entry, lkm_entry + sym.size() as usize));
                             } else {
                                 loaded_minfo.exported_symbols.push(exported_symbol);
                             }
@@ -432,6 +478,12 @@ impl ModuleManager {
                     }
                 }
                 // Now everything is done, and the entry can be safely plugged into the vector.
+                MODULE_SPANS.lock().push(ModuleSpan {
+                    name: loaded_minfo.info.name.clone(),
+                    start: loaded_minfo.vspace.start(),
+                    end: loaded_minfo.vspace.start() + loaded_minfo.vspace.size(),
+                    init_ranges: loaded_minfo.init_ranges.clone(),
+                });
                 self.loaded_modules.push(loaded_minfo);
                 if lkm_entry > 0 {
                     info!("[LKM] calling init_module at {:#x}", lkm_entry);
@@ -588,6 +640,14 @@ impl ModuleManager {
                 }
                 drop(mod_lock);
 
+                // probes on the module would write into its memory once freed
+                let (start, end) = (
+                    current_module.vspace.start(),
+                    current_module.vspace.start() + current_module.vspace.size(),
+                );
+                EBPF.module_unload(name, start, end);
+                kprobes_module_unload(name);
+                MODULE_SPANS.lock().retain(|span| span.name != name);
                 let _my_box = self.loaded_modules.remove(i);
                 unsafe {
                     LKM_MANAGER.force_unlock();
@@ -626,4 +686,45 @@ impl ModuleManager {
     pub fn get_kernel_symbols(&self) -> &Vec<(String, usize)> {
         return &self.kernel_symbols;
     }
+    /// Functions of the kernel and exported by modules whose name or
+    /// demangled path matches the glob `pattern`.
+    pub fn match_symbols(&self, pattern: &str) -> Vec<(String, usize)> {
+        let text = stext as usize..etext as usize;
+        self.kernel_symbols
+            .iter()
+            .filter(|(_, loc)| text.contains(loc))
+            .map(|(name, loc)| (name.as_str(), *loc))
+            .chain(self.loaded_modules.iter().flat_map(|module| {
+                module
+                    .exported_symbols
+                    .iter()
+                    .map(|sym| (sym.name.as_str(), sym.loc))
+            }))
+            .filter(|(name, _)| {
+                glob_match(pattern, name)
+                    || demangle(name).map_or(false, |path| glob_match(pattern, &path))
+            })
+            .map(|(name, loc)| (String::from(name), loc))
+            .collect()
+    }
+    /// Find the symbol containing `addr`, as its name and the offset into it.
+    pub fn lookup_address(&self, addr: usize) -> Option<(String, usize)> {
+        let mut best: Option<(&str, usize)> = None;
+        let symbols = self
+            .kernel_symbols
+            .iter()
+            .map(|(name, loc)| (name.as_str(), *loc))
+            .chain(self.loaded_modules.iter().flat_map(|module| {
+                module
+                    .exported_symbols
+                    .iter()
+                    .map(|sym| (sym.name.as_str(), sym.loc))
+            }));
+        for (name, loc) in symbols {
+            if loc <= addr && best.map_or(true, |(_, best_loc)| loc > best_loc) {
+                best = Some((name, loc));
+            }
+        }
+        best.map(|(name, loc)| (String::from(name), addr - loc))
+    }
 }
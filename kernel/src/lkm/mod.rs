@@ -1,5 +1,6 @@
 pub mod api;
 pub mod const_reloc;
+pub mod demangle;
 pub mod kernelvm;
 pub mod manager;
 pub mod structs;
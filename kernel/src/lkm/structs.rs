@@ -88,6 +88,8 @@ pub struct LoadedModule {
     pub used_counts: i32,
     pub using_counts: Arc<ModuleRef>,
     pub vspace: VirtualSpace,
+    /// code only run while the module loads, as (start, end)
+    pub init_ranges: Vec<(usize, usize)>,
     pub lock: Mutex<()>,
     pub state: ModuleState,
 }
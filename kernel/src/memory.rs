@@ -64,15 +64,19 @@ pub const fn kernel_offset(vaddr: usize) -> usize {
 pub struct GlobalFrameAlloc;
 
 impl FrameAllocator for GlobalFrameAlloc {
+    // kept out of line, so that it can be probed
+    #[inline(never)]
     fn alloc(&self) -> Option<usize> {
         // get the real address of the alloc frame
-        let ret = FRAME_ALLOCATOR
+        let frame = FRAME_ALLOCATOR
             .lock()
             .alloc()
             .map(|id| id * PAGE_SIZE + MEMORY_OFFSET);
+        // swap out a page for its frame when running out, with the allocator
+        // unlocked, as writing the page out may allocate
+        let ret = frame.or_else(crate::swap::reclaim);
         trace!("Allocate frame: {:x?}", ret);
         ret
-        // TODO: try to swap out when alloc failed
     }
     fn alloc_contiguous(&self, size: usize, align_log2: usize) -> Option<PhysAddr> {
         // get the real address of the alloc frame
@@ -0,0 +1,78 @@
+//! Socket filters: classic or eBPF programs deciding on received packets.
+//!
+//! A filter returns how many bytes of the packet to keep: 0 drops it, and
+//! anything shorter than the packet truncates it. Packet sockets filter the
+//! ethernet frame before queueing it. UDP and raw sockets are queued by
+//! smoltcp, so with a filter attached each packet is moved off that queue
+//! into one of the socket as soon as the interface has been polled, passing
+//! the UDP payload or the IP packet respectively to the filter.
+
+use crate::ebpf::cbpf::{convert, CbpfContext, SockFilter};
+use crate::ebpf::ebpf::run_accounted;
+use crate::ebpf::prog::{prog_load, BpfProg, ProgType};
+use crate::syscall::SysError;
+use alloc::fmt::{self, Debug, Formatter};
+use alloc::sync::Arc;
+use core::cmp::min;
+
+/// What a socket filter is run on, passed in r1.
+#[repr(C)]
+pub struct SkBuff {
+    /// length of the packet
+    pub len: u32,
+    pub pad: u32,
+    /// first byte of the packet
+    pub data: u64,
+    /// one past the last byte of the packet
+    pub data_end: u64,
+}
+
+/// offsets of `len` and `data` in `SkBuff`
+const SKB_LEN_OFF: i16 = 0;
+const SKB_DATA_OFF: i16 = 8;
+
+#[derive(Clone)]
+pub struct SocketFilter(Arc<BpfProg>);
+
+impl Debug for SocketFilter {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "SocketFilter(prog {})", self.0.id)
+    }
+}
+
+impl SocketFilter {
+    /// A filter from `SO_ATTACH_FILTER`.
+    pub fn classic(filter: &[SockFilter]) -> Result<Self, SysError> {
+        let insns = convert(
+            filter,
+            CbpfContext::Socket {
+                len_off: SKB_LEN_OFF,
+                data_off: SKB_DATA_OFF,
+            },
+        )?;
+        Ok(SocketFilter(prog_load(ProgType::SocketFilter, insns, |_| None)?))
+    }
+
+    /// A filter from `SO_ATTACH_BPF`.
+    pub fn ebpf(prog: Arc<BpfProg>) -> Result<Self, SysError> {
+        if prog.prog_type != ProgType::SocketFilter {
+            return Err(SysError::EINVAL);
+        }
+        Ok(SocketFilter(prog))
+    }
+
+    /// How many bytes of `packet` to keep. A filter that cannot run, as it
+    /// was hit from within a program on this hart, drops the packet.
+    pub fn run(&self, packet: &[u8]) -> usize {
+        let skb = SkBuff {
+            len: packet.len() as u32,
+            pad: 0,
+            data: packet.as_ptr() as u64,
+            data_end: packet.as_ptr() as u64 + packet.len() as u64,
+        };
+        match run_accounted(&self.0, &skb as *const SkBuff as u64) {
+            Some(ret) => min(ret as u32 as usize, packet.len()),
+            None => 0,
+        }
+    }
+}
@@ -1,5 +1,9 @@
+mod filter;
 mod structs;
 mod test;
+mod xdp;
 
+pub use self::filter::*;
 pub use self::structs::*;
 pub use self::test::server;
+pub use self::xdp::*;
@@ -1,11 +1,13 @@
+use super::SocketFilter;
 use crate::arch::rand;
 use crate::drivers::{NET_DRIVERS, SOCKET_ACTIVITY};
 use crate::sync::SpinNoIrqLock as Mutex;
 use crate::syscall::*;
 use crate::util;
 use alloc::boxed::Box;
+use alloc::collections::VecDeque;
 use alloc::fmt::Debug;
-use alloc::sync::Arc;
+use alloc::sync::{Arc, Weak};
 use alloc::vec::Vec;
 use bitflags::*;
 use core::cmp::min;
@@ -82,6 +84,10 @@ pub trait Socket: Send + Sync + Debug {
         warn!("ioctl is unimplemented for this socket");
         Ok(0)
     }
+    /// Attach a filter run on received packets, or detach it with `None`.
+    fn set_filter(&mut self, _filter: Option<SocketFilter>) -> SysResult {
+        Err(SysError::ENOPROTOOPT)
+    }
     fn box_clone(&self) -> Box<dyn Socket>;
 }
 
@@ -98,6 +104,12 @@ lazy_static! {
     /// every socket operation needs to lock this.
     pub static ref SOCKETS: Mutex<SocketSet<'static, 'static, 'static>> =
         Mutex::new(SocketSet::new(vec![]));
+
+    /// Packet sockets receiving a copy of every incoming frame.
+    static ref PACKET_SOCKETS: Mutex<Vec<Weak<PacketCapture>>> = Mutex::new(Vec::new());
+
+    /// UDP and raw sockets, whose packets pass their filter once queued.
+    static ref FILTERED_SOCKETS: Mutex<Vec<Weak<FilteredRecv>>> = Mutex::new(Vec::new());
 }
 
 #[derive(Debug, Clone)]
@@ -107,21 +119,36 @@ pub struct TcpSocketState {
     is_listening: bool,
 }
 
+// `recv` comes before `handle`, so that it is dropped first and the smoltcp
+// socket outlives every `FilteredRecv` still reachable from FILTERED_SOCKETS.
+
 #[derive(Debug, Clone)]
 pub struct UdpSocketState {
+    recv: Arc<FilteredRecv>,
     handle: GlobalSocketHandle,
     remote_endpoint: Option<IpEndpoint>, // remember remote endpoint for connect()
 }
 
 #[derive(Debug, Clone)]
 pub struct RawSocketState {
+    recv: Arc<FilteredRecv>,
     handle: GlobalSocketHandle,
     header_included: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct PacketSocketState {
-    // no state, only ethernet egress
+    capture: Arc<PacketCapture>,
+}
+
+/// Frames received by a packet socket.
+#[derive(Debug)]
+struct PacketCapture {
+    /// the interface bound to, all of them if `None`
+    ifindex: Mutex<Option<usize>>,
+    filter: FilterSlot,
+    /// (interface index, frame)
+    queue: Mutex<VecDeque<(usize, Vec<u8>)>>,
 }
 
 #[derive(Debug, Clone)]
@@ -129,6 +156,135 @@ pub struct NetlinkSocketState {
     data: Arc<Mutex<Vec<Vec<u8>>>>,
 }
 
+/// The filter of a socket, shared by all its file descriptors.
+#[derive(Debug, Clone)]
+struct FilterSlot(Arc<Mutex<Option<SocketFilter>>>);
+
+impl FilterSlot {
+    fn new() -> Self {
+        FilterSlot(Arc::new(Mutex::new(None)))
+    }
+
+    fn set(&self, filter: Option<SocketFilter>) -> SysResult {
+        let mut slot = self.0.lock();
+        if filter.is_none() && slot.is_none() {
+            return Err(SysError::ENOENT);
+        }
+        *slot = filter;
+        Ok(0)
+    }
+
+    /// How many bytes of `packet` to deliver: all of them without a filter.
+    fn run(&self, packet: &[u8]) -> usize {
+        let filter = self.0.lock().clone();
+        match filter {
+            Some(filter) => filter.run(packet),
+            None => packet.len(),
+        }
+    }
+
+    fn is_set(&self) -> bool {
+        self.0.lock().is_some()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum RecvKind {
+    Udp,
+    Raw,
+}
+
+/// Received packets of a UDP or raw socket that passed its filter.
+///
+/// smoltcp queues the packets of these sockets itself, so while a filter is
+/// attached they are moved off the smoltcp socket through the filter into
+/// `queue` as soon as an interface has been polled. Readiness and reads then
+/// only see packets the filter kept.
+#[derive(Debug)]
+struct FilteredRecv {
+    kind: RecvKind,
+    handle: SocketHandle,
+    filter: FilterSlot,
+    /// (packet, source)
+    queue: Mutex<VecDeque<(Vec<u8>, IpEndpoint)>>,
+}
+
+impl FilteredRecv {
+    fn new(kind: RecvKind, handle: SocketHandle) -> Arc<Self> {
+        let recv = Arc::new(FilteredRecv {
+            kind,
+            handle,
+            filter: FilterSlot::new(),
+            queue: Mutex::new(VecDeque::new()),
+        });
+        FILTERED_SOCKETS.lock().push(Arc::downgrade(&recv));
+        recv
+    }
+
+    /// Move the packets queued by smoltcp through the filter, if any.
+    fn drain(&self, sockets: &mut SocketSet<'static, 'static, 'static>) {
+        if !self.filter.is_set() {
+            return;
+        }
+        let limit = match self.kind {
+            RecvKind::Udp => UDP_METADATA_BUF,
+            RecvKind::Raw => RAW_METADATA_BUF,
+        };
+        loop {
+            let (packet, endpoint) = match self.kind {
+                RecvKind::Udp => {
+                    let mut socket = sockets.get::<UdpSocket>(self.handle);
+                    match socket.recv() {
+                        Ok((packet, endpoint)) => (packet.to_vec(), endpoint),
+                        Err(_) => return,
+                    }
+                }
+                RecvKind::Raw => {
+                    let mut socket = sockets.get::<RawSocket>(self.handle);
+                    match socket.recv() {
+                        Ok(packet) => {
+                            let src = Ipv4Packet::new_unchecked(packet).src_addr();
+                            (packet.to_vec(), IpEndpoint::new(IpAddress::Ipv4(src), 0))
+                        }
+                        Err(_) => return,
+                    }
+                }
+            };
+            let keep = self.filter.run(&packet);
+            if keep == 0 {
+                // dropped by the filter
+                continue;
+            }
+            let mut queue = self.queue.lock();
+            if queue.len() >= limit {
+                // the reader is too slow, drop the new packet
+                continue;
+            }
+            queue.push_back((packet[..keep].to_vec(), endpoint));
+        }
+    }
+
+    fn pop(&self) -> Option<(Vec<u8>, IpEndpoint)> {
+        self.queue.lock().pop_front()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.lock().is_empty()
+    }
+}
+
+/// Run the filters of UDP and raw sockets on the packets smoltcp queued for
+/// them. Called by net drivers right after polling an interface, with
+/// SOCKETS locked, so a reader checking its queue under SOCKETS misses no
+/// wakeup.
+pub fn filter_received(sockets: &mut SocketSet<'static, 'static, 'static>) {
+    let mut filtered = FILTERED_SOCKETS.lock();
+    filtered.retain(|recv| recv.strong_count() > 0);
+    for recv in filtered.iter().filter_map(|recv| recv.upgrade()) {
+        recv.drain(sockets);
+    }
+}
+
 /// A wrapper for `SocketHandle`.
 /// Auto increase and decrease reference count on Clone and Drop.
 #[derive(Debug)]
@@ -407,6 +563,7 @@ impl UdpSocketState {
         let handle = GlobalSocketHandle(SOCKETS.lock().add(socket));
 
         UdpSocketState {
+            recv: FilteredRecv::new(RecvKind::Udp, handle.0),
             handle,
             remote_endpoint: None,
         }
@@ -426,10 +583,21 @@ impl Socket for UdpSocketState {
     fn read(&self, data: &mut [u8]) -> (SysResult, Endpoint) {
         loop {
             let mut sockets = SOCKETS.lock();
+            self.recv.drain(&mut sockets);
+            if let Some((packet, remote_endpoint)) = self.recv.pop() {
+                let size = min(packet.len(), data.len());
+                data[..size].copy_from_slice(&packet[..size]);
+                drop(sockets);
+
+                poll_ifaces();
+                return (Ok(size), Endpoint::Ip(remote_endpoint));
+            }
             let mut socket = sockets.get::<UdpSocket>(self.handle.0);
 
             if socket.can_recv() {
-                if let Ok((size, remote_endpoint)) = socket.recv_slice(data) {
+                if let Ok((packet, remote_endpoint)) = socket.recv() {
+                    let size = min(packet.len(), data.len());
+                    data[..size].copy_from_slice(&packet[..size]);
                     let endpoint = remote_endpoint;
                     // avoid deadlock
                     drop(socket);
@@ -489,10 +657,11 @@ impl Socket for UdpSocketState {
 
     fn poll(&self) -> (bool, bool, bool) {
         let mut sockets = SOCKETS.lock();
+        self.recv.drain(&mut sockets);
         let socket = sockets.get::<UdpSocket>(self.handle.0);
 
         let (mut input, mut output, err) = (false, false, false);
-        if socket.can_recv() {
+        if !self.recv.is_empty() || socket.can_recv() {
             input = true;
         }
         if socket.can_send() {
@@ -575,6 +744,10 @@ impl Socket for UdpSocketState {
         self.remote_endpoint.clone().map(|e| Endpoint::Ip(e))
     }
 
+    fn set_filter(&mut self, filter: Option<SocketFilter>) -> SysResult {
+        self.recv.filter.set(filter)
+    }
+
     fn box_clone(&self) -> Box<dyn Socket> {
         Box::new(self.clone())
     }
@@ -599,6 +772,7 @@ impl RawSocketState {
         let handle = GlobalSocketHandle(SOCKETS.lock().add(socket));
 
         RawSocketState {
+            recv: FilteredRecv::new(RecvKind::Raw, handle.0),
             handle,
             header_included: false,
         }
@@ -609,10 +783,18 @@ impl Socket for RawSocketState {
     fn read(&self, data: &mut [u8]) -> (SysResult, Endpoint) {
         loop {
             let mut sockets = SOCKETS.lock();
+            self.recv.drain(&mut sockets);
+            if let Some((packet, endpoint)) = self.recv.pop() {
+                let size = min(packet.len(), data.len());
+                data[..size].copy_from_slice(&packet[..size]);
+                return (Ok(size), Endpoint::Ip(endpoint));
+            }
             let mut socket = sockets.get::<RawSocket>(self.handle.0);
 
-            if let Ok(size) = socket.recv_slice(data) {
-                let packet = Ipv4Packet::new_unchecked(data);
+            if let Ok(packet) = socket.recv() {
+                let size = min(packet.len(), data.len());
+                data[..size].copy_from_slice(&packet[..size]);
+                let packet = Ipv4Packet::new_unchecked(packet);
 
                 return (
                     Ok(size),
@@ -678,7 +860,11 @@ impl Socket for RawSocketState {
     }
 
     fn poll(&self) -> (bool, bool, bool) {
-        unimplemented!()
+        let mut sockets = SOCKETS.lock();
+        self.recv.drain(&mut sockets);
+        let socket = sockets.get::<RawSocket>(self.handle.0);
+        let input = !self.recv.is_empty() || socket.can_recv();
+        (input, socket.can_send(), false)
     }
 
     fn connect(&mut self, _endpoint: Endpoint) -> SysResult {
@@ -701,17 +887,71 @@ impl Socket for RawSocketState {
         }
         Ok(0)
     }
+
+    fn set_filter(&mut self, filter: Option<SocketFilter>) -> SysResult {
+        self.recv.filter.set(filter)
+    }
 }
 
 impl PacketSocketState {
     pub fn new() -> Self {
-        PacketSocketState {}
+        let capture = Arc::new(PacketCapture {
+            ifindex: Mutex::new(None),
+            filter: FilterSlot::new(),
+            queue: Mutex::new(VecDeque::new()),
+        });
+        PACKET_SOCKETS.lock().push(Arc::downgrade(&capture));
+        PacketSocketState { capture }
+    }
+}
+
+impl PacketCapture {
+    fn receive(&self, ifindex: usize, frame: &[u8]) {
+        match *self.ifindex.lock() {
+            Some(bound) if bound != ifindex => return,
+            _ => {}
+        }
+        let keep = self.filter.run(frame);
+        if keep == 0 {
+            return;
+        }
+        let mut queue = self.queue.lock();
+        if queue.len() >= PACKET_QUEUE_LEN {
+            // the reader is too slow, drop the new frame
+            return;
+        }
+        queue.push_back((ifindex, frame[..keep].to_vec()));
+    }
+}
+
+/// Hand a frame received on interface `ifindex` to the packet sockets,
+/// before the network stack sees it.
+///
+/// Called by net drivers with SOCKETS locked while polling, so a reader
+/// checking its queue under SOCKETS misses no wakeup.
+pub fn capture_packet(ifindex: usize, frame: &[u8]) {
+    let mut sockets = PACKET_SOCKETS.lock();
+    sockets.retain(|socket| socket.strong_count() > 0);
+    for socket in sockets.iter().filter_map(|socket| socket.upgrade()) {
+        socket.receive(ifindex, frame);
     }
 }
 
 impl Socket for PacketSocketState {
-    fn read(&self, _data: &mut [u8]) -> (SysResult, Endpoint) {
-        unimplemented!()
+    fn read(&self, data: &mut [u8]) -> (SysResult, Endpoint) {
+        loop {
+            let sockets = SOCKETS.lock();
+            let frame = self.capture.queue.lock().pop_front();
+            if let Some((ifindex, frame)) = frame {
+                let size = min(frame.len(), data.len());
+                data[..size].copy_from_slice(&frame[..size]);
+                return (
+                    Ok(size),
+                    Endpoint::LinkLevel(LinkLevelEndpoint::new(ifindex)),
+                );
+            }
+            SOCKET_ACTIVITY.wait(sockets);
+        }
     }
 
     fn write(&self, data: &[u8], sendto_endpoint: Option<Endpoint>) -> SysResult {
@@ -727,13 +967,36 @@ impl Socket for PacketSocketState {
     }
 
     fn poll(&self) -> (bool, bool, bool) {
-        unimplemented!()
+        (!self.capture.queue.lock().is_empty(), true, false)
     }
 
     fn connect(&mut self, _endpoint: Endpoint) -> SysResult {
         unimplemented!()
     }
 
+    fn bind(&mut self, endpoint: Endpoint) -> SysResult {
+        if let Endpoint::LinkLevel(endpoint) = endpoint {
+            if endpoint.interface_index >= NET_DRIVERS.read().len() {
+                return Err(SysError::ENODEV);
+            }
+            *self.capture.ifindex.lock() = Some(endpoint.interface_index);
+            Ok(0)
+        } else {
+            Err(SysError::EINVAL)
+        }
+    }
+
+    fn endpoint(&self) -> Option<Endpoint> {
+        self.capture
+            .ifindex
+            .lock()
+            .map(|ifindex| Endpoint::LinkLevel(LinkLevelEndpoint::new(ifindex)))
+    }
+
+    fn set_filter(&mut self, filter: Option<SocketFilter>) -> SysResult {
+        self.capture.filter.set(filter)
+    }
+
     fn box_clone(&self) -> Box<dyn Socket> {
         Box::new(self.clone())
     }
@@ -1104,3 +1367,5 @@ const UDP_RECVBUF: usize = 64 * 1024; // 64K
 const RAW_METADATA_BUF: usize = 1024;
 const RAW_SENDBUF: usize = 64 * 1024; // 64K
 const RAW_RECVBUF: usize = 64 * 1024; // 64K
+
+const PACKET_QUEUE_LEN: usize = 256; // frames
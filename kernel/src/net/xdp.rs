@@ -0,0 +1,201 @@
+//! XDP: eBPF programs run on every received frame before the network stack.
+//!
+//! A program attached to an interface sees the raw ethernet frame and may
+//! pass it on, drop it, send it back out of the same interface or redirect
+//! it to another one. Its frame is copied into a buffer with room to grow
+//! at both ends, which `bpf_xdp_adjust_head` and `bpf_xdp_adjust_tail` use.
+
+use super::capture_packet;
+use crate::consts::MAX_CPU_NUM;
+use crate::drivers::NET_DRIVERS;
+use crate::ebpf::ebpf::run_accounted;
+use crate::ebpf::prog::BpfProg;
+use crate::sync::SpinNoIrqLock as Mutex;
+use crate::syscall::SysError;
+use alloc::collections::btree_map::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub const XDP_ABORTED: u32 = 0;
+pub const XDP_DROP: u32 = 1;
+pub const XDP_PASS: u32 = 2;
+pub const XDP_TX: u32 = 3;
+pub const XDP_REDIRECT: u32 = 4;
+
+/// Fail to attach if a program is attached already.
+pub const XDP_FLAGS_UPDATE_IF_NOEXIST: u32 = 1;
+
+const XDP_HEADROOM: usize = 256;
+const XDP_TAILROOM: usize = 256;
+const ETH_HLEN: u64 = 14;
+
+/// What an XDP program is run on, passed in r1.
+#[repr(C)]
+pub struct XdpMd {
+    /// first byte of the frame
+    pub data: u64,
+    /// one past the last byte of the frame
+    pub data_end: u64,
+    /// index in `NET_DRIVERS` of the interface the frame came in on
+    pub ingress_ifindex: u32,
+    pub rx_queue_index: u32,
+    /// bounds of the buffer, for adjusting `data` and `data_end`
+    hard_start: u64,
+    hard_end: u64,
+}
+
+impl XdpMd {
+    /// Move the start of the frame by `delta`, keeping an ethernet header.
+    pub fn adjust_head(&mut self, delta: i64) -> Result<(), SysError> {
+        let data = offset(self.data, delta).ok_or(SysError::EINVAL)?;
+        if data < self.hard_start || data > self.data_end || self.data_end - data < ETH_HLEN {
+            return Err(SysError::EINVAL);
+        }
+        self.data = data;
+        Ok(())
+    }
+
+    /// Move the end of the frame by `delta`, keeping an ethernet header.
+    pub fn adjust_tail(&mut self, delta: i64) -> Result<(), SysError> {
+        let data_end = offset(self.data_end, delta).ok_or(SysError::EINVAL)?;
+        if data_end > self.hard_end || data_end < self.data || data_end - self.data < ETH_HLEN {
+            return Err(SysError::EINVAL);
+        }
+        self.data_end = data_end;
+        Ok(())
+    }
+}
+
+/// `addr` moved by `delta`, unless that wraps around.
+fn offset(addr: u64, delta: i64) -> Option<u64> {
+    if delta >= 0 {
+        addr.checked_add(delta as u64)
+    } else {
+        addr.checked_sub((delta as u64).wrapping_neg())
+    }
+}
+
+lazy_static! {
+    /// XDP programs by interface index.
+    static ref XDP_PROGS: Mutex<BTreeMap<usize, Arc<BpfProg>>> = Mutex::new(BTreeMap::new());
+
+    /// Target interface of `bpf_redirect` for the program running on each hart.
+    static ref REDIRECT_TARGET: Vec<AtomicUsize> =
+        (0..MAX_CPU_NUM).map(|_| AtomicUsize::new(usize::MAX)).collect();
+}
+
+/// Attach `prog` to interface `ifindex`, or detach its program with `None`.
+pub fn xdp_attach(ifindex: usize, prog: Option<Arc<BpfProg>>, flags: u32) -> Result<(), SysError> {
+    if ifindex >= NET_DRIVERS.read().len() {
+        return Err(SysError::ENODEV);
+    }
+    let mut progs = XDP_PROGS.lock();
+    match prog {
+        Some(prog) => {
+            if flags & XDP_FLAGS_UPDATE_IF_NOEXIST != 0 && progs.contains_key(&ifindex) {
+                return Err(SysError::EBUSY);
+            }
+            progs.insert(ifindex, prog);
+        }
+        None => {
+            progs.remove(&ifindex);
+        }
+    }
+    Ok(())
+}
+
+/// Attached programs as (interface index, program id).
+pub fn xdp_list() -> Vec<(usize, u32)> {
+    XDP_PROGS
+        .lock()
+        .iter()
+        .map(|(&ifindex, prog)| (ifindex, prog.id))
+        .collect()
+}
+
+/// Remember where the running program redirects its frame to.
+pub fn xdp_redirect(ifindex: usize) {
+    REDIRECT_TARGET[crate::arch::cpu::id()].store(ifindex, Ordering::Relaxed);
+}
+
+enum XdpAction {
+    Pass,
+    Drop,
+    Tx,
+    Redirect(usize),
+}
+
+/// Run the program of interface `ifindex` on `frame`, which is replaced by
+/// the frame as the program left it.
+fn xdp_run(ifindex: usize, frame: &mut Vec<u8>) -> XdpAction {
+    let prog = match XDP_PROGS.lock().get(&ifindex) {
+        Some(prog) => prog.clone(),
+        None => return XdpAction::Pass,
+    };
+    let mut buf = vec![0u8; XDP_HEADROOM + frame.len() + XDP_TAILROOM];
+    buf[XDP_HEADROOM..XDP_HEADROOM + frame.len()].copy_from_slice(frame);
+    let start = buf.as_ptr() as u64;
+    let mut md = XdpMd {
+        data: start + XDP_HEADROOM as u64,
+        data_end: start + (XDP_HEADROOM + frame.len()) as u64,
+        ingress_ifindex: ifindex as u32,
+        rx_queue_index: 0,
+        hard_start: start,
+        hard_end: start + buf.len() as u64,
+    };
+    let target = &REDIRECT_TARGET[crate::arch::cpu::id()];
+    target.store(usize::MAX, Ordering::Relaxed);
+    let ret = match run_accounted(&prog, &mut md as *mut XdpMd as u64) {
+        Some(ret) => ret as u32,
+        None => XDP_ABORTED,
+    };
+    frame.clear();
+    frame.extend_from_slice(&buf[(md.data - start) as usize..(md.data_end - start) as usize]);
+    match ret {
+        XDP_PASS => XdpAction::Pass,
+        XDP_TX => XdpAction::Tx,
+        XDP_REDIRECT => match target.load(Ordering::Relaxed) {
+            usize::MAX => XdpAction::Drop,
+            target => XdpAction::Redirect(target),
+        },
+        XDP_DROP => XdpAction::Drop,
+        _ => {
+            warn!("xdp: program {} aborted on interface {}", prog.id, ifindex);
+            XdpAction::Drop
+        }
+    }
+}
+
+fn transmit(ifindex: usize, frame: &[u8]) {
+    match NET_DRIVERS.read().get(ifindex) {
+        Some(iface) => {
+            if iface.send(frame).is_none() {
+                debug!("xdp: no room to send on interface {}", ifindex);
+            }
+        }
+        None => debug!("xdp: no interface {} to redirect to", ifindex),
+    }
+}
+
+/// The early receive path of net drivers, run on every frame received on
+/// interface `ifindex` before the network stack sees it: the XDP program of
+/// the interface, then packet sockets. Returns whether the frame, possibly
+/// changed, goes on to the network stack.
+pub fn receive_hook(ifindex: usize, frame: &mut Vec<u8>) -> bool {
+    match xdp_run(ifindex, frame) {
+        XdpAction::Pass => {
+            capture_packet(ifindex, frame);
+            true
+        }
+        XdpAction::Drop => false,
+        XdpAction::Tx => {
+            transmit(ifindex, frame);
+            false
+        }
+        XdpAction::Redirect(target) => {
+            transmit(target, frame);
+            false
+        }
+    }
+}
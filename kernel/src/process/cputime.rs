@@ -0,0 +1,40 @@
+//! CPU time spent by threads and processes
+//!
+//! A thread accounts the time running in user mode around `cx.run()`, and the
+//! rest of the time its future is polled as system time. The time is added to
+//! the thread and to its process. A process adds the time of its children, and
+//! their waited-for descendants, when it waits for them.
+
+use core::ops::{Add, AddAssign};
+use core::time::Duration;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CpuTime {
+    /// Time running in user mode
+    pub user: Duration,
+    /// Time running in the kernel on behalf of the thread
+    pub system: Duration,
+}
+
+impl CpuTime {
+    pub fn total(&self) -> Duration {
+        self.user + self.system
+    }
+}
+
+impl Add for CpuTime {
+    type Output = CpuTime;
+
+    fn add(self, other: CpuTime) -> CpuTime {
+        CpuTime {
+            user: self.user + other.user,
+            system: self.system + other.system,
+        }
+    }
+}
+
+impl AddAssign for CpuTime {
+    fn add_assign(&mut self, other: CpuTime) {
+        *self = *self + other;
+    }
+}
@@ -0,0 +1,271 @@
+//! User and group credentials of a process
+//!
+//! Credentials belong to the process, and are inherited by `fork`. `execve` of
+//! a set-user-ID or set-group-ID program changes the effective and saved IDs.
+//! File permissions are checked against the effective IDs, except by `access`,
+//! which checks the real ones.
+
+use crate::syscall::SysError;
+use alloc::vec::Vec;
+use rcore_fs::vfs::{FileType, Metadata};
+
+pub type Uid = u32;
+pub type Gid = u32;
+
+/// Max number of supplementary groups
+pub const NGROUPS_MAX: usize = 65536;
+
+/// Permission to check, as `R_OK`, `W_OK` and `X_OK` of access(2)
+pub const MAY_EXEC: usize = 1;
+pub const MAY_WRITE: usize = 2;
+pub const MAY_READ: usize = 4;
+
+pub const S_ISUID: usize = 0o4000;
+pub const S_ISGID: usize = 0o2000;
+pub const S_ISVTX: usize = 0o1000;
+
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    /// Real, effective and saved user ID
+    pub uid: Uid,
+    pub euid: Uid,
+    pub suid: Uid,
+    /// Real, effective and saved group ID
+    pub gid: Gid,
+    pub egid: Gid,
+    pub sgid: Gid,
+    /// Supplementary groups
+    pub groups: Vec<Gid>,
+}
+
+impl Credentials {
+    /// Whether the process runs as root, and passes any permission check
+    pub fn is_root(&self) -> bool {
+        self.euid == 0
+    }
+
+    pub fn in_group(&self, gid: Gid) -> bool {
+        self.egid == gid || self.groups.contains(&gid)
+    }
+
+    /// The credentials with the real IDs as effective ones, to check `access`
+    pub fn real(&self) -> Self {
+        Credentials {
+            euid: self.uid,
+            egid: self.gid,
+            ..self.clone()
+        }
+    }
+
+    /// Check `mask` of `MAY_*` permissions to a file with `metadata`
+    pub fn check_permission(&self, metadata: &Metadata, mask: usize) -> Result<(), SysError> {
+        let mode = metadata.mode as usize;
+        if self.is_root() {
+            // root can not execute a file no one can execute
+            if mask & MAY_EXEC == 0 || metadata.type_ == FileType::Dir || mode & 0o111 != 0 {
+                return Ok(());
+            }
+            return Err(SysError::EACCES);
+        }
+        let perm = if metadata.uid as Uid == self.euid {
+            mode >> 6
+        } else if self.in_group(metadata.gid as Gid) {
+            mode >> 3
+        } else {
+            mode
+        };
+        if perm & mask == mask {
+            Ok(())
+        } else {
+            Err(SysError::EACCES)
+        }
+    }
+
+    /// Check the entry `file` in the directory `dir` can be removed or renamed
+    pub fn check_remove(&self, dir: &Metadata, file: &Metadata) -> Result<(), SysError> {
+        self.check_permission(dir, MAY_WRITE | MAY_EXEC)?;
+        // only the owner can remove from a sticky directory, like /tmp
+        if dir.mode as usize & S_ISVTX != 0
+            && !self.is_root()
+            && file.uid as Uid != self.euid
+            && dir.uid as Uid != self.euid
+        {
+            return Err(SysError::EPERM);
+        }
+        Ok(())
+    }
+
+    /// Change the mode of a file with chmod(2)
+    pub fn chmod(&self, metadata: &mut Metadata, mode: usize) -> Result<(), SysError> {
+        if !self.is_root() && metadata.uid as Uid != self.euid {
+            return Err(SysError::EPERM);
+        }
+        let mut mode = mode & 0o7777;
+        if !self.is_root() && !self.in_group(metadata.gid as Gid) {
+            mode &= !S_ISGID;
+        }
+        metadata.mode = ((metadata.mode as usize & !0o7777) | mode) as _;
+        Ok(())
+    }
+
+    /// Change the owner of a file with chown(2), `None` to keep it
+    pub fn chown(
+        &self,
+        metadata: &mut Metadata,
+        uid: Option<Uid>,
+        gid: Option<Gid>,
+    ) -> Result<(), SysError> {
+        if !self.is_root() {
+            if metadata.uid as Uid != self.euid
+                || uid.map_or(false, |uid| uid != metadata.uid as Uid)
+                || gid.map_or(false, |gid| !self.in_group(gid))
+            {
+                return Err(SysError::EPERM);
+            }
+        }
+        // even root does not hand its set-user-ID programs to another owner
+        if metadata.type_ != FileType::Dir {
+            metadata.mode = (metadata.mode as usize & !(S_ISUID | S_ISGID)) as _;
+        }
+        if let Some(uid) = uid {
+            metadata.uid = uid as _;
+        }
+        if let Some(gid) = gid {
+            metadata.gid = gid as _;
+        }
+        Ok(())
+    }
+
+    /// Take the owner of the program with `metadata` on exec, if it is set-user-ID or set-group-ID,
+    /// unless `no_new_privs` is set
+    pub fn exec(&mut self, metadata: &Metadata, no_new_privs: bool) {
+        let mode = if no_new_privs { 0 } else { metadata.mode as usize };
+        if mode & S_ISUID != 0 {
+            self.euid = metadata.uid as Uid;
+        }
+        // set-group-ID without group execute is for mandatory locking
+        if mode & S_ISGID != 0 && mode & 0o010 != 0 {
+            self.egid = metadata.gid as Gid;
+        }
+        self.suid = self.euid;
+        self.sgid = self.egid;
+    }
+
+    /// setuid(2)
+    pub fn set_uid(&mut self, uid: Uid) -> Result<(), SysError> {
+        if self.is_root() {
+            self.uid = uid;
+            self.suid = uid;
+        } else if uid != self.uid && uid != self.suid {
+            return Err(SysError::EPERM);
+        }
+        self.euid = uid;
+        Ok(())
+    }
+
+    /// setgid(2)
+    pub fn set_gid(&mut self, gid: Gid) -> Result<(), SysError> {
+        if self.is_root() {
+            self.gid = gid;
+            self.sgid = gid;
+        } else if gid != self.gid && gid != self.sgid {
+            return Err(SysError::EPERM);
+        }
+        self.egid = gid;
+        Ok(())
+    }
+
+    /// setreuid(2), `None` to keep the ID
+    pub fn set_reuid(&mut self, ruid: Option<Uid>, euid: Option<Uid>) -> Result<(), SysError> {
+        if !self.is_root() {
+            if ruid.map_or(false, |id| id != self.uid && id != self.euid)
+                || euid.map_or(false, |id| {
+                    id != self.uid && id != self.euid && id != self.suid
+                })
+            {
+                return Err(SysError::EPERM);
+            }
+        }
+        let old_uid = self.uid;
+        if let Some(id) = ruid {
+            self.uid = id;
+        }
+        if let Some(id) = euid {
+            self.euid = id;
+        }
+        if ruid.is_some() || euid.map_or(false, |id| id != old_uid) {
+            self.suid = self.euid;
+        }
+        Ok(())
+    }
+
+    /// setregid(2), `None` to keep the ID
+    pub fn set_regid(&mut self, rgid: Option<Gid>, egid: Option<Gid>) -> Result<(), SysError> {
+        if !self.is_root() {
+            if rgid.map_or(false, |id| id != self.gid && id != self.egid)
+                || egid.map_or(false, |id| {
+                    id != self.gid && id != self.egid && id != self.sgid
+                })
+            {
+                return Err(SysError::EPERM);
+            }
+        }
+        let old_gid = self.gid;
+        if let Some(id) = rgid {
+            self.gid = id;
+        }
+        if let Some(id) = egid {
+            self.egid = id;
+        }
+        if rgid.is_some() || egid.map_or(false, |id| id != old_gid) {
+            self.sgid = self.egid;
+        }
+        Ok(())
+    }
+
+    /// setresuid(2), `None` to keep the ID
+    pub fn set_resuid(
+        &mut self,
+        ruid: Option<Uid>,
+        euid: Option<Uid>,
+        suid: Option<Uid>,
+    ) -> Result<(), SysError> {
+        let allowed = |id: Uid| id == self.uid || id == self.euid || id == self.suid;
+        if !self.is_root() && [ruid, euid, suid].iter().flatten().any(|&id| !allowed(id)) {
+            return Err(SysError::EPERM);
+        }
+        self.uid = ruid.unwrap_or(self.uid);
+        self.euid = euid.unwrap_or(self.euid);
+        self.suid = suid.unwrap_or(self.suid);
+        Ok(())
+    }
+
+    /// setresgid(2), `None` to keep the ID
+    pub fn set_resgid(
+        &mut self,
+        rgid: Option<Gid>,
+        egid: Option<Gid>,
+        sgid: Option<Gid>,
+    ) -> Result<(), SysError> {
+        let allowed = |id: Gid| id == self.gid || id == self.egid || id == self.sgid;
+        if !self.is_root() && [rgid, egid, sgid].iter().flatten().any(|&id| !allowed(id)) {
+            return Err(SysError::EPERM);
+        }
+        self.gid = rgid.unwrap_or(self.gid);
+        self.egid = egid.unwrap_or(self.egid);
+        self.sgid = sgid.unwrap_or(self.sgid);
+        Ok(())
+    }
+
+    /// setgroups(2)
+    pub fn set_groups(&mut self, groups: Vec<Gid>) -> Result<(), SysError> {
+        if !self.is_root() {
+            return Err(SysError::EPERM);
+        }
+        if groups.len() > NGROUPS_MAX {
+            return Err(SysError::EINVAL);
+        }
+        self.groups = groups;
+        Ok(())
+    }
+}
@@ -0,0 +1,209 @@
+//! Interval timers of a process
+//!
+//! An `IntervalTimer` expires at a deadline of `timer_now`, and then every
+//! interval, on top of `NAIVE_TIMER`. It backs `ITIMER_REAL`, `alarm`, POSIX
+//! timers and timerfd. `ITIMER_VIRTUAL` and `ITIMER_PROF` instead count down
+//! the CPU time of the process, as it is accounted.
+
+use super::CpuTime;
+use crate::arch::timer::timer_now;
+use crate::signal::Signal;
+use crate::sync::SpinNoIrqLock as Mutex;
+use crate::trap::NAIVE_TIMER;
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::convert::TryFrom;
+use core::time::Duration;
+
+lazy_static! {
+    /// Timers expired by `NAIVE_TIMER`, handled by `handle_expired` out of its lock
+    static ref EXPIRED: Mutex<Vec<(Weak<TimerInner>, usize)>> = Mutex::new(Vec::new());
+}
+
+/// The deadline of a timer set beyond the range of `Duration`, which never expires
+const FAR_FUTURE: Duration = Duration::from_secs(u64::max_value());
+
+struct TimerState {
+    deadline: Option<Duration>,
+    interval: Duration,
+    /// Increased when the timer is set, to ignore the expirations of old settings
+    generation: usize,
+    /// Expirations missed by the last notification
+    overrun: usize,
+}
+
+struct TimerInner {
+    state: Mutex<TimerState>,
+    notify: Box<dyn Fn(usize) + Send + Sync>,
+}
+
+/// A timer expiring at a deadline, and then every interval if it is not zero
+#[derive(Clone)]
+pub struct IntervalTimer(Arc<TimerInner>);
+
+impl IntervalTimer {
+    /// Create a disarmed timer, which calls `notify` with the number of expirations
+    pub fn new(notify: impl Fn(usize) + Send + Sync + 'static) -> Self {
+        let state = TimerState {
+            deadline: None,
+            interval: Duration::default(),
+            generation: 0,
+            overrun: 0,
+        };
+        IntervalTimer(Arc::new(TimerInner {
+            state: Mutex::new(state),
+            notify: Box::new(notify),
+        }))
+    }
+
+    /// Expire after `value` and then every `interval`, or disarm if `value` is zero.
+    /// Return the old time left and interval.
+    pub fn set(&self, value: Duration, interval: Duration) -> (Duration, Duration) {
+        let mut state = self.0.state.lock();
+        let old = time_left(&state);
+        state.generation += 1;
+        state.interval = interval;
+        state.overrun = 0;
+        if value == Duration::default() {
+            state.deadline = None;
+        } else {
+            let deadline = timer_now().checked_add(value).unwrap_or(FAR_FUTURE);
+            state.deadline = Some(deadline);
+            self.add(deadline, state.generation);
+        }
+        old
+    }
+
+    /// The time left and interval
+    pub fn get(&self) -> (Duration, Duration) {
+        time_left(&self.0.state.lock())
+    }
+
+    /// Expirations missed by the last notification
+    pub fn overrun(&self) -> usize {
+        self.0.state.lock().overrun
+    }
+
+    fn add(&self, deadline: Duration, generation: usize) {
+        let timer = Arc::downgrade(&self.0);
+        NAIVE_TIMER.lock().add(
+            deadline,
+            Box::new(move |_| EXPIRED.lock().push((timer, generation))),
+        );
+    }
+
+    fn expire(&self, generation: usize, now: Duration) {
+        let mut state = self.0.state.lock();
+        let deadline = match state.deadline {
+            Some(deadline) if state.generation == generation => deadline,
+            _ => return,
+        };
+        let mut count = 1;
+        if state.interval == Duration::default() {
+            state.deadline = None;
+        } else {
+            // the expirations missed are counted as overrun
+            let late = now.checked_sub(deadline).unwrap_or_default();
+            let missed = late.as_nanos() / state.interval.as_nanos();
+            count = count.saturating_add(usize::try_from(missed).unwrap_or(usize::max_value()));
+            let next = state
+                .interval
+                .as_nanos()
+                .checked_mul(count as u128)
+                .and_then(duration_from_nanos)
+                .and_then(|step| deadline.checked_add(step))
+                .unwrap_or(FAR_FUTURE);
+            state.deadline = Some(next);
+            self.add(next, generation);
+        }
+        state.overrun = count - 1;
+        drop(state);
+        (self.0.notify)(count);
+    }
+}
+
+/// The duration of `nanos`, None if it is out of range
+fn duration_from_nanos(nanos: u128) -> Option<Duration> {
+    const NANOS_PER_SEC: u128 = 1_000_000_000;
+    let secs = u64::try_from(nanos / NANOS_PER_SEC).ok()?;
+    Some(Duration::new(secs, (nanos % NANOS_PER_SEC) as u32))
+}
+
+fn time_left(state: &TimerState) -> (Duration, Duration) {
+    let left = match state.deadline {
+        // an expired timer not handled yet has a little time left
+        Some(deadline) => deadline
+            .checked_sub(timer_now())
+            .unwrap_or(Duration::from_nanos(1)),
+        None => Duration::default(),
+    };
+    (left, state.interval)
+}
+
+/// Handle the timers expired by `NAIVE_TIMER` at `now`, without its lock held
+pub fn handle_expired(now: Duration) {
+    let expired = core::mem::replace(&mut *EXPIRED.lock(), Vec::new());
+    for (timer, generation) in expired {
+        if let Some(timer) = timer.upgrade() {
+            IntervalTimer(timer).expire(generation, now);
+        }
+    }
+}
+
+/// A timer counting down CPU time
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CpuTimer {
+    /// Time left, zero if disarmed
+    pub value: Duration,
+    pub interval: Duration,
+}
+
+impl CpuTimer {
+    /// Count down `time`, return true if the timer expires
+    fn count_down(&mut self, time: Duration) -> bool {
+        if self.value == Duration::default() {
+            return false;
+        }
+        if time < self.value {
+            self.value -= time;
+            false
+        } else {
+            self.value = self.interval;
+            true
+        }
+    }
+}
+
+/// Timers of a process, which are not inherited by `fork`
+#[derive(Default)]
+pub struct ProcessTimers {
+    /// `ITIMER_REAL`, also set by `alarm`, created when it is first set
+    pub real: Option<IntervalTimer>,
+    /// `ITIMER_VIRTUAL`, counting down the user time
+    pub virt: CpuTimer,
+    /// `ITIMER_PROF`, counting down the user and system time
+    pub prof: CpuTimer,
+    /// POSIX timers by id
+    pub posix: BTreeMap<usize, IntervalTimer>,
+}
+
+impl ProcessTimers {
+    /// Count down the CPU time timers by `time`, return the signals to send
+    pub fn count_down(&mut self, time: CpuTime) -> [Option<Signal>; 2] {
+        let virt = self.virt.count_down(time.user);
+        let prof = self.prof.count_down(time.total());
+        [
+            if virt { Some(Signal::SIGVTALRM) } else { None },
+            if prof { Some(Signal::SIGPROF) } else { None },
+        ]
+    }
+
+    /// The lowest unused id of POSIX timers
+    pub fn free_id(&self) -> usize {
+        (0..).find(|id| !self.posix.contains_key(id)).unwrap()
+    }
+}
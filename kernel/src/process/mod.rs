@@ -10,10 +10,16 @@ use log::*;
 use trapframe::UserContext;
 
 mod abi;
+pub mod cputime;
+pub mod cred;
 pub mod futex;
+pub mod itimer;
 pub mod proc;
+pub mod rlimit;
+pub mod seccomp;
 pub mod structs;
 pub mod thread;
+pub mod usdt;
 
 use crate::sync::SpinNoIrqLock as Mutex;
 use core::{
@@ -21,8 +27,12 @@ use core::{
     pin::Pin,
     task::{Context, Poll},
 };
+pub use cputime::*;
+pub use cred::*;
 pub use futex::*;
+pub use itimer::*;
 pub use proc::*;
+pub use rlimit::*;
 pub use structs::*;
 pub use thread::*;
 
@@ -1,5 +1,10 @@
 use super::{
     abi::{self, ProcInitInfo},
+    cputime::CpuTime,
+    cred::Credentials,
+    itimer::ProcessTimers,
+    rlimit::*,
+    seccomp::Seccomp,
     Futex, Tid,
 };
 use crate::arch::paging::*;
@@ -12,15 +17,17 @@ use crate::process::thread::THREADS;
 use crate::sync::{Event, EventBus, SpinLock, SpinNoIrqLock as Mutex};
 use crate::{
     signal::{Siginfo, Signal, SignalAction, SignalStack, Sigset},
-    syscall::handle_syscall,
+    syscall::{handle_syscall, SysError},
 };
 use alloc::{
     boxed::Box, collections::BTreeMap, collections::VecDeque, string::String, sync::Arc,
     sync::Weak, vec::Vec,
 };
 use bitflags::_core::cell::Ref;
+use core::convert::TryInto;
 use core::fmt;
 use core::str;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use core::{
     future::Future,
     mem::MaybeUninit,
@@ -70,10 +77,42 @@ impl fmt::Display for Pid {
 /// process group id type
 pub type Pgid = i32;
 
+/// Length of the executable name kept for probes, including the NUL
+pub const COMM_LEN: usize = 16;
+
+/// Ids and executable name of a process, shared with its threads, which probes
+/// read without locking the process. Kept up to date by `Process::update_ids`.
+#[derive(Default)]
+pub struct ProcessIds {
+    pub pid: AtomicUsize,
+    pub ppid: AtomicUsize,
+    pub pgid: AtomicUsize,
+    /// real gid << 32 | real uid
+    pub uid_gid: AtomicU64,
+    comm: [AtomicU64; COMM_LEN / 8],
+}
+
+impl ProcessIds {
+    /// The file name of the executable, truncated and NUL padded
+    pub fn comm(&self) -> [u8; COMM_LEN] {
+        let mut comm = [0u8; COMM_LEN];
+        for (chunk, word) in comm.chunks_exact_mut(8).zip(self.comm.iter()) {
+            chunk.copy_from_slice(&word.load(Ordering::Relaxed).to_ne_bytes());
+        }
+        comm
+    }
+}
+
 pub struct Process {
     /// Virtual memory
     pub vm: Arc<Mutex<MemorySet>>,
 
+    /// Start of the heap, right after the program
+    pub brk_start: usize,
+
+    /// Program break, the end of the heap
+    pub brk: usize,
+
     /// Opened files
     pub files: BTreeMap<usize, FileLike>,
 
@@ -92,6 +131,9 @@ pub struct Process {
     /// Pid i.e. tgid, usually the tid of first thread
     pub pid: Pid,
 
+    /// Copy of the ids for probes
+    pub ids: Arc<ProcessIds>,
+
     //// Process group id
     pub pgid: Pgid,
 
@@ -109,7 +151,7 @@ pub struct Process {
     /// Events like exiting
     pub eventbus: Arc<Mutex<EventBus>>,
 
-    /// Exit code
+    /// Exit status reported by wait
     pub exit_code: usize,
 
     // delivered signals, tid specified thread, -1 stands for any thread
@@ -122,6 +164,27 @@ pub struct Process {
 
     /// shared memory
     pub shm_identifiers: ShmProc,
+
+    /// System call filters
+    pub seccomp: Seccomp,
+
+    /// User and group credentials
+    pub cred: Credentials,
+
+    /// No privileges are gained on exec, set by prctl(PR_SET_NO_NEW_PRIVS)
+    pub no_new_privs: bool,
+
+    /// Resource limits
+    pub rlimits: RLimits,
+
+    /// CPU time of all threads
+    pub cpu_time: CpuTime,
+
+    /// CPU time of waited-for children
+    pub children_cpu_time: CpuTime,
+
+    /// Interval timers and POSIX timers
+    pub timers: ProcessTimers,
 }
 
 lazy_static! {
@@ -159,28 +222,106 @@ pub fn add_to_process_table(proc: Arc<Mutex<Process>>, pid: Pid) {
     let mut process_table = PROCESSES.write();
 
     // set pid
-    proc.lock().pid = pid;
+    let mut proc_lock = proc.lock();
+    proc_lock.pid = pid;
+    proc_lock.update_ids();
+    drop(proc_lock);
 
     // put to process table
     process_table.insert(pid.get(), proc.clone());
 }
 
+/// Wait status of a process exited with `exit_code`
+pub fn exited_status(exit_code: usize) -> usize {
+    (exit_code & 0xff) << 8
+}
+
+/// Wait status of a process terminated by `signal`
+pub fn signaled_status(signal: Signal, core_dumped: bool) -> usize {
+    signal as usize | if core_dumped { 0x80 } else { 0 }
+}
+
 impl Process {
     /// Get lowest free fd
-    fn get_free_fd(&self) -> usize {
-        (0..).find(|i| !self.files.contains_key(i)).unwrap()
+    fn get_free_fd(&self) -> Result<usize, SysError> {
+        self.get_free_fd_from(0)
     }
 
-    /// get the lowest available fd great than or equal to arg
-    pub fn get_free_fd_from(&self, arg: usize) -> usize {
-        (arg..).find(|i| !self.files.contains_key(i)).unwrap()
+    /// get the lowest available fd great than or equal to arg, below RLIMIT_NOFILE
+    pub fn get_free_fd_from(&self, arg: usize) -> Result<usize, SysError> {
+        let limit = self.rlimits.cur(RLIMIT_NOFILE);
+        if arg >= limit {
+            return Err(SysError::EINVAL);
+        }
+        (arg..limit)
+            .find(|i| !self.files.contains_key(i))
+            .ok_or(SysError::EMFILE)
     }
 
     /// Add a file to the process, return its fd.
-    pub fn add_file(&mut self, file_like: FileLike) -> usize {
-        let fd = self.get_free_fd();
+    pub fn add_file(&mut self, file_like: FileLike) -> Result<usize, SysError> {
+        let fd = self.get_free_fd().map_err(|_| SysError::EMFILE)?;
         self.files.insert(fd, file_like);
-        fd
+        Ok(fd)
+    }
+
+    /// Account `time` run by the threads. Return the signals to send:
+    /// those of expired ITIMER_VIRTUAL and ITIMER_PROF, and for RLIMIT_CPU
+    /// SIGXCPU every second over the soft limit, and SIGKILL at the hard limit,
+    /// for which `send_cpu_time_signals` kills the process outright.
+    pub fn account_cpu_time(&mut self, time: CpuTime) -> [Option<Signal>; 3] {
+        let [virt, prof] = self.timers.count_down(time);
+        let last_secs = self.cpu_time.total().as_secs() as usize;
+        self.cpu_time += time;
+        let secs = self.cpu_time.total().as_secs() as usize;
+        let limit = if secs == last_secs {
+            None
+        } else if secs >= self.rlimits.max(RLIMIT_CPU) {
+            Some(Signal::SIGKILL)
+        } else if secs >= self.rlimits.cur(RLIMIT_CPU) {
+            Some(Signal::SIGXCPU)
+        } else {
+            None
+        };
+        [virt, prof, limit]
+    }
+
+    /// Publish the ids and executable name after they change
+    pub fn update_ids(&self) {
+        let ids = &self.ids;
+        ids.pid.store(self.pid.get(), Ordering::Relaxed);
+        ids.ppid.store(self.parent.0.get(), Ordering::Relaxed);
+        ids.pgid.store(self.pgid as usize, Ordering::Relaxed);
+        let uid_gid = ((self.cred.gid as u64) << 32) | self.cred.uid as u64;
+        ids.uid_gid.store(uid_gid, Ordering::Relaxed);
+        let mut comm = [0u8; COMM_LEN];
+        let name = self.exec_path.rsplit('/').next().unwrap_or("").as_bytes();
+        let len = name.len().min(COMM_LEN - 1);
+        comm[..len].copy_from_slice(&name[..len]);
+        for (chunk, word) in comm.chunks_exact(8).zip(ids.comm.iter()) {
+            word.store(u64::from_ne_bytes(chunk.try_into().unwrap()), Ordering::Relaxed);
+        }
+    }
+
+    /// `path` made absolute from the working directory, without `.`, `..` or
+    /// repeated slashes, so that probes of a binary are found under one name
+    /// however it is executed or attached to. Symbolic links are kept.
+    pub fn canonical_path(&self, path: &str) -> String {
+        let cwd = if path.starts_with('/') { "" } else { self.cwd.as_str() };
+        let mut parts: Vec<&str> = Vec::new();
+        for part in cwd.split('/').chain(path.split('/')) {
+            match part {
+                "" | "." => {}
+                ".." => {
+                    parts.pop();
+                }
+                part => parts.push(part),
+            }
+        }
+        if parts.is_empty() {
+            return String::from("/");
+        }
+        parts.iter().map(|part| format!("/{}", part)).collect()
     }
 
     /// Get futex by addr
@@ -192,7 +333,7 @@ impl Process {
     }
 
     /// Exit the process.
-    /// Kill all threads and notify parent with the exit code.
+    /// Kill all threads and notify parent with the exit status.
     pub fn exit(&mut self, exit_code: usize) {
         // avoid some strange dead lock
         // self.files.clear(); this does not work sometime, for unknown reason
@@ -203,6 +344,18 @@ impl Process {
             drop(file);
         }
 
+        // charge the time of running threads before the parent may wait for it
+        let threads = {
+            let thread_table = THREADS.read();
+            self.threads
+                .iter()
+                .filter_map(|tid| thread_table.get(tid).cloned())
+                .collect::<Vec<_>>()
+        };
+        for thread in threads.iter() {
+            thread.charge_cpu_time(self);
+        }
+
         // notify parent and fill exit code
         self.eventbus.lock().set(Event::PROCESS_QUIT);
         if let Some(parent) = self.parent.1.upgrade() {
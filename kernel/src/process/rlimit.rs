@@ -0,0 +1,118 @@
+//! Resource limits of a process
+//!
+//! Limits belong to the process, they are inherited by `fork` and kept across
+//! `execve`. The soft limit is the one enforced, and it can be changed freely
+//! up to the hard limit, which can only be lowered.
+
+use crate::consts::{MAX_PROCESS_NUM, USER_STACK_SIZE};
+use crate::syscall::SysError;
+
+pub const RLIMIT_CPU: usize = 0;
+pub const RLIMIT_FSIZE: usize = 1;
+pub const RLIMIT_DATA: usize = 2;
+pub const RLIMIT_STACK: usize = 3;
+pub const RLIMIT_CORE: usize = 4;
+pub const RLIMIT_RSS: usize = 5;
+pub const RLIMIT_NPROC: usize = 6;
+pub const RLIMIT_NOFILE: usize = 7;
+pub const RLIMIT_MEMLOCK: usize = 8;
+pub const RLIMIT_AS: usize = 9;
+pub const RLIMIT_LOCKS: usize = 10;
+pub const RLIMIT_SIGPENDING: usize = 11;
+pub const RLIMIT_MSGQUEUE: usize = 12;
+pub const RLIMIT_NICE: usize = 13;
+pub const RLIMIT_RTPRIO: usize = 14;
+pub const RLIMIT_RTTIME: usize = 15;
+pub const RLIM_NLIMITS: usize = 16;
+
+pub const RLIM_INFINITY: u64 = u64::max_value();
+
+/// Default size of the heap
+pub const DATA_LIMIT: u64 = 0x4000_0000; // 1 GB
+
+/// Default number of opened files
+const NOFILE_LIMIT: u64 = 1024;
+const NOFILE_MAX: u64 = 4096;
+
+/// Default number of queued signals, which also caps POSIX timers
+const SIGPENDING_LIMIT: u64 = 4096;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RLimit {
+    pub cur: u64, // soft limit
+    pub max: u64, // hard limit
+}
+
+impl RLimit {
+    pub const INFINITY: RLimit = RLimit::new(RLIM_INFINITY, RLIM_INFINITY);
+
+    pub const fn new(cur: u64, max: u64) -> Self {
+        RLimit { cur, max }
+    }
+}
+
+/// All resource limits of a process
+#[derive(Debug, Clone)]
+pub struct RLimits([RLimit; RLIM_NLIMITS]);
+
+impl Default for RLimits {
+    fn default() -> Self {
+        let mut limits = [RLimit::INFINITY; RLIM_NLIMITS];
+        limits[RLIMIT_DATA] = RLimit::new(DATA_LIMIT, RLIM_INFINITY);
+        limits[RLIMIT_STACK] = RLimit::new(USER_STACK_SIZE as u64, RLIM_INFINITY);
+        limits[RLIMIT_CORE] = RLimit::new(0, RLIM_INFINITY);
+        limits[RLIMIT_NPROC] = RLimit::new(MAX_PROCESS_NUM as u64, MAX_PROCESS_NUM as u64);
+        limits[RLIMIT_NOFILE] = RLimit::new(NOFILE_LIMIT, NOFILE_MAX);
+        limits[RLIMIT_MEMLOCK] = RLimit::new(0x10000, 0x10000);
+        limits[RLIMIT_NICE] = RLimit::new(0, 0);
+        limits[RLIMIT_RTPRIO] = RLimit::new(0, 0);
+        limits[RLIMIT_SIGPENDING] = RLimit::new(SIGPENDING_LIMIT, SIGPENDING_LIMIT);
+        RLimits(limits)
+    }
+}
+
+impl RLimits {
+    /// Get the limit of `resource`
+    pub fn get(&self, resource: usize) -> Result<RLimit, SysError> {
+        self.0.get(resource).cloned().ok_or(SysError::EINVAL)
+    }
+
+    /// Set the limit of `resource`, the hard limit can only be raised if `privileged`
+    pub fn set(
+        &mut self,
+        resource: usize,
+        limit: RLimit,
+        privileged: bool,
+    ) -> Result<(), SysError> {
+        let old = self.0.get_mut(resource).ok_or(SysError::EINVAL)?;
+        if limit.cur > limit.max {
+            return Err(SysError::EINVAL);
+        }
+        if limit.max > old.max && !privileged {
+            return Err(SysError::EPERM);
+        }
+        *old = limit;
+        Ok(())
+    }
+
+    /// The soft limit of `resource`, saturated to `usize`
+    pub fn cur(&self, resource: usize) -> usize {
+        let cur = self.0[resource].cur;
+        if cur > usize::max_value() as u64 {
+            usize::max_value()
+        } else {
+            cur as usize
+        }
+    }
+
+    /// The hard limit of `resource`, saturated to `usize`
+    pub fn max(&self, resource: usize) -> usize {
+        let max = self.0[resource].max;
+        if max > usize::max_value() as u64 {
+            usize::max_value()
+        } else {
+            max as usize
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! Seccomp: per-process system call filtering
+//!
+//! Filters are classic BPF programs translated to eBPF. They belong to the
+//! process, so all its threads are filtered alike; they are inherited by
+//! `fork` and kept across `execve`, and can only be added, never removed.
+
+use crate::arch::syscall::{SYS_EXIT, SYS_READ, SYS_RT_SIGRETURN, SYS_WRITE};
+use crate::ebpf::cbpf::{convert, CbpfContext, SockFilter};
+use crate::ebpf::prog::{prog_load, BpfProg, ProgType};
+use crate::ebpf::vm::interpret;
+use crate::syscall::SysError;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+pub const SECCOMP_MODE_DISABLED: usize = 0;
+pub const SECCOMP_MODE_STRICT: usize = 1;
+pub const SECCOMP_MODE_FILTER: usize = 2;
+
+pub const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+pub const SECCOMP_RET_KILL_THREAD: u32 = 0x0000_0000;
+pub const SECCOMP_RET_TRAP: u32 = 0x0003_0000;
+pub const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+pub const SECCOMP_RET_TRACE: u32 = 0x7ff0_0000;
+pub const SECCOMP_RET_LOG: u32 = 0x7ffc_0000;
+pub const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+pub const SECCOMP_RET_ACTION_FULL: u32 = 0xffff_0000;
+pub const SECCOMP_RET_DATA: u32 = 0x0000_ffff;
+
+/// Instructions of all filters of a process, counting 4 for each filter
+const MAX_INSNS_PER_PATH: usize = 32768;
+
+/// `AUDIT_ARCH_*` of the running kernel
+#[cfg(target_arch = "riscv64")]
+pub const AUDIT_ARCH: u32 = 0xc000_00f3;
+#[cfg(target_arch = "riscv32")]
+pub const AUDIT_ARCH: u32 = 0x4000_00f3;
+#[cfg(target_arch = "x86_64")]
+pub const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+pub const AUDIT_ARCH: u32 = 0xc000_00b7;
+#[cfg(target_arch = "mips")]
+pub const AUDIT_ARCH: u32 = 0x4000_0008;
+
+/// What a filter sees of a system call
+#[repr(C)]
+#[derive(Debug)]
+pub struct SeccompData {
+    pub nr: i32,
+    pub arch: u32,
+    pub instruction_pointer: u64,
+    pub args: [u64; 6],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeccompAction {
+    Allow,
+    /// allowed, and logged
+    Log,
+    /// fail with the errno
+    Errno(u16),
+    /// fail and raise SIGSYS with the value as `si_errno`
+    Trap(u16),
+    KillThread,
+    KillProcess,
+}
+
+#[derive(Clone, Default)]
+pub struct Seccomp {
+    pub mode: usize,
+    filters: Vec<Arc<BpfProg>>,
+}
+
+impl Seccomp {
+    /// Only allow read, write, exit and rt_sigreturn from now on.
+    pub fn set_strict(&mut self) -> Result<(), SysError> {
+        match self.mode {
+            SECCOMP_MODE_DISABLED | SECCOMP_MODE_STRICT => {
+                self.mode = SECCOMP_MODE_STRICT;
+                Ok(())
+            }
+            _ => Err(SysError::EINVAL),
+        }
+    }
+
+    /// Add a classic filter, run along with those installed before.
+    pub fn add_filter(&mut self, filter: &[SockFilter]) -> Result<(), SysError> {
+        if self.mode == SECCOMP_MODE_STRICT {
+            return Err(SysError::EINVAL);
+        }
+        let insns = convert(
+            filter,
+            CbpfContext::Seccomp {
+                size: size_of::<SeccompData>() as u32,
+            },
+        )?;
+        let total: usize = self
+            .filters
+            .iter()
+            .map(|prog| prog.insns.len() + 4)
+            .sum::<usize>()
+            + filter.len()
+            + 4;
+        if total > MAX_INSNS_PER_PATH {
+            return Err(SysError::ENOMEM);
+        }
+        let prog = prog_load(ProgType::Seccomp, insns, |_| None)?;
+        self.filters.push(prog);
+        self.mode = SECCOMP_MODE_FILTER;
+        Ok(())
+    }
+
+    /// Decide on a system call. With several filters the most restrictive
+    /// result wins.
+    pub fn check(&self, data: &SeccompData) -> SeccompAction {
+        match self.mode {
+            SECCOMP_MODE_DISABLED => SeccompAction::Allow,
+            SECCOMP_MODE_STRICT => match data.nr as usize {
+                SYS_READ | SYS_WRITE | SYS_EXIT | SYS_RT_SIGRETURN => SeccompAction::Allow,
+                _ => SeccompAction::KillThread,
+            },
+            _ => {
+                let ret = self
+                    .filters
+                    .iter()
+                    .map(|prog| interpret(prog, &[], data as *const SeccompData as u64) as u32)
+                    .min_by_key(|ret| (ret & SECCOMP_RET_ACTION_FULL) as i32)
+                    .unwrap_or(SECCOMP_RET_ALLOW);
+                let value = (ret & SECCOMP_RET_DATA) as u16;
+                match ret & SECCOMP_RET_ACTION_FULL {
+                    SECCOMP_RET_ALLOW => SeccompAction::Allow,
+                    SECCOMP_RET_LOG => SeccompAction::Log,
+                    SECCOMP_RET_ERRNO => SeccompAction::Errno(value),
+                    SECCOMP_RET_TRAP => SeccompAction::Trap(value),
+                    // there is no tracer to notify
+                    SECCOMP_RET_TRACE => SeccompAction::Errno(SysError::ENOSYS as u16),
+                    SECCOMP_RET_KILL_THREAD => SeccompAction::KillThread,
+                    _ => SeccompAction::KillProcess,
+                }
+            }
+        }
+    }
+}
+
+/// Whether `action` is known, for `SECCOMP_GET_ACTION_AVAIL`.
+pub fn action_avail(action: u32) -> bool {
+    match action {
+        SECCOMP_RET_KILL_PROCESS | SECCOMP_RET_KILL_THREAD | SECCOMP_RET_TRAP
+        | SECCOMP_RET_ERRNO | SECCOMP_RET_TRACE | SECCOMP_RET_LOG | SECCOMP_RET_ALLOW => true,
+        _ => false,
+    }
+}
@@ -1,4 +1,5 @@
 use super::abi::{self, ProcInitInfo};
+use super::usdt::{parse_stapsdt, usdt_record};
 use crate::arch::paging::*;
 use crate::fs::{FileHandle, FileLike, OpenOptions, FOLLOW_MAX_DEPTH};
 use crate::ipc::SemProc;
@@ -32,7 +33,7 @@ use trapframe::TrapFrame;
 use trapframe::UserContext;
 use xmas_elf::{
     header,
-    program::{Flags, SegmentData, Type},
+    program::{Flags, ProgramHeader, SegmentData, Type},
     ElfFile,
 };
 
@@ -55,36 +56,50 @@ impl ToMemoryAttr for Flags {
 
 /// Helper functions to process ELF file
 pub trait ElfExt {
-    /// Setup MemorySet according to the ELF file.
-    fn make_memory_set(&self, ms: &mut MemorySet, inode: &Arc<dyn INode>) -> usize;
+    /// Setup MemorySet according to the ELF file at `path`, and record its USDT probes.
+    /// Return the end of the program, where its heap starts.
+    fn make_memory_set(
+        &self,
+        ms: &mut MemorySet,
+        inode: &Arc<dyn INode>,
+        path: &str,
+    ) -> Result<usize, &'static str>;
 
     /// Get interpreter string if it has.
     fn get_interpreter(&self) -> Result<&str, &str>;
 
     /// Append current ELF file as interpreter into given memory set.
     /// This will insert the interpreter it a place which is "good enough" (since ld.so should be PIC).
+    /// Return the end of the interpreter.
     fn append_as_interpreter(
         &self,
         inode: &Arc<dyn INode>,
         memory_set: &mut MemorySet,
         bias: usize,
-    );
+    ) -> Result<usize, &'static str>;
 
     /// Get virtual address of PHDR section if it has.
     fn get_phdr_vaddr(&self) -> Option<u64>;
 }
 
 impl ElfExt for ElfFile<'_> {
-    fn make_memory_set(&self, ms: &mut MemorySet, inode: &Arc<dyn INode>) -> usize {
+    fn make_memory_set(
+        &self,
+        ms: &mut MemorySet,
+        inode: &Arc<dyn INode>,
+        path: &str,
+    ) -> Result<usize, &'static str> {
         debug!("creating MemorySet from ELF");
+        usdt_record(path, parse_stapsdt(self, inode));
         let mut farthest_memory: usize = 0;
         for ph in self.program_iter() {
             if ph.get_type() != Ok(Type::Load) {
                 continue;
             }
+            let end = segment_end(&ph, 0)?;
             ms.push(
                 ph.virtual_addr() as usize,
-                ph.virtual_addr() as usize + ph.mem_size() as usize,
+                end,
                 ph.flags().to_attr(),
                 File {
                     file: INodeForMap(inode.clone()),
@@ -95,23 +110,28 @@ impl ElfExt for ElfFile<'_> {
                 },
                 "elf",
             );
-            if ph.virtual_addr() as usize + ph.mem_size() as usize > farthest_memory {
-                farthest_memory = ph.virtual_addr() as usize + ph.mem_size() as usize;
-            }
+            farthest_memory = farthest_memory.max(end);
         }
 
-        Page::of_addr(farthest_memory + PAGE_SIZE).start_address()
+        page_end(farthest_memory)
     }
-    fn append_as_interpreter(&self, inode: &Arc<dyn INode>, ms: &mut MemorySet, bias: usize) {
+    fn append_as_interpreter(
+        &self,
+        inode: &Arc<dyn INode>,
+        ms: &mut MemorySet,
+        bias: usize,
+    ) -> Result<usize, &'static str> {
         debug!("inserting interpreter from ELF");
 
+        let mut farthest_memory: usize = bias;
         for ph in self.program_iter() {
             if ph.get_type() != Ok(Type::Load) {
                 continue;
             }
+            let end = segment_end(&ph, bias)?;
             ms.push(
                 ph.virtual_addr() as usize + bias,
-                ph.virtual_addr() as usize + ph.mem_size() as usize + bias,
+                end,
                 ph.flags().to_attr(),
                 File {
                     file: INodeForMap(inode.clone()),
@@ -121,8 +141,11 @@ impl ElfExt for ElfFile<'_> {
                     allocator: GlobalFrameAlloc,
                 },
                 "elf-interp",
-            )
+            );
+            farthest_memory = farthest_memory.max(end);
         }
+
+        page_end(farthest_memory)
     }
     fn get_interpreter(&self) -> Result<&str, &str> {
         let header = self
@@ -162,6 +185,21 @@ impl ElfExt for ElfFile<'_> {
     }
 }
 
+/// The end of the segment `ph` loaded at `bias`
+fn segment_end(ph: &ProgramHeader, bias: usize) -> Result<usize, &'static str> {
+    (ph.virtual_addr() as usize)
+        .checked_add(ph.mem_size() as usize)
+        .and_then(|end| end.checked_add(bias))
+        .ok_or("ELF segment out of address space")
+}
+
+/// `addr` rounded up to a page
+fn page_end(addr: usize) -> Result<usize, &'static str> {
+    addr.checked_add(PAGE_SIZE - 1)
+        .map(|addr| Page::of_addr(addr).start_address())
+        .ok_or("ELF segment out of address space")
+}
+
 #[derive(Clone)]
 pub struct INodeForMap(pub Arc<dyn INode>);
 
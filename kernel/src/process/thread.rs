@@ -1,25 +1,38 @@
 use super::{
     abi::{self, ProcInitInfo},
-    add_to_process_table, Pid, Process, PROCESSORS,
+    add_to_process_table,
+    cputime::CpuTime,
+    cred::Credentials,
+    current_thread,
+    itimer::ProcessTimers,
+    rlimit::RLimits,
+    seccomp::Seccomp,
+    signaled_status, Pid, Process, ProcessIds, PROCESSORS,
 };
 use crate::arch::interrupt::consts::{is_ebreak, is_intr, is_page_fault, is_reserved_inst, is_syscall, is_timer_intr};
-use crate::arch::interrupt::{get_trap_num, handle_reserved_inst};
+use crate::arch::interrupt::{get_trap_num, get_trap_signal, handle_reserved_inst};
 use crate::arch::{
     cpu,
     fp::FpState,
     memory::{get_page_fault_addr, set_page_table},
     paging::*,
+    timer::timer_now,
 };
 use crate::drivers::IRQ_MANAGER;
 use crate::fs::{FileHandle, FileLike, OpenOptions, FOLLOW_MAX_DEPTH};
 use crate::ipc::{SemProc, ShmProc};
 use crate::memory::{
     phys_to_virt, ByFrame, Delay, File, GlobalFrameAlloc, KernelStack, MemoryAttr, MemorySet, Read,
+    Swap,
 };
 use crate::process::structs::ElfExt;
+use crate::swap::GlobalSwap;
 use crate::sync::{EventBus, SpinLock, SpinNoIrqLock as Mutex};
 use crate::{
-    signal::{handle_signal, Siginfo, Signal, SignalAction, SignalStack, Sigset},
+    signal::{
+        handle_signal, send_fault_signal, send_signal, Siginfo, Signal, SignalAction, SignalStack,
+        BUS_ADRERR, Sigset, SEGV_ACCERR, SEGV_MAPERR, SI_KERNEL,
+    },
     syscall::handle_syscall,
 };
 use alloc::{
@@ -34,6 +47,7 @@ use core::{
     mem::MaybeUninit,
     pin::Pin,
     task::{Context, Poll},
+    time::Duration,
 };
 use log::*;
 use num::FromPrimitive;
@@ -73,6 +87,11 @@ pub struct ThreadInner {
     pub sig_mask: Sigset,
     /// signal alternate stack
     pub signal_alternate_stack: SignalStack,
+    /// CPU time of this thread
+    pub cpu_time: CpuTime,
+    /// Timer and user time when the CPU time was last charged to the process,
+    /// None when the thread is not polled
+    charged: Option<(Duration, Duration)>,
 }
 
 #[allow(dead_code)]
@@ -83,6 +102,8 @@ pub struct Thread {
     pub vm: Arc<Mutex<MemorySet>>,
     /// The process that this thread belongs to
     pub proc: Arc<Mutex<Process>>,
+    /// This is same as `proc.ids`, read without locking the process
+    pub ids: Arc<ProcessIds>,
     /// Thread id
     pub tid: Tid,
 }
@@ -111,14 +132,17 @@ impl Thread {
         self_ref
     }
 
-    /// Construct virtual memory of a new user process from ELF at `inode`.
-    /// Return `(MemorySet, entry_point, ustack_top)`
+    /// Construct virtual memory of a new user process from ELF at `inode`,
+    /// with a user stack of `stack_size` bytes (RLIMIT_STACK).
+    /// Return `(entry_point, ustack_top, brk)`
     pub fn new_user_vm(
         inode: &Arc<dyn INode>,
+        path: &str,
         args: Vec<String>,
         envs: Vec<String>,
+        stack_size: usize,
         vm: &mut MemorySet,
-    ) -> Result<(usize, usize), &'static str> {
+    ) -> Result<(usize, usize, usize), &'static str> {
         // Read ELF header
         // 0x3c0: magic number from ld-musl.so
         let mut data = [0u8; 0x3c0];
@@ -165,7 +189,9 @@ impl Thread {
         let mut entry_addr = elf.header.pt2.entry_point() as usize;
         // Make page table
         vm.clear();
-        let bias = elf.make_memory_set(vm, inode);
+        let mut brk = elf.make_memory_set(vm, inode, path)?;
+        // the interpreter is loaded right after the program
+        let bias = brk;
 
         // Check interpreter (for dynamic link)
         // When interpreter is used, map both dynamic linker and executable
@@ -181,7 +207,8 @@ impl Thread {
                 .read_at(0, &mut interp_data)
                 .map_err(|_| "failed to read from INode")?;
             let elf_interp = ElfFile::new(&interp_data)?;
-            elf_interp.append_as_interpreter(&interp_inode, vm, bias);
+            // and the heap starts after the interpreter
+            brk = elf_interp.append_as_interpreter(&interp_inode, vm, bias)?;
 
             // update auxiliary vector
             auxv.insert(abi::AT_ENTRY, elf.header.pt2.entry_point() as usize);
@@ -194,16 +221,19 @@ impl Thread {
 
         // User stack
         use crate::consts::{USER_STACK_OFFSET, USER_STACK_SIZE};
+        // no more than 256 MB, the whole stack is mapped lazily
+        const MAX_STACK_SIZE: usize = 0x1000_0000;
+        let stack_size = Page::of_addr(stack_size.min(MAX_STACK_SIZE)).start_address();
         let mut ustack_top = {
-            let ustack_buttom = USER_STACK_OFFSET;
             let ustack_top = USER_STACK_OFFSET + USER_STACK_SIZE;
+            let ustack_buttom = ustack_top - stack_size.max(PAGE_SIZE * 8);
 
             // user stack except top 4 pages
             vm.push(
                 ustack_buttom,
                 ustack_top - PAGE_SIZE * 4,
                 MemoryAttr::default().user().execute(),
-                Delay::new(GlobalFrameAlloc),
+                Swap::new(GlobalFrameAlloc, GlobalSwap),
                 "user_stack_delay",
             );
 
@@ -224,7 +254,7 @@ impl Thread {
             vm.with(|| ustack_top = init_info.push_at(ustack_top));
         }
 
-        Ok((entry_addr, ustack_top))
+        Ok((entry_addr, ustack_top, brk))
     }
 
     /// Make a new user process from ELF `data`
@@ -236,7 +266,15 @@ impl Thread {
     ) -> Arc<Thread> {
         // get virtual memory info
         let mut vm = MemorySet::new();
-        let (entry_addr, ustack_top) = Self::new_user_vm(inode, args, envs, &mut vm).unwrap();
+        let (entry_addr, ustack_top, brk) = Self::new_user_vm(
+            inode,
+            exec_path,
+            args,
+            envs,
+            crate::consts::USER_STACK_SIZE,
+            &mut vm,
+        )
+        .unwrap();
 
         let vm_token = vm.token();
         let vm = Arc::new(Mutex::new(vm));
@@ -319,6 +357,7 @@ impl Thread {
             context.status |= 1 << 15 | 1 << 14 | 1 << 13 | 1 << 12;
         }
 
+        let ids = Arc::new(ProcessIds::default());
         let thread = Thread {
             tid: 0, // allocated below
             inner: Mutex::new(ThreadInner {
@@ -329,16 +368,22 @@ impl Thread {
                 clear_child_tid: 0,
                 sig_mask: Sigset::default(),
                 signal_alternate_stack: SignalStack::default(),
+                cpu_time: CpuTime::default(),
+                charged: None,
             }),
             vm: vm.clone(),
+            ids: ids.clone(),
             proc: Arc::new(Mutex::new(Process {
                 vm,
+                brk_start: brk,
+                brk,
                 files,
                 cwd: String::from("/"),
                 exec_path: String::from(exec_path),
                 futexes: BTreeMap::default(),
                 semaphores: SemProc::default(),
                 pid: Pid::new(), // allocated later
+                ids,
                 pgid: 0,
                 parent: (Pid::new(), Weak::new()),
                 children: Vec::new(),
@@ -349,6 +394,13 @@ impl Thread {
                 dispositions: [SignalAction::default(); Signal::RTMAX + 1],
                 eventbus: EventBus::new(),
                 shm_identifiers: ShmProc::default(),
+                seccomp: Seccomp::default(),
+                cred: Credentials::default(),
+                no_new_privs: false,
+                rlimits: RLimits::default(),
+                cpu_time: CpuTime::default(),
+                children_cpu_time: CpuTime::default(),
+                timers: ProcessTimers::default(),
             })),
         };
 
@@ -362,9 +414,10 @@ impl Thread {
 
     /// Fork a new process from current one
     /// Only current process is persisted
-    pub fn fork(&self, tf: &UserContext) -> Arc<Thread> {
+    /// Return None if there is no memory left for it
+    pub fn fork(&self, tf: &UserContext) -> Option<Arc<Thread>> {
         // clone virtual memory
-        let vm = self.vm.lock().clone();
+        let vm = self.vm.lock().clone()?;
         let vm_token = vm.token();
         let vm = Arc::new(Mutex::new(vm));
 
@@ -374,14 +427,18 @@ impl Thread {
 
         let mut proc = self.proc.lock();
 
+        let ids = Arc::new(ProcessIds::default());
         let new_proc = Arc::new(Mutex::new(Process {
             vm: vm.clone(),
+            brk_start: proc.brk_start,
+            brk: proc.brk,
             files: proc.files.clone(), // share open file descriptions
             cwd: proc.cwd.clone(),
             exec_path: proc.exec_path.clone(),
             futexes: BTreeMap::default(),
             semaphores: proc.semaphores.clone(),
             pid: Pid::new(), // assigned later
+            ids: ids.clone(),
             pgid: proc.pgid,
             parent: (proc.pid.clone(), Arc::downgrade(&self.proc)),
             children: Vec::new(),
@@ -392,6 +449,13 @@ impl Thread {
             dispositions: proc.dispositions.clone(),
             eventbus: EventBus::new(),
             shm_identifiers: proc.shm_identifiers.clone(),
+            seccomp: proc.seccomp.clone(),
+            cred: proc.cred.clone(),
+            no_new_privs: proc.no_new_privs,
+            rlimits: proc.rlimits.clone(),
+            cpu_time: CpuTime::default(),
+            children_cpu_time: CpuTime::default(),
+            timers: ProcessTimers::default(),
         }));
 
         // new thread
@@ -411,9 +475,12 @@ impl Thread {
                 clear_child_tid: 0,
                 sig_mask,
                 signal_alternate_stack: sigaltstack,
+                cpu_time: CpuTime::default(),
+                charged: None,
             }),
             vm,
             proc: new_proc,
+            ids,
         }
         .add_to_table();
 
@@ -426,7 +493,7 @@ impl Thread {
         proc.children
             .push((child_pid, Arc::downgrade(&new_thread.proc)));
 
-        new_thread
+        Some(new_thread)
     }
 
     /// Create a new thread in the same process.
@@ -456,9 +523,12 @@ impl Thread {
                 context: Some(thread_context),
                 sig_mask,
                 signal_alternate_stack: sigaltstack,
+                cpu_time: CpuTime::default(),
+                charged: None,
             }),
             vm: self.vm.clone(),
             proc: self.proc.clone(),
+            ids: self.ids.clone(),
         };
         let res = thread.add_to_table();
         res.proc.lock().threads.push(res.tid);
@@ -473,6 +543,40 @@ impl Thread {
         self.inner.lock().context = Some(cx);
     }
 
+    /// Charge the time since the thread was last charged to it and to `proc`,
+    /// as system time except for the time it ran in user mode.
+    /// Return the signals to send to `proc` for its CPU time.
+    pub fn charge_cpu_time(&self, proc: &mut Process) -> [Option<Signal>; 3] {
+        let mut inner = self.inner.lock();
+        let (start, user) = match inner.charged {
+            Some(charged) => charged,
+            None => return [None; 3],
+        };
+        let now = timer_now();
+        let user_now = inner.cpu_time.user;
+        inner.charged = Some((now, user_now));
+        let user = user_now - user;
+        let system = (now - start).checked_sub(user).unwrap_or_default();
+        inner.cpu_time.system += system;
+        drop(inner);
+        proc.account_cpu_time(CpuTime { user, system })
+    }
+
+    /// Charge the rest of a poll of the thread, unless its process exited,
+    /// which charged the time of its threads already.
+    fn account_poll(&self) {
+        let signals = {
+            let mut proc = self.proc.lock();
+            if proc.exited() {
+                [None; 3]
+            } else {
+                self.charge_cpu_time(&mut proc)
+            }
+        };
+        self.inner.lock().charged = None;
+        send_cpu_time_signals(&self.proc, &signals);
+    }
+
     /// this thread has signal to handle
     pub fn has_signal_to_handle(&self) -> bool {
         self.proc
@@ -503,8 +607,11 @@ pub fn spawn(thread: Arc<Thread>) {
 
             trace!("go to user: {:#x?}", cx);
             thread_context.fp.restore();
+            let start = timer_now();
             cx.run();
+            let user_time = timer_now() - start;
             thread_context.fp.save();
+            thread.inner.lock().cpu_time.user += user_time;
             let trap_num = get_trap_num(&cx);
             trace!("back from user: {:#x?} trap_num {:#x}", cx, trap_num);
             let mut exit = false;
@@ -534,16 +641,14 @@ pub fn spawn(thread: Arc<Thread>) {
                             _ => unreachable!(),
                         };
                         if !handle_user_page_fault_ext(&thread, addr, access_type) {
-                            // TODO: SIGSEGV
-                            panic!("page fault handle failed");
+                            send_page_fault_signal(&thread, addr);
                         }
                     }
                     #[cfg(not(any(target_arch = "riscv32", target_arch = "riscv64")))]
                     {
                         use crate::arch::interrupt::handle_user_page_fault;
                         if !handle_user_page_fault(&thread, addr) {
-                            // TODO: SIGSEGV
-                            panic!("page fault handle failed");
+                            send_page_fault_signal(&thread, addr);
                         }
                     }
                 }
@@ -552,28 +657,29 @@ pub fn spawn(thread: Arc<Thread>) {
                     crate::arch::interrupt::ack(trap_num);
                     trace!("handle irq {:#x}", trap_num);
                     if is_timer_intr(trap_num) {
-                        do_yield = true;
                         crate::arch::interrupt::timer();
+                        do_yield = crate::sched::tick();
+                        let signals = thread.charge_cpu_time(&mut thread.proc.lock());
+                        exit = send_cpu_time_signals(&thread.proc, &signals);
                     }
                     IRQ_MANAGER.read().try_handle_interrupt(Some(trap_num));
                 }
                 _ if is_reserved_inst(trap_num) => {
                     if !handle_reserved_inst(cx) {
-                        panic!(
-                            "unhandled reserved intr in thread {} trap {:#x} {:x?}",
-                            thread.tid, trap_num, cx
-                        );
+                        let (signal, code, addr) = get_trap_signal(trap_num, cx);
+                        send_fault_signal(&thread, signal, code, addr);
                     }
                 }
                 _ if is_ebreak(trap_num) => {
-                    uprobes_trap_handler(cx);
+                    if !uprobes_trap_handler(cx) {
+                        let (signal, code, addr) = get_trap_signal(trap_num, cx);
+                        send_fault_signal(&thread, signal, code, addr);
+                    }
                 }
                 _ => {
-                    // println!("{:x?}", cx);
-                    panic!(
-                        "unhandled trap in thread {} trap {:#x} {:x?}",
-                        thread.tid, trap_num, cx
-                    );
+                    info!("unhandled trap in thread {} trap {:#x}", thread.tid, trap_num);
+                    let (signal, code, addr) = get_trap_signal(trap_num, cx);
+                    send_fault_signal(&thread, signal, code, addr);
                 }
             }
 
@@ -595,16 +701,70 @@ pub fn spawn(thread: Arc<Thread>) {
     spawn_thread(Box::pin(future), vmtoken, temp);
 }
 
+/// Send the signals of ITIMER_VIRTUAL, ITIMER_PROF and RLIMIT_CPU to `proc`.
+/// At the hard limit of RLIMIT_CPU the process is killed outright rather than
+/// sent SIGKILL, which would wait for a thread to take it.
+/// Return whether the process was killed.
+pub fn send_cpu_time_signals(proc: &Arc<Mutex<Process>>, signals: &[Option<Signal>]) -> bool {
+    for signal in signals.iter().flatten() {
+        if let Signal::SIGKILL = signal {
+            let mut proc = proc.lock();
+            if !proc.exited() {
+                proc.exit(signaled_status(Signal::SIGKILL, false));
+            }
+            return true;
+        }
+        let info = Siginfo {
+            signo: *signal as i32,
+            errno: 0,
+            code: SI_KERNEL,
+            field: Default::default(),
+        };
+        send_signal(proc.clone(), -1, info);
+    }
+    false
+}
+
+/// Send SIGBUS to `thread` for a page fault at `addr` on a page that can not
+/// be swapped in, or SIGSEGV for one that can not be handled otherwise
+fn send_page_fault_signal(thread: &Arc<Thread>, addr: usize) {
+    let (mapped, token) = {
+        let vm = thread.vm.lock();
+        (vm.iter().any(|area| area.contains(addr)), vm.token())
+    };
+    if mapped && crate::swap::is_swapped(token, addr) {
+        send_fault_signal(thread, Signal::SIGBUS, BUS_ADRERR, addr);
+        return;
+    }
+    let code = if mapped { SEGV_ACCERR } else { SEGV_MAPERR };
+    send_fault_signal(thread, Signal::SIGSEGV, code, addr);
+}
+
 fn spawn_thread(
     future: Pin<Box<dyn Future<Output = ()> + Send + 'static>>,
     vmtoken: usize,
     thread: Arc<Thread>,
 ) {
-    executor::spawn(PageTableSwitchWrapper {
-        inner: Mutex::new(future),
-        vmtoken,
-        thread,
-    });
+    // a new thread is scheduled like the thread creating it
+    let current = current_thread();
+    let param = current
+        .as_ref()
+        .and_then(|current| crate::sched::thread_param(current.tid))
+        .unwrap_or_default();
+    let affinity = current
+        .as_ref()
+        .and_then(|current| crate::sched::thread_affinity(current.tid))
+        .unwrap_or_else(crate::sched::CpuSet::all);
+    crate::sched::spawn_thread(
+        thread.tid,
+        param,
+        affinity,
+        Box::pin(PageTableSwitchWrapper {
+            inner: Mutex::new(future),
+            vmtoken,
+            thread,
+        }),
+    );
 }
 
 #[must_use = "future does nothing unless polled/`await`-ed"]
@@ -625,7 +785,13 @@ impl Future for PageTableSwitchWrapper {
         }
         // vmtoken won't change
         set_page_table(self.vmtoken);
+        {
+            let mut inner = self.thread.inner.lock();
+            let user = inner.cpu_time.user;
+            inner.charged = Some((timer_now(), user));
+        }
         let res = self.inner.lock().as_mut().poll(cx);
+        self.thread.account_poll();
         unsafe {
             PROCESSORS[cpu_id] = None;
         }
@@ -0,0 +1,342 @@
+//! USDT: statically defined tracepoints of user programs.
+//!
+//! `DTRACE_PROBE` and `STAP_PROBE` from `<sys/sdt.h>` leave a `nop` at the
+//! probe site and describe it in a `.note.stapsdt` ELF note: the address,
+//! the provider and name, and where each argument can be found, e.g.
+//! `-4@a0 8@16(sp) 4@$1`. Unattached probes cost nothing but the `nop`.
+//! Semaphores guarding probes are not incremented, so a probe behind one
+//! never fires.
+
+use crate::fs::FOLLOW_MAX_DEPTH;
+use crate::memory::copy_from_user;
+use alloc::collections::btree_map::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use rcore_fs::vfs::INode;
+use spin::Mutex;
+use trapframe::UserContext;
+use xmas_elf::{header, ElfFile};
+
+const NT_STAPSDT: u32 = 3;
+const SHT_NOTE: u32 = 7;
+
+/// The most arguments a probe can have
+pub const USDT_MAX_ARGS: usize = 12;
+
+/// Where an argument is found when the probe fires
+#[derive(Debug, Clone, PartialEq)]
+pub enum UsdtArgLoc {
+    /// a constant
+    Imm(i64),
+    /// a register, by number
+    Reg(usize),
+    /// memory at a register plus an offset
+    Mem { reg: usize, offset: isize },
+}
+
+#[derive(Debug, Clone)]
+pub struct UsdtArg {
+    /// size in bytes, 1, 2, 4 or 8
+    pub size: usize,
+    pub signed: bool,
+    pub loc: UsdtArgLoc,
+}
+
+#[derive(Debug, Clone)]
+pub struct UsdtProbe {
+    pub provider: String,
+    pub name: String,
+    /// address of the probe site
+    pub addr: usize,
+    /// address of the semaphore, 0 if there is none
+    pub semaphore: usize,
+    pub args: Vec<UsdtArg>,
+}
+
+/// What a program attached to a USDT probe is run on, passed in r1.
+#[repr(C)]
+pub struct UsdtContext {
+    /// arguments, sign or zero extended to 64 bits
+    pub args: [u64; USDT_MAX_ARGS],
+    pub nr_args: u64,
+    /// the `UserContext` of the thread that hit the probe
+    pub regs: u64,
+}
+
+lazy_static! {
+    /// Probes of the binaries executed so far, by path.
+    static ref USDT_PROBES: Mutex<BTreeMap<String, Arc<Vec<UsdtProbe>>>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// Register number of a riscv register name.
+fn reg_number(name: &str) -> Option<usize> {
+    const ABI_NAMES: [&str; 32] = [
+        "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+        "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+        "t5", "t6",
+    ];
+    if name == "fp" {
+        return Some(8);
+    }
+    if let Some(n) = ABI_NAMES.iter().position(|&abi| abi == name) {
+        return Some(n);
+    }
+    match name.strip_prefix('x')?.parse::<usize>() {
+        Ok(n) if n < 32 => Some(n),
+        _ => None,
+    }
+}
+
+fn parse_int(s: &str) -> Option<i64> {
+    let (neg, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s),
+    };
+    let value = match s.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None => s.parse::<i64>().ok()?,
+    };
+    Some(if neg { -value } else { value })
+}
+
+/// Parse one argument specifier, `[-]size@operand`.
+fn parse_arg(spec: &str) -> Option<UsdtArg> {
+    let at = spec.find('@')?;
+    let size = parse_int(&spec[..at])?;
+    let operand = &spec[at + 1..];
+    let loc = if let Some(imm) = operand.strip_prefix('$') {
+        UsdtArgLoc::Imm(parse_int(imm)?)
+    } else if let Some(open) = operand.find('(') {
+        let reg = operand[open + 1..].strip_suffix(')')?;
+        let offset = match &operand[..open] {
+            "" => 0,
+            offset => parse_int(offset)? as isize,
+        };
+        UsdtArgLoc::Mem {
+            reg: reg_number(reg)?,
+            offset,
+        }
+    } else if let Some(reg) = reg_number(operand) {
+        UsdtArgLoc::Reg(reg)
+    } else {
+        // riscv prints constants without `$`
+        UsdtArgLoc::Imm(parse_int(operand)?)
+    };
+    match size.abs() {
+        1 | 2 | 4 | 8 => Some(UsdtArg {
+            size: size.abs() as usize,
+            signed: size < 0,
+            loc,
+        }),
+        _ => None,
+    }
+}
+
+impl UsdtArg {
+    /// The value of the argument when the probe is hit with `cx`.
+    pub fn fetch(&self, cx: &UserContext) -> Option<u64> {
+        let regs = &cx.general as *const _ as *const usize;
+        let reg = |n: usize| unsafe { *regs.add(n) };
+        let raw = match self.loc {
+            UsdtArgLoc::Imm(imm) => return Some(imm as u64),
+            UsdtArgLoc::Reg(n) => reg(n) as u64,
+            UsdtArgLoc::Mem { reg: n, offset } => {
+                let addr = reg(n).wrapping_add(offset as usize);
+                match self.size {
+                    1 => copy_from_user(addr as *const u8)? as u64,
+                    2 => copy_from_user(addr as *const u16)? as u64,
+                    4 => copy_from_user(addr as *const u32)? as u64,
+                    _ => copy_from_user(addr as *const u64)?,
+                }
+            }
+        };
+        let shift = 64 - self.size * 8;
+        Some(if self.signed {
+            (((raw << shift) as i64) >> shift) as u64
+        } else {
+            (raw << shift) >> shift
+        })
+    }
+}
+
+impl UsdtProbe {
+    /// Collect the arguments when the probe is hit with `cx`. Arguments in
+    /// unreadable memory are 0.
+    pub fn context(&self, cx: &UserContext) -> UsdtContext {
+        let mut args = [0u64; USDT_MAX_ARGS];
+        for (value, arg) in args.iter_mut().zip(self.args.iter()) {
+            *value = arg.fetch(cx).unwrap_or(0);
+        }
+        UsdtContext {
+            args,
+            nr_args: self.args.len() as u64,
+            regs: cx as *const UserContext as u64,
+        }
+    }
+}
+
+/// Read `len` bytes of `inode` at `offset`, which must be within the file.
+fn read_inode(inode: &Arc<dyn INode>, offset: usize, len: usize) -> Option<Vec<u8>> {
+    if offset.checked_add(len)? > inode.metadata().ok()?.size {
+        return None;
+    }
+    let mut buf = vec![0u8; len];
+    match inode.read_at(offset, &mut buf) {
+        Ok(read) if read == len => Some(buf),
+        _ => None,
+    }
+}
+
+fn read_word(data: &[u8], offset: usize, size: usize) -> usize {
+    let mut bytes = [0u8; 8];
+    bytes[..size].copy_from_slice(&data[offset..offset + size]);
+    u64::from_le_bytes(bytes) as usize
+}
+
+fn c_str(data: &[u8]) -> Option<(&str, &[u8])> {
+    let end = data.iter().position(|&b| b == 0)?;
+    let s = core::str::from_utf8(&data[..end]).ok()?;
+    Some((s, &data[end + 1..]))
+}
+
+/// A section header: (name offset, type, address, file offset, size)
+type Section = (usize, u32, usize, usize, usize);
+
+/// Parse the `.note.stapsdt` section of `elf`, whose header was read from
+/// `inode`. Only the headers are in memory, so the sections are read from
+/// the file.
+pub fn parse_stapsdt(elf: &ElfFile, inode: &Arc<dyn INode>) -> Vec<UsdtProbe> {
+    parse(elf, inode).unwrap_or_default()
+}
+
+fn parse(elf: &ElfFile, inode: &Arc<dyn INode>) -> Option<Vec<UsdtProbe>> {
+    let word = match elf.header.pt1.class() {
+        header::Class::SixtyFour => 8,
+        _ => 4,
+    };
+    let pt2 = &elf.header.pt2;
+    let entsize = pt2.sh_entry_size() as usize;
+    let count = pt2.sh_count() as usize;
+    if count == 0 || entsize < 6 * word {
+        return None;
+    }
+    let table = read_inode(inode, pt2.sh_offset() as usize, entsize.checked_mul(count)?)?;
+    let sections: Vec<Section> = table
+        .chunks(entsize)
+        .map(|sh| {
+            (
+                read_word(sh, 0, 4),
+                read_word(sh, 4, 4) as u32,
+                read_word(sh, 8 + word, word),
+                read_word(sh, 8 + 2 * word, word),
+                read_word(sh, 8 + 3 * word, word),
+            )
+        })
+        .collect();
+    let shstr = sections.get(pt2.sh_str_index() as usize)?;
+    let names = read_inode(inode, shstr.3, shstr.4)?;
+    let named = |name: &str| {
+        sections.iter().find(|sh| {
+            names
+                .get(sh.0..)
+                .and_then(c_str)
+                .map_or(false, |(s, _)| s == name)
+        })
+    };
+    let note = named(".note.stapsdt")?;
+    if note.1 != SHT_NOTE {
+        return None;
+    }
+    let base = named(".stapsdt.base").map(|sh| sh.2);
+    let data = read_inode(inode, note.3, note.4)?;
+
+    let align = |n: usize| (n + 3) & !3;
+    let mut probes = Vec::new();
+    let mut rest = &data[..];
+    while rest.len() >= 12 {
+        let namesz = read_word(rest, 0, 4);
+        let descsz = read_word(rest, 4, 4);
+        let ty = read_word(rest, 8, 4) as u32;
+        let desc_start = 12 + align(namesz);
+        if desc_start + descsz > rest.len() {
+            break;
+        }
+        let owner = &rest[12..12 + namesz];
+        let desc = &rest[desc_start..desc_start + descsz];
+        rest = &rest[(desc_start + align(descsz)).min(rest.len())..];
+        if ty != NT_STAPSDT || owner != b"stapsdt\0" || desc.len() < 3 * word {
+            continue;
+        }
+        let mut addr = read_word(desc, 0, word);
+        let note_base = read_word(desc, word, word);
+        let mut semaphore = read_word(desc, 2 * word, word);
+        // prelinking moves `.stapsdt.base`, and the probes with it
+        if let Some(base) = base {
+            if note_base != 0 {
+                addr = addr.wrapping_add(base).wrapping_sub(note_base);
+                if semaphore != 0 {
+                    semaphore = semaphore.wrapping_add(base).wrapping_sub(note_base);
+                }
+            }
+        }
+        let (provider, strs) = match c_str(&desc[3 * word..]) {
+            Some(s) => s,
+            None => continue,
+        };
+        let (name, strs) = match c_str(strs) {
+            Some(s) => s,
+            None => continue,
+        };
+        let (args, _) = c_str(strs).unwrap_or(("", &[]));
+        let args: Option<Vec<UsdtArg>> = args.split_whitespace().map(parse_arg).collect();
+        match args {
+            Some(args) if args.len() <= USDT_MAX_ARGS => probes.push(UsdtProbe {
+                provider: provider.to_string(),
+                name: name.to_string(),
+                addr,
+                semaphore,
+                args,
+            }),
+            _ => warn!("usdt: cannot parse arguments of {}:{}", provider, name),
+        }
+    }
+    Some(probes)
+}
+
+/// Remember the probes of the binary at `path`, as it is executed. Paths
+/// are canonical, see `Process::canonical_path`.
+pub fn usdt_record(path: &str, probes: Vec<UsdtProbe>) {
+    let mut map = USDT_PROBES.lock();
+    if probes.is_empty() {
+        map.remove(path);
+    } else {
+        map.insert(path.to_string(), Arc::new(probes));
+    }
+}
+
+/// The probes of the binary at `path`, read from it unless it was executed.
+pub fn usdt_probes(path: &str) -> Option<Arc<Vec<UsdtProbe>>> {
+    if let Some(probes) = USDT_PROBES.lock().get(path) {
+        return Some(probes.clone());
+    }
+    let inode = crate::fs::ROOT_INODE
+        .lookup_follow(path, FOLLOW_MAX_DEPTH)
+        .ok()?;
+    let mut data = [0u8; 0x3c0];
+    inode.read_at(0, &mut data).ok()?;
+    let elf = ElfFile::new(&data).ok()?;
+    let probes = parse_stapsdt(&elf, &inode);
+    usdt_record(path, probes);
+    USDT_PROBES.lock().get(path).cloned()
+}
+
+/// All binaries known to have probes, with their probes.
+pub fn usdt_list() -> Vec<(String, Arc<Vec<UsdtProbe>>)> {
+    USDT_PROBES
+        .lock()
+        .iter()
+        .map(|(path, probes)| (path.clone(), probes.clone()))
+        .collect()
+}
@@ -57,12 +57,18 @@ impl<T: FrameAllocator> MemoryHandler for RvmPageTableHandlerDelay<T> {
         src_pt: &mut dyn PageTable,
         addr: HostVirtAddr,
         attr: &MemoryAttr,
-    ) {
+    ) -> bool {
         let entry = src_pt.get_entry(addr).expect("failed to get entry");
         if entry.present() {
             // eager map and copy data
+            let target = match self.allocator.alloc() {
+                Some(target) => target,
+                None => {
+                    self.map(pt, addr, attr);
+                    return false;
+                }
+            };
             let data = src_pt.get_page_slice_mut(addr);
-            let target = self.allocator.alloc().expect("failed to alloc frame");
             let entry = pt.map(addr, target);
             attr.apply(entry);
             pt.get_page_slice_mut(addr).copy_from_slice(data);
@@ -70,6 +76,7 @@ impl<T: FrameAllocator> MemoryHandler for RvmPageTableHandlerDelay<T> {
             // delay map
             self.map(pt, addr, attr);
         }
+        true
     }
 
     fn handle_page_fault(&self, pt: &mut dyn PageTable, addr: HostVirtAddr) -> bool {
@@ -83,7 +90,10 @@ impl<T: FrameAllocator> MemoryHandler for RvmPageTableHandlerDelay<T> {
         let mut rvm_pt = self.gpm.rvm_page_table.lock();
         let mut target = rvm_pt.query(guest_paddr).unwrap_or(0);
         if target == 0 {
-            target = self.allocator.alloc().expect("failed to alloc frame");
+            target = match self.allocator.alloc() {
+                Some(target) => target,
+                None => return false,
+            };
         }
         info!("guest_paddr={}, target={}", guest_paddr, target);
         rvm_pt
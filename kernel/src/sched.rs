@@ -0,0 +1,202 @@
+//! Run the futures of threads and kernel tasks by their scheduling policies
+//!
+//! Every spawned future is a task of `rcore_sched::SmpScheduler`. A task is
+//! polled when it is woken, real-time ones first, then the fair ones with the
+//! least virtual runtime. A user thread gives up the CPU on a timer interrupt
+//! only if `tick` finds a task that should run before it.
+//!
+//! Each hart has its own run queue, and runs the tasks placed on it by their
+//! affinity. An idle hart steals tasks from the others, or waits for the next
+//! interrupt, so a task woken for a sleeping hart waits at most a timer tick.
+
+use crate::arch::cpu;
+use crate::consts::MAX_CPU_NUM;
+use crate::sync::SpinNoIrqLock as Mutex;
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use rcore_sched::SmpScheduler;
+pub use rcore_sched::{CpuSet, CpuStat, Policy, SchedParam};
+
+struct Task {
+    /// The thread running in this task, if it is a user thread
+    tid: Option<usize>,
+    future: spin::Mutex<Pin<Box<dyn Future<Output = ()> + Send>>>,
+}
+
+struct Executor {
+    scheduler: SmpScheduler<usize>,
+    tasks: BTreeMap<usize, Arc<Task>>,
+    /// The task of each thread, by tid
+    threads: BTreeMap<usize, usize>,
+    next_id: usize,
+}
+
+lazy_static! {
+    static ref EXECUTOR: Mutex<Executor> = Mutex::new(Executor {
+        scheduler: SmpScheduler::new(MAX_CPU_NUM),
+        tasks: BTreeMap::new(),
+        threads: BTreeMap::new(),
+        next_id: 0,
+    });
+}
+
+/// The task running on each CPU
+static mut RUNNING: [Option<usize>; MAX_CPU_NUM] = [None; MAX_CPU_NUM];
+
+/// Spawn a kernel task
+pub fn spawn(future: impl Future<Output = ()> + Send + 'static) {
+    spawn_task(None, SchedParam::default(), CpuSet::all(), Box::pin(future));
+}
+
+/// Spawn the task of the thread `tid`, scheduled by `param` on the harts in `affinity`
+pub fn spawn_thread(
+    tid: usize,
+    param: SchedParam,
+    affinity: CpuSet,
+    future: Pin<Box<dyn Future<Output = ()> + Send + 'static>>,
+) {
+    spawn_task(Some(tid), param, affinity, future);
+}
+
+fn spawn_task(
+    tid: Option<usize>,
+    param: SchedParam,
+    affinity: CpuSet,
+    future: Pin<Box<dyn Future<Output = ()> + Send + 'static>>,
+) {
+    let mut executor = EXECUTOR.lock();
+    let id = executor.next_id;
+    executor.next_id += 1;
+    let task = Task {
+        tid,
+        future: spin::Mutex::new(future),
+    };
+    executor.tasks.insert(id, Arc::new(task));
+    if let Some(tid) = tid {
+        // a tid may be reused before the task of the exited thread finishes
+        executor.threads.insert(tid, id);
+    }
+    if !executor.scheduler.add(id, param, affinity, cpu::id()) {
+        // no hart in the affinity, which is checked by sched_setaffinity
+        executor.scheduler.add(id, param, CpuSet::all(), cpu::id());
+    }
+    executor.scheduler.wake(id);
+}
+
+/// Poll the runnable tasks of this hart until there is none
+pub fn run_until_idle() {
+    let cpu_id = cpu::id();
+    EXECUTOR.lock().scheduler.set_online(cpu_id);
+    loop {
+        let (id, task) = {
+            let mut executor = EXECUTOR.lock();
+            let id = match executor.scheduler.pick(cpu_id) {
+                Some(id) => id,
+                None => break,
+            };
+            (id, executor.tasks[&id].clone())
+        };
+        let waker = unsafe { Waker::from_raw(raw_waker(id)) };
+        let mut cx = Context::from_waker(&waker);
+        unsafe {
+            RUNNING[cpu_id] = Some(id);
+        }
+        let res = task.future.lock().as_mut().poll(&mut cx);
+        unsafe {
+            RUNNING[cpu_id] = None;
+        }
+
+        let mut executor = EXECUTOR.lock();
+        match res {
+            Poll::Ready(()) => {
+                executor.scheduler.remove(id);
+                executor.tasks.remove(&id);
+                if let Some(tid) = task.tid {
+                    if executor.threads.get(&tid) == Some(&id) {
+                        executor.threads.remove(&tid);
+                    }
+                }
+            }
+            Poll::Pending => executor.scheduler.put(id),
+        }
+    }
+}
+
+/// Account a timer tick of the task running on this CPU.
+/// Return true if it should yield to another task.
+pub fn tick() -> bool {
+    let id = match unsafe { RUNNING[cpu::id()] } {
+        Some(id) => id,
+        None => return false,
+    };
+    EXECUTOR.lock().scheduler.tick(id)
+}
+
+/// The scheduling parameters of the thread `tid`
+pub fn thread_param(tid: usize) -> Option<SchedParam> {
+    let executor = EXECUTOR.lock();
+    let id = *executor.threads.get(&tid)?;
+    executor.scheduler.param(id)
+}
+
+/// Change the scheduling parameters of the thread `tid`,
+/// return false if there is no such thread
+pub fn set_thread_param(tid: usize, param: SchedParam) -> bool {
+    let mut executor = EXECUTOR.lock();
+    match executor.threads.get(&tid) {
+        Some(&id) => executor.scheduler.set_param(id, param),
+        None => false,
+    }
+}
+
+/// The harts in `affinity` of the thread `tid`
+pub fn thread_affinity(tid: usize) -> Option<CpuSet> {
+    let executor = EXECUTOR.lock();
+    let id = *executor.threads.get(&tid)?;
+    executor.scheduler.affinity(id)
+}
+
+/// Let the thread `tid` run only on the harts in `affinity`,
+/// return false if there is no such thread, or no such hart
+pub fn set_thread_affinity(tid: usize, affinity: CpuSet) -> bool {
+    let mut executor = EXECUTOR.lock();
+    match executor.threads.get(&tid) {
+        Some(&id) => executor.scheduler.set_affinity(id, affinity),
+        None => false,
+    }
+}
+
+/// The harts running tasks
+pub fn online_cpus() -> CpuSet {
+    EXECUTOR.lock().scheduler.online()
+}
+
+/// Load statistics of the online harts
+pub fn cpu_stats() -> Vec<(usize, CpuStat)> {
+    let executor = EXECUTOR.lock();
+    let online = executor.scheduler.online();
+    online
+        .iter()
+        .map(|cpu| (cpu, executor.scheduler.stat(cpu)))
+        .collect()
+}
+
+/// A waker of the task `id`, which is stored in place of the data pointer
+fn raw_waker(id: usize) -> RawWaker {
+    RawWaker::new(id as *const (), &WAKER_VTABLE)
+}
+
+static WAKER_VTABLE: RawWakerVTable =
+    RawWakerVTable::new(clone_waker, wake_task, wake_task, drop_waker);
+
+fn clone_waker(data: *const ()) -> RawWaker {
+    raw_waker(data as usize)
+}
+
+fn wake_task(data: *const ()) {
+    EXECUTOR.lock().scheduler.wake(data as usize);
+}
+
+fn drop_waker(_data: *const ()) {}
@@ -70,10 +70,56 @@ impl Debug for SignalAction {
     }
 }
 
+/// si_code of SIGSYS raised by seccomp
+pub const SYS_SECCOMP: i32 = 1;
+
+/// si_code of SIGILL
+pub const ILL_ILLOPC: i32 = 1;
+pub const ILL_ILLTRP: i32 = 4;
+/// si_code of SIGFPE
+pub const FPE_INTDIV: i32 = 1;
+pub const FPE_FLTINV: i32 = 7;
+/// si_code of SIGSEGV
+pub const SEGV_MAPERR: i32 = 1;
+pub const SEGV_ACCERR: i32 = 2;
+/// si_code of SIGBUS
+pub const BUS_ADRALN: i32 = 1;
+pub const BUS_ADRERR: i32 = 2;
+/// si_code of SIGTRAP
+pub const TRAP_BRKPT: i32 = 1;
+
+/// `_sigfault` of `siginfo_t`
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct SigfaultFields {
+    pub addr: usize,
+}
+
+/// `_sigsys` of `siginfo_t`
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct SigsysFields {
+    pub call_addr: usize,
+    pub syscall: i32,
+    pub arch: u32,
+}
+
+/// `_timer` of `siginfo_t`
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct SigtimerFields {
+    pub tid: i32,
+    pub overrun: i32,
+    pub sigval: usize,
+}
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub union SiginfoFields {
     pad: [u8; Self::PAD_SIZE],
+    pub sigsys: SigsysFields,
+    pub sigfault: SigfaultFields,
+    pub sigtimer: SigtimerFields,
     // TODO: fill this union
 }
 
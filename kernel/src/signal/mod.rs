@@ -2,7 +2,7 @@ use crate::arch::{
     signal::{set_signal_handler, MachineContext, RET_CODE},
     syscall::SYS_RT_SIGRETURN,
 };
-use crate::process::{process, process_of, Process, Thread};
+use crate::process::{process, process_of, signaled_status, Process, Thread};
 use crate::sync::{Event, MutexGuard, SpinNoIrq, SpinNoIrqLock as Mutex};
 use alloc::sync::Arc;
 use bitflags::*;
@@ -107,6 +107,52 @@ pub fn send_signal(process: Arc<Mutex<Process>>, tid: isize, info: Siginfo) {
     )
 }
 
+/// Send the signal of a fault of `thread` at `addr`.
+/// It can not be blocked or ignored, or the fault would happen again,
+/// so its default action is restored in that case.
+pub fn send_fault_signal(thread: &Arc<Thread>, signal: Signal, code: i32, addr: usize) {
+    {
+        let mut process = thread.proc.lock();
+        let mut inner = thread.inner.lock();
+        let action = &mut process.dispositions[signal as usize];
+        if inner.sig_mask.contains(signal) || action.handler == SIG_IGN {
+            inner.sig_mask.remove(signal);
+            action.handler = SIG_DFL;
+        }
+    }
+    let mut info = Siginfo {
+        signo: signal as i32,
+        errno: 0,
+        code,
+        field: Default::default(),
+    };
+    info.field.sigfault = SigfaultFields { addr };
+    send_signal(thread.proc.clone(), thread.tid as isize, info);
+}
+
+/// Hook of the default action "Core", before the process is terminated.
+/// No core file is written for now, the fault is reported instead.
+fn core_dump(process: &Process, thread: &Arc<Thread>, info: &Siginfo, tf: &UserContext) {
+    use Signal::*;
+
+    let signal: Signal = <Signal as FromPrimitive>::from_i32(info.signo).unwrap();
+    match signal {
+        SIGILL | SIGTRAP | SIGBUS | SIGFPE | SIGSEGV => warn!(
+            "process {} thread {} killed by {:?} (code {}) at {:#x}, core dumped: {:x?}",
+            process.pid,
+            thread.tid,
+            signal,
+            info.code,
+            unsafe { info.field.sigfault.addr },
+            tf
+        ),
+        _ => warn!(
+            "process {} thread {} killed by {:?}, core dumped: {:x?}",
+            process.pid, thread.tid, signal, tf
+        ),
+    }
+}
+
 /// See musl struct __ucontext
 /// Not exactly the same for now
 #[repr(C)]
@@ -170,10 +216,22 @@ pub fn handle_signal(thread: &Arc<Thread>, tf: &mut UserContext) -> bool {
             // TODO: complete default actions
             x if x == SIG_DFL => {
                 match signal {
-                    SIGALRM | SIGHUP | SIGINT => {
+                    SIGHUP | SIGINT | SIGKILL | SIGUSR1 | SIGUSR2 | SIGPIPE | SIGALRM | SIGTERM
+                    | SIGSTKFLT | SIGVTALRM | SIGPROF | SIGIO | SIGPWR => {
+                        info!("default action: Term");
+                        process.exit(signaled_status(signal, false));
+                        return true;
+                    }
+                    SIGQUIT | SIGILL | SIGTRAP | SIGABRT | SIGBUS | SIGFPE | SIGSEGV | SIGXCPU
+                    | SIGXFSZ | SIGSYS => {
+                        info!("default action: Core");
+                        core_dump(&process, thread, &info, tf);
+                        process.exit(signaled_status(signal, true));
+                        return true;
+                    }
+                    _ if !signal.is_standard() => {
                         info!("default action: Term");
-                        // TODO: exit code ref please?
-                        process.exit(info.signo as usize + 128);
+                        process.exit(signaled_status(signal, false));
                         return true;
                     }
                     _ => (),
@@ -199,6 +257,8 @@ pub fn handle_signal(thread: &Arc<Thread>, tf: &mut UserContext) -> bool {
                 // 2. block mask in disposition
                 inner.sig_mask.add(signal);
                 inner.sig_mask.add_set(&action.mask);
+                inner.sig_mask.remove(SIGKILL);
+                inner.sig_mask.remove(SIGSTOP);
 
                 // save original signal alternate stack
                 let stack = inner.signal_alternate_stack;
@@ -238,7 +298,17 @@ pub fn handle_signal(thread: &Arc<Thread>, tf: &mut UserContext) -> bool {
                 } {
                     frame
                 } else {
-                    unimplemented!()
+                    // no stack for the handler, the process can only be killed
+                    let mut info = Siginfo {
+                        signo: SIGSEGV as i32,
+                        errno: 0,
+                        code: SI_KERNEL,
+                        field: Default::default(),
+                    };
+                    info.field.sigfault = SigfaultFields { addr: sig_sp };
+                    core_dump(&process, thread, &info, tf);
+                    process.exit(signaled_status(SIGSEGV, true));
+                    return true;
                 };
                 frame.info = info;
                 frame.ucontext = SignalUserContext {
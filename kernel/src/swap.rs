@@ -0,0 +1,280 @@
+//! Swap out user memory to a swap partition or a swap file
+//!
+//! Pages of `Swap` areas are tracked by `SWAP` once they are mapped to a frame.
+//! When the frame allocator runs out, the oldest one is written to the swap
+//! area and its frame is reused; a page fault on it reads it back.
+//!
+//! A swap area is prepared by mkswap: the first page holds the index of its
+//! last page, and ends with the signature "SWAPSPACE2".
+
+use crate::memory::{FrameAllocator, GlobalFrameAlloc, SwapSpace};
+use crate::process::PROCESSES;
+use crate::sync::SpinNoIrqLock as Mutex;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use rcore_fs::vfs::{FileType, FsError, INode};
+use rcore_memory::paging::PageTable;
+use rcore_memory::swap::{FifoSwapManager, SwapError, SwapExt, Swapper};
+use rcore_memory::{VirtAddr, PAGE_SIZE};
+
+const SWAP_SIGNATURE: &[u8] = b"SWAPSPACE2";
+/// Offsets in the first page of a swap area
+const SWAP_VERSION: usize = 1024;
+const SWAP_LAST_PAGE: usize = 1028;
+const SWAP_NR_BADPAGES: usize = 1032;
+
+lazy_static! {
+    pub static ref SWAP: Mutex<SwapExt<FifoSwapManager, BlockSwapper>> =
+        Mutex::new(SwapExt::new(FifoSwapManager::default(), BlockSwapper::default()));
+}
+
+/// A swap area in use
+struct SwapArea {
+    inode: Arc<dyn INode>,
+    /// `dev`, `inode` and `rdev` of its metadata, to find it again
+    id: (usize, usize, usize),
+    /// Page i of the area is in use, page 0 is the header
+    used: Vec<bool>,
+    free: usize,
+}
+
+/// Swap pages in and out of the swap area, if there is one
+#[derive(Default)]
+pub struct BlockSwapper {
+    area: Option<SwapArea>,
+}
+
+impl BlockSwapper {
+    /// Total and free pages of the swap area
+    pub fn pages(&self) -> (usize, usize) {
+        match &self.area {
+            Some(area) => (area.used.len() - 1, area.free),
+            None => (0, 0),
+        }
+    }
+}
+
+impl Swapper for BlockSwapper {
+    fn swap_out(&mut self, data: &[u8]) -> Result<usize, ()> {
+        let area = self.area.as_mut().ok_or(())?;
+        let token = (1..area.used.len()).find(|&i| !area.used[i]).ok_or(())?;
+        match area.inode.write_at(token * PAGE_SIZE, data) {
+            Ok(len) if len == PAGE_SIZE => {
+                area.used[token] = true;
+                area.free -= 1;
+                Ok(token)
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn swap_update(&mut self, token: usize, data: &[u8]) -> Result<(), ()> {
+        let area = self.area.as_mut().ok_or(())?;
+        match area.inode.write_at(token * PAGE_SIZE, data) {
+            Ok(len) if len == PAGE_SIZE => Ok(()),
+            _ => Err(()),
+        }
+    }
+
+    fn swap_in(&mut self, token: usize, data: &mut [u8]) -> Result<(), ()> {
+        let area = self.area.as_mut().ok_or(())?;
+        match area.inode.read_at(token * PAGE_SIZE, data) {
+            Ok(len) if len == PAGE_SIZE => self.swap_free(token),
+            _ => Err(()),
+        }
+    }
+
+    fn swap_free(&mut self, token: usize) -> Result<(), ()> {
+        let area = self.area.as_mut().ok_or(())?;
+        if !area.used.get(token).cloned().unwrap_or(false) {
+            return Err(());
+        }
+        area.used[token] = false;
+        area.free += 1;
+        Ok(())
+    }
+}
+
+/// The swap of `Swap` areas
+#[derive(Debug, Clone, Copy)]
+pub struct GlobalSwap;
+
+impl SwapSpace for GlobalSwap {
+    fn push(&self, pt: &mut dyn PageTable, addr: VirtAddr) {
+        SWAP.lock().set_swappable(pt.token(), addr);
+    }
+
+    fn remove(&self, pt: &mut dyn PageTable, addr: VirtAddr) -> bool {
+        SWAP.lock().remove_from_swappable(pt, addr)
+    }
+
+    fn swap_in(&self, pt: &mut dyn PageTable, addr: VirtAddr) -> Result<bool, SwapError> {
+        if !SWAP.lock().is_swapped(pt.token(), addr) {
+            return Ok(false);
+        }
+        // allocating may swap out other pages
+        let target = GlobalFrameAlloc.alloc().ok_or(SwapError::NoFrame)?;
+        let ret = SWAP.lock().swap_in(pt, addr, target);
+        match ret {
+            Ok(()) => Ok(true),
+            // swapped in by swapoff meanwhile
+            Err(SwapError::NotSwapped) => {
+                GlobalFrameAlloc.dealloc(target);
+                Ok(true)
+            }
+            Err(err) => {
+                GlobalFrameAlloc.dealloc(target);
+                warn!("failed to swap in page {:#x}: {:?}", addr, err);
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Whether the page at `addr` of the page table of `token` is swapped out
+pub fn is_swapped(token: usize, addr: VirtAddr) -> bool {
+    SWAP.lock().is_swapped(token, addr)
+}
+
+/// Run `f` on the page table of `token` with the memory set owning it locked.
+/// Return false if it can not be locked without waiting.
+fn lock_page_table(token: usize, f: &mut dyn FnMut(&mut dyn PageTable)) -> bool {
+    // the frame allocator may be called with any of these locks held
+    let processes = match PROCESSES.try_read() {
+        Some(processes) => processes,
+        None => return false,
+    };
+    for process in processes.values() {
+        let vm = match process.try_lock() {
+            Some(process) => process.vm.clone(),
+            None => continue,
+        };
+        let mut vm = match vm.try_lock() {
+            Some(vm) => vm,
+            None => continue,
+        };
+        if vm.token() == token {
+            f(vm.get_page_table_mut());
+            return true;
+        }
+    }
+    false
+}
+
+/// Swap out a page for its frame, when the frame allocator runs out.
+pub fn reclaim() -> Option<usize> {
+    // writing out the page may allocate, and must not wait for itself
+    let mut swap = SWAP.try_lock()?;
+    if swap.swapper().pages().1 == 0 {
+        return None;
+    }
+    match swap.swap_out_any(lock_page_table) {
+        Ok(frame) => Some(frame),
+        Err(err) => {
+            warn!("failed to swap out a page: {:?}", err);
+            None
+        }
+    }
+}
+
+/// Start swapping to `inode`, a block device or a file prepared by mkswap.
+pub fn swap_on(inode: Arc<dyn INode>) -> Result<(), FsError> {
+    let metadata = inode.metadata()?;
+    let pages = match metadata.type_ {
+        FileType::BlockDevice => usize::max_value(),
+        FileType::File => metadata.size / PAGE_SIZE,
+        _ => return Err(FsError::InvalidParam),
+    };
+    let mut header = vec![0u8; PAGE_SIZE];
+    if inode.read_at(0, &mut header)? != PAGE_SIZE
+        || &header[PAGE_SIZE - SWAP_SIGNATURE.len()..] != SWAP_SIGNATURE
+    {
+        return Err(FsError::InvalidParam);
+    }
+    let read_u32 = |offset: usize| {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&header[offset..offset + 4]);
+        u32::from_ne_bytes(bytes) as usize
+    };
+    if read_u32(SWAP_VERSION) != 1 || read_u32(SWAP_NR_BADPAGES) != 0 {
+        return Err(FsError::InvalidParam);
+    }
+    let pages = pages.min(read_u32(SWAP_LAST_PAGE) + 1);
+    if pages < 2 {
+        return Err(FsError::InvalidParam);
+    }
+
+    let mut swap = SWAP.lock();
+    let swapper = swap.swapper_mut();
+    if swapper.area.is_some() {
+        return Err(FsError::Busy);
+    }
+    info!("swap on: {} pages", pages - 1);
+    swapper.area = Some(SwapArea {
+        inode,
+        id: (metadata.dev, metadata.inode, metadata.rdev),
+        used: vec![false; pages],
+        free: pages - 1,
+    });
+    Ok(())
+}
+
+/// Swap in every page from `inode` and stop swapping to it.
+///
+/// Pages are swapped in under the lock of their owner, like they are swapped
+/// out. Those of busy owners are retried after the others, with `SWAP`
+/// unlocked so that the owners can go on, and the swap is busy if they stay
+/// busy for `SWAP_OFF_RETRIES` rounds.
+pub fn swap_off(inode: &Arc<dyn INode>) -> Result<(), FsError> {
+    const SWAP_OFF_RETRIES: usize = 1000;
+    let metadata = inode.metadata()?;
+    let id = (metadata.dev, metadata.inode, metadata.rdev);
+    let mut retries = 0;
+    loop {
+        let mut swap = SWAP.lock();
+        match &swap.swapper().area {
+            Some(area) if area.id == id => {}
+            _ => return Err(FsError::InvalidParam),
+        }
+        let swapped: Vec<_> = swap.swapped().collect();
+        if swapped.is_empty() {
+            info!("swap off");
+            swap.swapper_mut().area = None;
+            return Ok(());
+        }
+        let mut progress = false;
+        for frame in swapped {
+            let addr = frame.get_virtaddr();
+            // no page is swapped out to make room while the lock is held
+            let target = GlobalFrameAlloc.alloc().ok_or(FsError::NoDeviceSpace)?;
+            let mut ret = None;
+            lock_page_table(frame.get_token(), &mut |pt| {
+                ret = Some(swap.swap_in(pt, addr, target));
+            });
+            match ret {
+                Some(Ok(())) => progress = true,
+                Some(Err(err)) => {
+                    GlobalFrameAlloc.dealloc(target);
+                    warn!("failed to swap in page {:#x}: {:?}", addr, err);
+                    return Err(FsError::DeviceError);
+                }
+                None => GlobalFrameAlloc.dealloc(target),
+            }
+        }
+        drop(swap);
+        if progress {
+            retries = 0;
+        } else if retries < SWAP_OFF_RETRIES {
+            retries += 1;
+            core::sync::atomic::spin_loop_hint();
+        } else {
+            return Err(FsError::Busy);
+        }
+    }
+}
+
+/// Total and free swap, in bytes
+pub fn swap_info() -> (usize, usize) {
+    let (total, free) = SWAP.lock().swapper().pages();
+    (total * PAGE_SIZE, free * PAGE_SIZE)
+}
@@ -1,32 +1,547 @@
 use super::*;
 use core::convert::TryInto;
-use crate::kprobes::ProbePlace;
-use core::mem::transmute;
+use crate::kprobes::{kprobe_blacklisted, ProbePlace};
+use crate::ebpf::ebpf::{AttachOpts, SiteKey, EBPF};
+use crate::ebpf::info::prog_miss_cnt;
+use crate::ebpf::map::{map_create, map_get, map_next_id, BpfMap, MapType};
+use crate::ebpf::prog::{prog_get, prog_load, prog_next_id, BpfProg, ProgType};
+use core::sync::atomic::Ordering;
+use crate::lkm::manager::ModuleManager;
+use crate::fs::{BpfObjINode, BpfObject, FileHandle, FileLike, OpenOptions, BPF_FS};
+use crate::net::xdp_attach;
+use crate::process::usdt::{usdt_probes, UsdtProbe};
+use num::FromPrimitive;
+use spin::Mutex;
+
+// commands of `bpf(2)`
+const BPF_MAP_CREATE: usize = 0;
+const BPF_MAP_LOOKUP_ELEM: usize = 1;
+const BPF_MAP_UPDATE_ELEM: usize = 2;
+const BPF_MAP_DELETE_ELEM: usize = 3;
+const BPF_PROG_LOAD: usize = 5;
+const BPF_OBJ_PIN: usize = 6;
+const BPF_OBJ_GET: usize = 7;
+const BPF_PROG_ATTACH: usize = 8;
+const BPF_PROG_DETACH: usize = 9;
+const BPF_PROG_GET_NEXT_ID: usize = 11;
+const BPF_MAP_GET_NEXT_ID: usize = 12;
+const BPF_PROG_GET_FD_BY_ID: usize = 13;
+const BPF_MAP_GET_FD_BY_ID: usize = 14;
+const BPF_OBJ_GET_INFO_BY_FD: usize = 15;
+const BPF_LINK_CREATE: usize = 28;
+/// rCore specific: attach a program to USDT probes by `provider:name`
+const BPF_USDT_ATTACH: usize = 1000;
+/// rCore specific: attach a program to many kernel functions at once
+const BPF_KPROBE_MULTI_ATTACH: usize = 1001;
+
+// attach types of `BPF_LINK_CREATE`
+const BPF_XDP: u32 = 37;
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct MapElemAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct ObjAttr {
+    pathname: u64,
+    bpf_fd: u32,
+    file_flags: u32,
+}
+
+/// Attach a loaded program to a probe, the same places `sys_register_ebpf` takes.
+/// Programs at the same address run by `priority`, lowest first, and are
+/// given `cookie` by `bpf_get_attach_cookie`. Both are 0 for callers passing
+/// the attribute without them.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct ProgAttachAttr {
+    prog_fd: u32,
+    probe_place: u32,
+    addr: u64,
+    path: u64,
+    priority: i32,
+    _pad: u32,
+    cookie: u64,
+}
+
+/// Attach a program to the kernel functions named by `syms`, an array of
+/// `cnt` names or globs matched against symbols and their demangled paths.
+/// Every name must match, and either all matched functions are probed or
+/// none is. `cookies`, if not null, holds the cookie of each name.
+#[repr(C)]
+#[derive(Debug)]
+pub struct KprobeMultiAttr {
+    prog_fd: u32,
+    probe_place: u32,
+    syms: u64,
+    cnt: u32,
+    priority: i32,
+    cookies: u64,
+}
+
+/// Attach a program to an interface, which is an index in `NET_DRIVERS`.
+/// Only XDP is supported, and unlike linux no link is created: a `prog_fd`
+/// of -1 detaches the program of the interface.
+#[repr(C)]
+#[derive(Debug)]
+pub struct LinkCreateAttr {
+    prog_fd: u32,
+    target_ifindex: u32,
+    attach_type: u32,
+    flags: u32,
+}
+
+/// Attach to every site of the USDT probe `provider:name` of the binary at
+/// `path`, with `cookie` for `bpf_get_attach_cookie`, 0 if not passed.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct UsdtAttachAttr {
+    prog_fd: u32,
+    _pad: u32,
+    path: u64,
+    probe: u64,
+    cookie: u64,
+}
+
+/// `start_id` in, `next_id` out; also the id of `*_GET_FD_BY_ID`.
+#[repr(C)]
+#[derive(Debug)]
+pub struct GetIdAttr {
+    start_id: u32,
+    next_id: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct InfoAttr {
+    bpf_fd: u32,
+    info_len: u32,
+    info: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct BpfProgInfo {
+    id: u32,
+    insn_cnt: u32,
+    run_cnt: u64,
+    run_time_ns: u64,
+    miss_cnt: u64,
+    /// number of probes the program is attached to
+    attach_cnt: u32,
+    nr_map_ids: u32,
+    /// attachments dropped as the module they probed unloaded
+    detached_cnt: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct BpfMapInfo {
+    id: u32,
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+}
+
+/// The probe place encoded as a number by user space.
+fn probe_place(pp: usize) -> Result<ProbePlace, SysError> {
+    <ProbePlace as FromPrimitive>::from_usize(pp).ok_or(SysError::EINVAL)
+}
 
 impl Syscall<'_> {
+    /// Programs run in the kernel and maps outlive their owner, so only root uses them.
+    pub(super) fn check_bpf_allowed(&self) -> Result<(), SysError> {
+        if !self.process().cred.is_root() {
+            return Err(SysError::EPERM);
+        }
+        Ok(())
+    }
+
     pub fn sys_register_ebpf(&mut self, addr: usize, base: *const u8, len: usize, pt: usize, path: *const u8) -> SysResult {
+        self.check_bpf_allowed()?;
         let slice = unsafe { self.vm().check_read_array(base, len)? };
-        let path = check_and_clone_cstr(path)?;
-        let pp: ProbePlace = unsafe { transmute(pt as u16)};
+        let pp = probe_place(pt)?;
+        let path = self.probe_path(&pp, path)?;
         let prog = slice
             .chunks_exact(8)
             .map(|x| u64::from_le_bytes(x.try_into().unwrap()))
             .collect::<alloc::vec::Vec<u64>>();
+        let prog = self.load_prog(ProgType::Kprobe, prog)?;
         // println!("path");
-        if crate::ebpf::ebpf_register(addr, prog, path, pp) != 0 {
+        if crate::ebpf::ebpf_register(addr, &prog, path, pp) != 0 {
             return Err(SysError::EINVAL);
         }
         Ok(0)
     }
 
-    pub fn sys_unregister_ebpf(&mut self, addr: usize) -> SysResult {
-        if crate::ebpf::ebpf_unregister(addr) != 0 {
+    /// Maps and programs are handed to user space as file descriptors,
+    /// which keep them alive until closed.
+    pub fn sys_bpf(&mut self, cmd: usize, attr: usize, size: usize) -> SysResult {
+        info!("bpf: cmd: {}, attr: {:#x}, size: {}", cmd, attr, size);
+        // this covers handing out existing objects by id too
+        self.check_bpf_allowed()?;
+        match cmd {
+            BPF_MAP_CREATE => {
+                let attr = unsafe { self.vm().check_read_ptr(attr as *const MapCreateAttr)? };
+                let map = map_create(
+                    attr.map_type,
+                    attr.key_size as usize,
+                    attr.value_size as usize,
+                    attr.max_entries as usize,
+                )?;
+                self.add_bpf_fd(BpfObjINode::new(BpfObject::Map(map)), "bpf-map")
+            }
+            BPF_MAP_LOOKUP_ELEM | BPF_MAP_UPDATE_ELEM | BPF_MAP_DELETE_ELEM => {
+                let attr = unsafe { self.vm().check_read_ptr(attr as *const MapElemAttr)? };
+                let map = self.get_bpf_map(attr.map_fd as usize)?;
+                let mut map = map.lock();
+                let key = unsafe { self.vm().check_read_array(attr.key as *const u8, map.key_size)? };
+                match cmd {
+                    BPF_MAP_LOOKUP_ELEM => {
+                        let value_size = map.value_size;
+                        let found = map.lookup(key).ok_or(SysError::ENOENT)?;
+                        let out = unsafe { self.vm().check_write_array(attr.value as *mut u8, value_size)? };
+                        out.copy_from_slice(unsafe { core::slice::from_raw_parts(found, value_size) });
+                        Ok(0)
+                    }
+                    BPF_MAP_UPDATE_ELEM => {
+                        let value = unsafe { self.vm().check_read_array(attr.value as *const u8, map.value_size)? };
+                        if map.map_type == MapType::ProgArray {
+                            // the value is the fd of the program to store
+                            let prog_fd = u32::from_le_bytes([value[0], value[1], value[2], value[3]]);
+                            let prog = self.get_bpf_prog(prog_fd as usize)?;
+                            let index = u32::from_le_bytes([key[0], key[1], key[2], key[3]]);
+                            map.set_prog(index, prog)?;
+                        } else {
+                            map.update(key, value, attr.flags)?;
+                        }
+                        Ok(0)
+                    }
+                    _ => {
+                        map.delete(key)?;
+                        Ok(0)
+                    }
+                }
+            }
+            BPF_PROG_LOAD => {
+                let attr = unsafe { self.vm().check_read_ptr(attr as *const ProgLoadAttr)? };
+                let slice = unsafe {
+                    self.vm()
+                        .check_read_array(attr.insns as *const u64, attr.insn_cnt as usize)?
+                };
+                let prog_type = ProgType::from_u32(attr.prog_type).ok_or(SysError::EINVAL)?;
+                let prog = self.load_prog(prog_type, slice.to_vec())?;
+                self.add_bpf_fd(BpfObjINode::new(BpfObject::Prog(prog)), "bpf-prog")
+            }
+            BPF_OBJ_PIN => {
+                let attr = unsafe { self.vm().check_read_ptr(attr as *const ObjAttr)? };
+                let path = check_and_clone_cstr(attr.pathname as *const u8)?;
+                let object = self.get_bpf_object(attr.bpf_fd as usize)?;
+                info!("bpf: pin fd {} at {}", attr.bpf_fd, path);
+                let (dir, name) = BPF_FS.lookup_parent(&path)?;
+                dir.pin(name, object)?;
+                Ok(0)
+            }
+            BPF_OBJ_GET => {
+                let attr = unsafe { self.vm().check_read_ptr(attr as *const ObjAttr)? };
+                let path = check_and_clone_cstr(attr.pathname as *const u8)?;
+                let inode = BPF_FS.get(&path)?;
+                self.add_bpf_fd(inode, &path)
+            }
+            BPF_PROG_ATTACH => {
+                let attr: ProgAttachAttr = self.read_attr(attr, size)?;
+                let prog = self.get_bpf_prog_of(attr.prog_fd as usize, ProgType::Kprobe)?;
+                let pp = probe_place(attr.probe_place as usize)?;
+                let path = self.probe_path(&pp, attr.path as *const u8)?;
+                let opts = AttachOpts {
+                    priority: attr.priority,
+                    cookie: attr.cookie,
+                };
+                if crate::ebpf::ebpf_attach(attr.addr as usize, &prog, path, pp, opts) != 0 {
+                    return Err(SysError::EINVAL);
+                }
+                Ok(0)
+            }
+            BPF_PROG_DETACH => {
+                // detach the program of `prog_fd` only, or everything at the address with -1
+                let attr: ProgAttachAttr = self.read_attr(attr, size)?;
+                let pp = probe_place(attr.probe_place as usize)?;
+                let key = self.site_key(&pp, attr.path as *const u8, attr.addr as usize)?;
+                if attr.prog_fd as i32 == -1 {
+                    if crate::ebpf::ebpf_unregister(&key) != 0 {
+                        return Err(SysError::EINVAL);
+                    }
+                    return Ok(0);
+                }
+                let prog = self.get_bpf_prog(attr.prog_fd as usize)?;
+                if crate::ebpf::ebpf_detach(&key, prog.id) != 0 {
+                    return Err(SysError::ENOENT);
+                }
+                Ok(0)
+            }
+            BPF_PROG_GET_NEXT_ID | BPF_MAP_GET_NEXT_ID => {
+                let attr = unsafe { self.vm().check_write_ptr(attr as *mut GetIdAttr)? };
+                let next = if cmd == BPF_PROG_GET_NEXT_ID {
+                    prog_next_id(attr.start_id)
+                } else {
+                    map_next_id(attr.start_id)
+                };
+                attr.next_id = next.ok_or(SysError::ENOENT)?;
+                Ok(0)
+            }
+            BPF_PROG_GET_FD_BY_ID => {
+                let attr = unsafe { self.vm().check_read_ptr(attr as *const GetIdAttr)? };
+                let prog = prog_get(attr.start_id).ok_or(SysError::ENOENT)?;
+                self.add_bpf_fd(BpfObjINode::new(BpfObject::Prog(prog)), "bpf-prog")
+            }
+            BPF_MAP_GET_FD_BY_ID => {
+                let attr = unsafe { self.vm().check_read_ptr(attr as *const GetIdAttr)? };
+                let map = map_get(attr.start_id).ok_or(SysError::ENOENT)?;
+                self.add_bpf_fd(BpfObjINode::new(BpfObject::Map(map)), "bpf-map")
+            }
+            BPF_OBJ_GET_INFO_BY_FD => {
+                let attr = unsafe { self.vm().check_write_ptr(attr as *mut InfoAttr)? };
+                match self.get_bpf_object(attr.bpf_fd as usize)? {
+                    BpfObject::Prog(prog) => {
+                        let info = BpfProgInfo {
+                            id: prog.id,
+                            insn_cnt: prog.insns.len() as u32,
+                            run_cnt: prog.stats.run_cnt.load(Ordering::Relaxed),
+                            run_time_ns: prog.stats.run_time_ns.load(Ordering::Relaxed),
+                            miss_cnt: prog_miss_cnt(&prog),
+                            attach_cnt: EBPF
+                                .attachments()
+                                .iter()
+                                .filter(|a| a.prog_id == prog.id)
+                                .count() as u32,
+                            nr_map_ids: prog.map_ids().len() as u32,
+                            detached_cnt: prog.stats.detached_cnt.load(Ordering::Relaxed),
+                        };
+                        attr.info_len = self.write_info(attr.info, attr.info_len, &info)?;
+                    }
+                    BpfObject::Map(map) => {
+                        let map = map.lock();
+                        let info = BpfMapInfo {
+                            id: map.id,
+                            map_type: map.map_type as u32,
+                            key_size: map.key_size as u32,
+                            value_size: map.value_size as u32,
+                            max_entries: map.max_entries as u32,
+                        };
+                        attr.info_len = self.write_info(attr.info, attr.info_len, &info)?;
+                    }
+                }
+                Ok(0)
+            }
+            BPF_LINK_CREATE => {
+                let attr = unsafe { self.vm().check_read_ptr(attr as *const LinkCreateAttr)? };
+                if attr.attach_type != BPF_XDP {
+                    return Err(SysError::EINVAL);
+                }
+                let prog = match attr.prog_fd as i32 {
+                    -1 => None,
+                    fd => Some(self.get_bpf_prog_of(fd as usize, ProgType::Xdp)?),
+                };
+                xdp_attach(attr.target_ifindex as usize, prog, attr.flags)?;
+                Ok(0)
+            }
+            BPF_USDT_ATTACH => {
+                let attr: UsdtAttachAttr = self.read_attr(attr, size)?;
+                let prog = self.get_bpf_prog_of(attr.prog_fd as usize, ProgType::Tracepoint)?;
+                let path = self.process().canonical_path(&check_and_clone_cstr(attr.path as *const u8)?);
+                let spec = check_and_clone_cstr(attr.probe as *const u8)?;
+                let mut parts = spec.splitn(2, ':');
+                let (provider, name) = match (parts.next(), parts.next()) {
+                    (Some(provider), Some(name)) => (provider, name),
+                    _ => return Err(SysError::EINVAL),
+                };
+                let probes = usdt_probes(&path).ok_or(SysError::ENOENT)?;
+                let matched: Vec<&UsdtProbe> = probes
+                    .iter()
+                    .filter(|p| p.provider == provider && p.name == name)
+                    .collect();
+                if matched.is_empty() {
+                    return Err(SysError::ENOENT);
+                }
+                if EBPF.register_usdt(&matched, &prog, path, attr.cookie) != 0 {
+                    return Err(SysError::EINVAL);
+                }
+                Ok(matched.len())
+            }
+            BPF_KPROBE_MULTI_ATTACH => {
+                let attr = unsafe { self.vm().check_read_ptr(attr as *const KprobeMultiAttr)? };
+                let prog = self.get_bpf_prog_of(attr.prog_fd as usize, ProgType::Kprobe)?;
+                let pp = probe_place(attr.probe_place as usize)?;
+                if let ProbePlace::User(_) = pp {
+                    return Err(SysError::EINVAL);
+                }
+                let syms = unsafe {
+                    self.vm()
+                        .check_read_array(attr.syms as *const u64, attr.cnt as usize)?
+                };
+                let cookies = match attr.cookies {
+                    0 => None,
+                    cookies => Some(unsafe {
+                        self.vm()
+                            .check_read_array(cookies as *const u64, attr.cnt as usize)?
+                    }),
+                };
+                let mut addrs = Vec::new();
+                for (i, &sym) in syms.iter().enumerate() {
+                    let pattern = check_and_clone_cstr(sym as *const u8)?;
+                    let mut matched = ModuleManager::with(|mm| mm.match_symbols(&pattern));
+                    if pattern.contains(|c| c == '*' || c == '?') {
+                        // a glob spans functions that cannot be probed, which a name does not
+                        matched.retain(|&(_, addr)| kprobe_blacklisted(addr).is_none());
+                    }
+                    if matched.is_empty() {
+                        warn!("bpf: no kernel function matches {}", pattern);
+                        return Err(SysError::ENOENT);
+                    }
+                    let cookie = cookies.map_or(0, |cookies| cookies[i]);
+                    addrs.extend(matched.into_iter().map(|(_, addr)| (addr, cookie)));
+                }
+                // a function matched by several names keeps the cookie of the first
+                addrs.sort_by_key(|&(addr, _)| addr);
+                addrs.dedup_by_key(|&mut (addr, _)| addr);
+                if crate::ebpf::ebpf_attach_multi(&addrs, &prog, pp, attr.priority) != 0 {
+                    return Err(SysError::EINVAL);
+                }
+                Ok(addrs.len())
+            }
+            _ => Err(SysError::EINVAL),
+        }
+    }
+
+    /// Remove the probe registered by `sys_register_ebpf` with the same `pt` and `path`.
+    pub fn sys_unregister_ebpf(&mut self, addr: usize, pt: usize, path: *const u8) -> SysResult {
+        self.check_bpf_allowed()?;
+        let pp = probe_place(pt)?;
+        let key = self.site_key(&pp, path, addr)?;
+        if crate::ebpf::ebpf_unregister(&key) != 0 {
             return Err(SysError::EINVAL);
         }
         Ok(0)
     }
 
+    /// The probed place at `addr`, in the binary at `path` for user probes.
+    fn site_key(&self, pp: &ProbePlace, path: *const u8, addr: usize) -> Result<SiteKey, SysError> {
+        let path = self.probe_path(pp, path)?;
+        Ok(SiteKey::new(pp, path, addr))
+    }
+
+    /// The canonical path of the binary probed by a user probe, empty for
+    /// kernel probes.
+    fn probe_path(&self, pp: &ProbePlace, path: *const u8) -> Result<String, SysError> {
+        match pp {
+            ProbePlace::Kernel(_) => Ok(String::new()),
+            ProbePlace::User(_) => Ok(self.process().canonical_path(&check_and_clone_cstr(path)?)),
+        }
+    }
+
     pub async fn sys_test_async(&mut self) -> SysResult {
         Ok(0)
     }
-}
\ No newline at end of file
+
+    /// Load a program whose map references are fds of the current process.
+    fn load_prog(&self, prog_type: ProgType, insns: Vec<u64>) -> Result<Arc<BpfProg>, SysError> {
+        prog_load(prog_type, insns, |fd| self.get_bpf_map(fd as usize).ok())
+    }
+
+    /// Read an attribute of which user space may pass only a prefix, the
+    /// rest being zero.
+    fn read_attr<T: Default>(&self, ptr: usize, size: usize) -> Result<T, SysError> {
+        let mut attr = T::default();
+        let len = size.min(core::mem::size_of::<T>());
+        let src = unsafe { self.vm().check_read_array(ptr as *const u8, len)? };
+        let dst = unsafe { core::slice::from_raw_parts_mut(&mut attr as *mut T as *mut u8, len) };
+        dst.copy_from_slice(src);
+        Ok(attr)
+    }
+
+    /// Copy at most `len` bytes of `info` to user space, returning the length written.
+    fn write_info<T>(&self, ptr: u64, len: u32, info: &T) -> Result<u32, SysError> {
+        let len = (len as usize).min(core::mem::size_of::<T>());
+        let out = unsafe { self.vm().check_write_array(ptr as *mut u8, len)? };
+        let src = unsafe { core::slice::from_raw_parts(info as *const T as *const u8, len) };
+        out.copy_from_slice(src);
+        Ok(len as u32)
+    }
+
+    fn add_bpf_fd(&self, inode: Arc<dyn INode>, path: &str) -> SysResult {
+        let file = FileHandle::new(
+            inode,
+            OpenOptions {
+                read: true,
+                write: true,
+                append: false,
+                nonblock: false,
+            },
+            String::from(path),
+            false,
+            false,
+        );
+        self.process().add_file(FileLike::File(file))
+    }
+
+    fn get_bpf_object(&self, fd: usize) -> Result<BpfObject, SysError> {
+        let inode = self.process().get_file_const(fd)?.inode();
+        let object = inode
+            .as_any_ref()
+            .downcast_ref::<BpfObjINode>()
+            .ok_or(SysError::EINVAL)?
+            .object
+            .clone();
+        Ok(object)
+    }
+
+    fn get_bpf_map(&self, fd: usize) -> Result<Arc<Mutex<BpfMap>>, SysError> {
+        match self.get_bpf_object(fd)? {
+            BpfObject::Map(map) => Ok(map),
+            _ => Err(SysError::EINVAL),
+        }
+    }
+
+    pub(super) fn get_bpf_prog(&self, fd: usize) -> Result<Arc<BpfProg>, SysError> {
+        match self.get_bpf_object(fd)? {
+            BpfObject::Prog(prog) => Ok(prog),
+            _ => Err(SysError::EINVAL),
+        }
+    }
+
+    /// The program of `fd`, which must be of `prog_type` to be attached.
+    fn get_bpf_prog_of(&self, fd: usize, prog_type: ProgType) -> Result<Arc<BpfProg>, SysError> {
+        let prog = self.get_bpf_prog(fd)?;
+        if prog.prog_type != prog_type {
+            warn!("bpf: program {} is {:?}, not {:?}", prog.id, prog.prog_type, prog_type);
+            return Err(SysError::EINVAL);
+        }
+        Ok(prog)
+    }
+}
@@ -20,6 +20,7 @@ use core::task::{Context, Poll};
 
 use bitvec::prelude::{BitSlice, BitVec, Lsb0};
 
+use super::proc::id_arg;
 use super::*;
 use crate::fs::epoll::EpollInstance;
 use crate::fs::fcntl::{FD_CLOEXEC, F_SETFD, O_CLOEXEC, O_NONBLOCK};
@@ -311,7 +312,7 @@ impl Syscall<'_> {
         info!("epoll_create1: flags: {:?}", flags);
         let mut proc = self.process();
         let epoll_instance = EpollInstance::new(flags);
-        let fd = proc.add_file(FileLike::EpollInstance(epoll_instance));
+        let fd = proc.add_file(FileLike::EpollInstance(epoll_instance))?;
         Ok(fd)
     }
 
@@ -600,6 +601,8 @@ impl Syscall<'_> {
                     if flags.contains(OpenFlags::EXCLUSIVE) {
                         return Err(SysError::EEXIST);
                     }
+                    proc.cred
+                        .check_permission(&file_inode.metadata()?, flags.permission())?;
                     if flags.contains(OpenFlags::TRUNCATE) {
                         if let Err(e) = file_inode.resize(0) {
                             // TODO: do something? what about device file?
@@ -608,7 +611,10 @@ impl Syscall<'_> {
                     file_inode
                 }
                 Err(FsError::EntryNotFound) => {
+                    proc.cred
+                        .check_permission(&dir_inode.metadata()?, MAY_WRITE | MAY_EXEC)?;
                     let inode = dir_inode.create(file_name, FileType::File, mode as u32)?;
+                    set_owner(&inode, &proc.cred);
                     TimeSpec::update(&inode);
                     TimeSpec::update(&dir_inode);
                     inode
@@ -616,7 +622,10 @@ impl Syscall<'_> {
                 Err(e) => return Err(SysError::from(e)),
             }
         } else {
-            proc.lookup_inode_at(dir_fd, &path, true)?
+            let inode = proc.lookup_inode_at(dir_fd, &path, true)?;
+            proc.cred
+                .check_permission(&inode.metadata()?, flags.permission())?;
+            inode
         };
 
         let file = FileHandle::new(
@@ -632,7 +641,7 @@ impl Syscall<'_> {
             debug!("files before open {:#?}", proc.files);
         }
 
-        let fd = proc.add_file(FileLike::File(file));
+        let fd = proc.add_file(FileLike::File(file))?;
         Ok(fd)
     }
 
@@ -660,7 +669,6 @@ impl Syscall<'_> {
         mode: usize,
         flags: usize,
     ) -> SysResult {
-        // TODO: check permissions based on uid/gid
         let proc = self.process();
         let path = check_and_clone_cstr(path)?;
         let flags = AtFlags::from_bits_truncate(flags);
@@ -671,8 +679,97 @@ impl Syscall<'_> {
                 dirfd as isize, path, mode, flags
             );
         }
-        let _inode =
+        let inode =
+            proc.lookup_inode_at(dirfd, &path, !flags.contains(AtFlags::SYMLINK_NOFOLLOW))?;
+        // F_OK only checks the existence
+        if mode != 0 {
+            let cred = if flags.contains(AtFlags::EACCESS) {
+                proc.cred.clone()
+            } else {
+                proc.cred.real()
+            };
+            cred.check_permission(&inode.metadata()?, mode & (MAY_READ | MAY_WRITE | MAY_EXEC))?;
+        }
+        Ok(0)
+    }
+
+    pub fn sys_chmod(&mut self, path: *const u8, mode: usize) -> SysResult {
+        self.sys_fchmodat(AT_FDCWD, path, mode, 0)
+    }
+
+    pub fn sys_fchmod(&mut self, fd: usize, mode: usize) -> SysResult {
+        info!("fchmod: fd: {}, mode: {:#o}", fd, mode);
+        let proc = self.process();
+        let inode = proc.get_file_const(fd)?.inode();
+        let mut metadata = inode.metadata()?;
+        proc.cred.chmod(&mut metadata, mode)?;
+        inode.set_metadata(&metadata)?;
+        Ok(0)
+    }
+
+    pub fn sys_fchmodat(
+        &mut self,
+        dirfd: usize,
+        path: *const u8,
+        mode: usize,
+        flags: usize,
+    ) -> SysResult {
+        let proc = self.process();
+        let path = check_and_clone_cstr(path)?;
+        let flags = AtFlags::from_bits_truncate(flags);
+        info!(
+            "fchmodat: dirfd: {}, path: {:?}, mode: {:#o}, flags: {:?}",
+            dirfd as isize, path, mode, flags
+        );
+        let inode =
+            proc.lookup_inode_at(dirfd, &path, !flags.contains(AtFlags::SYMLINK_NOFOLLOW))?;
+        let mut metadata = inode.metadata()?;
+        proc.cred.chmod(&mut metadata, mode)?;
+        inode.set_metadata(&metadata)?;
+        Ok(0)
+    }
+
+    pub fn sys_chown(&mut self, path: *const u8, uid: usize, gid: usize) -> SysResult {
+        self.sys_fchownat(AT_FDCWD, path, uid, gid, 0)
+    }
+
+    pub fn sys_lchown(&mut self, path: *const u8, uid: usize, gid: usize) -> SysResult {
+        self.sys_fchownat(AT_FDCWD, path, uid, gid, AtFlags::SYMLINK_NOFOLLOW.bits())
+    }
+
+    pub fn sys_fchown(&mut self, fd: usize, uid: usize, gid: usize) -> SysResult {
+        info!(
+            "fchown: fd: {}, uid: {}, gid: {}",
+            fd, uid as isize, gid as isize
+        );
+        let proc = self.process();
+        let inode = proc.get_file_const(fd)?.inode();
+        let mut metadata = inode.metadata()?;
+        proc.cred.chown(&mut metadata, id_arg(uid), id_arg(gid))?;
+        inode.set_metadata(&metadata)?;
+        Ok(0)
+    }
+
+    pub fn sys_fchownat(
+        &mut self,
+        dirfd: usize,
+        path: *const u8,
+        uid: usize,
+        gid: usize,
+        flags: usize,
+    ) -> SysResult {
+        let proc = self.process();
+        let path = check_and_clone_cstr(path)?;
+        let flags = AtFlags::from_bits_truncate(flags);
+        info!(
+            "fchownat: dirfd: {}, path: {:?}, uid: {}, gid: {}, flags: {:?}",
+            dirfd as isize, path, uid as isize, gid as isize, flags
+        );
+        let inode =
             proc.lookup_inode_at(dirfd, &path, !flags.contains(AtFlags::SYMLINK_NOFOLLOW))?;
+        let mut metadata = inode.metadata()?;
+        proc.cred.chown(&mut metadata, id_arg(uid), id_arg(gid))?;
+        inode.set_metadata(&metadata)?;
         Ok(0)
     }
 
@@ -866,6 +963,9 @@ impl Syscall<'_> {
 
     fn dup_impl(&mut self, fd1: usize, fd2: usize, flags: usize) -> SysResult {
         let mut proc = self.process();
+        if fd2 >= proc.rlimits.cur(RLIMIT_NOFILE) {
+            return Err(SysError::EBADF);
+        }
         // close fd2 first if it is opened
         proc.files.remove(&fd2);
 
@@ -1000,7 +1100,10 @@ impl Syscall<'_> {
         if dir_inode.find(file_name).is_ok() {
             return Err(SysError::EEXIST);
         }
+        proc.cred
+            .check_permission(&dir_inode.metadata()?, MAY_WRITE | MAY_EXEC)?;
         let inode = dir_inode.create(file_name, FileType::Dir, mode as u32)?;
+        set_owner(&inode, &proc.cred);
         TimeSpec::update(&inode);
         TimeSpec::update(&dir_inode);
         Ok(0)
@@ -1014,9 +1117,11 @@ impl Syscall<'_> {
         let (dir_path, file_name) = split_path(&path);
         let dir_inode = proc.lookup_inode(dir_path)?;
         let file_inode = dir_inode.find(file_name)?;
-        if file_inode.metadata()?.type_ != FileType::Dir {
+        let metadata = file_inode.metadata()?;
+        if metadata.type_ != FileType::Dir {
             return Err(SysError::ENOTDIR);
         }
+        proc.cred.check_remove(&dir_inode.metadata()?, &metadata)?;
         dir_inode.unlink(file_name)?;
         Ok(0)
     }
@@ -1101,9 +1206,11 @@ impl Syscall<'_> {
         let (dir_path, file_name) = split_path(&path);
         let dir_inode = proc.lookup_inode_at(dirfd, dir_path, true)?;
         let file_inode = dir_inode.find(file_name)?;
-        if file_inode.metadata()?.type_ == FileType::Dir {
+        let metadata = file_inode.metadata()?;
+        if metadata.type_ == FileType::Dir {
             return Err(SysError::EISDIR);
         }
+        proc.cred.check_remove(&dir_inode.metadata()?, &metadata)?;
         dir_inode.unlink(file_name)?;
         Ok(0)
     }
@@ -1130,7 +1237,7 @@ impl Syscall<'_> {
             String::from("pipe_r:[]"),
             true,
             (flags & O_CLOEXEC) != 0,
-        )));
+        )))?;
 
         let write_fd = proc.add_file(FileLike::File(FileHandle::new(
             Arc::new(write),
@@ -1144,6 +1251,13 @@ impl Syscall<'_> {
             true,
             (flags & O_CLOEXEC) != 0,
         )));
+        let write_fd = match write_fd {
+            Ok(fd) => fd,
+            Err(err) => {
+                proc.files.remove(&read_fd);
+                return Err(err);
+            }
+        };
 
         fds[0] = read_fd as u32;
         fds[1] = write_fd as u32;
@@ -1335,7 +1449,7 @@ impl Syscall<'_> {
                     F_DUPFD_CLOEXEC => {
                         info!("fcntl: dupfd_cloexec: arg: {:#x}", arg);
                         // let file_like = proc.get_file_like(fd1)?.clone();
-                        let new_fd = proc.get_free_fd_from(arg);
+                        let new_fd = proc.get_free_fd_from(arg)?;
                         core::mem::drop(proc);
                         self.dup_impl(fd, new_fd, 1)
                     }
@@ -1406,17 +1520,15 @@ impl Process {
         }
 
         let follow_max_depth = if follow { FOLLOW_MAX_DEPTH } else { 0 };
-        if dirfd == AT_FDCWD {
-            Ok(ROOT_INODE
-                .lookup(&self.cwd)?
-                .lookup_follow(path, follow_max_depth)?)
+        let start = if dirfd == AT_FDCWD {
+            ROOT_INODE.lookup(&self.cwd)?
         } else {
-            let file = match self.files.get(&dirfd).ok_or(SysError::EBADF)? {
-                FileLike::File(file) => file,
+            match self.files.get(&dirfd).ok_or(SysError::EBADF)? {
+                FileLike::File(file) => file.inode(),
                 _ => return Err(SysError::EBADF),
-            };
-            Ok(file.lookup_follow(path, follow_max_depth)?)
-        }
+            }
+        };
+        lookup_searchable(start, path, follow_max_depth, &self.cred)
     }
 
     pub fn lookup_inode(&self, path: &str) -> Result<Arc<dyn INode>, SysError> {
@@ -1424,6 +1536,55 @@ impl Process {
     }
 }
 
+/// Resolve `path` from the directory `start` like `INode::lookup_follow`,
+/// but only through directories `cred` may search.
+fn lookup_searchable(
+    start: Arc<dyn INode>,
+    path: &str,
+    mut follow_times: usize,
+    cred: &Credentials,
+) -> Result<Arc<dyn INode>, SysError> {
+    let mut result = start;
+    let mut rest_path = String::from(path);
+    while !rest_path.is_empty() {
+        let metadata = result.metadata()?;
+        if metadata.type_ != FileType::Dir {
+            return Err(SysError::ENOTDIR);
+        }
+        if rest_path.starts_with('/') {
+            result = result.fs().root_inode();
+            rest_path = String::from(&rest_path[1..]);
+            continue;
+        }
+        let name = match rest_path.find('/') {
+            None => core::mem::take(&mut rest_path),
+            Some(pos) => {
+                let name = String::from(&rest_path[..pos]);
+                rest_path = String::from(&rest_path[pos + 1..]);
+                name
+            }
+        };
+        if name.is_empty() {
+            continue;
+        }
+        cred.check_permission(&metadata, MAY_EXEC)?;
+        let inode = result.find(&name)?;
+        if inode.metadata()?.type_ == FileType::SymLink && follow_times > 0 {
+            follow_times -= 1;
+            // the link is resolved from the directory holding it
+            let target = String::from_utf8(inode.read_as_vec()?).map_err(|_| SysError::ENOENT)?;
+            rest_path = if rest_path.is_empty() {
+                target
+            } else {
+                target + "/" + &rest_path
+            };
+        } else {
+            result = inode;
+        }
+    }
+    Ok(result)
+}
+
 /// Split a `path` str to `(base_path, file_name)`
 fn split_path(path: &str) -> (&str, &str) {
     let mut split = path.trim_end_matches('/').rsplitn(2, '/');
@@ -1435,6 +1596,16 @@ fn split_path(path: &str) -> (&str, &str) {
     (dir_path, file_name)
 }
 
+/// Make the current user the owner of a new file
+fn set_owner(inode: &Arc<dyn INode>, cred: &Credentials) {
+    if let Ok(mut metadata) = inode.metadata() {
+        metadata.uid = cred.euid as _;
+        metadata.gid = cred.egid as _;
+        // silently fail for file systems without owners
+        inode.set_metadata(&metadata).ok();
+    }
+}
+
 impl From<FsError> for SysError {
     fn from(error: FsError) -> Self {
         match error {
@@ -1465,6 +1636,8 @@ bitflags! {
     struct AtFlags: usize {
         const EMPTY_PATH = 0x1000;
         const SYMLINK_NOFOLLOW = 0x100;
+        /// check access with the effective IDs
+        const EACCESS = 0x200;
     }
 }
 
@@ -1498,6 +1671,17 @@ impl OpenFlags {
         let b = self.bits() & 0b11;
         b == OpenFlags::WRONLY.bits() || b == OpenFlags::RDWR.bits()
     }
+    /// `MAY_*` permissions to open a file
+    fn permission(&self) -> usize {
+        let mut mask = 0;
+        if self.readable() {
+            mask |= MAY_READ;
+        }
+        if self.writable() || self.contains(OpenFlags::TRUNCATE) {
+            mask |= MAY_WRITE;
+        }
+        mask
+    }
     fn to_options(&self) -> OpenOptions {
         OpenOptions {
             read: self.readable(),
@@ -1,10 +1,11 @@
 use rcore_fs::vfs::MMapArea;
-use rcore_memory::memory_set::handler::{Delay, File, Linear, Shared};
+use rcore_memory::memory_set::handler::{Delay, File, Linear, Shared, Swap};
 use rcore_memory::memory_set::MemoryAttr;
 use rcore_memory::PAGE_SIZE;
 
 use super::*;
 use crate::memory::GlobalFrameAlloc;
+use crate::swap::GlobalSwap;
 
 impl Syscall<'_> {
     pub fn sys_mmap(
@@ -24,6 +25,9 @@ impl Syscall<'_> {
         );
 
         let mut proc = self.process();
+        if len > proc.rlimits.cur(RLIMIT_AS).saturating_sub(self.vm().size()) {
+            return Err(SysError::ENOMEM);
+        }
         let mut addr = addr;
         if addr == 0 {
             // although NULL can be a valid address
@@ -36,7 +40,13 @@ impl Syscall<'_> {
             // we have to map it to addr, so remove the old mapping first
             self.vm().pop_with_split(addr, addr + len);
         } else {
-            addr = self.vm().find_free_area(addr, len);
+            // leave the heap room to grow up to its limit
+            let heap_start = proc.brk_start;
+            let heap_end = heap_start
+                .saturating_add(proc.rlimits.cur(RLIMIT_DATA).min(DATA_LIMIT as usize));
+            addr = self
+                .vm()
+                .find_free_area_outside(addr, len, heap_start, heap_end);
         }
 
         if flags.contains(MmapFlags::ANONYMOUS) {
@@ -54,7 +64,7 @@ impl Syscall<'_> {
                     addr,
                     addr + len,
                     prot.to_attr(),
-                    Delay::new(GlobalFrameAlloc),
+                    Swap::new(GlobalFrameAlloc, GlobalSwap),
                     "mmap_anon",
                 );
                 return Ok(addr);
@@ -98,6 +108,69 @@ impl Syscall<'_> {
         self.vm().pop_with_split(addr, addr + len);
         Ok(0)
     }
+
+    pub fn sys_brk(&mut self, addr: usize) -> SysResult {
+        info!("brk: addr: {:#x}", addr);
+        let mut proc = self.process();
+        let mut vm = self.vm();
+        let (start, brk) = (proc.brk_start, proc.brk);
+        // the break is returned unchanged if it is queried or can not be moved
+        if addr < start || addr - start > proc.rlimits.cur(RLIMIT_DATA) {
+            return Ok(brk);
+        }
+        let end = (brk + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let new_end = (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        if new_end > end {
+            if vm.iter().any(|area| area.is_overlap_with(end, new_end))
+                || new_end - end > proc.rlimits.cur(RLIMIT_AS).saturating_sub(vm.size())
+            {
+                return Ok(brk);
+            }
+            // the heap area may have been unmapped in part or whole,
+            // so it is only extended if it still ends at the break
+            let heap_end = vm
+                .iter()
+                .find(|area| area.start_addr() == start)
+                .map(|area| area.end_addr());
+            if heap_end == Some(end) {
+                vm.extend(end, new_end);
+            } else {
+                vm.push(
+                    end,
+                    new_end,
+                    MemoryAttr::default().user(),
+                    Swap::new(GlobalFrameAlloc, GlobalSwap),
+                    "heap",
+                );
+            }
+        } else if new_end < end {
+            vm.pop_with_split(new_end, end);
+        }
+        proc.brk = addr;
+        Ok(addr)
+    }
+
+    pub fn sys_swapon(&mut self, path: *const u8, flags: usize) -> SysResult {
+        let path = check_and_clone_cstr(path)?;
+        info!("swapon: path: {:?}, flags: {:#x}", path, flags);
+        if !self.process().cred.is_root() {
+            return Err(SysError::EPERM);
+        }
+        let inode = self.process().lookup_inode(&path)?;
+        crate::swap::swap_on(inode)?;
+        Ok(0)
+    }
+
+    pub fn sys_swapoff(&mut self, path: *const u8) -> SysResult {
+        let path = check_and_clone_cstr(path)?;
+        info!("swapoff: path: {:?}", path);
+        if !self.process().cred.is_root() {
+            return Err(SysError::EPERM);
+        }
+        let inode = self.process().lookup_inode(&path)?;
+        crate::swap::swap_off(&inode)?;
+        Ok(0)
+    }
 }
 
 bitflags! {
@@ -1,8 +1,13 @@
 #![allow(dead_code)]
 
+use super::proc::check_sched_owner;
 use super::*;
 use crate::arch::cpu;
-use crate::consts::{ARCH, USER_STACK_SIZE};
+use crate::consts::ARCH;
+use crate::ebpf::cbpf::{SockFilter, SockFprog};
+use crate::process::seccomp::*;
+use crate::sched::{self, CpuSet};
+use crate::signal::{send_signal, Siginfo, SigsysFields, SYS_SECCOMP};
 use crate::syscall::SysError::ETIMEDOUT;
 use crate::trap::TICK_ACTIVITY;
 use core::mem::size_of;
@@ -37,26 +42,55 @@ impl Syscall<'_> {
         Ok(0)
     }
 
-    pub fn sys_sched_getaffinity(&mut self, pid: usize, size: usize, mask: *mut u32) -> SysResult {
+    pub fn sys_sched_getaffinity(&mut self, tid: usize, size: usize, mask: *mut u64) -> SysResult {
         info!(
-            "sched_getaffinity: pid: {}, size: {}, mask: {:?}",
-            pid, size, mask
+            "sched_getaffinity: tid: {}, size: {}, mask: {:?}",
+            tid, size, mask
         );
-        let mask = unsafe { self.vm().check_write_array(mask, size / size_of::<u32>())? };
-
-        // we only have 4 cpu at most.
-        // so just set it.
-        mask[0] = 0b1111;
-        mask[1] = 0;
-        mask[2] = 0;
-        mask[3] = 0;
+        // harts beyond MAX_CPUS are never used, the mask needs no more bytes
+        if size < size_of::<u64>() {
+            return Err(SysError::EINVAL);
+        }
+        let mask = unsafe { self.vm().check_write_ptr(mask)? };
+        let (tid, _) = self.sched_thread(tid)?;
+        let affinity = sched::thread_affinity(tid).ok_or(SysError::ESRCH)?;
+        *mask = affinity.intersection(sched::online_cpus()).bits();
+        Ok(size_of::<u64>())
+    }
+
+    pub fn sys_sched_setaffinity(&mut self, tid: usize, size: usize, mask: *const u8) -> SysResult {
+        info!(
+            "sched_setaffinity: tid: {}, size: {}, mask: {:?}",
+            tid, size, mask
+        );
+        let mask = unsafe { self.vm().check_read_array(mask, size)? };
+        let mut bytes = [0u8; size_of::<u64>()];
+        let len = size.min(bytes.len());
+        bytes[..len].copy_from_slice(&mask[..len]);
+        let affinity = CpuSet::from_bits(u64::from_ne_bytes(bytes));
+        if affinity.intersection(sched::online_cpus()).is_empty() {
+            return Err(SysError::EINVAL);
+        }
+
+        let (tid, proc) = self.sched_thread(tid)?;
+        let cred = self.process().cred.clone();
+        check_sched_owner(&cred, &proc.lock())?;
+        if !sched::set_thread_affinity(tid, affinity) {
+            return Err(SysError::ESRCH);
+        }
         Ok(0)
     }
 
     pub fn sys_sysinfo(&mut self, sys_info: *mut SysInfo) -> SysResult {
         let sys_info = unsafe { self.vm().check_write_ptr(sys_info)? };
 
-        let sysinfo = SysInfo::default();
+        let (totalswap, freeswap) = crate::swap::swap_info();
+        let sysinfo = SysInfo {
+            totalswap: totalswap as u64,
+            freeswap: freeswap as u64,
+            mem_unit: 1,
+            ..SysInfo::default()
+        };
         *sys_info = sysinfo;
         Ok(0)
     }
@@ -147,40 +181,42 @@ impl Syscall<'_> {
             "prlimit64: pid: {}, resource: {}, new_limit: {:x?}, old_limit: {:x?}",
             pid, resource, new_limit, old_limit
         );
-        match resource {
-            RLIMIT_STACK => {
-                if !old_limit.is_null() {
-                    let old_limit = unsafe { self.vm().check_write_ptr(old_limit)? };
-                    *old_limit = RLimit {
-                        cur: USER_STACK_SIZE as u64,
-                        max: USER_STACK_SIZE as u64,
-                    };
-                }
-                Ok(0)
-            }
-            RLIMIT_NOFILE => {
-                if !old_limit.is_null() {
-                    let old_limit = unsafe { self.vm().check_write_ptr(old_limit)? };
-                    *old_limit = RLimit {
-                        cur: 1024,
-                        max: 1024,
-                    };
-                }
-                Ok(0)
-            }
-            RLIMIT_RSS | RLIMIT_AS => {
-                if !old_limit.is_null() {
-                    let old_limit = unsafe { self.vm().check_write_ptr(old_limit)? };
-                    // 1GB
-                    *old_limit = RLimit {
-                        cur: 1024 * 1024 * 1024,
-                        max: 1024 * 1024 * 1024,
-                    };
-                }
-                Ok(0)
-            }
-            _ => Err(SysError::ENOSYS),
+        let new_limit = if !new_limit.is_null() {
+            Some(unsafe { *self.vm().check_read_ptr(new_limit)? })
+        } else {
+            None
+        };
+        let old_limit = if !old_limit.is_null() {
+            Some(unsafe { self.vm().check_write_ptr(old_limit)? })
+        } else {
+            None
+        };
+        let cred = self.process().cred.clone();
+        let proc = if pid == 0 {
+            self.thread.proc.clone()
+        } else {
+            process(pid).ok_or(SysError::ESRCH)?
+        };
+        let mut proc = proc.lock();
+        if !cred.is_root() && proc.cred.uid != cred.uid {
+            return Err(SysError::EPERM);
+        }
+        let limit = proc.rlimits.get(resource)?;
+        if let Some(new_limit) = new_limit {
+            proc.rlimits.set(resource, new_limit, cred.is_root())?;
         }
+        if let Some(old_limit) = old_limit {
+            *old_limit = limit;
+        }
+        Ok(0)
+    }
+
+    pub fn sys_getrlimit(&mut self, resource: usize, rlim: *mut RLimit) -> SysResult {
+        self.sys_prlimit64(0, resource, core::ptr::null(), rlim)
+    }
+
+    pub fn sys_setrlimit(&mut self, resource: usize, rlim: *const RLimit) -> SysResult {
+        self.sys_prlimit64(0, resource, rlim, core::ptr::null_mut())
     }
 
     pub fn sys_getrandom(&mut self, buf: *mut u8, len: usize, _flag: u32) -> SysResult {
@@ -197,8 +233,148 @@ impl Syscall<'_> {
 
         Ok(len)
     }
+
+    pub fn sys_prctl(&mut self, option: usize, arg2: usize, arg3: usize) -> SysResult {
+        match option {
+            PR_GET_SECCOMP => Ok(self.process().seccomp.mode),
+            PR_SET_SECCOMP => match arg2 {
+                SECCOMP_MODE_STRICT => self.sys_seccomp(SECCOMP_SET_MODE_STRICT, 0, 0),
+                SECCOMP_MODE_FILTER => self.sys_seccomp(SECCOMP_SET_MODE_FILTER, 0, arg3),
+                _ => Err(SysError::EINVAL),
+            },
+            PR_SET_NO_NEW_PRIVS => {
+                // the flag can not be unset
+                if arg2 != 1 || arg3 != 0 {
+                    return Err(SysError::EINVAL);
+                }
+                self.process().no_new_privs = true;
+                Ok(0)
+            }
+            PR_GET_NO_NEW_PRIVS => Ok(self.process().no_new_privs as usize),
+            _ => self.unimplemented("prctl", Ok(0)),
+        }
+    }
+
+    pub fn sys_seccomp(&mut self, op: usize, flags: usize, args: usize) -> SysResult {
+        info!("seccomp: op: {}, flags: {:#x}, args: {:#x}", op, flags, args);
+        match op {
+            SECCOMP_SET_MODE_STRICT => {
+                if flags != 0 || args != 0 {
+                    return Err(SysError::EINVAL);
+                }
+                self.process().seccomp.set_strict()?;
+                Ok(0)
+            }
+            SECCOMP_SET_MODE_FILTER => {
+                // filters are shared by all threads, so they are always in sync
+                if flags & !SECCOMP_FILTER_FLAG_TSYNC != 0 {
+                    return Err(SysError::EINVAL);
+                }
+                // a filter could fool a set-user-ID program run afterwards
+                {
+                    let proc = self.process();
+                    if !proc.no_new_privs && !proc.cred.is_root() {
+                        return Err(SysError::EACCES);
+                    }
+                }
+                let fprog = unsafe { self.vm().check_read_ptr(args as *const SockFprog)? };
+                let filter = unsafe {
+                    self.vm()
+                        .check_read_array(fprog.filter as *const SockFilter, fprog.len as usize)?
+                };
+                self.process().seccomp.add_filter(filter)?;
+                Ok(0)
+            }
+            SECCOMP_GET_ACTION_AVAIL => {
+                let action = unsafe { self.vm().check_read_ptr(args as *const u32)? };
+                if flags != 0 || !action_avail(*action) {
+                    return Err(SysError::EOPNOTSUPP);
+                }
+                Ok(0)
+            }
+            _ => Err(SysError::EINVAL),
+        }
+    }
+
+    /// Run the seccomp filters of the process on a system call.
+    /// Returns the result of the call if it is not to be performed.
+    pub fn check_seccomp(&mut self, id: usize, args: &[usize; 6]) -> Option<isize> {
+        let data = SeccompData {
+            nr: id as i32,
+            arch: AUDIT_ARCH,
+            instruction_pointer: self.syscall_ip() as u64,
+            args: [
+                args[0] as u64,
+                args[1] as u64,
+                args[2] as u64,
+                args[3] as u64,
+                args[4] as u64,
+                args[5] as u64,
+            ],
+        };
+        let action = self.process().seccomp.check(&data);
+        match action {
+            SeccompAction::Allow => None,
+            SeccompAction::Log => {
+                warn!("seccomp: syscall {} of {} logged", id, self.thread.tid);
+                None
+            }
+            SeccompAction::Errno(errno) => Some(-(errno.min(MAX_ERRNO) as isize)),
+            SeccompAction::Trap(errno) => {
+                info!("seccomp: syscall {} of {} trapped", id, self.thread.tid);
+                let mut info = Siginfo {
+                    signo: Signal::SIGSYS as i32,
+                    errno: errno as i32,
+                    code: SYS_SECCOMP,
+                    field: Default::default(),
+                };
+                info.field.sigsys = SigsysFields {
+                    call_addr: data.instruction_pointer as usize,
+                    syscall: data.nr,
+                    arch: data.arch,
+                };
+                send_signal(self.thread.proc.clone(), self.thread.tid as isize, info);
+                Some(-(SysError::ENOSYS as isize))
+            }
+            SeccompAction::KillThread => {
+                warn!("seccomp: syscall {} of {} killed the thread", id, self.thread.tid);
+                // the process dies of SIGSYS if it was the last thread
+                self.exit_thread(signaled_status(Signal::SIGSYS, true)).ok();
+                Some(0)
+            }
+            SeccompAction::KillProcess => {
+                warn!("seccomp: syscall {} of {} killed the process", id, self.thread.tid);
+                self.exit_process(signaled_status(Signal::SIGSYS, true)).ok();
+                Some(0)
+            }
+        }
+    }
+
+    /// Address of the instruction making the current system call
+    fn syscall_ip(&self) -> usize {
+        // the pc is already past the instruction on riscv and mips
+        #[cfg(riscv)]
+        return self.context.sepc - 4;
+        #[cfg(mipsel)]
+        return self.context.epc - 4;
+        #[cfg(not(any(riscv, mipsel)))]
+        return 0;
+    }
 }
 
+const PR_GET_SECCOMP: usize = 21;
+const PR_SET_SECCOMP: usize = 22;
+const PR_SET_NO_NEW_PRIVS: usize = 38;
+const PR_GET_NO_NEW_PRIVS: usize = 39;
+
+const SECCOMP_SET_MODE_STRICT: usize = 0;
+const SECCOMP_SET_MODE_FILTER: usize = 1;
+const SECCOMP_GET_ACTION_AVAIL: usize = 2;
+
+const SECCOMP_FILTER_FLAG_TSYNC: usize = 1;
+
+const MAX_ERRNO: u16 = 4095;
+
 const LINUX_REBOOT_CMD_RESTART: u32 = 0x01234567;
 const LINUX_REBOOT_CMD_HALT: u32 = 0xCDEF0123;
 const LINUX_REBOOT_CMD_CAD_ON: u32 = 0x89ABCDEF;
@@ -224,15 +400,3 @@ pub struct SysInfo {
     freehigh: u64,
     mem_unit: u32,
 }
-
-const RLIMIT_STACK: usize = 3;
-const RLIMIT_RSS: usize = 5;
-const RLIMIT_NOFILE: usize = 7;
-const RLIMIT_AS: usize = 9;
-
-#[repr(C)]
-#[derive(Debug, Default)]
-pub struct RLimit {
-    cur: u64, // soft limit
-    max: u64, // hard limit
-}
@@ -119,6 +119,10 @@ impl Syscall<'_> {
             debug!("{}:{}:{} syscall id {} begin", cid, pid, tid, id);
         }
 
+        if let Some(ret) = self.check_seccomp(id, &args) {
+            return ret;
+        }
+
         // use platform-specific syscal numbers
         // See https://filippo.io/linux-syscall-table/
         // And https://fedora.juszkiewicz.com.pl/syscalls.html.
@@ -178,10 +182,12 @@ impl Syscall<'_> {
             SYS_READLINKAT => {
                 self.sys_readlinkat(args[0], args[1] as *const u8, args[2] as *mut u8, args[3])
             }
-            SYS_FCHMOD => self.unimplemented("fchmod", Ok(0)),
-            SYS_FCHMODAT => self.unimplemented("fchmodat", Ok(0)),
-            SYS_FCHOWN => self.unimplemented("fchown", Ok(0)),
-            SYS_FCHOWNAT => self.unimplemented("fchownat", Ok(0)),
+            SYS_FCHMOD => self.sys_fchmod(args[0], args[1]),
+            SYS_FCHMODAT => self.sys_fchmodat(args[0], args[1] as *const u8, args[2], args[3]),
+            SYS_FCHOWN => self.sys_fchown(args[0], args[1], args[2]),
+            SYS_FCHOWNAT => {
+                self.sys_fchownat(args[0], args[1] as *const u8, args[2], args[3], args[4])
+            }
             SYS_FACCESSAT => self.sys_faccessat(args[0], args[1] as *const u8, args[2], args[3]),
             SYS_DUP3 => self.sys_dup3(args[0], args[1], args[2]),
             SYS_PIPE2 => self.sys_pipe2(args[0] as *mut u32, args[1]), // TODO: handle `flags`
@@ -244,11 +250,13 @@ impl Syscall<'_> {
             SYS_UMOUNT2 => self.unimplemented("umount2", Err(SysError::EACCES)),
 
             // memory
-            SYS_BRK => self.unimplemented("brk", Err(SysError::ENOMEM)),
+            SYS_BRK => self.sys_brk(args[0]),
             SYS_MMAP => self.sys_mmap(args[0], args[1], args[2], args[3], args[4], args[5]),
             SYS_MPROTECT => self.sys_mprotect(args[0], args[1], args[2]),
             SYS_MUNMAP => self.sys_munmap(args[0], args[1]),
             SYS_MADVISE => self.unimplemented("madvise", Ok(0)),
+            SYS_SWAPON => self.sys_swapon(args[0] as *const u8, args[1]),
+            SYS_SWAPOFF => self.sys_swapoff(args[0] as *const u8),
 
             // signal
             SYS_RT_SIGACTION => self.sys_rt_sigaction(
@@ -270,9 +278,20 @@ impl Syscall<'_> {
             SYS_KILL => self.sys_kill(args[0] as isize, args[1]),
 
             // schedule
-            SYS_SCHED_YIELD => self.sys_yield(),
+            SYS_SCHED_YIELD => self.sys_yield().await,
+            SYS_SCHED_SETSCHEDULER => {
+                self.sys_sched_setscheduler(args[0], args[1], UserInPtr::from(args[2]))
+            }
+            SYS_SCHED_GETSCHEDULER => self.sys_sched_getscheduler(args[0]),
+            SYS_SCHED_SETPARAM => self.sys_sched_setparam(args[0], UserInPtr::from(args[1])),
+            SYS_SCHED_GETPARAM => self.sys_sched_getparam(args[0], UserOutPtr::from(args[1])),
+            SYS_SCHED_GET_PRIORITY_MAX => self.sys_sched_get_priority_max(args[0]),
+            SYS_SCHED_GET_PRIORITY_MIN => self.sys_sched_get_priority_min(args[0]),
+            SYS_SCHED_SETAFFINITY => {
+                self.sys_sched_setaffinity(args[0], args[1], args[2] as *const u8)
+            }
             SYS_SCHED_GETAFFINITY => {
-                self.sys_sched_getaffinity(args[0], args[1], args[2] as *mut u32)
+                self.sys_sched_getaffinity(args[0], args[1], args[2] as *mut u64)
             }
 
             // socket
@@ -334,9 +353,13 @@ impl Syscall<'_> {
             SYS_EXIT => self.sys_exit(args[0] as usize),
             SYS_EXIT_GROUP => self.sys_exit_group(args[0]),
             SYS_WAIT4 => {
-                self.sys_wait4(args[0] as isize, UserInOutPtr::from(args[1]))
-                    .await
-            } // TODO: wait4
+                self.sys_wait4(
+                    args[0] as isize,
+                    UserInOutPtr::from(args[1]),
+                    UserOutPtr::from(args[3]),
+                )
+                .await
+            }
             SYS_SET_TID_ADDRESS => self.sys_set_tid_address(args[0] as *mut u32),
             SYS_FUTEX => {
                 self.sys_futex(
@@ -351,7 +374,34 @@ impl Syscall<'_> {
 
             // time
             SYS_NANOSLEEP => self.sys_nanosleep(UserInPtr::from(args[0])).await,
-            SYS_SETITIMER => self.unimplemented("setitimer", Ok(0)),
+            SYS_GETITIMER => self.sys_getitimer(args[0], UserOutPtr::from(args[1])),
+            SYS_SETITIMER => self.sys_setitimer(
+                args[0],
+                UserInPtr::from(args[1]),
+                UserOutPtr::from(args[2]),
+            ),
+            SYS_TIMER_CREATE => self.sys_timer_create(
+                args[0],
+                UserInPtr::from(args[1]),
+                UserOutPtr::from(args[2]),
+            ),
+            SYS_TIMER_SETTIME => self.sys_timer_settime(
+                args[0],
+                args[1],
+                UserInPtr::from(args[2]),
+                UserOutPtr::from(args[3]),
+            ),
+            SYS_TIMER_GETTIME => self.sys_timer_gettime(args[0], UserOutPtr::from(args[1])),
+            SYS_TIMER_GETOVERRUN => self.sys_timer_getoverrun(args[0]),
+            SYS_TIMER_DELETE => self.sys_timer_delete(args[0]),
+            SYS_TIMERFD_CREATE => self.sys_timerfd_create(args[0], args[1]),
+            SYS_TIMERFD_SETTIME => self.sys_timerfd_settime(
+                args[0],
+                args[1],
+                UserInPtr::from(args[2]),
+                UserOutPtr::from(args[3]),
+            ),
+            SYS_TIMERFD_GETTIME => self.sys_timerfd_gettime(args[0], UserOutPtr::from(args[1])),
             SYS_GETTIMEOFDAY => {
                 self.sys_gettimeofday(UserOutPtr::from(args[0]), UserInPtr::from(args[1]))
             }
@@ -386,28 +436,42 @@ impl Syscall<'_> {
             SYS_GETTID => self.sys_gettid(),
             SYS_UNAME => self.sys_uname(args[0] as *mut u8),
             SYS_UMASK => self.unimplemented("umask", Ok(0o777)),
-            //        SYS_GETRLIMIT => self.sys_getrlimit(),
-            SYS_SETRLIMIT => self.unimplemented("setrlimit", Ok(0)),
+            SYS_GETRLIMIT => self.sys_getrlimit(args[0], args[1] as *mut RLimit),
+            SYS_SETRLIMIT => self.sys_setrlimit(args[0], args[1] as *const RLimit),
             SYS_GETRUSAGE => self.sys_getrusage(args[0], args[1] as *mut RUsage),
             SYS_SYSINFO => self.sys_sysinfo(args[0] as *mut SysInfo),
             SYS_TIMES => self.sys_times(args[0] as *mut Tms),
-            SYS_GETUID => self.unimplemented("getuid", Ok(0)),
-            SYS_GETGID => self.unimplemented("getgid", Ok(0)),
-            SYS_SETUID => self.unimplemented("setuid", Ok(0)),
-            SYS_GETEUID => self.unimplemented("geteuid", Ok(0)),
-            SYS_GETEGID => self.unimplemented("getegid", Ok(0)),
+            SYS_GETUID => self.sys_getuid(),
+            SYS_GETGID => self.sys_getgid(),
+            SYS_SETUID => self.sys_setuid(args[0]),
+            SYS_GETEUID => self.sys_geteuid(),
+            SYS_GETEGID => self.sys_getegid(),
             SYS_GETPPID => self.sys_getppid(),
             SYS_SETSID => self.unimplemented("setsid", Ok(0)),
             SYS_GETPGID => self.sys_getpgid(args[0]),
             SYS_SETPGID => self.sys_setpgid(args[0], args[1]),
-            SYS_GETGROUPS => self.unimplemented("getgroups", Ok(0)),
+            SYS_GETGROUPS => self.sys_getgroups(args[0], args[1] as *mut u32),
             SYS_RT_SIGTIMEDWAIT => self.unimplemented("rt_sigtimedwait", Ok(0)),
-            SYS_SETGROUPS => self.unimplemented("setgroups", Ok(0)),
-            SYS_SETRESUID => self.unimplemented("setresuid", Ok(0)),
-            SYS_SETRESGID => self.unimplemented("setresgid", Ok(0)),
-            SYS_SETGID => self.unimplemented("setgid", Ok(0)),
-            SYS_SETPRIORITY => self.sys_set_priority(args[0]),
-            SYS_PRCTL => self.unimplemented("prctl", Ok(0)),
+            SYS_SETGROUPS => self.sys_setgroups(args[0], args[1] as *const u32),
+            SYS_SETREUID => self.sys_setreuid(args[0], args[1]),
+            SYS_SETREGID => self.sys_setregid(args[0], args[1]),
+            SYS_SETRESUID => self.sys_setresuid(args[0], args[1], args[2]),
+            SYS_SETRESGID => self.sys_setresgid(args[0], args[1], args[2]),
+            SYS_GETRESUID => self.sys_getresuid(
+                args[0] as *mut u32,
+                args[1] as *mut u32,
+                args[2] as *mut u32,
+            ),
+            SYS_GETRESGID => self.sys_getresgid(
+                args[0] as *mut u32,
+                args[1] as *mut u32,
+                args[2] as *mut u32,
+            ),
+            SYS_SETGID => self.sys_setgid(args[0]),
+            SYS_SETPRIORITY => self.sys_setpriority(args[0], args[1], args[2]),
+            SYS_GETPRIORITY => self.sys_getpriority(args[0], args[1]),
+            SYS_PRCTL => self.sys_prctl(args[0], args[1], args[2]),
+            SYS_SECCOMP => self.sys_seccomp(args[0], args[1], args[2]),
             SYS_MEMBARRIER => self.unimplemented("membarrier", Ok(0)),
             SYS_PRLIMIT64 => self.sys_prlimit64(
                 args[0],
@@ -441,8 +505,9 @@ impl Syscall<'_> {
             SYS_GET_PADDR => {
                 self.sys_get_paddr(args[0] as *const u64, args[1] as *mut u64, args[2])
             }
+            SYS_BPF => self.sys_bpf(args[0], args[1], args[2]),
             SYS_REGISTER_EBPF => self.sys_register_ebpf(args[0], args[1] as *const u8, args[2], args[3], args[4] as *const u8),
-            SYS_UNREGISTER_EBPF => self.sys_unregister_ebpf(args[0]),
+            SYS_UNREGISTER_EBPF => self.sys_unregister_ebpf(args[0], args[1], args[2] as *const u8),
             SYS_TEST_ASYNC => self.sys_test_async().await,
 
             _ => {
@@ -562,7 +627,7 @@ impl Syscall<'_> {
                 args[4] as *const TimeVal,
             ),
             SYS_DUP2 => self.sys_dup2(args[0], args[1]),
-            SYS_ALARM => self.unimplemented("alarm", Ok(0)),
+            SYS_ALARM => self.sys_alarm(args[0]),
             SYS_FORK => self.sys_fork(),
             SYS_VFORK => self.sys_vfork(),
             SYS_RENAME => self.sys_rename(args[0] as *const u8, args[1] as *const u8),
@@ -572,8 +637,9 @@ impl Syscall<'_> {
             SYS_UNLINK => self.sys_unlink(args[0] as *const u8),
             SYS_SYMLINK => self.sys_symlink(args[0] as *const u8, args[1] as *const u8),
             SYS_READLINK => self.sys_readlink(args[0] as *const u8, args[1] as *mut u8, args[2]),
-            SYS_CHMOD => self.unimplemented("chmod", Ok(0)),
-            SYS_CHOWN => self.unimplemented("chown", Ok(0)),
+            SYS_CHMOD => self.sys_chmod(args[0] as *const u8, args[1]),
+            SYS_CHOWN => self.sys_chown(args[0] as *const u8, args[1], args[2]),
+            SYS_LCHOWN => self.sys_lchown(args[0] as *const u8, args[1], args[2]),
             SYS_ARCH_PRCTL => self.sys_arch_prctl(args[0] as i32, args[1]),
             SYS_TIME => self.sys_time(args[0] as *mut u64),
             SYS_EPOLL_CREATE => self.sys_epoll_create(args[0]),
@@ -636,6 +702,7 @@ pub enum SysError {
     EIDRM = 43,
     ENOTSOCK = 80,
     ENOPROTOOPT = 92,
+    EOPNOTSUPP = 95,
     EPFNOSUPPORT = 96,
     EAFNOSUPPORT = 97,
     ENOBUFS = 105,
@@ -695,6 +762,7 @@ impl fmt::Display for SysError {
                 ELOOP => "Too many symbolic links encountered",
                 ENOTSOCK => "Socket operation on non-socket",
                 ENOPROTOOPT => "Protocol not available",
+                EOPNOTSUPP => "Operation not supported",
                 EPFNOSUPPORT => "Protocol family not supported",
                 EAFNOSUPPORT => "Address family not supported by protocol",
                 ENOBUFS => "No buffer space available",
@@ -2,11 +2,12 @@
 
 use super::fs::IoVecs;
 use super::*;
+use crate::ebpf::cbpf::{SockFilter, SockFprog};
 use crate::fs::FileLike;
 use crate::memory::MemorySet;
 use crate::net::{
     Endpoint, LinkLevelEndpoint, NetlinkEndpoint, NetlinkSocketState, PacketSocketState,
-    RawSocketState, Socket, TcpSocketState, UdpSocketState,
+    RawSocketState, Socket, SocketFilter, TcpSocketState, UdpSocketState,
 };
 use alloc::boxed::Box;
 use core::cmp::min;
@@ -39,7 +40,7 @@ impl Syscall<'_> {
             },
             _ => return Err(SysError::EAFNOSUPPORT),
         };
-        let fd = proc.add_file(FileLike::Socket(socket));
+        let fd = proc.add_file(FileLike::Socket(socket))?;
         Ok(fd)
     }
 
@@ -55,6 +56,27 @@ impl Syscall<'_> {
             "setsockopt: fd: {}, level: {}, optname: {}",
             fd, level, optname
         );
+        if level == SOL_SOCKET {
+            match optname {
+                SO_ATTACH_FILTER => {
+                    let fprog = unsafe { self.vm().check_read_ptr(optval as *const SockFprog)? };
+                    let filter = unsafe {
+                        self.vm()
+                            .check_read_array(fprog.filter as *const SockFilter, fprog.len as usize)?
+                    };
+                    let filter = SocketFilter::classic(filter)?;
+                    return self.process().get_socket(fd)?.set_filter(Some(filter));
+                }
+                SO_ATTACH_BPF => {
+                    self.check_bpf_allowed()?;
+                    let prog_fd = unsafe { *self.vm().check_read_ptr(optval as *const u32)? };
+                    let filter = SocketFilter::ebpf(self.get_bpf_prog(prog_fd as usize)?)?;
+                    return self.process().get_socket(fd)?.set_filter(Some(filter));
+                }
+                SO_DETACH_FILTER => return self.process().get_socket(fd)?.set_filter(None),
+                _ => {}
+            }
+        }
         let mut proc = self.process();
         let data = unsafe { self.vm().check_read_array(optval, optlen)? };
         let socket = proc.get_socket(fd)?;
@@ -236,7 +258,7 @@ impl Syscall<'_> {
         let socket = proc.get_socket(fd)?;
         let (new_socket, remote_endpoint) = socket.accept()?;
 
-        let new_fd = proc.add_file(FileLike::Socket(new_socket));
+        let new_fd = proc.add_file(FileLike::Socket(new_socket))?;
 
         if !addr.is_null() {
             let sockaddr_in = SockAddr::from(remote_endpoint);
@@ -527,6 +549,9 @@ pub const SOL_SOCKET: usize = 1;
 pub const SO_SNDBUF: usize = 7;
 pub const SO_RCVBUF: usize = 8;
 pub const SO_LINGER: usize = 13;
+pub const SO_ATTACH_FILTER: usize = 26;
+pub const SO_DETACH_FILTER: usize = 27;
+pub const SO_ATTACH_BPF: usize = 50;
 
 pub const TCP_CONGESTION: usize = 13;
 
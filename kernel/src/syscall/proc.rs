@@ -3,6 +3,7 @@
 use super::*;
 use crate::arch::timer::timer_now;
 use crate::fs::FileLike;
+use crate::sched::{self, Policy, SchedParam};
 use crate::signal::{send_signal, Signal};
 use crate::{
     sync::{wait_for_event, Event, EventBus, SpinNoIrqLock as Mutex},
@@ -18,11 +19,32 @@ use core::{
     time::Duration,
 };
 use crate::kprobes::uprobes_init;
+use rcore_sched::{MAX_NICE, MAX_RT_PRIO, MIN_NICE};
 
 impl Syscall<'_> {
+    /// Fail with EAGAIN if the real user of the current process has as many
+    /// processes as its RLIMIT_NPROC allows.
+    fn check_nproc(&self) -> Result<(), SysError> {
+        let (uid, limit) = {
+            let proc = self.process();
+            (proc.cred.uid, proc.rlimits.cur(RLIMIT_NPROC))
+        };
+        // no process is locked with the table locked, as forking locks them the other way
+        let processes: Vec<_> = PROCESSES.read().values().cloned().collect();
+        let count = processes
+            .iter()
+            .filter(|proc| proc.lock().cred.uid == uid)
+            .count();
+        if count >= limit {
+            return Err(SysError::EAGAIN);
+        }
+        Ok(())
+    }
+
     /// Fork the current process. Return the child's PID.
     pub fn sys_fork(&mut self) -> SysResult {
-        let new_thread = self.thread.fork(self.context);
+        self.check_nproc()?;
+        let new_thread = self.thread.fork(self.context).ok_or(SysError::ENOMEM)?;
         let pid = new_thread.proc.lock().pid.get();
         info!("fork: {} -> {}", self.process().pid, pid);
         spawn(new_thread);
@@ -64,6 +86,9 @@ impl Syscall<'_> {
             );
             return Err(SysError::ENOSYS);
         }
+        if !clone_flags.contains(CloneFlags::THREAD) {
+            self.check_nproc()?;
+        }
         let parent_tid_ref = unsafe { self.vm().check_write_ptr(parent_tid)? };
         // child_tid buffer should not be set because CLONE_CHILD_SETTID flag is not specified in the current implementation
         let child_tid_ref = unsafe { self.vm().check_write_ptr(child_tid)? };
@@ -83,8 +108,16 @@ impl Syscall<'_> {
 
     /// Wait for the process exit.
     /// Return the PID. Store exit code to `wstatus` if it's not null.
-    pub async fn sys_wait4(&mut self, pid: isize, wstatus: UserInOutPtr<i32>) -> SysResult {
-        info!("wait4: pid: {}, code: {:?}", pid, wstatus);
+    pub async fn sys_wait4(
+        &mut self,
+        pid: isize,
+        wstatus: UserInOutPtr<i32>,
+        mut rusage: UserOutPtr<RUsage>,
+    ) -> SysResult {
+        info!(
+            "wait4: pid: {}, code: {:?}, rusage: {:?}",
+            pid, wstatus, rusage
+        );
         let wstatus = if !wstatus.is_null() {
             Some(wstatus)
         } else {
@@ -114,7 +147,7 @@ impl Syscall<'_> {
                         if let Some(c) = child.upgrade() {
                             let p = c.lock();
                             if p.exited() {
-                                res = Some((p.pid, p.exit_code));
+                                res = Some((p.pid, p.exit_code, p.cpu_time + p.children_cpu_time));
                                 break;
                             }
                         } else {
@@ -128,20 +161,21 @@ impl Syscall<'_> {
                     if let Some(c) = process(pid) {
                         let p = c.lock();
                         if p.exited() {
-                            res = Some((p.pid, p.exit_code));
+                            res = Some((p.pid, p.exit_code, p.cpu_time + p.children_cpu_time));
                         }
                     }
                     res
                 }
             };
             // if found, return
-            if let Some((pid, exit_code)) = find {
+            if let Some((pid, exit_code, cpu_time)) = find {
                 info!("wait: found pid {}", pid);
 
                 // write before removing to handle EFAULT
                 if let Some(mut wstatus) = wstatus {
                     wstatus.write(exit_code as i32)?;
                 }
+                rusage.write_if_not_null(RUsage::from(cpu_time))?;
 
                 // remove from process table
                 if true {
@@ -151,6 +185,7 @@ impl Syscall<'_> {
 
                 // remove from children
                 proc.children.retain(|(p, _)| *p != pid);
+                proc.children_cpu_time += cpu_time;
 
                 return Ok(pid.get());
             }
@@ -220,12 +255,21 @@ impl Syscall<'_> {
 
         // Read program file
         let inode = proc.lookup_inode(&path)?;
+        // probes of the binary are keyed by this path
+        let path = proc.canonical_path(&path);
+        let metadata = inode.metadata()?;
+        if metadata.type_ != FileType::File {
+            return Err(SysError::EACCES);
+        }
+        proc.cred.check_permission(&metadata, MAY_EXEC)?;
 
         // Make new Thread
         // Re-create vm
+        let stack_size = proc.rlimits.cur(RLIMIT_STACK);
         let mut vm = self.vm();
-        let (entry_addr, ustack_top) =
-            Thread::new_user_vm(&inode, args, envs, &mut vm).map_err(|_| SysError::EINVAL)?;
+        let (entry_addr, ustack_top, brk) =
+            Thread::new_user_vm(&inode, &path, args, envs, stack_size, &mut vm)
+                .map_err(|_| SysError::EINVAL)?;
 
         // Kill other threads
         // TODO: stop and wait until they are finished
@@ -259,11 +303,23 @@ impl Syscall<'_> {
 
         // Modify exec path
         proc.exec_path = path.clone();
+        proc.update_ids();
+
+        // run as the owner of a set-user-ID program
+        let no_new_privs = proc.no_new_privs;
+        proc.cred.exec(&metadata, no_new_privs);
+
+        // new heap
+        proc.brk_start = brk;
+        proc.brk = brk;
 
         // reset disposition (man signal(7))
         for d in proc.dispositions.iter_mut() {
             *d = SignalAction::default();
         }
+
+        // POSIX timers are deleted, interval timers are kept (man execve(2))
+        proc.timers.posix.clear();
         drop(proc);
 
         // Modify the TrapFrame
@@ -274,8 +330,8 @@ impl Syscall<'_> {
         Ok(0)
     }
 
-    pub fn sys_yield(&mut self) -> SysResult {
-        //thread::yield_now();
+    pub async fn sys_yield(&mut self) -> SysResult {
+        yield_now().await;
         Ok(0)
     }
 
@@ -313,6 +369,7 @@ impl Syscall<'_> {
             // TODO: check process pid is the child of calling process
             let mut proc = proc.lock();
             proc.pgid = pgid as Pgid;
+            proc.update_ids();
             Ok(0)
         } else {
             Err(ESRCH)
@@ -336,17 +393,144 @@ impl Syscall<'_> {
         }
     }
 
+    pub fn sys_getuid(&mut self) -> SysResult {
+        Ok(self.process().cred.uid as usize)
+    }
+
+    pub fn sys_geteuid(&mut self) -> SysResult {
+        Ok(self.process().cred.euid as usize)
+    }
+
+    pub fn sys_getgid(&mut self) -> SysResult {
+        Ok(self.process().cred.gid as usize)
+    }
+
+    pub fn sys_getegid(&mut self) -> SysResult {
+        Ok(self.process().cred.egid as usize)
+    }
+
+    pub fn sys_setuid(&mut self, uid: usize) -> SysResult {
+        info!("setuid: uid: {}", uid as isize);
+        let uid = id_arg(uid).ok_or(SysError::EINVAL)?;
+        let mut proc = self.process();
+        proc.cred.set_uid(uid)?;
+        proc.update_ids();
+        Ok(0)
+    }
+
+    pub fn sys_setgid(&mut self, gid: usize) -> SysResult {
+        info!("setgid: gid: {}", gid as isize);
+        let gid = id_arg(gid).ok_or(SysError::EINVAL)?;
+        let mut proc = self.process();
+        proc.cred.set_gid(gid)?;
+        proc.update_ids();
+        Ok(0)
+    }
+
+    pub fn sys_setreuid(&mut self, ruid: usize, euid: usize) -> SysResult {
+        info!("setreuid: ruid: {}, euid: {}", ruid as isize, euid as isize);
+        let mut proc = self.process();
+        proc.cred.set_reuid(id_arg(ruid), id_arg(euid))?;
+        proc.update_ids();
+        Ok(0)
+    }
+
+    pub fn sys_setregid(&mut self, rgid: usize, egid: usize) -> SysResult {
+        info!("setregid: rgid: {}, egid: {}", rgid as isize, egid as isize);
+        let mut proc = self.process();
+        proc.cred.set_regid(id_arg(rgid), id_arg(egid))?;
+        proc.update_ids();
+        Ok(0)
+    }
+
+    pub fn sys_setresuid(&mut self, ruid: usize, euid: usize, suid: usize) -> SysResult {
+        info!(
+            "setresuid: ruid: {}, euid: {}, suid: {}",
+            ruid as isize, euid as isize, suid as isize
+        );
+        let mut proc = self.process();
+        proc.cred
+            .set_resuid(id_arg(ruid), id_arg(euid), id_arg(suid))?;
+        proc.update_ids();
+        Ok(0)
+    }
+
+    pub fn sys_setresgid(&mut self, rgid: usize, egid: usize, sgid: usize) -> SysResult {
+        info!(
+            "setresgid: rgid: {}, egid: {}, sgid: {}",
+            rgid as isize, egid as isize, sgid as isize
+        );
+        let mut proc = self.process();
+        proc.cred
+            .set_resgid(id_arg(rgid), id_arg(egid), id_arg(sgid))?;
+        proc.update_ids();
+        Ok(0)
+    }
+
+    pub fn sys_getresuid(&mut self, ruid: *mut u32, euid: *mut u32, suid: *mut u32) -> SysResult {
+        let cred = self.process().cred.clone();
+        let vm = self.vm();
+        unsafe {
+            *vm.check_write_ptr(ruid)? = cred.uid;
+            *vm.check_write_ptr(euid)? = cred.euid;
+            *vm.check_write_ptr(suid)? = cred.suid;
+        }
+        Ok(0)
+    }
+
+    pub fn sys_getresgid(&mut self, rgid: *mut u32, egid: *mut u32, sgid: *mut u32) -> SysResult {
+        let cred = self.process().cred.clone();
+        let vm = self.vm();
+        unsafe {
+            *vm.check_write_ptr(rgid)? = cred.gid;
+            *vm.check_write_ptr(egid)? = cred.egid;
+            *vm.check_write_ptr(sgid)? = cred.sgid;
+        }
+        Ok(0)
+    }
+
+    /// Get supplementary groups, or their number if `size` is 0
+    pub fn sys_getgroups(&mut self, size: usize, list: *mut u32) -> SysResult {
+        let groups = self.process().cred.groups.clone();
+        if size == 0 {
+            return Ok(groups.len());
+        }
+        if size < groups.len() {
+            return Err(SysError::EINVAL);
+        }
+        let list = unsafe { self.vm().check_write_array(list, groups.len())? };
+        list.copy_from_slice(&groups);
+        Ok(groups.len())
+    }
+
+    pub fn sys_setgroups(&mut self, size: usize, list: *const u32) -> SysResult {
+        info!("setgroups: size: {}", size);
+        if size > NGROUPS_MAX {
+            return Err(SysError::EINVAL);
+        }
+        let groups = unsafe { self.vm().check_read_array(list, size)? }.to_vec();
+        self.process().cred.set_groups(groups)?;
+        Ok(0)
+    }
+
     /// Exit the current thread
     pub fn sys_exit(&mut self, exit_code: usize) -> SysResult {
-        let tid = self.thread.tid;
-        info!("exit: {}, code: {}", tid, exit_code);
+        info!("exit: {}, code: {}", self.thread.tid, exit_code);
+        self.exit_thread(exited_status(exit_code))
+    }
 
+    /// Exit the current thread, and the process with `status` if the thread
+    /// is its last one
+    pub fn exit_thread(&mut self, status: usize) -> SysResult {
+        let tid = self.thread.tid;
         let mut proc = self.process();
+        // charge the thread while it still counts for the process
+        let signals = self.thread.charge_cpu_time(&mut proc);
         proc.threads.retain(|&id| id != tid);
 
         // for last thread, exit the process
         if proc.threads.len() == 0 {
-            proc.exit(exit_code);
+            proc.exit(status);
         }
 
         // perform futex wake 1
@@ -363,17 +547,25 @@ impl Syscall<'_> {
             }
         }
 
+        let exited = proc.exited();
         drop(proc);
+        if !exited {
+            send_cpu_time_signals(&self.thread.proc, &signals);
+        }
         self.exit = true;
         Ok(0)
     }
 
     /// Exit the current thread group (i.e. process)
     pub fn sys_exit_group(&mut self, exit_code: usize) -> SysResult {
-        let mut proc = self.process();
-        info!("exit_group: {}, code: {}", proc.pid, exit_code);
+        info!("exit_group: {}, code: {}", self.process().pid, exit_code);
+        self.exit_process(exited_status(exit_code))
+    }
 
-        proc.exit(exit_code);
+    /// Exit the current process with `status`
+    pub fn exit_process(&mut self, status: usize) -> SysResult {
+        let mut proc = self.process();
+        proc.exit(status);
         drop(proc);
         // TODO: quit other threads
         self.exit = true;
@@ -392,10 +584,184 @@ impl Syscall<'_> {
         Ok(0)
     }
 
-    pub fn sys_set_priority(&mut self, priority: usize) -> SysResult {
+    pub fn sys_sched_setscheduler(
+        &mut self,
+        tid: usize,
+        policy: usize,
+        param: UserInPtr<i32>,
+    ) -> SysResult {
+        let priority = param.read()?;
+        info!(
+            "sched_setscheduler: tid: {}, policy: {}, priority: {}",
+            tid, policy, priority
+        );
+        let policy = Policy::from_raw(policy).ok_or(SysError::EINVAL)?;
+        let priority = rt_priority_arg(priority)?;
+        self.set_sched_param(tid, |old| SchedParam::new(policy, priority, old.nice))
+    }
+
+    pub fn sys_sched_getscheduler(&mut self, tid: usize) -> SysResult {
+        let (tid, _) = self.sched_thread(tid)?;
+        let param = sched::thread_param(tid).ok_or(ESRCH)?;
+        Ok(param.policy.raw())
+    }
+
+    pub fn sys_sched_setparam(&mut self, tid: usize, param: UserInPtr<i32>) -> SysResult {
+        let priority = param.read()?;
+        info!("sched_setparam: tid: {}, priority: {}", tid, priority);
+        let priority = rt_priority_arg(priority)?;
+        self.set_sched_param(tid, |old| SchedParam::new(old.policy, priority, old.nice))
+    }
+
+    pub fn sys_sched_getparam(&mut self, tid: usize, param: UserOutPtr<i32>) -> SysResult {
+        let (tid, _) = self.sched_thread(tid)?;
+        let priority = sched::thread_param(tid).ok_or(ESRCH)?.priority;
+        param.write(priority as i32)?;
         Ok(0)
     }
 
+    pub fn sys_sched_get_priority_max(&mut self, policy: usize) -> SysResult {
+        let policy = Policy::from_raw(policy).ok_or(SysError::EINVAL)?;
+        Ok(if policy.is_realtime() {
+            MAX_RT_PRIO as usize
+        } else {
+            0
+        })
+    }
+
+    pub fn sys_sched_get_priority_min(&mut self, policy: usize) -> SysResult {
+        let policy = Policy::from_raw(policy).ok_or(SysError::EINVAL)?;
+        Ok(if policy.is_realtime() { 1 } else { 0 })
+    }
+
+    /// Set the nice value of every thread of the processes selected by `which` and `who`
+    pub fn sys_setpriority(&mut self, which: usize, who: usize, prio: usize) -> SysResult {
+        info!(
+            "setpriority: which: {}, who: {}, prio: {}",
+            which, who, prio as i32
+        );
+        // out of range values are limited to MIN_NICE..=MAX_NICE
+        let nice = (prio as i32).max(MIN_NICE as i32).min(MAX_NICE as i32) as i8;
+        for proc in self.priority_targets(which, who)? {
+            let threads = proc.lock().threads.clone();
+            for tid in threads {
+                self.set_sched_param(tid, |old| Some(old.with_nice(nice)))?;
+            }
+        }
+        Ok(0)
+    }
+
+    /// Get the highest priority of the processes selected by `which` and `who`,
+    /// as 20 minus the nice value
+    pub fn sys_getpriority(&mut self, which: usize, who: usize) -> SysResult {
+        let mut nice = MAX_NICE;
+        for proc in self.priority_targets(which, who)? {
+            let threads = proc.lock().threads.clone();
+            for tid in threads {
+                if let Some(param) = sched::thread_param(tid) {
+                    nice = nice.min(param.nice);
+                }
+            }
+        }
+        Ok((20 - nice as isize) as usize)
+    }
+
+    /// The thread `tid` and its process, `tid` 0 for the calling thread
+    pub(super) fn sched_thread(
+        &self,
+        tid: usize,
+    ) -> Result<(usize, Arc<Mutex<Process>>), SysError> {
+        if tid == 0 {
+            return Ok((self.thread.tid, self.thread.proc.clone()));
+        }
+        let proc = process_of(tid).ok_or(ESRCH)?;
+        Ok((tid, proc))
+    }
+
+    /// Change the scheduling parameters of the thread `tid` by `param`,
+    /// which returns `None` if they are invalid
+    fn set_sched_param(
+        &self,
+        tid: usize,
+        param: impl FnOnce(SchedParam) -> Option<SchedParam>,
+    ) -> SysResult {
+        let (tid, proc) = self.sched_thread(tid)?;
+        let old = sched::thread_param(tid).ok_or(ESRCH)?;
+        let new = param(old).ok_or(SysError::EINVAL)?;
+        let cred = self.process().cred.clone();
+        if !cred.is_root() {
+            let target = proc.lock();
+            check_sched_owner(&cred, &target)?;
+            // without privilege, the priority can only be raised up to
+            // RLIMIT_RTPRIO and RLIMIT_NICE of the target
+            if new.policy.is_realtime() {
+                let old_priority = if old.policy.is_realtime() {
+                    old.priority
+                } else {
+                    0
+                };
+                let limit = target.rlimits.cur(RLIMIT_RTPRIO);
+                if new.priority > old_priority && new.priority as usize > limit {
+                    return Err(SysError::EPERM);
+                }
+            }
+            let limit = target.rlimits.cur(RLIMIT_NICE);
+            if new.nice < old.nice && (20 - new.nice as isize) as usize > limit {
+                return Err(SysError::EPERM);
+            }
+            if old.policy == Policy::Idle && new.policy != Policy::Idle && limit == 0 {
+                return Err(SysError::EPERM);
+            }
+        }
+        if !sched::set_thread_param(tid, new) {
+            return Err(ESRCH);
+        }
+        Ok(0)
+    }
+
+    /// The processes selected by `which` and `who` of setpriority(2), 0 for the caller's
+    fn priority_targets(
+        &self,
+        which: usize,
+        who: usize,
+    ) -> Result<Vec<Arc<Mutex<Process>>>, SysError> {
+        let procs = match which {
+            PRIO_PROCESS => {
+                if who == 0 {
+                    vec![self.thread.proc.clone()]
+                } else {
+                    process(who).into_iter().collect()
+                }
+            }
+            PRIO_PGRP => {
+                let pgid = if who == 0 {
+                    self.process().pgid
+                } else {
+                    who as Pgid
+                };
+                process_group(pgid)
+            }
+            PRIO_USER => {
+                let uid = if who == 0 {
+                    self.process().cred.uid
+                } else {
+                    who as Uid
+                };
+                PROCESSES
+                    .read()
+                    .values()
+                    .filter(|proc| proc.lock().cred.uid == uid)
+                    .cloned()
+                    .collect()
+            }
+            _ => return Err(SysError::EINVAL),
+        };
+        if procs.is_empty() {
+            return Err(ESRCH);
+        }
+        Ok(procs)
+    }
+
     pub fn sys_set_tid_address(&mut self, tidptr: *mut u32) -> SysResult {
         info!("set_tid_address: {:?}", tidptr);
         self.thread.inner.lock().clear_child_tid = tidptr as usize;
@@ -452,6 +818,31 @@ impl Future for SleepFuture {
     }
 }
 
+/// Check a process with `cred` may change the scheduling of threads in `target`
+pub(super) fn check_sched_owner(cred: &Credentials, target: &Process) -> Result<(), SysError> {
+    if !cred.is_root() && cred.euid != target.cred.uid && cred.euid != target.cred.euid {
+        return Err(SysError::EPERM);
+    }
+    Ok(())
+}
+
+/// Real-time priority of `struct sched_param`
+fn rt_priority_arg(priority: i32) -> Result<u8, SysError> {
+    if priority < 0 || priority > MAX_RT_PRIO as i32 {
+        return Err(SysError::EINVAL);
+    }
+    Ok(priority as u8)
+}
+
+/// An ID argument of set*id, `None` for -1 to keep the ID
+pub(super) fn id_arg(id: usize) -> Option<u32> {
+    if id as u32 == u32::max_value() {
+        None
+    } else {
+        Some(id as u32)
+    }
+}
+
 bitflags! {
     pub struct CloneFlags: usize {
         const CSIGNAL =         0x000000ff;
@@ -480,3 +871,8 @@ bitflags! {
         const IO =              0x80000000;
     }
 }
+
+/// `which` of setpriority(2)
+const PRIO_PROCESS: usize = 0;
+const PRIO_PGRP: usize = 1;
+const PRIO_USER: usize = 2;
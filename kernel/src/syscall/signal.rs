@@ -101,6 +101,9 @@ impl Syscall<'_> {
                 }
                 _ => return Err(EINVAL),
             }
+            // SIGKILL and SIGSTOP can not be blocked
+            inner.sig_mask.remove(Signal::SIGKILL);
+            inner.sig_mask.remove(Signal::SIGSTOP);
         }
         return Ok(0);
     }
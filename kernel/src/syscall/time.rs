@@ -2,6 +2,9 @@
 
 use super::*;
 use crate::consts::USEC_PER_TICK;
+use crate::fs::fcntl::{O_CLOEXEC, O_NONBLOCK};
+use crate::fs::{FileHandle, FileLike, OpenOptions, TimerFd};
+use crate::signal::{send_signal, Siginfo, SiginfoFields, SigtimerFields, SI_KERNEL, SI_TIMER};
 use core::time::Duration;
 use lazy_static::lazy_static;
 use rcore_fs::vfs::Timespec;
@@ -26,7 +29,11 @@ impl Syscall<'_> {
     pub fn sys_clock_gettime(&mut self, clock: usize, mut ts: UserOutPtr<TimeSpec>) -> SysResult {
         info!("clock_gettime: clock: {:?}, ts: {:?}", clock, ts);
 
-        let timespec = TimeSpec::get_epoch();
+        let timespec = match clock {
+            CLOCK_PROCESS_CPUTIME_ID => TimeSpec::from(self.process().cpu_time.total()),
+            CLOCK_THREAD_CPUTIME_ID => TimeSpec::from(self.thread.inner.lock().cpu_time.total()),
+            _ => TimeSpec::get_epoch(),
+        };
         ts.write(timespec)?;
         Ok(0)
     }
@@ -45,21 +52,13 @@ impl Syscall<'_> {
         info!("getrusage: who: {}, rusage: {:?}", who, rusage);
         let rusage = unsafe { self.vm().check_write_ptr(rusage)? };
 
-        let tick_base = *TICK_BASE;
-        let tick = unsafe { crate::trap::wall_tick() as u64 };
-
-        let usec = (tick - tick_base) * USEC_PER_TICK as u64;
-        let new_rusage = RUsage {
-            utime: TimeVal {
-                sec: (usec / USEC_PER_SEC) as usize,
-                usec: (usec % USEC_PER_SEC) as usize,
-            },
-            stime: TimeVal {
-                sec: (usec / USEC_PER_SEC) as usize,
-                usec: (usec % USEC_PER_SEC) as usize,
-            },
+        let time = match who as isize {
+            RUSAGE_SELF => self.process().cpu_time,
+            RUSAGE_CHILDREN => self.process().children_cpu_time,
+            RUSAGE_THREAD => self.thread.inner.lock().cpu_time,
+            _ => return Err(SysError::EINVAL),
         };
-        *rusage = new_rusage;
+        *rusage = RUsage::from(time);
         Ok(0)
     }
 
@@ -67,18 +66,281 @@ impl Syscall<'_> {
         info!("times: buf: {:?}", buf);
         let buf = unsafe { self.vm().check_write_ptr(buf)? };
 
-        let _tick_base = *TICK_BASE;
+        let tick_base = *TICK_BASE;
         let tick = unsafe { crate::trap::wall_tick() as u64 };
+        let uptime = Duration::from_micros((tick - tick_base) * USEC_PER_TICK as u64);
 
+        let proc = self.process();
         let new_buf = Tms {
-            tms_utime: 0,
-            tms_stime: 0,
-            tms_cutime: 0,
-            tms_cstime: 0,
+            tms_utime: clock_ticks(proc.cpu_time.user),
+            tms_stime: clock_ticks(proc.cpu_time.system),
+            tms_cutime: clock_ticks(proc.children_cpu_time.user),
+            tms_cstime: clock_ticks(proc.children_cpu_time.system),
         };
 
         *buf = new_buf;
-        Ok(tick as usize)
+        Ok(clock_ticks(uptime) as usize)
+    }
+
+    pub fn sys_getitimer(&mut self, which: usize, mut value: UserOutPtr<ITimerVal>) -> SysResult {
+        info!("getitimer: which: {}, value: {:?}", which, value);
+        let (left, interval) = self.itimer(which)?;
+        value.write(ITimerVal::new(left, interval))?;
+        Ok(0)
+    }
+
+    pub fn sys_setitimer(
+        &mut self,
+        which: usize,
+        new: UserInPtr<ITimerVal>,
+        mut old: UserOutPtr<ITimerVal>,
+    ) -> SysResult {
+        info!(
+            "setitimer: which: {}, new: {:?}, old: {:?}",
+            which, new, old
+        );
+        let new = new.read()?;
+        let (left, interval) = self.set_itimer(which, new.value()?, new.interval()?)?;
+        old.write_if_not_null(ITimerVal::new(left, interval))?;
+        Ok(0)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn sys_alarm(&mut self, seconds: usize) -> SysResult {
+        info!("alarm: seconds: {}", seconds);
+        // the argument is an unsigned int
+        let value = Duration::from_secs(seconds as u32 as u64);
+        let (left, _) = self.set_itimer(ITIMER_REAL, value, Duration::default())?;
+        // the time left is rounded, but an alarm pending is never reported as 0
+        let mut secs = left.as_secs();
+        if left.subsec_nanos() >= 500_000_000 || (secs == 0 && left.subsec_nanos() > 0) {
+            secs += 1;
+        }
+        Ok(secs as usize)
+    }
+
+    /// The time left and interval of the interval timer `which`
+    fn itimer(&self, which: usize) -> Result<(Duration, Duration), SysError> {
+        let proc = self.process();
+        match which {
+            ITIMER_REAL => Ok(match &proc.timers.real {
+                Some(timer) => timer.get(),
+                None => (Duration::default(), Duration::default()),
+            }),
+            ITIMER_VIRTUAL => Ok((proc.timers.virt.value, proc.timers.virt.interval)),
+            ITIMER_PROF => Ok((proc.timers.prof.value, proc.timers.prof.interval)),
+            _ => Err(SysError::EINVAL),
+        }
+    }
+
+    /// Set the interval timer `which`, return the old time left and interval
+    fn set_itimer(
+        &self,
+        which: usize,
+        value: Duration,
+        interval: Duration,
+    ) -> Result<(Duration, Duration), SysError> {
+        let mut proc = self.process();
+        let timer = match which {
+            ITIMER_REAL => {
+                let weak = Arc::downgrade(&self.thread.proc);
+                proc.timers
+                    .real
+                    .get_or_insert_with(|| {
+                        IntervalTimer::new(move |_| {
+                            if let Some(proc) = weak.upgrade() {
+                                send_signal(proc, -1, kernel_siginfo(Signal::SIGALRM));
+                            }
+                        })
+                    })
+                    .clone()
+            }
+            ITIMER_VIRTUAL | ITIMER_PROF => {
+                let timer = if which == ITIMER_VIRTUAL {
+                    &mut proc.timers.virt
+                } else {
+                    &mut proc.timers.prof
+                };
+                let old = (timer.value, timer.interval);
+                *timer = CpuTimer { value, interval };
+                return Ok(old);
+            }
+            _ => return Err(SysError::EINVAL),
+        };
+        drop(proc);
+        Ok(timer.set(value, interval))
+    }
+
+    pub fn sys_timer_create(
+        &mut self,
+        clock: usize,
+        sevp: UserInPtr<SigEvent>,
+        mut timerid: UserOutPtr<i32>,
+    ) -> SysResult {
+        info!(
+            "timer_create: clock: {}, sevp: {:?}, timerid: {:?}",
+            clock, sevp, timerid
+        );
+        check_timer_clock(clock)?;
+        let event = match sevp.read_if_not_null()? {
+            Some(event) => event,
+            None => SigEvent {
+                value: 0,
+                signo: Signal::SIGALRM as i32,
+                notify: SIGEV_SIGNAL,
+                tid: 0,
+            },
+        };
+        let tid = match event.notify {
+            SIGEV_NONE | SIGEV_SIGNAL => -1,
+            SIGEV_THREAD_ID => {
+                if !self.process().threads.contains(&(event.tid as usize)) {
+                    return Err(SysError::EINVAL);
+                }
+                event.tid as isize
+            }
+            _ => return Err(SysError::EINVAL),
+        };
+        if event.notify != SIGEV_NONE && <Signal as FromPrimitive>::from_i32(event.signo).is_none()
+        {
+            return Err(SysError::EINVAL);
+        }
+
+        let mut proc = self.process();
+        // each timer may hold a queued signal
+        if proc.timers.posix.len() >= proc.rlimits.cur(RLIMIT_SIGPENDING) {
+            return Err(SysError::EAGAIN);
+        }
+        let id = proc.timers.free_id();
+        let weak = Arc::downgrade(&self.thread.proc);
+        let timer = IntervalTimer::new(move |count| {
+            if event.notify == SIGEV_NONE {
+                return;
+            }
+            if let Some(proc) = weak.upgrade() {
+                let info = Siginfo {
+                    signo: event.signo,
+                    errno: 0,
+                    code: SI_TIMER,
+                    field: SiginfoFields {
+                        sigtimer: SigtimerFields {
+                            tid: id as i32,
+                            overrun: (count - 1).min(DELAYTIMER_MAX) as i32,
+                            sigval: event.value,
+                        },
+                    },
+                };
+                send_signal(proc, tid, info);
+            }
+        });
+        timerid.write(id as i32)?;
+        proc.timers.posix.insert(id, timer);
+        Ok(0)
+    }
+
+    pub fn sys_timer_settime(
+        &mut self,
+        timerid: usize,
+        flags: usize,
+        new: UserInPtr<ITimerSpec>,
+        mut old: UserOutPtr<ITimerSpec>,
+    ) -> SysResult {
+        info!(
+            "timer_settime: timerid: {}, flags: {:#x}, new: {:?}, old: {:?}",
+            timerid, flags, new, old
+        );
+        let timer = self.posix_timer(timerid)?;
+        let new = new.read()?;
+        let value = timer_value(&new, flags)?;
+        let (left, interval) = timer.set(value, new.interval()?);
+        old.write_if_not_null(ITimerSpec::new(left, interval))?;
+        Ok(0)
+    }
+
+    pub fn sys_timer_gettime(
+        &mut self,
+        timerid: usize,
+        mut value: UserOutPtr<ITimerSpec>,
+    ) -> SysResult {
+        info!("timer_gettime: timerid: {}, value: {:?}", timerid, value);
+        let (left, interval) = self.posix_timer(timerid)?.get();
+        value.write(ITimerSpec::new(left, interval))?;
+        Ok(0)
+    }
+
+    pub fn sys_timer_getoverrun(&mut self, timerid: usize) -> SysResult {
+        info!("timer_getoverrun: timerid: {}", timerid);
+        let overrun = self.posix_timer(timerid)?.overrun();
+        Ok(overrun.min(DELAYTIMER_MAX))
+    }
+
+    pub fn sys_timer_delete(&mut self, timerid: usize) -> SysResult {
+        info!("timer_delete: timerid: {}", timerid);
+        // a timer dropped is never notified again
+        match self.process().timers.posix.remove(&timerid) {
+            Some(_) => Ok(0),
+            None => Err(SysError::EINVAL),
+        }
+    }
+
+    fn posix_timer(&self, timerid: usize) -> Result<IntervalTimer, SysError> {
+        let proc = self.process();
+        let timer = proc.timers.posix.get(&timerid).ok_or(SysError::EINVAL)?;
+        Ok(timer.clone())
+    }
+
+    pub fn sys_timerfd_create(&mut self, clock: usize, flags: usize) -> SysResult {
+        info!("timerfd_create: clock: {}, flags: {:#x}", clock, flags);
+        check_timer_clock(clock)?;
+        if flags & !(TFD_NONBLOCK | TFD_CLOEXEC) != 0 {
+            return Err(SysError::EINVAL);
+        }
+        let file = FileHandle::new(
+            Arc::new(TimerFd::new()),
+            OpenOptions {
+                read: true,
+                write: false,
+                append: false,
+                nonblock: (flags & TFD_NONBLOCK) != 0,
+            },
+            String::from("anon_inode:[timerfd]"),
+            true,
+            (flags & TFD_CLOEXEC) != 0,
+        );
+        let fd = self.process().add_file(FileLike::File(file))?;
+        Ok(fd)
+    }
+
+    pub fn sys_timerfd_settime(
+        &mut self,
+        fd: usize,
+        flags: usize,
+        new: UserInPtr<ITimerSpec>,
+        mut old: UserOutPtr<ITimerSpec>,
+    ) -> SysResult {
+        info!(
+            "timerfd_settime: fd: {}, flags: {:#x}, new: {:?}, old: {:?}",
+            fd, flags, new, old
+        );
+        let inode = self.process().get_file(fd)?.inode();
+        let timerfd = timerfd(&inode)?;
+        let new = new.read()?;
+        let value = timer_value(&new, flags)?;
+        let (left, interval) = timerfd.set(value, new.interval()?);
+        old.write_if_not_null(ITimerSpec::new(left, interval))?;
+        Ok(0)
+    }
+
+    pub fn sys_timerfd_gettime(
+        &mut self,
+        fd: usize,
+        mut value: UserOutPtr<ITimerSpec>,
+    ) -> SysResult {
+        info!("timerfd_gettime: fd: {}, value: {:?}", fd, value);
+        let inode = self.process().get_file(fd)?.inode();
+        let (left, interval) = timerfd(&inode)?.get();
+        value.write(ITimerSpec::new(left, interval))?;
+        Ok(0)
     }
 }
 
@@ -98,6 +360,46 @@ const USEC_PER_MSEC: u64 = 1_000;
 const NSEC_PER_USEC: u64 = 1_000;
 const NSEC_PER_MSEC: u64 = 1_000_000;
 
+/// Clock ticks per second of `times`, as `sysconf(_SC_CLK_TCK)`
+const CLOCKS_PER_SEC: u64 = 100;
+
+const CLOCK_PROCESS_CPUTIME_ID: usize = 2;
+const CLOCK_THREAD_CPUTIME_ID: usize = 3;
+
+/// `who` of getrusage(2)
+const RUSAGE_SELF: isize = 0;
+const RUSAGE_CHILDREN: isize = -1;
+const RUSAGE_THREAD: isize = 1;
+
+/// `which` of setitimer(2)
+const ITIMER_REAL: usize = 0;
+const ITIMER_VIRTUAL: usize = 1;
+const ITIMER_PROF: usize = 2;
+
+const CLOCK_REALTIME: usize = 0;
+const CLOCK_MONOTONIC: usize = 1;
+const CLOCK_BOOTTIME: usize = 7;
+
+/// `flags` of timer_settime(2) and timerfd_settime(2)
+const TIMER_ABSTIME: usize = 1;
+
+/// `sigev_notify` of timer_create(2)
+const SIGEV_SIGNAL: i32 = 0;
+const SIGEV_NONE: i32 = 1;
+const SIGEV_THREAD_ID: i32 = 4;
+
+/// The maximum overrun reported by timer_getoverrun(2)
+const DELAYTIMER_MAX: usize = i32::MAX as usize;
+
+/// `flags` of timerfd_create(2)
+const TFD_NONBLOCK: usize = O_NONBLOCK;
+const TFD_CLOEXEC: usize = O_CLOEXEC;
+
+/// Convert `time` to clock ticks of `times`
+fn clock_ticks(time: Duration) -> u64 {
+    time.as_secs() * CLOCKS_PER_SEC + time.subsec_nanos() as u64 * CLOCKS_PER_SEC / 1_000_000_000
+}
+
 /// Get time since epoch in usec
 fn get_epoch_usec() -> u64 {
     let tick_base = *TICK_BASE;
@@ -119,6 +421,17 @@ impl TimeVal {
         (self.sec as u64) * MSEC_PER_SEC + (self.usec as u64) / USEC_PER_MSEC
     }
 
+    /// The duration, or `EINVAL` if `sec` is negative or `usec` is out of range
+    pub fn checked_duration(&self) -> Result<Duration, SysError> {
+        if (self.sec as isize) < 0 || self.usec as u64 >= USEC_PER_SEC {
+            return Err(SysError::EINVAL);
+        }
+        Ok(Duration::new(
+            self.sec as u64,
+            (self.usec as u64 * NSEC_PER_USEC) as u32,
+        ))
+    }
+
     pub fn get_epoch() -> Self {
         let usec = get_epoch_usec();
         TimeVal {
@@ -128,6 +441,15 @@ impl TimeVal {
     }
 }
 
+impl From<Duration> for TimeVal {
+    fn from(time: Duration) -> Self {
+        TimeVal {
+            sec: time.as_secs() as usize,
+            usec: time.subsec_micros() as usize,
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct TimeSpec {
@@ -144,6 +466,14 @@ impl TimeSpec {
         Duration::new(self.sec as u64, self.nsec as u32)
     }
 
+    /// The duration, or `EINVAL` if `sec` is negative or `nsec` is out of range
+    pub fn checked_duration(&self) -> Result<Duration, SysError> {
+        if (self.sec as isize) < 0 || self.nsec >= 1_000_000_000 {
+            return Err(SysError::EINVAL);
+        }
+        Ok(self.to_duration())
+    }
+
     pub fn get_epoch() -> Self {
         let usec = get_epoch_usec();
         TimeSpec {
@@ -169,6 +499,15 @@ impl TimeSpec {
     }
 }
 
+impl From<Duration> for TimeSpec {
+    fn from(time: Duration) -> Self {
+        TimeSpec {
+            sec: time.as_secs() as usize,
+            nsec: time.subsec_nanos() as usize,
+        }
+    }
+}
+
 impl Into<Timespec> for TimeSpec {
     fn into(self) -> Timespec {
         Timespec {
@@ -178,11 +517,123 @@ impl Into<Timespec> for TimeSpec {
     }
 }
 
-// ignore other fields for now
+/// `struct itimerval` of getitimer(2)
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ITimerVal {
+    interval: TimeVal,
+    value: TimeVal,
+}
+
+impl ITimerVal {
+    fn new(value: Duration, interval: Duration) -> Self {
+        ITimerVal {
+            interval: interval.into(),
+            value: value.into(),
+        }
+    }
+
+    fn value(&self) -> Result<Duration, SysError> {
+        self.value.checked_duration()
+    }
+
+    fn interval(&self) -> Result<Duration, SysError> {
+        self.interval.checked_duration()
+    }
+}
+
+/// `struct itimerspec` of timer_settime(2)
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ITimerSpec {
+    interval: TimeSpec,
+    value: TimeSpec,
+}
+
+impl ITimerSpec {
+    fn new(value: Duration, interval: Duration) -> Self {
+        ITimerSpec {
+            interval: interval.into(),
+            value: value.into(),
+        }
+    }
+
+    fn value(&self) -> Result<Duration, SysError> {
+        self.value.checked_duration()
+    }
+
+    fn interval(&self) -> Result<Duration, SysError> {
+        self.interval.checked_duration()
+    }
+}
+
+/// The beginning of `struct sigevent` of timer_create(2)
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct SigEvent {
+    value: usize,
+    signo: i32,
+    notify: i32,
+    tid: i32,
+}
+
+/// Check the clock of a POSIX timer or timerfd, which all count the time of `timer_now`
+fn check_timer_clock(clock: usize) -> Result<(), SysError> {
+    match clock {
+        CLOCK_REALTIME | CLOCK_MONOTONIC | CLOCK_BOOTTIME => Ok(()),
+        _ => Err(SysError::EINVAL),
+    }
+}
+
+/// The time until `new.value` expires, which is since the epoch if `TIMER_ABSTIME` is set
+fn timer_value(new: &ITimerSpec, flags: usize) -> Result<Duration, SysError> {
+    let value = new.value()?;
+    if flags & TIMER_ABSTIME == 0 || value == Duration::default() {
+        return Ok(value);
+    }
+    let now = Duration::from_micros(get_epoch_usec());
+    // a time already passed expires at once
+    Ok(value
+        .checked_sub(now)
+        .filter(|left| *left != Duration::default())
+        .unwrap_or(Duration::from_nanos(1)))
+}
+
+/// The timerfd of `inode`
+fn timerfd(inode: &Arc<dyn INode>) -> Result<&TimerFd, SysError> {
+    inode
+        .as_any_ref()
+        .downcast_ref::<TimerFd>()
+        .ok_or(SysError::EINVAL)
+}
+
+/// The info of `signal` sent by the kernel
+fn kernel_siginfo(signal: Signal) -> Siginfo {
+    Siginfo {
+        signo: signal as i32,
+        errno: 0,
+        code: SI_KERNEL,
+        field: Default::default(),
+    }
+}
+
 #[repr(C)]
 pub struct RUsage {
     utime: TimeVal,
     stime: TimeVal,
+    /// maxrss, ixrss, idrss, isrss, minflt, majflt, nswap, inblock, oublock,
+    /// msgsnd, msgrcv, nsignals, nvcsw, nivcsw, which are not tracked
+    others: [isize; 14],
+}
+
+impl From<CpuTime> for RUsage {
+    fn from(time: CpuTime) -> Self {
+        RUsage {
+            utime: time.user.into(),
+            stime: time.system.into(),
+            others: [0; 14],
+        }
+    }
 }
 
 #[repr(C)]
@@ -41,6 +41,7 @@ pub fn timer() {
 
     let now = crate::arch::timer::timer_now();
     NAIVE_TIMER.lock().expire(now);
+    crate::process::handle_expired(now);
 }
 
 pub fn serial(c: u8) {
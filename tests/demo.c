@@ -1,6 +1,7 @@
 #define size_t unsigned long int
 
 static long (*bpf_trace_printk)(const char* fmt, int fmt_size, long p1, long p2, long p3) = (void*)6;
+static unsigned long long (*bpf_get_attach_cookie)(void* ctx) = (void*)67;
 
 struct GeneralRegs {
   size_t zero;
@@ -43,8 +44,25 @@ struct TrapFrame {
     size_t sepc;
 };
 
-int prog(struct TrapFrame* cx) {
-    char fmt[] = "from ebpf: the probed address is {}";
-    bpf_trace_printk(fmt, 37, cx->sepc, 0, 0);
+/* attach types */
+#define BPF_PROBE_KERNEL_INSN 0
+#define BPF_PROBE_KERNEL_FUNC 1
+#define BPF_PROBE_USER_INSN 2
+#define BPF_PROBE_USER_FUNC 3
+
+/* what a program attached to a kernel or user probe is run on */
+struct probe_context {
+    /* struct TrapFrame of a kernel probe, the user context of a user probe */
+    struct TrapFrame* regs;
+    /* address of the probe */
+    size_t addr;
+    /* BPF_PROBE_* */
+    unsigned int attach_type;
+    unsigned int pad;
+};
+
+int prog(struct probe_context* ctx) {
+    char fmt[] = "from ebpf: the probed address is {}, type {}, cookie {}";
+    bpf_trace_printk(fmt, 55, ctx->addr, ctx->attach_type, bpf_get_attach_cookie(ctx));
     return 0;
 }